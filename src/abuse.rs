@@ -0,0 +1,108 @@
+use std::{
+    collections::HashMap,
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use matrix_sdk::{
+    Client, Room,
+    ruma::{OwnedUserId, UserId},
+};
+
+use crate::{admin, state::Store};
+
+/// How many times the same source may fail within [`WINDOW`] before its
+/// sender is put on cooldown.
+const THRESHOLD: usize = 3;
+
+/// How far back failures are remembered.
+const WINDOW: Duration = Duration::from_secs(10 * 60);
+
+/// How long a cooldown lasts.
+const COOLDOWN: Duration = Duration::from_secs(15 * 60);
+
+/// A sender and the hash of the source they sent.
+type Key = (OwnedUserId, u64);
+
+/// Recent failures, by sender and source.
+static FAILURES: LazyLock<Mutex<HashMap<Key, Vec<Instant>>>> = LazyLock::new(Default::default);
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// How much longer `user` is on cooldown, if they are.
+pub async fn cooldown(store: &Store, user: &UserId) -> Option<Duration> {
+    let until = match store.cooldown(user).await {
+        Ok(until) => until?,
+        Err(err) => {
+            eprintln!("Couldn't check whether {user} is on cooldown: {err}");
+            return None;
+        }
+    };
+
+    until
+        .checked_sub(now())
+        .filter(|left| *left > 0)
+        .map(Duration::from_secs)
+}
+
+/// Record that `source` from `user` failed to render or hit a limit.
+///
+/// Sending the same failing source over and over is either spam or probing
+/// the limits, so it puts the sender on cooldown and tells the admin room.
+pub async fn record_failure(
+    client: &Client,
+    store: &Store,
+    room: &Room,
+    user: &UserId,
+    source: &str,
+) {
+    let mut hasher = DefaultHasher::new();
+    source.trim().hash(&mut hasher);
+    let key = (user.to_owned(), hasher.finish());
+
+    let failures = {
+        let mut all = FAILURES.lock().unwrap();
+        all.retain(|_, times| {
+            times.retain(|time| time.elapsed() < WINDOW);
+            !times.is_empty()
+        });
+
+        let times = all.entry(key.clone()).or_default();
+        times.push(Instant::now());
+        let failures = times.len();
+        if failures >= THRESHOLD {
+            all.remove(&key);
+        }
+
+        failures
+    };
+
+    if failures < THRESHOLD {
+        return;
+    }
+
+    let until = now() + COOLDOWN.as_secs();
+    if let Err(err) = store.set_cooldown(user, until).await {
+        eprintln!("Couldn't put {user} on cooldown: {err}");
+        return;
+    }
+
+    println!("Put {user} on cooldown for sending the same failing source {failures} times");
+    admin::notify(
+        client,
+        &format!(
+            "{user} sent the same failing source {failures} times within {} minutes in {}, \
+             they're on cooldown for {} minutes",
+            WINDOW.as_secs() / 60,
+            room.room_id(),
+            COOLDOWN.as_secs() / 60,
+        ),
+    )
+    .await;
+}
@@ -0,0 +1,209 @@
+use std::{
+    mem,
+    sync::{Mutex, OnceLock},
+    time::Duration,
+};
+
+use matrix_sdk::{
+    Client,
+    ruma::{OwnedRoomId, OwnedUserId, UserId, events::room::message::RoomMessageEventContent},
+};
+use tokio::sync::Notify;
+
+use crate::{config, queue, state::Store, stats};
+
+/// How often the audit trail is posted, batched so busy rooms don't flood the
+/// admin room.
+const AUDIT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The client [`report`] posts with, once logged in.
+static CLIENT: OnceLock<Client> = OnceLock::new();
+
+/// The audit entries not posted yet.
+static AUDIT: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Notified by `,typadmin shutdown`.
+static SHUTDOWN: Notify = Notify::const_new();
+
+const USAGE: &str = "Usage: `,typadmin rooms`, `,typadmin leave <room>`, \
+                     `,typadmin block|unblock <user>`, `,typadmin blocked`, `,typadmin queue`, `,typadmin stats` or `,typadmin shutdown`";
+
+/// Post `text` to the operator's admin room, if one is configured.
+pub async fn notify(client: &Client, text: &str) {
+    let Some(room_id) = config::get().admin.room.clone() else {
+        return;
+    };
+
+    let Some(room) = client.get_room(&room_id) else {
+        eprintln!("Can't notify {room_id}, the bot isn't in it");
+        return;
+    };
+
+    if let Err(err) = room.send(RoomMessageEventContent::notice_plain(text)).await {
+        eprintln!("Can't notify {room_id} ({err})");
+    }
+}
+
+/// Let [`report`] post with `client`.
+pub fn init(client: &Client) {
+    let _ = CLIENT.set(client.clone());
+}
+
+/// Post an error to the admin room in the background, from places that
+/// don't have the client at hand.
+pub fn report(text: String) {
+    let Some(client) = CLIENT.get().cloned() else {
+        return;
+    };
+
+    // Not supervised, reports come from the supervisor itself.
+    tokio::spawn(async move { notify(&client, &text).await });
+}
+
+/// Add a command to the audit trail, if `admin.audit` is on.
+pub fn audit(entry: String) {
+    let config = config::get();
+    if config.admin.audit && config.admin.room.is_some() {
+        AUDIT.lock().unwrap().push(entry);
+    }
+}
+
+/// Post the audit trail every [`AUDIT_INTERVAL`].
+pub async fn post_audit(client: Client) {
+    loop {
+        tokio::time::sleep(AUDIT_INTERVAL).await;
+
+        let entries = mem::take(&mut *AUDIT.lock().unwrap());
+        if !entries.is_empty() {
+            notify(&client, &format!("Audit:\n{}", entries.join("\n"))).await;
+        }
+    }
+}
+
+/// Whether `user` is one of the configured admin users.
+pub fn is_admin(user: &UserId) -> bool {
+    config::get().admin.users.iter().any(|admin| admin == user)
+}
+
+/// Wait for an admin to ask for a shutdown.
+pub async fn shutdown_requested() {
+    SHUTDOWN.notified().await;
+}
+
+/// Handle `,typadmin <command>` from `sender`, returning the reply or the
+/// text of an error reply.
+///
+/// Only the configured admin users can run these, in any room the bot is in.
+pub async fn command(
+    args: &str,
+    client: &Client,
+    sender: &UserId,
+    store: &Store,
+) -> Result<String, String> {
+    if !is_admin(sender) {
+        return Err("Only the bot's operators can use `,typadmin`".into());
+    }
+
+    let (command, arg) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+    match (command, arg.trim()) {
+        ("rooms", "") => {
+            let mut rooms = client.joined_rooms();
+            rooms.sort_by_key(|room| std::cmp::Reverse(room.joined_members_count()));
+
+            let mut lines = vec![format!("In {} room(s):", rooms.len())];
+            lines.extend(rooms.iter().map(|room| {
+                format!(
+                    "• {} {} ({} members)",
+                    room.room_id(),
+                    room.name().unwrap_or_default(),
+                    room.joined_members_count()
+                )
+            }));
+            Ok(lines.join("\n"))
+        }
+        ("leave", room_id) if !room_id.is_empty() => {
+            let room_id =
+                OwnedRoomId::try_from(room_id).map_err(|_| format!("{room_id} isn't a room ID"))?;
+            let room = client
+                .get_room(&room_id)
+                .ok_or_else(|| format!("I'm not in {room_id}"))?;
+
+            room.leave()
+                .await
+                .map_err(|err| format!("Couldn't leave {room_id}: {err}"))?;
+            println!("Left {room_id}, asked by {sender}");
+            Ok(format!("Left {room_id}"))
+        }
+        (action @ ("block" | "unblock"), user) if !user.is_empty() => {
+            let user =
+                OwnedUserId::try_from(user).map_err(|_| format!("{user} isn't a user ID"))?;
+            let block = action == "block";
+            if block && is_admin(&user) {
+                return Err(format!(
+                    "{user} is an operator, remove them from `admin.users` first"
+                ));
+            }
+            if !block && config::get().admin.blocked.contains(&user) {
+                return Err(format!(
+                    "{user} is blocked in the config, remove them from `admin.blocked` instead"
+                ));
+            }
+
+            store
+                .update(|state| {
+                    if block {
+                        state.blocked.insert(user.clone());
+                    } else {
+                        state.blocked.remove(&user);
+                    }
+                })
+                .await
+                .map_err(|err| err.to_string())?;
+            println!("{sender} {action}ed {user}");
+            Ok(if block {
+                format!("{user} is blocked, their commands are ignored")
+            } else {
+                format!("{user} can use the bot again")
+            })
+        }
+        ("blocked", "") => {
+            let mut blocked = store
+                .read(|state| state.blocked.iter().cloned().collect::<Vec<_>>())
+                .await;
+            blocked.extend(config::get().admin.blocked.iter().cloned());
+            blocked.sort();
+            blocked.dedup();
+
+            if blocked.is_empty() {
+                return Ok("Nobody is blocked".into());
+            }
+            Ok(format!(
+                "Blocked:\n{}",
+                blocked
+                    .iter()
+                    .map(|user| format!("• {user}"))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            ))
+        }
+        ("queue", "") => {
+            let (running, waiting) = queue::depth();
+            let persisted = store
+                .queued()
+                .await
+                .map_err(|err| format!("Couldn't read the queued renders: {err}"))?
+                .len();
+
+            Ok(format!(
+                "{running} render(s) running, {waiting} waiting, {persisted} kept across restarts"
+            ))
+        }
+        ("stats", "") => stats::admin(store).await,
+        ("shutdown", "") => {
+            println!("{sender} asked for a shutdown");
+            SHUTDOWN.notify_one();
+            Ok("Shutting down…".into())
+        }
+        _ => Err(USAGE.into()),
+    }
+}
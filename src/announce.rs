@@ -0,0 +1,101 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use matrix_sdk::{Client, ruma::events::room::message::RoomMessageEventContent};
+
+use crate::{changelog, config, state::Store};
+
+/// Post the startup notice to every room in `announce.rooms`.
+///
+/// Announcements are skipped when the previous one is more recent than
+/// `announce.interval` seconds (10 minutes by default), so a crash loop
+/// doesn't flood the rooms.
+pub async fn startup(client: &Client, store: &Store) -> anyhow::Result<()> {
+    let config = config::get();
+    let announce = &config.announce;
+    if announce.rooms.is_empty() {
+        return Ok(());
+    }
+
+    let interval = Duration::from_secs(announce.interval);
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+
+    let last = store.read(|state| state.last_announcement).await;
+    if last.is_some_and(|last| now.saturating_sub(Duration::from_secs(last)) < interval) {
+        println!("Skipping the startup announcement, the last one was too recent");
+        return Ok(());
+    }
+
+    store
+        .update(|state| state.last_announcement = Some(now.as_secs()))
+        .await?;
+
+    for room_id in &announce.rooms {
+        let Some(room) = client.get_room(room_id) else {
+            eprintln!("Can't announce in {room_id}, the bot isn't in it");
+            continue;
+        };
+
+        if let Err(err) = room
+            .send(RoomMessageEventContent::notice_plain(&announce.startup))
+            .await
+        {
+            eprintln!("Can't announce in {room_id} ({err})");
+        }
+    }
+
+    Ok(())
+}
+
+/// Tell the rooms that turned on `,typ whatsnew` about an upgrade, once per
+/// version.
+///
+/// Only done with `announce.upgrades`.
+pub async fn upgrade(client: &Client, store: &Store) -> anyhow::Result<()> {
+    if !config::get().announce.upgrades {
+        return Ok(());
+    }
+
+    let version = env!("CARGO_PKG_VERSION");
+    let last = store.read(|state| state.last_version.clone()).await;
+    if last.as_deref() == Some(version) {
+        return Ok(());
+    }
+
+    store
+        .update(|state| state.last_version = Some(version.to_owned()))
+        .await?;
+
+    let Some(release) = changelog::current() else {
+        return Ok(());
+    };
+    let message = format!(
+        "typit was updated to {version}: {}. See `,typ whatsnew` for everything new",
+        release.notes.first().copied().unwrap_or_default()
+    );
+
+    let rooms = store
+        .read(|state| {
+            state
+                .rooms
+                .iter()
+                .filter(|(_, settings)| settings.whatsnew)
+                .map(|(room_id, _)| room_id.clone())
+                .collect::<Vec<_>>()
+        })
+        .await;
+
+    for room_id in rooms {
+        let Some(room) = client.get_room(&room_id) else {
+            continue;
+        };
+
+        if let Err(err) = room
+            .send(RoomMessageEventContent::notice_plain(&message))
+            .await
+        {
+            eprintln!("Can't announce the upgrade in {room_id} ({err})");
+        }
+    }
+
+    Ok(())
+}
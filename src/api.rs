@@ -0,0 +1,136 @@
+use std::sync::LazyLock;
+
+use matrix_sdk::ruma::{OwnedRoomId, OwnedUserId};
+
+use crate::{
+    budget::{Deadline, Stage},
+    config, metrics,
+    options::{self, Format, PageMode},
+    queue::{self, Rejected},
+    ratelimit::{self, Limited},
+    render::{self, Output},
+    size,
+    state::Store,
+    trace::RequestId,
+};
+
+/// Who renders over HTTP are queued and rate limited as. They share one
+/// user's queue slot and limits, whichever service sends them.
+static USER: LazyLock<OwnedUserId> =
+    LazyLock::new(|| OwnedUserId::try_from("@typit-http:localhost").unwrap());
+static ROOM: LazyLock<OwnedRoomId> =
+    LazyLock::new(|| OwnedRoomId::try_from("!typit-http:localhost").unwrap());
+
+/// An HTTP response: the status line, the content type and the body.
+pub type Response = (&'static str, &'static str, Vec<u8>);
+
+fn text(status: &'static str, body: impl Into<String>) -> Response {
+    (
+        status,
+        "text/plain; charset=utf-8",
+        body.into().into_bytes(),
+    )
+}
+
+/// Whether `authorization`, the `Authorization` header, carries the
+/// configured `http.render_token`.
+pub fn authorized(authorization: Option<&str>) -> bool {
+    let config = config::get();
+    let Some(token) = &config.http.render_token else {
+        return false;
+    };
+
+    authorization
+        .and_then(|authorization| authorization.strip_prefix("Bearer "))
+        .is_some_and(|given| given.trim() == token)
+}
+
+/// Handle `POST /render`, whose body is what would follow `,typ`: leading
+/// `--flag`s, then the Typst source.
+///
+/// The result is a PNG, multi-page documents stitched into one, or a PDF with
+/// `--pdf`. Renders go through the same queue, rate limits and time budget as
+/// the ones asked for in rooms.
+pub async fn render(body: &str, store: &Store) -> Response {
+    let id = RequestId::new();
+    metrics::RENDERS.inc();
+
+    let (mut options, source) = match options::parse(body) {
+        Ok(parsed) => parsed,
+        Err(err) => return text("400 Bad Request", err),
+    };
+    if options.dm || options.pages == PageMode::Separate {
+        return text("400 Bad Request", "`--dm` and `--pages` only work in rooms");
+    }
+    if source.trim().is_empty() {
+        return text("400 Bad Request", "There's no source to render");
+    }
+    if source.len() > size::max_source() {
+        return text(
+            "413 Content Too Large",
+            format!("The source is longer than {} bytes", size::max_source()),
+        );
+    }
+
+    match ratelimit::take(store, &USER, &ROOM).await {
+        Ok(None) => {}
+        Ok(Some(Limited::User(wait) | Limited::Room(wait))) => {
+            return text(
+                "429 Too Many Requests",
+                format!("Rate limited, try again in {}s", wait.as_secs() + 1),
+            );
+        }
+        Err(err) => eprintln!("[{id}] Couldn't check the rate limit: {err}"),
+    }
+
+    let mut ticket = match queue::join(&USER, false).await {
+        Ok(ticket) => ticket,
+        Err(Rejected::Busy | Rejected::Full) => {
+            return text("503 Service Unavailable", "Too many renders are queued");
+        }
+        Err(Rejected::Closed) => return text("503 Service Unavailable", "Shutting down"),
+    };
+    let Some(_permit) = ticket.wait().await else {
+        return text("503 Service Unavailable", "Shutting down");
+    };
+
+    println!("[{id}] Rendering over HTTP");
+    if options.flavor.is_none() {
+        options.flavor = config::get().render.flavor;
+    }
+    let deadline = Deadline::start(id);
+    let output = match render::render(source, &options, &deadline, id).await {
+        Ok(output) => output,
+        Err(err) => {
+            eprintln!("[{id}] Couldn't render over HTTP: {err}");
+            return text(
+                "500 Internal Server Error",
+                format!("Couldn't render, ref: {id}"),
+            );
+        }
+    };
+
+    match output {
+        Output::Pages { mut pages, .. } => {
+            if options.format == Format::Pdf {
+                return ("200 OK", "application/pdf", pages.remove(0));
+            }
+            if pages.len() > 1 {
+                let stitched = tokio::task::spawn_blocking(move || render::stitch(&pages));
+                return match deadline.run(Stage::Encode, stitched).await {
+                    Ok(Ok(Ok(page))) => ("200 OK", "image/png", page),
+                    Ok(_) => text(
+                        "500 Internal Server Error",
+                        format!("Couldn't stitch the pages, ref: {id}"),
+                    ),
+                    Err(stopped) => text("504 Gateway Timeout", stopped.to_string()),
+                };
+            }
+
+            ("200 OK", "image/png", pages.remove(0))
+        }
+        Output::Error(err) => text("422 Unprocessable Content", err),
+        Output::Timeout(exceeded) => text("504 Gateway Timeout", exceeded.to_string()),
+        Output::Cancelled => text("503 Service Unavailable", "Cancelled"),
+    }
+}
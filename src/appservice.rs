@@ -0,0 +1,181 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex, OnceLock},
+};
+
+use matrix_sdk::{
+    Client, Room, RoomState,
+    event_handler::Ctx,
+    ruma::{OwnedRoomId, OwnedUserId, RoomId, UserId, api::client::membership::leave_room},
+};
+use serde::Deserialize;
+use serde_json::{Value, json};
+
+use crate::{api::Response, config, invites, message, policy, state::Store};
+
+/// How many transaction IDs are remembered, homeservers retry the ones they
+/// didn't get an answer to.
+const SEEN_TRANSACTIONS: usize = 256;
+
+/// The client transactions are handled with, once logged in.
+static CLIENT: OnceLock<Client> = OnceLock::new();
+
+static SEEN: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+
+/// Whether the bot runs as an application service instead of syncing.
+pub fn enabled() -> bool {
+    config::get().appservice.as_token.is_some()
+}
+
+/// Handle the transactions the homeserver pushes with `client`.
+pub fn init(client: &Client) {
+    let _ = CLIENT.set(client.clone());
+}
+
+/// The bot's user, which the registration's `sender_localpart` is for.
+pub fn user_id() -> anyhow::Result<OwnedUserId> {
+    Ok(UserId::parse(&config::get().matrix.username)?)
+}
+
+/// The registration file to give the homeserver, in YAML.
+///
+/// Strings are written as JSON, which is valid YAML too.
+pub fn registration() -> anyhow::Result<String> {
+    let config = config::get();
+    let appservice = &config.appservice;
+    let user_id = user_id()?;
+    let url = match (&appservice.url, config.http.address) {
+        (Some(url), _) => url.clone(),
+        (None, Some(address)) => format!("http://{address}"),
+        (None, None) => anyhow::bail!("`appservice.url` or `http.address` must be set"),
+    };
+    let quote = |s: &str| json!(s).to_string();
+
+    Ok(format!(
+        "id: {}\nurl: {}\nas_token: {}\nhs_token: {}\nsender_localpart: {}\n\
+         rate_limited: false\nnamespaces:\n  users: []\n  aliases: []\n  rooms: []\n",
+        quote(&appservice.id),
+        quote(&url),
+        quote(appservice.as_token.as_deref().unwrap_or_default()),
+        quote(appservice.hs_token.as_deref().unwrap_or_default()),
+        quote(user_id.localpart()),
+    ))
+}
+
+#[derive(Deserialize)]
+struct Transaction {
+    events: Vec<Value>,
+}
+
+/// Handle the transaction `id` the homeserver pushed to
+/// `PUT /_matrix/app/v1/transactions/{id}`.
+///
+/// Its messages, reactions and invites go through the same handlers as the
+/// ones a sync gets.
+pub async fn transaction(
+    id: &str,
+    authorization: Option<&str>,
+    body: &[u8],
+    store: &Arc<Store>,
+) -> Response {
+    let config = config::get();
+    let authorized = authorization
+        .and_then(|authorization| authorization.strip_prefix("Bearer "))
+        .zip(config.appservice.hs_token.as_deref())
+        .is_some_and(|(given, token)| given.trim() == token);
+    if !authorized {
+        return error("403 Forbidden", "M_FORBIDDEN", "Wrong hs_token");
+    }
+    let Some(client) = CLIENT.get() else {
+        return error("503 Service Unavailable", "M_UNKNOWN", "Starting up");
+    };
+
+    {
+        let mut seen = SEEN.lock().unwrap();
+        if seen.iter().any(|seen| seen == id) {
+            return ("200 OK", "application/json", b"{}".to_vec());
+        }
+        if seen.len() >= SEEN_TRANSACTIONS {
+            seen.pop_front();
+        }
+        seen.push_back(id.to_owned());
+    }
+
+    let transaction: Transaction = match serde_json::from_slice(body) {
+        Ok(transaction) => transaction,
+        Err(err) => return error("400 Bad Request", "M_NOT_JSON", &err.to_string()),
+    };
+    for event in transaction.events {
+        if let Err(err) = dispatch(client, store, event).await {
+            eprintln!("Couldn't handle an event of transaction {id}: {err}");
+        }
+    }
+
+    ("200 OK", "application/json", b"{}".to_vec())
+}
+
+fn error(status: &'static str, errcode: &str, message: &str) -> Response {
+    let body = json!({ "errcode": errcode, "error": message });
+
+    (status, "application/json", body.to_string().into_bytes())
+}
+
+async fn dispatch(client: &Client, store: &Arc<Store>, event: Value) -> anyhow::Result<()> {
+    let Some(room_id) = event["room_id"].as_str() else {
+        return Ok(());
+    };
+    let room_id = RoomId::parse(room_id)?;
+
+    match event["type"].as_str() {
+        Some("m.room.message") => {
+            let room = room(client, &room_id).await?;
+            let event = serde_json::from_value(event)?;
+            message::on_room_message(event, room, client.clone(), Ctx(store.clone())).await;
+        }
+        Some("m.reaction") => {
+            let room = room(client, &room_id).await?;
+            let event = serde_json::from_value(event)?;
+            message::on_reaction(event, room, client.clone(), Ctx(store.clone())).await;
+        }
+        Some("m.room.power_levels") => {
+            let room = room(client, &room_id).await?;
+            let event = serde_json::from_value(event)?;
+            policy::on_power_levels(event, room, Ctx(store.clone())).await;
+        }
+        Some("m.room.member")
+            if event["state_key"].as_str() == Some(user_id()?.as_str())
+                && event["content"]["membership"] == "invite" =>
+        {
+            let inviter = UserId::parse(event["sender"].as_str().unwrap_or_default())?;
+            invited(client, room_id, &inviter).await?;
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+/// `room_id`, joined if the client doesn't know it as joined yet. Without a
+/// sync, joining is how the client learns about a room.
+async fn room(client: &Client, room_id: &RoomId) -> anyhow::Result<Room> {
+    match client.get_room(room_id) {
+        Some(room) if room.state() == RoomState::Joined => Ok(room),
+        _ => Ok(client.join_room_by_id(room_id).await?),
+    }
+}
+
+/// Accept the invite to `room_id` unless [`invites::refusal`] refuses it.
+async fn invited(client: &Client, room_id: OwnedRoomId, inviter: &UserId) -> anyhow::Result<()> {
+    if let Some(reason) = invites::refusal(&room_id, inviter) {
+        println!("Refused the invite to {room_id} from {inviter}: {reason}");
+        if config::get().invites.reject {
+            client.send(leave_room::v3::Request::new(room_id)).await?;
+        }
+        return Ok(());
+    }
+
+    client.join_room_by_id(&room_id).await?;
+    println!("Joined {room_id}, invited by {inviter}");
+
+    Ok(())
+}
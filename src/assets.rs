@@ -0,0 +1,331 @@
+use std::path::{Path, PathBuf};
+
+use matrix_sdk::{
+    Client, Room,
+    media::{MediaFormat, MediaRequestParameters},
+    ruma::{
+        EventId, RoomId, UserId,
+        events::{
+            AnySyncMessageLikeEvent, AnySyncTimelineEvent, SyncMessageLikeEvent,
+            room::{MediaSource, message::MessageType},
+        },
+    },
+};
+use sha2::{Digest, Sha256};
+use tokio::fs;
+
+use crate::{
+    config,
+    options::RenderOptions,
+    policy,
+    state::{Asset, Store},
+};
+
+/// How many assets a room can have.
+const MAX_ASSETS: usize = 50;
+
+/// The longest asset name.
+const MAX_NAME_LENGTH: usize = 64;
+
+/// In bytes, the largest asset.
+const MAX_SIZE: u64 = 2 * 1024 * 1024;
+
+/// In bytes, how much the assets of a room can take up together.
+const MAX_ROOM_SIZE: u64 = 20 * 1024 * 1024;
+
+/// The file types typst can use as images.
+const EXTENSIONS: &[&str] = &["png", "jpg", "jpeg", "gif", "svg", "webp"];
+
+const USAGE: &str = "Usage: `,asset upload <name>` as the caption of an image or replying to \
+                     one, `,asset list` or `,asset delete <name>`";
+
+/// The assets of a room, for a render to use.
+#[derive(Debug, Clone)]
+pub struct Assets {
+    pub dir: PathBuf,
+    /// Changes whenever an asset does, so cached renders aren't reused.
+    pub version: String,
+}
+
+/// Where the file to upload with `,asset upload` is.
+pub enum Attachment<'a> {
+    /// In the message the command replies to.
+    Event(&'a EventId),
+    /// In the message the command is the caption of.
+    Media(MediaSource),
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// The asset directory of `room`, in `storage.assets_dir`.
+///
+/// Room IDs can have any character in them, so the directory is named after
+/// a hash of it.
+fn dir(room: &RoomId) -> PathBuf {
+    config::get()
+        .storage
+        .assets_dir
+        .join(hex(&Sha256::digest(room.as_str())[..16]))
+}
+
+/// Let renders with `options` in `room` use its assets, if it has any.
+pub async fn attach(room: &Room, store: &Store, options: &mut RenderOptions) {
+    let assets = store.room(room.room_id()).await.assets;
+    if assets.is_empty() {
+        return;
+    }
+
+    let mut hashes: Vec<_> = assets
+        .iter()
+        .map(|(name, asset)| format!("{name}:{}", asset.hash))
+        .collect();
+    hashes.sort();
+
+    options.assets = Some(Assets {
+        dir: dir(room.room_id()),
+        version: hex(&Sha256::digest(hashes.join("/"))),
+    });
+}
+
+/// Copy `assets` to `assets/` in the scratch directory `scratch`, where the
+/// documents find them.
+pub async fn copy(assets: &Assets, scratch: &Path) -> std::io::Result<()> {
+    let target = scratch.join("assets");
+    fs::create_dir_all(&target).await?;
+
+    let mut entries = fs::read_dir(&assets.dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        fs::copy(entry.path(), target.join(entry.file_name())).await?;
+    }
+
+    Ok(())
+}
+
+/// Delete the asset directory of `room`, once the bot left it for good.
+pub async fn forget(room: &RoomId) {
+    let dir = dir(room);
+    if let Err(err) = fs::remove_dir_all(&dir).await
+        && err.kind() != std::io::ErrorKind::NotFound
+    {
+        eprintln!("Couldn't delete the assets of {room} ({err})");
+    }
+}
+
+/// Handle `,asset upload|list|delete`, returning the text of the reply.
+///
+/// Assets are shared by the whole room, and documents use them as
+/// `assets/<name>`.
+pub async fn command(
+    args: &str,
+    attachment: Option<Attachment<'_>>,
+    room: &Room,
+    client: &Client,
+    sender: &UserId,
+    store: &Store,
+) -> Result<String, String> {
+    let args = args.trim();
+    let (action, name) = args
+        .split_once(char::is_whitespace)
+        .map_or((args, ""), |(action, name)| (action, name.trim()));
+
+    let assets = store.room(room.room_id()).await.assets;
+
+    match action {
+        "upload" if !name.is_empty() => {
+            let valid = name.len() <= MAX_NAME_LENGTH
+                && !name.starts_with('.')
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+            if !valid {
+                return Err(format!(
+                    "Asset names are up to {MAX_NAME_LENGTH} letters, digits, `-`, `_` and `.`"
+                ));
+            }
+            let extension = name
+                .rsplit_once('.')
+                .map(|(_, extension)| extension.to_ascii_lowercase())
+                .filter(|extension| EXTENSIONS.contains(&extension.as_str()))
+                .ok_or_else(|| format!("Asset names end in .{}", EXTENSIONS.join(", .")))?;
+
+            match assets.get(name) {
+                Some(existing) if !can_change(existing, room, sender).await => {
+                    return Err(format!(
+                        "`{name}` was uploaded by {}, only they and moderators can replace it",
+                        existing.author
+                    ));
+                }
+                None if assets.len() >= MAX_ASSETS => {
+                    return Err(format!(
+                        "This room already has {MAX_ASSETS} assets, delete one first"
+                    ));
+                }
+                _ => {}
+            }
+
+            let source = match attachment {
+                Some(Attachment::Media(source)) => source,
+                Some(Attachment::Event(event)) => replied_media(room, event).await?,
+                None => return Err(USAGE.into()),
+            };
+            let data = client
+                .media()
+                .get_media_content(
+                    &MediaRequestParameters {
+                        source,
+                        format: MediaFormat::File,
+                    },
+                    false,
+                )
+                .await
+                .map_err(|err| {
+                    eprintln!("Couldn't download the asset {name}: {err}");
+                    "Couldn't download the file".to_owned()
+                })?;
+
+            let size = data.len() as u64;
+            if size > MAX_SIZE {
+                return Err(format!("Assets can be up to {} KiB large", MAX_SIZE / 1024));
+            }
+            let others: u64 = assets
+                .iter()
+                .filter(|(other, _)| *other != name)
+                .map(|(_, asset)| asset.size)
+                .sum();
+            if others + size > MAX_ROOM_SIZE {
+                return Err(format!(
+                    "The assets here can take up to {} MiB together, delete some first",
+                    MAX_ROOM_SIZE / 1024 / 1024
+                ));
+            }
+            let is_image = match extension.as_str() {
+                "svg" => std::str::from_utf8(&data).is_ok_and(|svg| svg.contains("<svg")),
+                _ => image::guess_format(&data).is_ok(),
+            };
+            if !is_image {
+                return Err(format!("That file isn't a .{extension} image"));
+            }
+
+            let dir = dir(room.room_id());
+            let written = async {
+                fs::create_dir_all(&dir).await?;
+                fs::write(dir.join(name), &data).await
+            };
+            written.await.map_err(|err| {
+                eprintln!("Couldn't write the asset {name}: {err}");
+                "Couldn't save the file".to_owned()
+            })?;
+
+            let asset = Asset {
+                author: sender.to_owned(),
+                size,
+                hash: hex(&Sha256::digest(&data)),
+            };
+            save(room, store, name, Some(asset)).await?;
+
+            Ok(format!(
+                "Saved `{name}` ({} KiB), use it with `#image(\"assets/{name}\")`",
+                size.div_ceil(1024)
+            ))
+        }
+        "list" => {
+            if assets.is_empty() {
+                return Ok("No assets here yet, upload one with `,asset upload <name>`".into());
+            }
+
+            let mut names: Vec<_> = assets.iter().collect();
+            names.sort_by_key(|(name, _)| *name);
+            let total: u64 = assets.values().map(|asset| asset.size).sum();
+
+            let mut lines = vec![format!(
+                "{} asset(s) here, {} of {} KiB used:",
+                names.len(),
+                total.div_ceil(1024),
+                MAX_ROOM_SIZE / 1024
+            )];
+            lines.extend(names.into_iter().map(|(name, asset)| {
+                format!(
+                    "• assets/{name} ({} KiB, by {})",
+                    asset.size.div_ceil(1024),
+                    asset.author
+                )
+            }));
+
+            Ok(lines.join("\n"))
+        }
+        "delete" if !name.is_empty() => {
+            let asset = assets.get(name).ok_or_else(|| {
+                format!("There's no asset called `{name}` here, see `,asset list`")
+            })?;
+            if !can_change(asset, room, sender).await {
+                return Err(format!(
+                    "`{name}` was uploaded by {}, only they and moderators can delete it",
+                    asset.author
+                ));
+            }
+
+            if let Err(err) = fs::remove_file(dir(room.room_id()).join(name)).await {
+                eprintln!("Couldn't delete the asset {name}: {err}");
+            }
+            save(room, store, name, None).await?;
+            Ok(format!("Deleted `{name}`"))
+        }
+        _ => Err(USAGE.into()),
+    }
+}
+
+/// The image or file in the message `event_id` of `room`.
+async fn replied_media(room: &Room, event_id: &EventId) -> Result<MediaSource, String> {
+    let event = room.event(event_id, None).await.map_err(|err| {
+        eprintln!("Couldn't fetch the replied to {event_id}: {err}");
+        "Couldn't fetch the message you replied to".to_owned()
+    })?;
+
+    match event.raw().deserialize() {
+        Ok(AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::RoomMessage(
+            SyncMessageLikeEvent::Original(event),
+        ))) => media(&event.content.msgtype),
+        _ => None,
+    }
+    .ok_or_else(|| "Reply to an image or a file to upload it".into())
+}
+
+/// Where the image or file in `msgtype` is, if it has one.
+pub fn media(msgtype: &MessageType) -> Option<MediaSource> {
+    match msgtype {
+        MessageType::Image(image) => Some(image.source.clone()),
+        MessageType::File(file) => Some(file.source.clone()),
+        _ => None,
+    }
+}
+
+/// Whether `user` may replace or delete `asset`, being its author or a
+/// moderator.
+async fn can_change(asset: &Asset, room: &Room, user: &UserId) -> bool {
+    asset.author == user || policy::is_moderator(room, user).await
+}
+
+/// Replace the metadata of the asset `name` of `room`, or delete it.
+async fn save(room: &Room, store: &Store, name: &str, asset: Option<Asset>) -> Result<(), String> {
+    store
+        .update(|state| {
+            let assets = &mut state
+                .rooms
+                .entry(room.room_id().to_owned())
+                .or_default()
+                .assets;
+
+            match asset {
+                Some(asset) => {
+                    assets.insert(name.to_owned(), asset);
+                }
+                None => {
+                    assets.remove(name);
+                }
+            }
+        })
+        .await
+        .map_err(|err| err.to_string())
+}
@@ -0,0 +1,103 @@
+/// The most math segments of a message rendered, the rest is left out.
+const MAX_SEGMENTS: usize = 8;
+
+/// The Typst math in `body`, its `$...$` segments one per paragraph, if it
+/// has any.
+///
+/// Dollars are common outside of math, prices above all, so a segment only
+/// counts as `$x$`, without spaces inside the dollars, or `$ x $`, with
+/// spaces inside both like Typst's display math. It mustn't span lines, be
+/// preceded by a letter or digit (`US$5`) nor followed by a digit, and `\$`
+/// and code spans are skipped.
+pub fn source(body: &str) -> Option<String> {
+    let mut segments = vec![];
+
+    for line in body.lines() {
+        let mut opening = None;
+        let mut in_code = false;
+        let mut escaped = false;
+
+        for (i, c) in line.char_indices() {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '`' => {
+                    in_code = !in_code;
+                    opening = None;
+                }
+                '$' if !in_code => match opening {
+                    Some(start) if is_math(line, start, i) => {
+                        segments.push(&line[start..=i]);
+                        opening = None;
+                    }
+                    _ => {
+                        let glued = line[..i]
+                            .chars()
+                            .next_back()
+                            .is_some_and(char::is_alphanumeric);
+                        opening = (!glued).then_some(i);
+                    }
+                },
+                _ => {}
+            }
+        }
+    }
+
+    segments.truncate(MAX_SEGMENTS);
+    (!segments.is_empty()).then(|| segments.join("\n\n"))
+}
+
+/// Whether the dollars at `start` and `end` of `line` delimit math.
+fn is_math(line: &str, start: usize, end: usize) -> bool {
+    let inner = &line[start + 1..end];
+    let spaced = |c: Option<char>| c.is_some_and(char::is_whitespace);
+
+    !inner.trim().is_empty()
+        && spaced(inner.chars().next()) == spaced(inner.chars().next_back())
+        && !line[end + 1..].starts_with(|c: char| c.is_ascii_digit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_math() {
+        assert_eq!(source("so $x^2$ it is").as_deref(), Some("$x^2$"));
+        assert_eq!(source("$ a + b $").as_deref(), Some("$ a + b $"));
+        assert_eq!(source("$a$ and\n$b$").as_deref(), Some("$a$\n\n$b$"));
+    }
+
+    #[test]
+    fn skips_prices() {
+        assert_eq!(source("it's $5 or $10"), None);
+        assert_eq!(source("it's US$5"), None);
+        assert_eq!(source("$5$10"), None);
+    }
+
+    #[test]
+    fn skips_escapes_and_code() {
+        assert_eq!(source(r"\$x$ is fine"), None);
+        assert_eq!(source("`$x$`"), None);
+        assert_eq!(source("`$` then $y$").as_deref(), Some("$y$"));
+    }
+
+    #[test]
+    fn needs_matching_spaces() {
+        assert_eq!(source("$ x$"), None);
+        assert_eq!(source("$x $"), None);
+        assert_eq!(source("$  $"), None);
+    }
+
+    #[test]
+    fn stays_on_one_line() {
+        assert_eq!(source("$x\ny$"), None);
+    }
+
+    #[test]
+    fn renders_at_most_max_segments() {
+        let body = "$a$ ".repeat(MAX_SEGMENTS + 2);
+
+        assert_eq!(source(&body).unwrap().matches("$a$").count(), MAX_SEGMENTS);
+    }
+}
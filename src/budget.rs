@@ -0,0 +1,224 @@
+use std::{
+    fmt,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use tokio_util::sync::CancellationToken;
+
+use crate::{config, templates, trace::RequestId};
+
+/// The stages a render goes through, each with its own time budget.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Compile,
+    Encode,
+    Upload,
+    Send,
+}
+
+impl Stage {
+    pub fn name(self) -> &'static str {
+        match self {
+            Stage::Compile => "compile",
+            Stage::Encode => "encode",
+            Stage::Upload => "upload",
+            Stage::Send => "send",
+        }
+    }
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// How long each stage, and a whole request, may take.
+///
+/// Configured in the `timeouts` section of the [config](crate::config).
+#[derive(Debug)]
+pub struct Budget {
+    pub compile: Duration,
+    pub encode: Duration,
+    pub upload: Duration,
+    pub send: Duration,
+    pub total: Duration,
+}
+
+impl Budget {
+    fn from_config() -> Self {
+        let timeouts = &config::get().timeouts;
+
+        Self {
+            compile: Duration::from_secs(timeouts.compile),
+            encode: Duration::from_secs(timeouts.encode),
+            upload: Duration::from_secs(timeouts.upload),
+            send: Duration::from_secs(timeouts.send),
+            total: Duration::from_secs(timeouts.total),
+        }
+    }
+
+    pub fn stage(&self, stage: Stage) -> Duration {
+        match stage {
+            Stage::Compile => self.compile,
+            Stage::Encode => self.encode,
+            Stage::Upload => self.upload,
+            Stage::Send => self.send,
+        }
+    }
+}
+
+/// The configured budget, which changes when the config is reloaded.
+pub fn get() -> Budget {
+    Budget::from_config()
+}
+
+/// A stage ran out of time.
+#[derive(Debug, Clone, Copy)]
+pub struct Exceeded {
+    pub stage: Stage,
+    pub limit: Duration,
+}
+
+impl fmt::Display for Exceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let template = match self.stage {
+            Stage::Compile => &templates::get().timeout,
+            _ => &templates::get().stage_timeout,
+        };
+
+        f.write_str(
+            &templates::fill_limit(template, self.limit.as_secs())
+                .replace("{stage}", self.stage.name()),
+        )
+    }
+}
+
+/// Why a request stopped before it was done.
+#[derive(Debug, Clone, Copy)]
+pub enum Stopped {
+    Exceeded(Exceeded),
+    /// The request was cancelled, nobody is waiting for it anymore.
+    Cancelled,
+}
+
+impl From<Exceeded> for Stopped {
+    fn from(exceeded: Exceeded) -> Self {
+        Stopped::Exceeded(exceeded)
+    }
+}
+
+impl fmt::Display for Stopped {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Stopped::Exceeded(exceeded) => exceeded.fmt(f),
+            Stopped::Cancelled => f.write_str("The request was cancelled"),
+        }
+    }
+}
+
+/// Tracks the time spent by a single request, and whether it was cancelled.
+#[derive(Debug, Clone)]
+pub struct Deadline {
+    started: Instant,
+    /// What the budgets are divided by.
+    divisor: u32,
+    id: RequestId,
+    cancel: CancellationToken,
+    /// The time spent compiling so far, in milliseconds.
+    compiling: Arc<AtomicU64>,
+}
+
+impl Deadline {
+    pub fn start(id: RequestId) -> Self {
+        Self {
+            started: Instant::now(),
+            divisor: 1,
+            id,
+            cancel: CancellationToken::new(),
+            compiling: Arc::default(),
+        }
+    }
+
+    /// The request this is the deadline of.
+    pub fn id(&self) -> RequestId {
+        self.id
+    }
+
+    /// The same deadline with every budget halved.
+    pub fn strict(self) -> Self {
+        Self { divisor: 2, ..self }
+    }
+
+    /// The same deadline, cancelled along with `cancel`.
+    pub fn cancelled_by(self, cancel: CancellationToken) -> Self {
+        Self { cancel, ..self }
+    }
+
+    /// Count `elapsed` as time spent compiling, for the usage statistics.
+    pub fn compiled(&self, elapsed: Duration) {
+        self.compiling
+            .fetch_add(elapsed.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// The time spent compiling so far, zero if every result was cached.
+    pub fn compile_time(&self) -> Duration {
+        Duration::from_millis(self.compiling.load(Ordering::Relaxed))
+    }
+
+    /// Resolves once the request is cancelled.
+    pub async fn cancelled(&self) {
+        self.cancel.cancelled().await
+    }
+
+    /// When `stage`, starting now, has to be done by (its own budget, or
+    /// whatever is left of the total), and the error to report if it isn't.
+    pub fn window(&self, stage: Stage) -> (Instant, Exceeded) {
+        let now = Instant::now();
+        let end = (now + get().stage(stage) / self.divisor)
+            .min(self.started + get().total / self.divisor);
+
+        (
+            end,
+            Exceeded {
+                stage,
+                limit: end.saturating_duration_since(now),
+            },
+        )
+    }
+
+    /// Log that `exceeded` happened, and hand it back.
+    pub fn record(&self, exceeded: Exceeded) -> Exceeded {
+        println!(
+            "[{}] The {} stage ran out of time ({:?})",
+            self.id, exceeded.stage, exceeded.limit
+        );
+
+        exceeded
+    }
+
+    /// Run `future` as `stage`, giving up when it's over budget or the
+    /// request is cancelled.
+    pub async fn run<F: IntoFuture>(&self, stage: Stage, future: F) -> Result<F::Output, Stopped> {
+        if self.cancel.is_cancelled() {
+            return Err(Stopped::Cancelled);
+        }
+
+        let (end, exceeded) = self.window(stage);
+
+        tokio::select! {
+            biased;
+            _ = self.cancel.cancelled() => {
+                println!("[{}] Cancelled during the {stage} stage", self.id);
+                Err(Stopped::Cancelled)
+            }
+            output = tokio::time::timeout_at(end.into(), future) => {
+                output.map_err(|_| self.record(exceeded).into())
+            }
+        }
+    }
+}
@@ -0,0 +1,289 @@
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::{config, options::RenderOptions, render::Output, storage};
+
+/// Recent compile results, so popular snippets skip the compiler.
+///
+/// Holds up to `render.cache_size` results (64 by default, 0 turns it off),
+/// each for `render.cache_ttl` seconds (an hour by default), evicting the
+/// least recently used one when full.
+///
+/// Instances sharing their storage keep the results in the [shared]
+/// (storage::shared) database instead, so one compiles for all of them.
+#[derive(Default)]
+struct Cache {
+    entries: HashMap<String, Entry>,
+    /// Bumped on every access, to find the least recently used entry.
+    clock: u64,
+}
+
+struct Entry {
+    output: Output,
+    inserted: Instant,
+    used: u64,
+}
+
+impl Cache {
+    fn get(&mut self, key: &str, ttl: Duration) -> Option<Output> {
+        self.clock += 1;
+
+        let entry = self.entries.get_mut(key)?;
+        if entry.inserted.elapsed() > ttl {
+            self.entries.remove(key);
+            return None;
+        }
+
+        entry.used = self.clock;
+        Some(entry.output.clone())
+    }
+
+    fn insert(&mut self, key: String, output: &Output, size: usize, ttl: Duration) {
+        if size == 0 {
+            return;
+        }
+
+        self.entries
+            .retain(|_, entry| entry.inserted.elapsed() <= ttl);
+
+        // The size may have shrunk with a config reload.
+        while self.entries.len() >= size
+            && let Some(oldest) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.used)
+                .map(|(key, _)| key.clone())
+        {
+            self.entries.remove(&oldest);
+        }
+
+        self.clock += 1;
+        self.entries.insert(
+            key,
+            Entry {
+                output: output.clone(),
+                inserted: Instant::now(),
+                used: self.clock,
+            },
+        );
+    }
+}
+
+static CACHE: LazyLock<Mutex<Cache>> = LazyLock::new(Mutex::default);
+
+/// The cache key of rendering `source` with `options`, with the fallback
+/// preamble if `fallback`.
+pub fn key(source: &str, options: &RenderOptions, fallback: bool) -> String {
+    let flags = format!(
+        "{:?}/{:?}/{:?}/{}/{}/{:?}/{:?}/{}/{}/{}/{}/{:?}/{fallback}",
+        options.engine,
+        options.format,
+        options.ppi,
+        options.raw,
+        options.safe,
+        options.flavor.unwrap_or_default(),
+        options.size,
+        options.style.margin(),
+        options.style.radius(),
+        options.style.shadow(),
+        options.math,
+        options.assets.as_ref().map(|assets| &assets.version),
+    );
+
+    let mut hasher = Sha256::new();
+    hasher.update(flags.as_bytes());
+    hasher.update([0]);
+    hasher.update(source.as_bytes());
+
+    hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// The cached output under `key`, if it's still fresh.
+pub async fn get(key: &str) -> Option<Output> {
+    let ttl = config::get().render.cache_ttl;
+
+    let shared_key = key.to_owned();
+    match storage::shared(move |shared| shared.cached(&shared_key, ttl)).await {
+        Some(Ok(cached)) => cached.as_deref().and_then(decode),
+        Some(Err(err)) => {
+            eprintln!("Couldn't read the shared render cache: {err}");
+            None
+        }
+        None => CACHE.lock().unwrap().get(key, Duration::from_secs(ttl)),
+    }
+}
+
+/// Cache `output` under `key`, if it's a compile result rather than a timeout.
+pub async fn insert(key: String, output: &Output) {
+    if !matches!(output, Output::Pages { .. } | Output::Error(_)) {
+        return;
+    }
+
+    let config = config::get();
+    let (size, ttl) = (config.render.cache_size, config.render.cache_ttl);
+
+    if storage::is_shared() {
+        let Some(encoded) = encode(output).filter(|_| size > 0) else {
+            return;
+        };
+        if let Some(Err(err)) =
+            storage::shared(move |shared| shared.cache(&key, &encoded, size)).await
+        {
+            eprintln!("Couldn't write to the shared render cache: {err}");
+        }
+        return;
+    }
+
+    CACHE
+        .lock()
+        .unwrap()
+        .insert(key, output, size, Duration::from_secs(ttl));
+}
+
+/// `output` as stored in the shared cache, if it's cached at all: `E` and the
+/// diagnostics, or `P`, the warnings and every page, each after its length.
+fn encode(output: &Output) -> Option<Vec<u8>> {
+    let mut encoded = vec![];
+    let mut push = |bytes: &[u8]| {
+        encoded.extend_from_slice(&(bytes.len() as u64).to_le_bytes());
+        encoded.extend_from_slice(bytes);
+    };
+
+    let tag = match output {
+        Output::Error(diagnostics) => {
+            push(diagnostics.as_bytes());
+            b'E'
+        }
+        Output::Pages { pages, warnings } => {
+            push(warnings.as_bytes());
+            for page in pages {
+                push(page);
+            }
+            b'P'
+        }
+        Output::Timeout(_) | Output::Cancelled => return None,
+    };
+    encoded.insert(0, tag);
+
+    Some(encoded)
+}
+
+/// The output [`encode`]d as `encoded`.
+fn decode(encoded: &[u8]) -> Option<Output> {
+    let (&tag, mut rest) = encoded.split_first()?;
+    let mut parts = vec![];
+    while !rest.is_empty() {
+        let (len, after) = rest.split_first_chunk::<8>()?;
+        let len = usize::try_from(u64::from_le_bytes(*len)).ok()?;
+        if after.len() < len {
+            return None;
+        }
+        let (part, after) = after.split_at(len);
+        parts.push(part.to_vec());
+        rest = after;
+    }
+
+    let mut parts = parts.into_iter();
+    let text = String::from_utf8(parts.next()?).ok()?;
+    match tag {
+        b'E' => Some(Output::Error(text)),
+        b'P' => Some(Output::Pages {
+            pages: parts.collect(),
+            warnings: text,
+        }),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TTL: Duration = Duration::from_secs(60);
+
+    fn output(text: &str) -> Output {
+        Output::Error(text.into())
+    }
+
+    fn cached(cache: &mut Cache, key: &str, ttl: Duration) -> Option<String> {
+        match cache.get(key, ttl)? {
+            Output::Error(text) => Some(text),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used() {
+        let mut cache = Cache::default();
+        cache.insert("a".into(), &output("a"), 2, TTL);
+        cache.insert("b".into(), &output("b"), 2, TTL);
+
+        // Using `a` makes `b` the oldest.
+        assert_eq!(cached(&mut cache, "a", TTL).as_deref(), Some("a"));
+        cache.insert("c".into(), &output("c"), 2, TTL);
+
+        assert_eq!(cached(&mut cache, "b", TTL), None);
+        assert_eq!(cached(&mut cache, "a", TTL).as_deref(), Some("a"));
+        assert_eq!(cached(&mut cache, "c", TTL).as_deref(), Some("c"));
+    }
+
+    #[test]
+    fn shrinks_to_a_smaller_size() {
+        let mut cache = Cache::default();
+        for key in ["a", "b", "c"] {
+            cache.insert(key.into(), &output(key), 3, TTL);
+        }
+
+        cache.insert("d".into(), &output("d"), 2, TTL);
+
+        assert_eq!(cache.entries.len(), 2);
+        assert_eq!(cached(&mut cache, "c", TTL).as_deref(), Some("c"));
+        assert_eq!(cached(&mut cache, "d", TTL).as_deref(), Some("d"));
+    }
+
+    #[test]
+    fn expires_entries() {
+        let mut cache = Cache::default();
+        cache.insert("a".into(), &output("a"), 2, TTL);
+        std::thread::sleep(Duration::from_millis(5));
+
+        assert_eq!(cached(&mut cache, "a", Duration::ZERO), None);
+        // Expired entries are gone, not just hidden.
+        assert_eq!(cached(&mut cache, "a", TTL), None);
+    }
+
+    #[test]
+    fn encodes_for_the_shared_cache() {
+        let pages = Output::Pages {
+            pages: vec![b"one".to_vec(), vec![], b"three".to_vec()],
+            warnings: "deprecated".into(),
+        };
+        let Some(Output::Pages { pages, warnings }) = decode(&encode(&pages).unwrap()) else {
+            panic!("not pages");
+        };
+        assert_eq!(pages, [b"one".to_vec(), vec![], b"three".to_vec()]);
+        assert_eq!(warnings, "deprecated");
+
+        let error = encode(&output("unknown variable")).unwrap();
+        assert!(matches!(decode(&error), Some(Output::Error(text)) if text == "unknown variable"));
+        assert!(decode(&error[..error.len() - 1]).is_none());
+        assert!(encode(&Output::Cancelled).is_none());
+    }
+
+    #[test]
+    fn caches_nothing_with_size_zero() {
+        let mut cache = Cache::default();
+        cache.insert("a".into(), &output("a"), 0, TTL);
+
+        assert_eq!(cached(&mut cache, "a", TTL), None);
+    }
+}
@@ -0,0 +1,56 @@
+use crate::{
+    config,
+    diagnostics::{self, Diagnostic},
+    options::Flavor,
+    render::escape_string,
+};
+
+/// Whether failed renders get an error card, `render.error_cards`.
+pub fn enabled() -> bool {
+    config::get().render.error_cards
+}
+
+/// A standalone typst document showing `diagnostic` on a card themed with
+/// `flavor`, with the line of `source` it points at.
+pub fn source(diagnostic: &Diagnostic, source: &str, flavor: Flavor) -> String {
+    let (base, text) = flavor.colors();
+    let accent = match diagnostic.severity.as_str() {
+        "error" => "f38ba8",
+        _ => "f9e2af",
+    };
+
+    let mut heading = diagnostic.severity.clone();
+    if let Some((line, column)) = diagnostic.position {
+        heading.push_str(&format!(" at line {line}, column {column}"));
+    }
+
+    let mut card = format!(
+        r##"#set page(width: 16cm, height: auto, margin: 16pt, fill: rgb("#{base}"))
+#set text(size: 14pt, fill: rgb("#{text}"))
+#block(width: 100%, inset: 12pt, radius: 6pt, stroke: 2pt + rgb("#{accent}"))[
+  #text(weight: "bold", fill: rgb("#{accent}"), "{heading}")
+
+  #"{message}"
+"##,
+        heading = escape_string(&heading),
+        message = escape_string(&diagnostic.message),
+    );
+
+    if let Some((snippet, caret)) = diagnostics::snippet(diagnostic, source) {
+        card.push_str(&format!(
+            "  #raw(\"{}\", block: true)\n",
+            escape_string(&format!("{snippet}\n{caret}"))
+        ));
+    }
+
+    for hint in &diagnostic.hints {
+        card.push_str(&format!(
+            "  #emph(\"{}\")\n",
+            escape_string(&format!("hint: {hint}"))
+        ));
+    }
+
+    card.push_str("]\n");
+
+    card
+}
@@ -0,0 +1,110 @@
+use matrix_sdk::{Room, ruma::UserId};
+
+use crate::{policy, state::Store};
+
+/// A release and what changed in it for users.
+pub struct Release {
+    pub version: &'static str,
+    pub notes: &'static [&'static str],
+}
+
+/// Every release, latest first.
+pub const RELEASES: &[Release] = &[Release {
+    version: "0.1.0",
+    notes: &[
+        "Multi-page documents are stitched into one image, `--pages` sends each page",
+        "`--flavor`, `--dm`, `--raw`, `--pdf`, `--ppi` and `--scale` flags",
+        "`--margin`, `--radius` and `--shadow` style the card renders are drawn on",
+        "`,tex`, `,dot` and `,mermaid` render LaTeX, Graphviz and Mermaid",
+        "`,typ matrix` renders a source across several flavors or resolutions",
+        "`,typ theme` sets and votes on the room's default flavor",
+        "`,typ check` reports errors and warnings without rendering",
+        "`,calc` evaluates an expression",
+        "Errors point at the offending line of your source",
+        "`,src` replied to a render gets its source back",
+        "`,typ again` and `,typ history` render earlier sources again",
+        "`,doc` builds a document across several messages",
+        "`,template save` keeps snippets for the room, `,template render` fills in their arguments",
+        "`,typ dashboard` charts the room's usage for moderators",
+        "`,fonts` lists the fonts you can use",
+        "`--safe` and `,typ safe` render without packages",
+        "Text shrinks for long sources, `--size` sets it yourself",
+        "`,typ features` lists what renders can do in the room",
+        "`,typ help` lists the commands, flags, flavors and limits",
+        "`,stats` sums up the renders of the room, with its top users",
+        "Sending the same render twice gets a link to the first one instead of a second render",
+        "`,asset upload <name>` keeps images for documents to use as `assets/<name>`",
+        "Renders start faster, with typst started ahead of them",
+        "`,version` reports the versions of typit, matrix-sdk and typst",
+        "`,typ language de|es|fr` answers in German, Spanish or French",
+        "`,typ power` limits commands to members with a power level",
+        "`,typ disable` and `,typ enable` switch me off and on in a room",
+        "Commands sent in a thread are answered in it, even after a restart",
+        "`,typ mentions on` lets you render by mentioning me, like `@typit: $x^2$`",
+        "`,typ` renders the ```` ```typst ```` code blocks of a message, not the text around them",
+        "`,typ automath on` renders the `$...$` math of every message",
+        "`,m sum_(i=1)^n i` renders an equation, `--math` lays out any source like one",
+        "`,typ` replied to a message renders that message",
+        "Commands sent as replies from older clients don't render the quoted message",
+        "Failed uploads and other errors on my side get a reply instead of silence",
+        "Replies that fail to send are retried, and sent after a restart if need be",
+        "Renders show a blurred placeholder while they load, large ones a thumbnail",
+        "`--webp` posts a smaller lossless WebP image",
+        "I leave rooms everyone else left, and forget the settings of rooms I'm removed from",
+    ],
+}];
+
+/// The running release, when it's in [`RELEASES`].
+pub fn current() -> Option<&'static Release> {
+    RELEASES
+        .iter()
+        .find(|release| release.version == env!("CARGO_PKG_VERSION"))
+}
+
+/// The notes of `release` as a bulleted list.
+pub fn format(release: &Release) -> String {
+    let mut out = vec![format!("What's new in typit {}:", release.version)];
+    out.extend(release.notes.iter().map(|note| format!("• {note}")));
+
+    out.join("\n")
+}
+
+/// Handle `,typ whatsnew [on|off]`, returning the reply or the text of an
+/// error reply.
+///
+/// Turning it on subscribes the room to upgrade announcements.
+pub async fn command(
+    args: &str,
+    room: &Room,
+    sender: &UserId,
+    store: &Store,
+) -> Result<String, String> {
+    let subscribed = match args {
+        "" => {
+            return Ok(current().map_or_else(
+                || format!("No release notes for typit {}", env!("CARGO_PKG_VERSION")),
+                format,
+            ));
+        }
+        "on" => true,
+        "off" => false,
+        _ => return Err("Usage: `,typ whatsnew [on|off]`".into()),
+    };
+
+    if !policy::is_moderator(room, sender).await {
+        return Err("Only moderators can change upgrade announcements".into());
+    }
+
+    store
+        .update(|state| {
+            state
+                .rooms
+                .entry(room.room_id().to_owned())
+                .or_default()
+                .whatsnew = subscribed
+        })
+        .await
+        .map_err(|err| err.to_string())?;
+
+    Ok(format!("Upgrade announcements are now {args}"))
+}
@@ -0,0 +1,159 @@
+use matrix_sdk::ruma::events::room::message::{MessageType, OriginalSyncRoomMessageEvent};
+
+use crate::{config, options};
+
+/// The code block languages rendered as Typst.
+const LANGUAGES: &[&str] = &["typst", "typ"];
+
+/// The command `event` asks for with its source in fenced ```` ```typst ````
+/// code blocks, like `,typ --pdf here's my draft` followed by one.
+///
+/// Clients mangle the whitespace of the plain body, but not of the code blocks
+/// in the formatted one, and the prose around them isn't rendered. It's
+/// `event` with `body`, the command, keeping its flags and the blocks as the
+/// source, so it's handled, queued and resumed like any other command.
+pub fn command(
+    event: &OriginalSyncRoomMessageEvent,
+    body: &str,
+) -> Option<OriginalSyncRoomMessageEvent> {
+    let MessageType::Text(text) = &event.content.msgtype else {
+        return None;
+    };
+    let blocks = blocks(&text.formatted.as_ref()?.body);
+    if blocks.is_empty() {
+        return None;
+    }
+
+    let (command, rest) = body.split_once(char::is_whitespace)?;
+    if command != ",typ" && command != ",typraw" {
+        return None;
+    }
+    let args = rest.trim_start();
+    let (subcommand, args) = match args.split_once(char::is_whitespace) {
+        Some((subcommand @ ("check" | "matrix"), args)) => (Some(subcommand), args),
+        _ => (None, args),
+    };
+    // Only the flags are kept, the prose after them goes.
+    let source = match subcommand {
+        Some("matrix") => options::parse_matrix(args).ok()?.1,
+        _ => options::parse(args).ok()?.1,
+    };
+    let head = body[1..body.len() - source.len()].trim_end();
+
+    let mut event = event.clone();
+    event.content.msgtype = MessageType::text_plain(format!(
+        "{}{head}\n{}",
+        config::get().commands.prefix,
+        blocks.join("\n\n")
+    ));
+    Some(event)
+}
+
+/// The contents of the Typst code blocks in the HTML `html`, leaving out the
+/// quote of a reply fallback.
+pub fn blocks(html: &str) -> Vec<String> {
+    let mut blocks = vec![];
+    let mut rest = html
+        .split_once("</mx-reply>")
+        .map_or(html, |(_, rest)| rest);
+
+    while let Some(start) = rest.find("<pre><code class=\"language-") {
+        rest = &rest[start + "<pre><code class=\"language-".len()..];
+        let Some((language, after)) = rest.split_once("\">") else {
+            break;
+        };
+        let Some((code, after)) = after.split_once("</code></pre>") else {
+            break;
+        };
+        rest = after;
+
+        if LANGUAGES.contains(&language) {
+            blocks.push(html_escape::decode_html_entities(code.trim_end()).into_owned());
+        }
+    }
+
+    blocks
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    /// A message with `body`, and the `html` of the formatted one.
+    fn event(body: &str, html: &str) -> OriginalSyncRoomMessageEvent {
+        serde_json::from_value(json!({
+            "type": "m.room.message",
+            "event_id": "$command",
+            "sender": "@alice:localhost",
+            "origin_server_ts": 0,
+            "content": {
+                "msgtype": "m.text",
+                "body": body,
+                "format": "org.matrix.custom.html",
+                "formatted_body": html,
+            },
+        }))
+        .unwrap()
+    }
+
+    fn body(event: &OriginalSyncRoomMessageEvent) -> &str {
+        event.content.body()
+    }
+
+    const BLOCK: &str = "<pre><code class=\"language-typst\">#x\n</code></pre>";
+
+    #[test]
+    fn skips_the_reply_fallback() {
+        let html = format!("<mx-reply><blockquote>{BLOCK}</blockquote></mx-reply>{BLOCK}");
+
+        assert_eq!(blocks(&html), ["#x"]);
+        assert_eq!(
+            blocks(&format!("<mx-reply>{BLOCK}</mx-reply>")),
+            [] as [&str; 0]
+        );
+    }
+
+    #[test]
+    fn keeps_typst_blocks_only() {
+        let html = "<pre><code class=\"language-rust\">fn main() {}</code></pre>\
+                    <pre><code class=\"language-typ\">#y</code></pre>";
+
+        assert_eq!(blocks(html), ["#y"]);
+    }
+
+    #[test]
+    fn decodes_entities() {
+        let html =
+            "<pre><code class=\"language-typst\">$a &lt; b$ &amp; &quot;c&quot;</code></pre>";
+
+        assert_eq!(blocks(html), ["$a < b$ & \"c\""]);
+    }
+
+    #[test]
+    fn keeps_the_flags_and_drops_the_prose() {
+        let command = |text| {
+            command(&event(text, &format!("{text}{BLOCK}")), text)
+                .map(|event| body(&event).to_owned())
+        };
+
+        assert_eq!(
+            command(",typ --pdf here's my draft").as_deref(),
+            Some(",typ --pdf\n#x")
+        );
+        assert_eq!(
+            command(",typ check --pdf  what's wrong?").as_deref(),
+            Some(",typ check --pdf\n#x")
+        );
+        assert_eq!(command(",typ look").as_deref(), Some(",typ\n#x"));
+        assert_eq!(command("look at this"), None);
+    }
+
+    #[test]
+    fn needs_a_block() {
+        let text = ",typ --pdf $x$";
+
+        assert!(command(&event(text, text), text).is_none());
+    }
+}
@@ -0,0 +1,1133 @@
+use std::{
+    borrow::Cow,
+    env,
+    net::SocketAddr,
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, LazyLock, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context, bail};
+use matrix_sdk::ruma::{OwnedRoomId, OwnedServerName, OwnedUserId, UserId};
+use serde::Deserialize;
+use tokio::{
+    signal::unix::{SignalKind, signal},
+    sync::watch,
+};
+
+use crate::{
+    encryption::Sharing,
+    first_sync::FirstSync,
+    options::Flavor,
+    sandbox::{self, Sandbox},
+    style,
+};
+
+/// The bot's core settings.
+///
+/// Read from the TOML file at `CONFIG_FILE`, or `config.toml` if there is one,
+/// and every setting can be overridden by the environment variable noted next
+/// to it, so deployments configured through `.env` keep working.
+///
+/// Everything but the `matrix`, `storage`, `accounts`, `appservice` and
+/// `encryption` sections, `recovery.first_sync`, the template files and
+/// `http.address` is reloaded when the file changes or the bot gets a SIGHUP.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub matrix: Matrix,
+    pub storage: Storage,
+    pub timeouts: Timeouts,
+    pub commands: Commands,
+    pub render: Render,
+    pub compiler: Compiler,
+    pub style: Style,
+    pub packages: Packages,
+    pub limits: Limits,
+    pub admin: Admin,
+    pub http: Http,
+    pub invites: Invites,
+    pub space: Space,
+    pub appservice: Appservice,
+    pub announce: Announce,
+    pub encryption: Encryption,
+    pub recovery: Recovery,
+    pub templates: Templates,
+    /// The accounts the bot runs as besides the one in `matrix`, only set in
+    /// the config file. The one in `matrix` alone posts to the admin room and
+    /// looks up the Space.
+    pub accounts: Vec<Account>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Matrix {
+    /// `HOMESERVER`
+    pub homeserver: String,
+    /// `USERNAME`
+    pub username: String,
+    /// `PASSWORD`
+    pub password: String,
+    /// Logs in with this instead of the password, `ACCESS_TOKEN`. The
+    /// username has to be the full user ID then.
+    pub access_token: Option<String>,
+    /// The device of the access token, `DEVICE_ID`.
+    pub device_id: Option<String>,
+    /// `password` or `sso`, `LOGIN_METHOD`.
+    pub login: Login,
+    /// The local port single sign-on redirects back to, `SSO_PORT`. Any free
+    /// one by default.
+    pub sso_port: u16,
+    /// `classic` or `sliding`, `SYNC_MODE`.
+    pub sync: SyncMode,
+    /// Restores the cross-signing identity and room keys into a new device,
+    /// `RECOVERY_KEY`. The one saved next to the session file by default.
+    pub recovery_key: Option<String>,
+}
+
+/// An account the bot runs as, with its own homeserver, matrix-sdk store and
+/// session file. They all share the state and the render queue.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Account {
+    pub matrix: Matrix,
+    pub db_dir: PathBuf,
+    pub session_file: PathBuf,
+}
+
+/// How the bot logs in when there's no session yet and no access token.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Login {
+    #[default]
+    Password,
+    /// Single sign-on through the browser, for homeservers without password
+    /// login.
+    Sso,
+}
+
+impl FromStr for Login {
+    type Err = anyhow::Error;
+
+    fn from_str(login: &str) -> Result<Self, Self::Err> {
+        match login {
+            "password" => Ok(Login::Password),
+            "sso" => Ok(Login::Sso),
+            _ => bail!("must be `password` or `sso`"),
+        }
+    }
+}
+
+/// How the bot keeps up with its rooms.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SyncMode {
+    /// `/sync`, which every homeserver supports.
+    #[default]
+    Classic,
+    /// Simplified sliding sync (MSC4186), with smaller responses and a faster
+    /// startup on accounts in many large rooms.
+    Sliding,
+}
+
+impl FromStr for SyncMode {
+    type Err = anyhow::Error;
+
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        match mode {
+            "classic" => Ok(SyncMode::Classic),
+            "sliding" => Ok(SyncMode::Sliding),
+            _ => bail!("must be `classic` or `sliding`"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Storage {
+    /// The matrix-sdk store, `DB_DIR`.
+    pub db_dir: PathBuf,
+    /// `SESSION_FILE`
+    pub session_file: PathBuf,
+    /// `sqlite`, `json` or `memory`, `STORAGE_BACKEND`.
+    pub backend: String,
+    /// Where the backend stores the state, `STATE_FILE`.
+    pub state_file: Option<String>,
+    /// How many days the answered commands, the sources of the renders for
+    /// `,src` and the uploads reused for identical outputs are kept, `0` to
+    /// keep them forever, `RETENTION_DAYS`.
+    pub retention_days: u64,
+    /// Where the files uploaded with `,asset upload` are kept, a directory
+    /// per room, `ASSETS_DIR`.
+    pub assets_dir: PathBuf,
+    /// Encrypts the session file, `SESSION_PASSPHRASE`.
+    pub session_passphrase: Option<String>,
+    /// Encrypts the matrix-sdk store, `STORE_PASSPHRASE`. It can't be added
+    /// to or removed from an existing store.
+    pub store_passphrase: Option<String>,
+    /// Read the passphrases that aren't set from the OS keyring, as the
+    /// `session` and `store` accounts of the `typit-matrix` service,
+    /// `PASSPHRASE_KEYRING`.
+    pub keyring: bool,
+    /// Shares the backend, the render cache and the render queue with the
+    /// other processes running under other IDs, `INSTANCE_ID`. Only the
+    /// sqlite backend can be shared.
+    pub instance_id: Option<String>,
+}
+
+impl Default for Storage {
+    fn default() -> Self {
+        Self {
+            db_dir: PathBuf::new(),
+            session_file: PathBuf::new(),
+            backend: "sqlite".into(),
+            state_file: None,
+            retention_days: 30,
+            assets_dir: "assets".into(),
+            session_passphrase: None,
+            store_passphrase: None,
+            keyring: false,
+            instance_id: None,
+        }
+    }
+}
+
+/// In seconds, `TIMEOUT_COMPILE`, `TIMEOUT_ENCODE`, `TIMEOUT_UPLOAD`,
+/// `TIMEOUT_SEND` and `TIMEOUT_TOTAL`, and how long requests may take to
+/// finish on shutdown, `TIMEOUT_SHUTDOWN`.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Timeouts {
+    pub compile: u64,
+    pub encode: u64,
+    pub upload: u64,
+    pub send: u64,
+    pub total: u64,
+    pub shutdown: u64,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            compile: 25,
+            encode: 10,
+            upload: 60,
+            send: 30,
+            total: 120,
+            shutdown: 30,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Commands {
+    /// What commands start with instead of `,`, `COMMAND_PREFIX`.
+    pub prefix: String,
+    /// Reply with notices, which bots don't answer, `REPLY_NOTICES`.
+    pub notices: bool,
+    /// How old commands and reactions may be when they arrive, in seconds,
+    /// `COMMAND_MAX_AGE`. Older ones, like those from before a restart, are
+    /// left to the recovery of missed commands.
+    pub max_age: u64,
+    /// The language of the replies in rooms that didn't pick one,
+    /// `DEFAULT_LANGUAGE`.
+    pub language: String,
+    /// No receipts, typing notices, reactions or presence in any room,
+    /// `QUIET_MODE`.
+    pub quiet: bool,
+}
+
+impl Default for Commands {
+    fn default() -> Self {
+        Self {
+            prefix: ",".into(),
+            notices: false,
+            max_age: 30,
+            language: "en".into(),
+            quiet: false,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Render {
+    /// The flavor of rooms that didn't pick one, `DEFAULT_FLAVOR`.
+    pub flavor: Option<Flavor>,
+    /// Post images as lossless WebP when that's smaller, like `--webp`,
+    /// `RENDER_WEBP`.
+    pub webp: bool,
+    /// Refuse to start when typst is missing or too old, rather than warn
+    /// the admin room, `REQUIRE_TYPST`.
+    pub require_typst: bool,
+    /// How many `typst` processes are kept started ahead of renders, so they
+    /// skip the startup and the font search, `RENDER_WORKERS`. 0 turns it
+    /// off.
+    pub workers: usize,
+    /// How many compile results are cached, `RENDER_CACHE_SIZE`. 0 turns the
+    /// cache off.
+    pub cache_size: usize,
+    /// How long compile results are cached, in seconds, `RENDER_CACHE_TTL`.
+    pub cache_ttl: u64,
+    /// How many renders may run at once, `RENDER_CONCURRENCY`. The number of
+    /// CPUs by default.
+    pub concurrency: Option<usize>,
+    /// How many renders may wait for a slot, `RENDER_QUEUE`.
+    pub queue: usize,
+    /// Render compile errors on a card rather than as text, `ERROR_CARDS`.
+    pub error_cards: bool,
+    /// Embed where a render came from in it and its reply, `PROVENANCE`.
+    pub provenance: bool,
+}
+
+impl Default for Render {
+    fn default() -> Self {
+        Self {
+            flavor: None,
+            webp: false,
+            require_typst: false,
+            workers: 2,
+            cache_size: 64,
+            cache_ttl: 3600,
+            concurrency: None,
+            queue: 16,
+            error_cards: false,
+            provenance: false,
+        }
+    }
+}
+
+/// How the compilers run.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Compiler {
+    /// `none`, `bwrap` or a wrapper command, `SANDBOX`.
+    pub sandbox: Sandbox,
+    /// In MiB of address space, `RENDER_MEMORY_LIMIT`.
+    pub memory_limit: Option<u64>,
+    /// In seconds of CPU time over all threads, `RENDER_CPU_LIMIT`.
+    pub cpu_limit: Option<u64>,
+    /// More directories to look for fonts in, `FONT_DIRS` separated like
+    /// `PATH`.
+    pub font_dirs: Vec<PathBuf>,
+}
+
+/// The card renders are drawn on when the flags don't say otherwise.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Style {
+    /// In points, `STYLE_MARGIN`.
+    pub margin: u32,
+    /// In points, `STYLE_RADIUS`.
+    pub radius: u32,
+    /// `STYLE_SHADOW`
+    pub shadow: bool,
+}
+
+impl Default for Style {
+    fn default() -> Self {
+        Self {
+            margin: 28,
+            radius: 0,
+            shadow: false,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Packages {
+    /// Where typst caches downloaded packages, `PACKAGE_CACHE_DIR`. Its own
+    /// default if unset.
+    pub cache_dir: Option<PathBuf>,
+    /// Where packages are vendored, looked in before the cache,
+    /// `PACKAGE_DIR`.
+    pub dir: Option<PathBuf>,
+    /// Never download packages, they have to be vendored,
+    /// `PACKAGES_OFFLINE`.
+    pub offline: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// In renders per minute for each user, which is also the burst size,
+    /// `RATE_LIMIT_USER`.
+    pub user_rate: f64,
+    /// In renders per minute for each room, which is also the burst size,
+    /// `RATE_LIMIT_ROOM`.
+    pub room_rate: f64,
+    /// In seconds, how long the same command from the same user is answered
+    /// with a link to its render, `DUPLICATE_WINDOW`. 0 turns it off.
+    pub duplicate_window: u64,
+    /// In seconds, how long after a render the same user's next one is
+    /// dropped, `RENDER_COOLDOWN`.
+    pub cooldown: u64,
+    /// The longest source accepted, in bytes, `MAX_SOURCE_LENGTH`.
+    pub max_source: usize,
+    /// The longest compiler output posted as a message, in bytes, longer
+    /// output is attached as a file, `MAX_ERROR_LENGTH`.
+    pub max_error: usize,
+    /// How many background tasks may run at once, `MAX_TASKS`.
+    pub max_tasks: usize,
+    /// In seconds, how long a `,doc` document nobody touches is kept,
+    /// `DOC_IDLE`.
+    pub doc_idle: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            user_rate: 6.0,
+            room_rate: 30.0,
+            duplicate_window: 120,
+            cooldown: 2,
+            max_source: 20_000,
+            max_error: 4000,
+            max_tasks: 1024,
+            doc_idle: 3600,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Admin {
+    /// Where operator alerts, errors and the audit trail go, `ADMIN_ROOM`.
+    pub room: Option<OwnedRoomId>,
+    /// Users treated as moderators in every room, `ADMIN_USERS` separated by
+    /// commas.
+    pub users: Vec<OwnedUserId>,
+    /// Post who ran which command where to the room, `ADMIN_AUDIT`.
+    pub audit: bool,
+    /// Users whose commands are ignored, `BLOCKED_USERS` separated by commas.
+    /// More can be blocked with `,typadmin block`.
+    pub blocked: Vec<OwnedUserId>,
+}
+
+impl Default for Admin {
+    fn default() -> Self {
+        Self {
+            room: None,
+            users: vec![],
+            audit: true,
+            blocked: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Http {
+    /// Where `/metrics`, `/health`, `/ready` and `/render` are served,
+    /// `HTTP_ADDRESS`. Nowhere by default.
+    pub address: Option<SocketAddr>,
+    /// The bearer token `POST /render` needs, `HTTP_RENDER_TOKEN`. It's off
+    /// without one.
+    pub render_token: Option<String>,
+}
+
+/// Which invites the bot accepts. With an allowlist set, only invites
+/// matching one are, and the denylists win over the allowlists.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Invites {
+    /// `INVITE_ALLOW_ROOMS`, separated by commas.
+    pub allow_rooms: Vec<OwnedRoomId>,
+    /// `INVITE_DENY_ROOMS`, separated by commas.
+    pub deny_rooms: Vec<OwnedRoomId>,
+    /// The homeservers of the inviter, `INVITE_ALLOW_SERVERS` separated by
+    /// commas.
+    pub allow_servers: Vec<OwnedServerName>,
+    /// The homeservers of the inviter or the room, `INVITE_DENY_SERVERS`
+    /// separated by commas.
+    pub deny_servers: Vec<OwnedServerName>,
+    /// Reject refused invites instead of leaving them pending,
+    /// `INVITE_REJECT`.
+    pub reject: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Space {
+    /// Only respond in and accept invites to the rooms of this Space,
+    /// `SPACE_ID`.
+    pub id: Option<OwnedRoomId>,
+    /// How often its rooms are looked up again, in minutes,
+    /// `SPACE_REFRESH`.
+    pub refresh: u64,
+}
+
+impl Default for Space {
+    fn default() -> Self {
+        Self {
+            id: None,
+            refresh: 15,
+        }
+    }
+}
+
+/// Run as an application service, which the homeserver pushes events to,
+/// instead of syncing. `typit-matrix registration` prints the registration
+/// file for the homeserver.
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Appservice {
+    /// `APPSERVICE_ID`
+    pub id: String,
+    /// Where the homeserver reaches `http.address`, `APPSERVICE_URL`.
+    pub url: Option<String>,
+    /// Turns the mode on, `APPSERVICE_AS_TOKEN`.
+    pub as_token: Option<String>,
+    /// `APPSERVICE_HS_TOKEN`
+    pub hs_token: Option<String>,
+}
+
+impl Default for Appservice {
+    fn default() -> Self {
+        Self {
+            id: "typit".into(),
+            url: None,
+            as_token: None,
+            hs_token: None,
+        }
+    }
+}
+
+/// What the bot posts on startup and after upgrades.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Announce {
+    /// Where the startup notice goes, `ANNOUNCE_ROOMS` separated by commas.
+    pub rooms: Vec<OwnedRoomId>,
+    /// In seconds, how long after the last startup notice no other goes out,
+    /// so a crash loop doesn't flood the rooms, `ANNOUNCE_INTERVAL`.
+    pub interval: u64,
+    /// `ANNOUNCE_STARTUP`
+    pub startup: String,
+    /// Tell the rooms that turned on `,typ whatsnew` about upgrades,
+    /// `ANNOUNCE_UPGRADES`.
+    pub upgrades: bool,
+}
+
+impl Default for Announce {
+    fn default() -> Self {
+        Self {
+            rooms: vec![],
+            interval: 600,
+            startup: "typit restarted, renders from the last few minutes may have been missed"
+                .into(),
+            upgrades: false,
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Encryption {
+    /// Which devices get the room keys, `all`, `cross-signed` or `trusted`,
+    /// `ROOM_KEY_SHARING`.
+    pub room_key_sharing: Sharing,
+}
+
+/// Catching up on what happened while the bot was down.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Recovery {
+    /// `skip`, `backfill` or `full`, `FIRST_SYNC`. `--first-sync` overrides
+    /// it.
+    pub first_sync: FirstSync,
+    /// In seconds, how old missed commands may be to still be answered,
+    /// `RECOVERY_WINDOW`. 0 turns recovery off.
+    pub window: u64,
+    /// How many missed commands are answered at most, the latest ones,
+    /// `RECOVERY_MAX`.
+    pub max: usize,
+}
+
+impl Default for Recovery {
+    fn default() -> Self {
+        Self {
+            first_sync: FirstSync::default(),
+            window: 600,
+            max: 20,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Templates {
+    /// A JSON object overriding the English replies, `TEMPLATES_FILE`.
+    pub file: Option<PathBuf>,
+    /// Where more `<language>.json` files are, `LOCALES_DIR`.
+    pub locales_dir: Option<PathBuf>,
+    /// What the `{contact}` of the templates is, `CONTACT`.
+    pub contact: String,
+}
+
+static CONFIG: LazyLock<RwLock<Arc<Config>>> = LazyLock::new(Default::default);
+
+/// Told about every reload, see [`subscribe`].
+static RELOADED: LazyLock<watch::Sender<()>> = LazyLock::new(Default::default);
+
+/// How often the config file is checked for changes.
+const WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The environment variable `var`, if it's set to something. Empty ones, like
+/// those left blank in `.env.example`, count as unset.
+fn var(var: &str) -> Option<String> {
+    env::var(var).ok().filter(|value| !value.is_empty())
+}
+
+/// Override `field` with the comma-separated list in the environment variable
+/// `var`, if it's set.
+fn env_list<T>(field: &mut Vec<T>, var: &str) -> anyhow::Result<()>
+where
+    T: for<'a> TryFrom<&'a str>,
+    for<'a> <T as TryFrom<&'a str>>::Error: std::error::Error + Send + Sync + 'static,
+{
+    if let Some(value) = self::var(var) {
+        *field = value
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(|item| {
+                T::try_from(item).with_context(|| format!("`{var}` has an invalid entry {item}"))
+            })
+            .collect::<anyhow::Result<_>>()?;
+    }
+
+    Ok(())
+}
+
+/// Override `field` with the environment variable `var`, if it's set.
+fn env_override<T: FromStr>(field: &mut T, var: &str) -> anyhow::Result<()>
+where
+    T::Err: Into<anyhow::Error>,
+{
+    if let Some(value) = self::var(var) {
+        *field = parse(&value, var)?;
+    }
+
+    Ok(())
+}
+
+/// Override `field` with the environment variable `var`, if it's set.
+fn env_option<T: FromStr>(field: &mut Option<T>, var: &str) -> anyhow::Result<()>
+where
+    T::Err: Into<anyhow::Error>,
+{
+    if let Some(value) = self::var(var) {
+        *field = Some(parse(&value, var)?);
+    }
+
+    Ok(())
+}
+
+/// `value`, the value of the environment variable `var`, parsed.
+fn parse<T: FromStr>(value: &str, var: &str) -> anyhow::Result<T>
+where
+    T::Err: Into<anyhow::Error>,
+{
+    value
+        .parse()
+        .map_err(Into::<anyhow::Error>::into)
+        .with_context(|| format!("`{var}` is invalid ({value:?})"))
+}
+
+/// Override `field` with the switch in the environment variable `var`, if
+/// it's set: `true` or `1` for on, `false` or `0` for off.
+fn env_flag(field: &mut bool, var: &str) -> anyhow::Result<()> {
+    match self::var(var).as_deref() {
+        None => {}
+        Some("true" | "1") => *field = true,
+        Some("false" | "0") => *field = false,
+        Some(value) => bail!("`{var}` must be `true` or `false`, not {value:?}"),
+    }
+
+    Ok(())
+}
+
+impl Config {
+    /// Every account the bot runs as, the one in `matrix` first.
+    pub fn accounts(&self) -> Vec<Account> {
+        let main = Account {
+            matrix: self.matrix.clone(),
+            db_dir: self.storage.db_dir.clone(),
+            session_file: self.storage.session_file.clone(),
+        };
+
+        std::iter::once(main)
+            .chain(self.accounts.iter().cloned())
+            .collect()
+    }
+
+    /// Read, override and validate the configuration.
+    fn read() -> anyhow::Result<Self> {
+        let mut config = Self::from_file()?;
+        config.apply_env()?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    fn from_file() -> anyhow::Result<Self> {
+        let Some(path) = path() else {
+            return Ok(Self::default());
+        };
+
+        let config = std::fs::read_to_string(&path)
+            .with_context(|| format!("Can't read the config file {}", path.display()))?;
+
+        toml::from_str(&config)
+            .with_context(|| format!("The config file {} is invalid", path.display()))
+    }
+
+    fn apply_env(&mut self) -> anyhow::Result<()> {
+        env_override(&mut self.matrix.homeserver, "HOMESERVER")?;
+        env_override(&mut self.matrix.username, "USERNAME")?;
+        env_override(&mut self.matrix.password, "PASSWORD")?;
+        env_option(&mut self.matrix.access_token, "ACCESS_TOKEN")?;
+        env_option(&mut self.matrix.device_id, "DEVICE_ID")?;
+        env_option(&mut self.matrix.recovery_key, "RECOVERY_KEY")?;
+        env_override(&mut self.matrix.login, "LOGIN_METHOD")?;
+        env_override(&mut self.matrix.sso_port, "SSO_PORT")?;
+        env_override(&mut self.matrix.sync, "SYNC_MODE")?;
+
+        env_override(&mut self.storage.db_dir, "DB_DIR")?;
+        env_override(&mut self.storage.session_file, "SESSION_FILE")?;
+        env_override(&mut self.storage.backend, "STORAGE_BACKEND")?;
+        env_option(&mut self.storage.state_file, "STATE_FILE")?;
+        env_override(&mut self.storage.retention_days, "RETENTION_DAYS")?;
+        env_override(&mut self.storage.assets_dir, "ASSETS_DIR")?;
+        env_option(&mut self.storage.session_passphrase, "SESSION_PASSPHRASE")?;
+        env_option(&mut self.storage.store_passphrase, "STORE_PASSPHRASE")?;
+        env_flag(&mut self.storage.keyring, "PASSPHRASE_KEYRING")?;
+        env_option(&mut self.storage.instance_id, "INSTANCE_ID")?;
+
+        env_override(&mut self.timeouts.compile, "TIMEOUT_COMPILE")?;
+        env_override(&mut self.timeouts.encode, "TIMEOUT_ENCODE")?;
+        env_override(&mut self.timeouts.upload, "TIMEOUT_UPLOAD")?;
+        env_override(&mut self.timeouts.send, "TIMEOUT_SEND")?;
+        env_override(&mut self.timeouts.total, "TIMEOUT_TOTAL")?;
+        env_override(&mut self.timeouts.shutdown, "TIMEOUT_SHUTDOWN")?;
+
+        env_override(&mut self.commands.prefix, "COMMAND_PREFIX")?;
+        env_flag(&mut self.commands.notices, "REPLY_NOTICES")?;
+        env_override(&mut self.commands.max_age, "COMMAND_MAX_AGE")?;
+        env_override(&mut self.commands.language, "DEFAULT_LANGUAGE")?;
+        env_flag(&mut self.commands.quiet, "QUIET_MODE")?;
+
+        if let Some(flavor) = var("DEFAULT_FLAVOR") {
+            self.render.flavor = Some(
+                flavor
+                    .parse()
+                    .map_err(|err| anyhow::anyhow!("`DEFAULT_FLAVOR` is invalid ({err})"))?,
+            );
+        }
+        env_flag(&mut self.render.webp, "RENDER_WEBP")?;
+        env_flag(&mut self.render.require_typst, "REQUIRE_TYPST")?;
+        env_override(&mut self.render.workers, "RENDER_WORKERS")?;
+        env_override(&mut self.render.cache_size, "RENDER_CACHE_SIZE")?;
+        env_override(&mut self.render.cache_ttl, "RENDER_CACHE_TTL")?;
+        env_option(&mut self.render.concurrency, "RENDER_CONCURRENCY")?;
+        env_override(&mut self.render.queue, "RENDER_QUEUE")?;
+        env_flag(&mut self.render.error_cards, "ERROR_CARDS")?;
+        env_flag(&mut self.render.provenance, "PROVENANCE")?;
+
+        env_override(&mut self.compiler.sandbox, "SANDBOX")?;
+        env_option(&mut self.compiler.memory_limit, "RENDER_MEMORY_LIMIT")?;
+        env_option(&mut self.compiler.cpu_limit, "RENDER_CPU_LIMIT")?;
+        if let Some(dirs) = env::var_os("FONT_DIRS").filter(|dirs| !dirs.is_empty()) {
+            self.compiler.font_dirs = env::split_paths(&dirs).collect();
+        }
+
+        env_override(&mut self.style.margin, "STYLE_MARGIN")?;
+        env_override(&mut self.style.radius, "STYLE_RADIUS")?;
+        env_flag(&mut self.style.shadow, "STYLE_SHADOW")?;
+
+        env_option(&mut self.packages.cache_dir, "PACKAGE_CACHE_DIR")?;
+        env_option(&mut self.packages.dir, "PACKAGE_DIR")?;
+        env_flag(&mut self.packages.offline, "PACKAGES_OFFLINE")?;
+
+        env_override(&mut self.limits.user_rate, "RATE_LIMIT_USER")?;
+        env_override(&mut self.limits.room_rate, "RATE_LIMIT_ROOM")?;
+        env_override(&mut self.limits.duplicate_window, "DUPLICATE_WINDOW")?;
+        env_override(&mut self.limits.cooldown, "RENDER_COOLDOWN")?;
+        env_override(&mut self.limits.max_source, "MAX_SOURCE_LENGTH")?;
+        env_override(&mut self.limits.max_error, "MAX_ERROR_LENGTH")?;
+        env_override(&mut self.limits.max_tasks, "MAX_TASKS")?;
+        env_override(&mut self.limits.doc_idle, "DOC_IDLE")?;
+
+        env_option(&mut self.admin.room, "ADMIN_ROOM")?;
+        env_list(&mut self.admin.users, "ADMIN_USERS")?;
+        env_flag(&mut self.admin.audit, "ADMIN_AUDIT")?;
+        env_list(&mut self.admin.blocked, "BLOCKED_USERS")?;
+
+        env_list(&mut self.invites.allow_rooms, "INVITE_ALLOW_ROOMS")?;
+        env_list(&mut self.invites.deny_rooms, "INVITE_DENY_ROOMS")?;
+        env_list(&mut self.invites.allow_servers, "INVITE_ALLOW_SERVERS")?;
+        env_list(&mut self.invites.deny_servers, "INVITE_DENY_SERVERS")?;
+        env_flag(&mut self.invites.reject, "INVITE_REJECT")?;
+
+        env_option(&mut self.space.id, "SPACE_ID")?;
+        env_override(&mut self.space.refresh, "SPACE_REFRESH")?;
+
+        env_override(&mut self.appservice.id, "APPSERVICE_ID")?;
+        env_option(&mut self.appservice.url, "APPSERVICE_URL")?;
+        env_option(&mut self.appservice.as_token, "APPSERVICE_AS_TOKEN")?;
+        env_option(&mut self.appservice.hs_token, "APPSERVICE_HS_TOKEN")?;
+
+        env_option(&mut self.http.render_token, "HTTP_RENDER_TOKEN")?;
+        env_option(&mut self.http.address, "HTTP_ADDRESS")?;
+
+        env_list(&mut self.announce.rooms, "ANNOUNCE_ROOMS")?;
+        env_override(&mut self.announce.interval, "ANNOUNCE_INTERVAL")?;
+        env_override(&mut self.announce.startup, "ANNOUNCE_STARTUP")?;
+        env_flag(&mut self.announce.upgrades, "ANNOUNCE_UPGRADES")?;
+
+        env_override(&mut self.encryption.room_key_sharing, "ROOM_KEY_SHARING")?;
+
+        env_override(&mut self.recovery.first_sync, "FIRST_SYNC")?;
+        env_override(&mut self.recovery.window, "RECOVERY_WINDOW")?;
+        env_override(&mut self.recovery.max, "RECOVERY_MAX")?;
+
+        env_option(&mut self.templates.file, "TEMPLATES_FILE")?;
+        env_option(&mut self.templates.locales_dir, "LOCALES_DIR")?;
+        env_override(&mut self.templates.contact, "CONTACT")?;
+
+        Ok(())
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        let required = [
+            (
+                "matrix.homeserver",
+                "HOMESERVER",
+                self.matrix.homeserver.is_empty(),
+            ),
+            (
+                "matrix.username",
+                "USERNAME",
+                self.matrix.username.is_empty() && self.matrix.login != Login::Sso,
+            ),
+            (
+                "matrix.password",
+                "PASSWORD",
+                self.matrix.password.is_empty()
+                    && self.matrix.access_token.is_none()
+                    && self.appservice.as_token.is_none()
+                    && self.matrix.login != Login::Sso,
+            ),
+            (
+                "storage.db_dir",
+                "DB_DIR",
+                self.storage.db_dir.as_os_str().is_empty(),
+            ),
+            (
+                "storage.session_file",
+                "SESSION_FILE",
+                self.storage.session_file.as_os_str().is_empty(),
+            ),
+        ];
+        for (setting, var, missing) in required {
+            if missing {
+                bail!("`{setting}` must be set in the config file, or `{var}` in the environment");
+            }
+        }
+
+        if self.matrix.access_token.is_some() {
+            if self.matrix.device_id.is_none() {
+                bail!(
+                    "`matrix.device_id` (or `DEVICE_ID`) must be set to log in with an access token"
+                );
+            }
+            if UserId::parse(&self.matrix.username).is_err() {
+                bail!(
+                    "`matrix.username` must be the full user ID, like @typit:example.org, to log \
+                     in with an access token"
+                );
+            }
+        }
+
+        if self.appservice.as_token.is_some() {
+            if self.appservice.hs_token.is_none() || self.http.address.is_none() {
+                bail!(
+                    "`appservice.hs_token` and `http.address` must be set to run as an \
+                     application service"
+                );
+            }
+            if UserId::parse(&self.matrix.username).is_err() {
+                bail!(
+                    "`matrix.username` must be the full user ID, like @typit:example.org, to run \
+                     as an application service"
+                );
+            }
+        }
+
+        if self.storage.instance_id.is_some() && self.storage.backend != "sqlite" {
+            bail!(
+                "`storage.instance_id` (or `INSTANCE_ID`) needs the `sqlite` backend, the only one \
+                 instances can share"
+            );
+        }
+
+        if !self.matrix.homeserver.starts_with("https://")
+            && !self.matrix.homeserver.starts_with("http://")
+        {
+            bail!(
+                "`matrix.homeserver` must be a URL starting with http:// or https://, not {:?}",
+                self.matrix.homeserver
+            );
+        }
+
+        for (i, account) in self.accounts.iter().enumerate() {
+            let matrix = &account.matrix;
+            let required = [
+                ("matrix.homeserver", matrix.homeserver.is_empty()),
+                (
+                    "matrix.username",
+                    matrix.username.is_empty() && matrix.login != Login::Sso,
+                ),
+                (
+                    "matrix.password",
+                    matrix.password.is_empty()
+                        && matrix.access_token.is_none()
+                        && matrix.login != Login::Sso,
+                ),
+                ("db_dir", account.db_dir.as_os_str().is_empty()),
+                ("session_file", account.session_file.as_os_str().is_empty()),
+            ];
+            for (setting, missing) in required {
+                if missing {
+                    bail!("`accounts[{i}].{setting}` must be set");
+                }
+            }
+
+            if matrix.access_token.is_some()
+                && (matrix.device_id.is_none() || UserId::parse(&matrix.username).is_err())
+            {
+                bail!(
+                    "`accounts[{i}].matrix` needs `device_id` and the full user ID as `username` \
+                     to log in with an access token"
+                );
+            }
+            if !matrix.homeserver.starts_with("https://")
+                && !matrix.homeserver.starts_with("http://")
+            {
+                bail!(
+                    "`accounts[{i}].matrix.homeserver` must be a URL starting with http:// or \
+                     https://, not {:?}",
+                    matrix.homeserver
+                );
+            }
+        }
+
+        if !self.accounts.is_empty() && self.appservice.as_token.is_some() {
+            bail!("`accounts` can't be used when running as an application service");
+        }
+
+        let accounts = self.accounts();
+        for (i, account) in accounts.iter().enumerate() {
+            if accounts[..i].iter().any(|other| {
+                other.db_dir == account.db_dir || other.session_file == account.session_file
+            }) {
+                bail!("Every account needs its own `db_dir` and `session_file`");
+            }
+        }
+
+        if !["sqlite", "json", "memory"].contains(&self.storage.backend.as_str()) {
+            bail!(
+                "`storage.backend` must be `sqlite`, `json` or `memory`, not `{}`",
+                self.storage.backend
+            );
+        }
+
+        let timeouts = &self.timeouts;
+        for (name, secs) in [
+            ("compile", timeouts.compile),
+            ("encode", timeouts.encode),
+            ("upload", timeouts.upload),
+            ("send", timeouts.send),
+            ("total", timeouts.total),
+        ] {
+            if secs == 0 {
+                bail!("`timeouts.{name}` must be at least a second");
+            }
+        }
+        if timeouts.compile > timeouts.total {
+            bail!("`timeouts.compile` can't be longer than `timeouts.total`");
+        }
+
+        let prefix = &self.commands.prefix;
+        if prefix.is_empty() || prefix.chars().any(char::is_whitespace) {
+            bail!("`commands.prefix` must be non-empty and without spaces, not {prefix:?}");
+        }
+
+        if self.commands.max_age == 0 {
+            bail!("`commands.max_age` must be at least a second");
+        }
+
+        if self.space.refresh == 0 {
+            bail!("`space.refresh` must be at least a minute");
+        }
+
+        let limits = &self.limits;
+        if limits.user_rate <= 0.0 || limits.room_rate <= 0.0 {
+            bail!("`limits.user_rate` and `limits.room_rate` must be positive");
+        }
+        for (name, value) in [
+            ("max_source", limits.max_source),
+            ("max_error", limits.max_error),
+            ("max_tasks", limits.max_tasks),
+        ] {
+            if value == 0 {
+                bail!("`limits.{name}` must be at least 1");
+            }
+        }
+        if limits.doc_idle == 0 {
+            bail!("`limits.doc_idle` must be at least a second");
+        }
+
+        if self.render.concurrency == Some(0) || self.render.queue == 0 {
+            bail!("`render.concurrency` and `render.queue` must be at least 1");
+        }
+
+        if let Some(program) = self.compiler.sandbox.program()
+            && sandbox::which(program).is_none()
+        {
+            bail!("The `compiler.sandbox` program `{program}` can't be found");
+        }
+        if self.compiler.memory_limit == Some(0) || self.compiler.cpu_limit == Some(0) {
+            bail!("`compiler.memory_limit` and `compiler.cpu_limit` must be at least 1");
+        }
+
+        if self.style.margin > style::MAX_POINTS || self.style.radius > style::MAX_POINTS {
+            bail!(
+                "`style.margin` and `style.radius` can't be more than {} points",
+                style::MAX_POINTS
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// The config file, `CONFIG_FILE` or `config.toml` if there is one.
+fn path() -> Option<PathBuf> {
+    match env::var_os("CONFIG_FILE") {
+        Some(path) => Some(path.into()),
+        None => Some(PathBuf::from("config.toml")).filter(|path| path.exists()),
+    }
+}
+
+/// Read and validate the configuration.
+pub fn load() -> anyhow::Result<()> {
+    *CONFIG.write().unwrap() = Arc::new(Config::read()?);
+
+    Ok(())
+}
+
+/// Read the configuration again, keeping the current one if the new one is
+/// invalid.
+pub fn reload() {
+    let config = match Config::read() {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("Keeping the current config, the new one is invalid: {err:#}");
+            return;
+        }
+    };
+
+    let current = get();
+    if config.matrix != current.matrix
+        || config.storage != current.storage
+        || config.http.address != current.http.address
+        || config.appservice != current.appservice
+        || config.accounts != current.accounts
+        || config.encryption != current.encryption
+        || config.recovery.first_sync != current.recovery.first_sync
+        || config.templates.file != current.templates.file
+        || config.templates.locales_dir != current.templates.locales_dir
+    {
+        eprintln!(
+            "Changes to the `matrix`, `storage`, `accounts`, `appservice` and `encryption` \
+             sections and the `recovery.first_sync`, `templates.file`, \
+             `templates.locales_dir` and `http.address` settings only apply after a restart"
+        );
+    }
+
+    *CONFIG.write().unwrap() = Arc::new(config);
+    RELOADED.send_replace(());
+    println!("Reloaded the config");
+}
+
+/// Be told when the configuration is reloaded, for what has to be redone
+/// rather than read again.
+pub fn subscribe() -> watch::Receiver<()> {
+    RELOADED.subscribe()
+}
+
+/// Reload the configuration on SIGHUP or when the config file changes.
+pub async fn watch() {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => Some(hangup),
+        Err(err) => {
+            eprintln!("Can't listen for SIGHUP, only watching the config file ({err})");
+            None
+        }
+    };
+    let modified = || {
+        path()
+            .and_then(|path| std::fs::metadata(path).ok())
+            .and_then(|metadata| metadata.modified().ok())
+    };
+    let mut last: Option<SystemTime> = modified();
+
+    loop {
+        tokio::select! {
+            Some(()) = async { hangup.as_mut()?.recv().await } => {
+                println!("Got SIGHUP, reloading the config");
+            }
+            _ = tokio::time::sleep(WATCH_INTERVAL) => {
+                let now = modified();
+                if now == last {
+                    continue;
+                }
+                last = now;
+                println!("The config file changed, reloading it");
+            }
+        }
+
+        reload();
+    }
+}
+
+/// The current configuration.
+pub fn get() -> Arc<Config> {
+    CONFIG.read().unwrap().clone()
+}
+
+/// Use `config` from now on, for the tests.
+#[cfg(test)]
+pub fn set(config: Config) {
+    *CONFIG.write().unwrap() = Arc::new(config);
+}
+
+/// `body` with the configured prefix swapped for the `,` commands are matched
+/// against, or the body as is when the prefix is the default.
+pub fn command(body: &str) -> Cow<'_, str> {
+    let prefix = &get().commands.prefix;
+    if prefix == "," {
+        return Cow::Borrowed(body);
+    }
+
+    match body.strip_prefix(prefix.as_str()) {
+        Some(rest) => Cow::Owned(format!(",{rest}")),
+        // Not a command, and it mustn't be mistaken for one either.
+        None => Cow::Owned(String::new()),
+    }
+}
@@ -0,0 +1,116 @@
+use std::{
+    collections::HashMap,
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+use matrix_sdk::ruma::{EventId, OwnedEventId, OwnedRoomId, OwnedUserId, RoomId, UserId};
+
+use crate::config;
+
+/// A room, a sender and the hash of the command they sent.
+type Key = (OwnedRoomId, OwnedUserId, u64);
+
+/// A render that was asked for recently.
+struct Seen {
+    /// When it was asked for, or answered once it was.
+    at: Instant,
+    /// The reply with the render, `None` while it's running.
+    reply: Option<OwnedEventId>,
+}
+
+/// Recent renders, by room, sender and command.
+static SEEN: LazyLock<Mutex<HashMap<Key, Seen>>> = LazyLock::new(Default::default);
+
+/// When each user last had a render let through.
+static LAST: LazyLock<Mutex<HashMap<OwnedUserId, Instant>>> = LazyLock::new(Default::default);
+
+/// What to do with a render, see [`check`].
+pub enum Check {
+    /// Render it, telling the guard about the reply.
+    Render(Guard),
+    /// The same render is still running, it was sent twice.
+    Running,
+    /// The same render was answered recently, with this reply.
+    Rendered(OwnedEventId),
+    /// The sender's previous render was let through less than
+    /// `limits.cooldown` ago.
+    TooSoon,
+}
+
+/// A render let through by [`check`]. Dropped without a reply, like when the
+/// render failed on our side, the same command can be sent again right away.
+pub struct Guard(Option<Key>);
+
+impl Guard {
+    /// Remember `reply` as the answer to the render, for duplicates to point
+    /// to.
+    pub fn answered(mut self, reply: &EventId) {
+        let Some(key) = self.0.take() else {
+            return;
+        };
+
+        SEEN.lock().unwrap().insert(
+            key,
+            Seen {
+                at: Instant::now(),
+                reply: Some(reply.to_owned()),
+            },
+        );
+    }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        if let Some(key) = self.0.take() {
+            SEEN.lock().unwrap().remove(&key);
+        }
+    }
+}
+
+/// Whether to render `command`, with the source it resolved to, for `user`
+/// in `room`.
+///
+/// The same command sent again within `limits.duplicate_window`, by a double
+/// send or a bridge echoing it, is collapsed into the first one, and renders
+/// sent by the same user faster than `limits.cooldown` are dropped.
+pub fn check(room: &RoomId, user: &UserId, command: &str, source: &str) -> Check {
+    let limits = &config::get().limits;
+    let window = Duration::from_secs(limits.duplicate_window);
+    let cooldown = Duration::from_secs(limits.cooldown);
+
+    let mut hasher = DefaultHasher::new();
+    (command.trim(), source.trim()).hash(&mut hasher);
+    let key = (room.to_owned(), user.to_owned(), hasher.finish());
+
+    let mut seen = SEEN.lock().unwrap();
+    // Running renders are kept until they're answered or given up.
+    seen.retain(|_, seen| seen.reply.is_none() || seen.at.elapsed() < window);
+    if let Some(seen) = seen.get(&key) {
+        return match &seen.reply {
+            Some(reply) => Check::Rendered(reply.clone()),
+            None => Check::Running,
+        };
+    }
+
+    let mut last = LAST.lock().unwrap();
+    last.retain(|_, at| at.elapsed() < cooldown);
+    if last.contains_key(user) {
+        return Check::TooSoon;
+    }
+    last.insert(user.to_owned(), Instant::now());
+
+    if window.is_zero() {
+        return Check::Render(Guard(None));
+    }
+    seen.insert(
+        key.clone(),
+        Seen {
+            at: Instant::now(),
+            reply: None,
+        },
+    );
+
+    Check::Render(Guard(Some(key)))
+}
@@ -0,0 +1,137 @@
+/// One error or warning from typst's `--diagnostic-format short` output.
+#[derive(Debug)]
+pub struct Diagnostic {
+    pub severity: String,
+    /// 1-based position in the user's source, when it points into it.
+    pub position: Option<(usize, usize)>,
+    pub message: String,
+    pub hints: Vec<String>,
+}
+
+/// Parse typst's short diagnostics, translating positions so they're relative
+/// to the user's source rather than the document with the preamble, which is
+/// `line_offset` lines long.
+pub fn parse(output: &str, line_offset: usize) -> Vec<Diagnostic> {
+    let mut diagnostics: Vec<Diagnostic> = vec![];
+
+    for line in output.lines() {
+        if let Some(diagnostic) = parse_line(line, line_offset) {
+            diagnostics.push(diagnostic);
+        } else if let Some(last) = diagnostics.last_mut()
+            && !line.trim().is_empty()
+        {
+            // Hints and anything else we don't recognize belong to the
+            // diagnostic above them.
+            let line = line.trim();
+            last.hints
+                .push(line.strip_prefix("hint: ").unwrap_or(line).to_owned());
+        }
+    }
+
+    diagnostics
+}
+
+/// Parse `<path>:<line>:<column>: <severity>: <message>`.
+fn parse_line(line: &str, line_offset: usize) -> Option<Diagnostic> {
+    let (location, rest) = line
+        .split_once(": error: ")
+        .map(|(location, message)| (location, ("error", message)))
+        .or_else(|| {
+            line.split_once(": warning: ")
+                .map(|(location, message)| (location, ("warning", message)))
+        })?;
+    let (severity, message) = rest;
+
+    let mut parts = location.rsplitn(3, ':');
+    let column = parts.next()?.parse::<usize>().ok()?;
+    let line = parts.next()?.parse::<usize>().ok()?;
+
+    Some(Diagnostic {
+        severity: severity.to_owned(),
+        position: line
+            .checked_sub(line_offset)
+            .filter(|line| *line > 0)
+            .map(|line| (line, column)),
+        message: message.to_owned(),
+        hints: vec![],
+    })
+}
+
+/// Format `diagnostics` as text, with the offending line of `source`.
+pub fn to_plain(diagnostics: &[Diagnostic], source: &str) -> String {
+    let mut out = vec![];
+
+    for diagnostic in diagnostics {
+        match diagnostic.position {
+            Some((line, column)) => out.push(format!(
+                "{} at {line}:{column}: {}",
+                diagnostic.severity, diagnostic.message
+            )),
+            None => out.push(format!("{}: {}", diagnostic.severity, diagnostic.message)),
+        }
+
+        if let Some((snippet, caret)) = snippet(diagnostic, source) {
+            out.push(snippet);
+            out.push(caret);
+        }
+
+        for hint in &diagnostic.hints {
+            out.push(format!("hint: {hint}"));
+        }
+    }
+
+    out.join("\n")
+}
+
+/// Format `diagnostics` as HTML, with the offending line of `source`
+/// highlighted.
+pub fn to_html(diagnostics: &[Diagnostic], source: &str) -> String {
+    let mut out = String::new();
+
+    for diagnostic in diagnostics {
+        let color = match diagnostic.severity.as_str() {
+            "error" => "#f38ba8",
+            _ => "#f9e2af",
+        };
+
+        out.push_str(&format!(
+            "<p><font color=\"{color}\"><b>{}</b></font>",
+            diagnostic.severity
+        ));
+        if let Some((line, column)) = diagnostic.position {
+            out.push_str(&format!(" at line {line}, column {column}"));
+        }
+        out.push_str(&format!(
+            ": {}</p>",
+            html_escape::encode_safe(&diagnostic.message)
+        ));
+
+        if let Some((snippet, caret)) = snippet(diagnostic, source) {
+            out.push_str(&format!(
+                "<pre><code>{}\n<font color=\"{color}\">{}</font></code></pre>",
+                html_escape::encode_safe(&snippet),
+                caret
+            ));
+        }
+
+        for hint in &diagnostic.hints {
+            out.push_str(&format!(
+                "<p><i>hint: {}</i></p>",
+                html_escape::encode_safe(hint)
+            ));
+        }
+    }
+
+    out
+}
+
+/// The source line a diagnostic points at, and a caret under its column.
+pub fn snippet(diagnostic: &Diagnostic, source: &str) -> Option<(String, String)> {
+    let (line, column) = diagnostic.position?;
+    let text = source.lines().nth(line - 1)?;
+
+    let gutter = format!("{line} | ");
+    let caret = format!("{}^", " ".repeat(gutter.len() + column.saturating_sub(1)));
+
+    Some((format!("{gutter}{text}"), caret))
+}
@@ -0,0 +1,151 @@
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use matrix_sdk::{Room, ruma::UserId};
+
+use crate::{
+    config,
+    state::{Document, Store},
+};
+
+/// What a `,doc` command asks for.
+pub enum Action<'a> {
+    /// Reply with some text.
+    Reply(String),
+    /// Render the document, with flags.
+    Render(String, &'a str),
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Handle `,doc begin|append|render|end`, returning what to do or the text of
+/// an error reply.
+pub async fn command<'a>(
+    args: &'a str,
+    room: &Room,
+    user: &UserId,
+    store: &Store,
+) -> Result<Action<'a>, String> {
+    let args = args.trim();
+    let (name, rest) = args
+        .split_once(char::is_whitespace)
+        .map_or((args, ""), |(name, rest)| (name, rest.trim()));
+
+    let document = store
+        .room(room.room_id())
+        .await
+        .documents
+        .get(user)
+        .cloned();
+
+    match (name, document) {
+        ("begin", _) => {
+            save(room, user, store, Some(rest.to_owned())).await?;
+            Ok(Action::Reply(
+                "Started a document, add to it with `,doc append <code>` and render it with \
+                 `,doc render`"
+                    .into(),
+            ))
+        }
+        ("append", Some(document)) => {
+            let source = if document.source.is_empty() {
+                rest.to_owned()
+            } else {
+                format!("{}\n{rest}", document.source)
+            };
+            let lines = source.lines().count();
+
+            save(room, user, store, Some(source)).await?;
+            Ok(Action::Reply(format!("The document has {lines} line(s)")))
+        }
+        ("render", Some(document)) => {
+            save(room, user, store, Some(document.source.clone())).await?;
+            Ok(Action::Render(document.source, rest))
+        }
+        ("end", Some(_)) => {
+            save(room, user, store, None).await?;
+            Ok(Action::Reply("Discarded your document".into()))
+        }
+        ("append" | "render" | "end", None) => {
+            Err("You don't have a document here, start one with `,doc begin`".into())
+        }
+        _ => Err("Usage: `,doc begin|append <code>|render [flags]|end`".into()),
+    }
+}
+
+/// Replace `user`'s document in `room`, or drop it.
+async fn save(
+    room: &Room,
+    user: &UserId,
+    store: &Store,
+    source: Option<String>,
+) -> Result<(), String> {
+    store
+        .update(|state| {
+            let documents = &mut state
+                .rooms
+                .entry(room.room_id().to_owned())
+                .or_default()
+                .documents;
+
+            match source {
+                Some(source) => {
+                    documents.insert(
+                        user.to_owned(),
+                        Document {
+                            source,
+                            updated: now(),
+                        },
+                    );
+                }
+                None => {
+                    documents.remove(user);
+                }
+            }
+        })
+        .await
+        .map_err(|err| err.to_string())
+}
+
+/// Drop the documents nobody touched for `limits.doc_idle` seconds (an hour by
+/// default), checking every minute.
+pub async fn expire(store: Arc<Store>) {
+    loop {
+        tokio::time::sleep(Duration::from_secs(60)).await;
+
+        let idle = config::get().limits.doc_idle;
+        let cutoff = now().saturating_sub(idle);
+        let stale = store
+            .read(|state| {
+                state
+                    .rooms
+                    .values()
+                    .flat_map(|room| room.documents.values())
+                    .any(|document| document.updated < cutoff)
+            })
+            .await;
+        if !stale {
+            continue;
+        }
+
+        let result = store
+            .update(|state| {
+                for room in state.rooms.values_mut() {
+                    room.documents
+                        .retain(|_, document| document.updated >= cutoff);
+                }
+            })
+            .await;
+
+        if let Err(err) = result {
+            eprintln!("Couldn't expire idle documents: {err}");
+        }
+    }
+}
@@ -0,0 +1,99 @@
+use std::str::FromStr;
+
+use anyhow::bail;
+use matrix_sdk::{Client, Room, ruma::UserId};
+use matrix_sdk_base::crypto::CollectStrategy;
+use serde::Deserialize;
+
+use crate::config;
+
+/// Which devices the bot shares its room keys with in encrypted rooms.
+///
+/// Configured with `encryption.room_key_sharing` or `ROOM_KEY_SHARING`:
+/// `all` (the default) shares with every device that isn't blacklisted,
+/// `cross-signed` only with devices signed by their owner, and `trusted` only
+/// with devices the bot verified.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Sharing {
+    #[default]
+    All,
+    CrossSigned,
+    Trusted,
+}
+
+impl Sharing {
+    pub fn strategy(self) -> CollectStrategy {
+        match self {
+            Sharing::All => CollectStrategy::AllDevices,
+            Sharing::CrossSigned => CollectStrategy::IdentityBasedStrategy,
+            Sharing::Trusted => CollectStrategy::OnlyTrustedDevices,
+        }
+    }
+}
+
+impl FromStr for Sharing {
+    type Err = anyhow::Error;
+
+    fn from_str(sharing: &str) -> Result<Self, Self::Err> {
+        match sharing {
+            "all" => Ok(Sharing::All),
+            "cross-signed" => Ok(Sharing::CrossSigned),
+            "trusted" => Ok(Sharing::Trusted),
+            _ => bail!("must be `all`, `cross-signed` or `trusted`"),
+        }
+    }
+}
+
+/// The configured sharing policy.
+pub fn sharing() -> Sharing {
+    config::get().encryption.room_key_sharing
+}
+
+/// Warn when the bot's own cross-signing isn't set up, without it the
+/// stricter sharing policies can't send anything to encrypted rooms.
+pub async fn check(client: &Client) {
+    let sharing = sharing();
+    if sharing == Sharing::All {
+        return;
+    }
+
+    let ready = client
+        .encryption()
+        .cross_signing_status()
+        .await
+        .is_some_and(|status| status.has_master && status.has_self_signing);
+
+    if !ready {
+        eprintln!(
+            "Room key sharing is {sharing:?} but the bot has no cross-signing keys, \
+             replies to encrypted rooms will fail until it's set up"
+        );
+    }
+}
+
+/// Whether `user` has a device that would get the keys to read the bot's
+/// replies in `room`.
+///
+/// Users with only unverified devices are skipped rather than sent replies
+/// they can't decrypt.
+pub async fn can_read(client: &Client, room: &Room, user: &UserId) -> bool {
+    let sharing = sharing();
+    if sharing == Sharing::All || !room.encryption_state().is_encrypted() {
+        return true;
+    }
+
+    let devices = match client.encryption().get_user_devices(user).await {
+        Ok(devices) => devices,
+        Err(err) => {
+            eprintln!("Couldn't look up the devices of {user} ({err})");
+            return false;
+        }
+    };
+
+    devices.devices().any(|device| match sharing {
+        Sharing::All => true,
+        Sharing::CrossSigned => device.is_cross_signed_by_owner(),
+        Sharing::Trusted => device.is_verified(),
+    })
+}
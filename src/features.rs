@@ -0,0 +1,79 @@
+use matrix_sdk::Room;
+
+use crate::{
+    config,
+    encryption::{self, Sharing},
+    packages,
+    sandbox::{self, Sandbox},
+    state::Store,
+};
+
+/// The program each render command needs.
+const PROGRAMS: &[(&str, &str)] = &[
+    ("typst", "`,typ`"),
+    ("tectonic", "`,tex`"),
+    ("dot", "`,dot`"),
+    ("mmdc", "`,mermaid`"),
+];
+
+fn line(enabled: bool, text: &str) -> String {
+    format!("{} {text}", if enabled { "✓" } else { "✗" })
+}
+
+/// Handle `,typ features`, listing what renders in `room` can and can't do
+/// with the current config.
+pub async fn command(room: &Room, store: &Store) -> String {
+    let settings = store.room(room.room_id()).await;
+    let mut lines = vec!["What I can do here:".to_owned()];
+
+    for (program, command) in PROGRAMS {
+        let installed = sandbox::which(program).is_some();
+        lines.push(line(installed, &format!("{command} renders")));
+    }
+
+    lines.push(line(
+        !settings.images_blocked,
+        if settings.images_blocked {
+            "PNG images, posting them failed here so renders are PDFs"
+        } else {
+            "PNG images"
+        },
+    ));
+    lines.push(line(true, "PDF output with `--pdf`"));
+    lines.push(line(
+        !settings.images_blocked,
+        if config::get().render.webp {
+            "WebP images, when they're smaller than PNG"
+        } else {
+            "WebP images with `--webp`, when they're smaller than PNG"
+        },
+    ));
+
+    let packages = if settings.safe {
+        line(false, "Packages, safe mode is on in this room")
+    } else if matches!(sandbox::get(), Sandbox::Bubblewrap) || packages::offline() {
+        line(true, "Packages, only the ones already downloaded")
+    } else {
+        line(true, "Packages from `@preview`")
+    };
+    lines.push(packages);
+
+    let sharing = match encryption::sharing() {
+        Sharing::All => "every device",
+        Sharing::CrossSigned => "cross-signed devices",
+        Sharing::Trusted => "verified devices",
+    };
+    lines.push(if room.encryption_state().is_encrypted() {
+        line(
+            true,
+            &format!("End-to-end encryption, replies go to {sharing}"),
+        )
+    } else {
+        line(false, "End-to-end encryption, this room isn't encrypted")
+    });
+
+    lines.push(line(false, "Data files attached to the command"));
+    lines.push(line(false, "Animations"));
+
+    lines.join("\n")
+}
@@ -0,0 +1,91 @@
+use std::{env, str::FromStr};
+
+use anyhow::{Context, bail};
+use matrix_sdk::{
+    config::SyncSettings,
+    ruma::{
+        UInt,
+        api::client::filter::{Filter, FilterDefinition, RoomEventFilter},
+    },
+};
+use serde::Deserialize;
+
+use crate::config;
+
+/// What the bot does with the history it gets on startup.
+///
+/// Selected with `--first-sync <mode>`, or `recovery.first_sync` (`FIRST_SYNC`)
+/// when the flag isn't given.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FirstSync {
+    /// Ignore everything sent before the bot started, except for the
+    /// commands missed since the last run of a restored session.
+    #[default]
+    Skip,
+    /// Also answer the commands in the recovery window on a fresh login.
+    Backfill,
+    /// Fetch the full state of every room, with only the latest event of
+    /// each timeline and no presence or typing, which keeps it manageable on
+    /// accounts in many rooms.
+    Full,
+}
+
+impl FirstSync {
+    /// Read the mode from the command line, or the config.
+    pub fn from_args() -> anyhow::Result<Self> {
+        let mut args = env::args().skip(1);
+        let mut mode = None;
+
+        while let Some(arg) = args.next() {
+            if let Some(value) = arg.strip_prefix("--first-sync=") {
+                mode = Some(value.to_owned());
+            } else if arg == "--first-sync" {
+                let Some(value) = args.next() else {
+                    bail!("`--first-sync` needs a mode");
+                };
+                mode = Some(value);
+            }
+        }
+
+        match mode {
+            Some(mode) => mode
+                .parse()
+                .with_context(|| format!("`--first-sync` is invalid ({mode:?})")),
+            None => Ok(config::get().recovery.first_sync),
+        }
+    }
+
+    /// The settings of the first sync, based on those of the ones after it.
+    pub fn settings(self, settings: SyncSettings) -> SyncSettings {
+        if self != FirstSync::Full {
+            return settings;
+        }
+
+        let mut filter = FilterDefinition::with_lazy_loading();
+        filter.presence = Filter::ignore_all();
+        filter.room.ephemeral = RoomEventFilter::ignore_all();
+        filter.room.timeline.limit = Some(UInt::from(1u32));
+
+        settings.filter(filter.into()).full_state(true)
+    }
+
+    /// Whether the commands missed while the bot was down get answered, given
+    /// whether the session was `restored`.
+    pub fn recovers(self, restored: bool) -> bool {
+        restored || self == FirstSync::Backfill
+    }
+}
+
+impl FromStr for FirstSync {
+    type Err = anyhow::Error;
+
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        match mode {
+            "skip" => Ok(FirstSync::Skip),
+            "backfill" => Ok(FirstSync::Backfill),
+            "full" => Ok(FirstSync::Full),
+            _ => bail!("must be `skip`, `backfill` or `full`"),
+        }
+    }
+}
@@ -0,0 +1,66 @@
+use std::{env, path::PathBuf};
+
+use matrix_sdk::{Room, ruma::events::room::message::OriginalSyncRoomMessageEvent};
+use tokio::{process::Command, sync::OnceCell};
+
+use crate::{config, trace::RequestId};
+
+/// The font families the compiler can use, listed once.
+static FAMILIES: OnceCell<Vec<String>> = OnceCell::const_new();
+
+/// The extra font directories, `compiler.font_dirs`.
+pub fn dirs() -> Vec<PathBuf> {
+    config::get().compiler.font_dirs.clone()
+}
+
+/// Point `command` at the extra font directories.
+pub fn configure(command: &mut Command) {
+    let dirs = dirs();
+    if !dirs.is_empty()
+        && let Ok(dirs) = env::join_paths(dirs)
+    {
+        command.env("TYPST_FONT_PATHS", dirs);
+    }
+}
+
+/// The font families available to the compiler.
+async fn families() -> anyhow::Result<&'static Vec<String>> {
+    FAMILIES
+        .get_or_try_init(|| async {
+            let mut command = Command::new("typst");
+            configure(&mut command);
+            let output = command.arg("fonts").kill_on_drop(true).output().await?;
+            if !output.status.success() {
+                anyhow::bail!("{}", String::from_utf8_lossy(&output.stderr).trim());
+            }
+
+            Ok(String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .map(str::trim)
+                .filter(|family| !family.is_empty())
+                .map(Into::into)
+                .collect())
+        })
+        .await
+}
+
+/// Handle `,fonts`, listing the fonts `#set text(font: ...)` can use.
+pub async fn command(room: &Room, event: &OriginalSyncRoomMessageEvent) -> String {
+    let id = RequestId::new();
+    println!(
+        "[{id}] {} listed the fonts in {}",
+        event.sender,
+        room.room_id()
+    );
+
+    match families().await {
+        Ok(families) => format!(
+            "Fonts you can `#set text(font: ...)` to:\n{}",
+            families.join("\n")
+        ),
+        Err(err) => {
+            eprintln!("[{id}] Couldn't list the fonts: {err}");
+            format!("Couldn't list the fonts\n\nref: {id}")
+        }
+    }
+}
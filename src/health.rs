@@ -0,0 +1,47 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use serde_json::json;
+
+use crate::{appservice, queue, sandbox};
+
+/// How long after the last successful sync the bot counts as wedged. Syncs
+/// long-poll for 30 seconds, so a healthy loop never gets close.
+const MAX_SYNC_AGE: Duration = Duration::from_secs(120);
+
+static LAST_SYNC: Mutex<Option<Instant>> = Mutex::new(None);
+
+/// Record a successful sync.
+pub fn synced() {
+    *LAST_SYNC.lock().unwrap() = Some(Instant::now());
+}
+
+/// Whether the bot is alive (`/health`) or ready to render (`/ready`), and
+/// the report behind it as JSON.
+///
+/// The bot is alive until its sync loop stops making progress, which it
+/// can't before the first sync. It's ready once it synced recently and
+/// typst is installed.
+pub fn check(readiness: bool) -> (bool, String) {
+    let since_sync = LAST_SYNC.lock().unwrap().map(|last| last.elapsed());
+    // Application services don't sync, the homeserver pushes to them.
+    let syncing = appservice::enabled() || since_sync.is_some_and(|age| age <= MAX_SYNC_AGE);
+    let typst = sandbox::which("typst").is_some();
+    let (running, waiting) = queue::depth();
+
+    let ok = if readiness {
+        syncing && typst
+    } else {
+        syncing || since_sync.is_none()
+    };
+    let report = json!({
+        "status": if ok { "ok" } else { "failing" },
+        "seconds_since_sync": since_sync.map(|age| age.as_secs()),
+        "queue": { "running": running, "waiting": waiting },
+        "typst": typst,
+    });
+
+    (ok, report.to_string())
+}
@@ -0,0 +1,163 @@
+use matrix_sdk::Room;
+
+use crate::{
+    budget, config, message,
+    options::{DEFAULT_PPI, Engine, FLAGS, Flavor, MAX_PPI},
+    policy, ratelimit, sandbox, size,
+    state::Store,
+    templates,
+};
+
+/// The commands besides the render ones, with what they do.
+const COMMANDS: &[(&str, &str)] = &[
+    (",m <math>", "renders an equation"),
+    (",calc <expression>", "evaluates an expression"),
+    (",src", "replied to a render, gets its source back"),
+    (",fonts", "lists the fonts you can use"),
+    (
+        ",asset upload|list|delete",
+        "keeps images for documents to use as `assets/<name>`",
+    ),
+    (",stats", "sums up the renders here and everywhere"),
+    (",version", "reports the versions I run on"),
+];
+
+/// The `,typ` subcommands, with what they do.
+const SUBCOMMANDS: &[(&str, &str)] = &[
+    (
+        "check <code>",
+        "reports errors and warnings without rendering",
+    ),
+    (
+        "matrix --themes <flavors> --ppi <ppis> <code>",
+        "renders across several flavors or resolutions",
+    ),
+    ("again [flags]", "renders your last source again"),
+    (
+        "history [<n> [flags]]",
+        "lists or renders your earlier sources",
+    ),
+    (
+        "theme <flavor>|vote|votes|apply",
+        "sets the room's default flavor",
+    ),
+    ("features", "lists what renders can do here"),
+    ("whatsnew [on|off]", "shows the latest changes"),
+    ("dashboard", "charts the room's usage, for moderators"),
+    (
+        "quiet|safe|mentions|automath on|off",
+        "changes how I behave here, for moderators",
+    ),
+    (
+        "power <level>|off",
+        "limits commands to a power level, for moderators",
+    ),
+    (
+        "language [<language>]",
+        "picks the language of my replies here, for moderators",
+    ),
+    (
+        "disable|enable",
+        "switches me off and on here, for moderators",
+    ),
+];
+
+/// What the render command `prefix` does.
+fn describe(prefix: &str, engine: Engine, raw: bool) -> &'static str {
+    match (prefix, engine) {
+        (",doc", _) => "builds a document across several messages, `begin|append|render|end`",
+        (",template", _) => "saves and renders the room's snippets, `save|render|list|delete`",
+        (_, Engine::Typst) if raw => "renders Typst without the preamble",
+        (_, Engine::Typst) => "renders Typst",
+        (_, Engine::Latex) => "renders LaTeX",
+        (_, Engine::Graphviz) => "renders a Graphviz graph",
+        (_, Engine::Mermaid) => "renders a Mermaid diagram",
+    }
+}
+
+/// What `usage` does in the language of the current command, `english` if
+/// it has no translation.
+fn translate<'a>(usage: &str, english: &'a str) -> &'a str {
+    templates::get()
+        .help
+        .get(usage)
+        .map_or(english, String::as_str)
+}
+
+/// Handle `,typ help`, describing the commands, flags, flavors and limits in
+/// `room` from the ones the bot knows.
+pub async fn command(room: &Room, store: &Store) -> String {
+    let settings = store.room(room.room_id()).await;
+    // Commands are shown with the prefix the deployment uses.
+    let prefix = config::get().commands.prefix.clone();
+    let command = |name: &str| format!("`{prefix}{}`", &name[1..]);
+
+    let templates = templates::get();
+
+    let mut lines = vec![
+        templates::fill(&templates.help_header),
+        String::new(),
+        templates.help_commands.clone(),
+    ];
+    for (name, engine, raw) in message::COMMANDS {
+        let text = translate(name, describe(name, *engine, *raw));
+        let mut line = format!("• {} {text}", command(name));
+        if sandbox::which(engine.program()).is_none() {
+            line.push_str(" ✗");
+        }
+        lines.push(line);
+    }
+    lines.extend(
+        COMMANDS
+            .iter()
+            .map(|(name, text)| format!("• {} {}", command(name), translate(name, text))),
+    );
+    lines.extend(SUBCOMMANDS.iter().map(|(name, text)| {
+        let usage = format!(",typ {name}");
+        format!("• {} {}", command(&usage), translate(&usage, text))
+    }));
+
+    lines.push(String::new());
+    lines.push(templates.help_flags.clone());
+    lines.extend(
+        FLAGS
+            .iter()
+            .map(|(flag, text)| format!("• `{flag}` {}", translate(flag, text))),
+    );
+
+    let default = policy::flavor(&settings).unwrap_or_default();
+    let flavors: Vec<_> = Flavor::ALL.iter().map(|flavor| flavor.as_str()).collect();
+    lines.push(String::new());
+    lines.push(format!(
+        "{} {}",
+        templates
+            .help_flavors
+            .replace("{default}", default.as_str()),
+        flavors.join(", ")
+    ));
+
+    let low_priority = room.is_low_priority();
+    let divisor = if low_priority { 2 } else { 1 };
+    let budget = budget::get();
+    lines.push(String::new());
+    lines.push(templates.help_limits.clone());
+    lines.extend([
+        format!(
+            "• {}s to compile, {}s in all",
+            budget.compile.as_secs() / divisor,
+            budget.total.as_secs() / divisor
+        ),
+        format!("• {} bytes of source", size::max_source()),
+        format!(
+            "• {} ppi at most",
+            if low_priority { DEFAULT_PPI } else { MAX_PPI }
+        ),
+        format!(
+            "• {} renders a minute per person, {} per room",
+            ratelimit::user_rate(),
+            ratelimit::room_rate()
+        ),
+    ]);
+
+    lines.join("\n")
+}
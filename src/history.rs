@@ -0,0 +1,84 @@
+use matrix_sdk::ruma::{RoomId, UserId};
+
+use crate::state::{RenderRecord, Store};
+
+/// How many sources are kept per user and room.
+const LIMIT: usize = 10;
+
+/// How much of a source's first line is shown in the list.
+const PREVIEW_LENGTH: usize = 60;
+
+/// Add `record` to the front of `user`'s history in `room`.
+pub async fn record(store: &Store, room: &RoomId, user: &UserId, record: RenderRecord) {
+    let result = store
+        .update_history(room, user, |history| {
+            // Re-rendering the latest source with other flags shouldn't bury the rest.
+            if history
+                .first()
+                .is_some_and(|latest| latest.source == record.source)
+            {
+                history.remove(0);
+            }
+
+            history.insert(0, record);
+            history.truncate(LIMIT);
+        })
+        .await;
+
+    if let Err(err) = result {
+        eprintln!("Couldn't update the history of {user}: {err}");
+    }
+}
+
+/// The `index`th (1-based, latest first) source in `user`'s history in
+/// `room`, or the text of an error reply.
+pub async fn recall(
+    store: &Store,
+    room: &RoomId,
+    user: &UserId,
+    index: usize,
+) -> Result<RenderRecord, String> {
+    let mut history = store
+        .history(room, user)
+        .await
+        .map_err(|err| err.to_string())?;
+
+    if index == 0 || index > history.len() {
+        return Err(match history.len() {
+            0 => "You haven't rendered anything yet".into(),
+            len => format!("Your history only goes back {len} render(s)"),
+        });
+    }
+
+    Ok(history.swap_remove(index - 1))
+}
+
+/// `user`'s history in `room` as a numbered list.
+pub async fn list(store: &Store, room: &RoomId, user: &UserId) -> Result<String, String> {
+    let history = store
+        .history(room, user)
+        .await
+        .map_err(|err| err.to_string())?;
+
+    if history.is_empty() {
+        return Ok("You haven't rendered anything yet".into());
+    }
+
+    let mut out = vec![];
+    for (index, record) in history.iter().enumerate() {
+        let first_line = record.source.trim().lines().next().unwrap_or_default();
+        let mut preview: String = first_line.chars().take(PREVIEW_LENGTH).collect();
+        if preview.len() < first_line.len() {
+            preview.push('…');
+        }
+
+        out.push(format!(
+            "{}. {preview} ({})",
+            index + 1,
+            record.options.engine.language()
+        ));
+    }
+    out.push("Re-render one with `,typ history <n> [flags]`".into());
+
+    Ok(out.join("\n"))
+}
@@ -0,0 +1,155 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{
+    api::{self, Response},
+    appservice, health, metrics,
+    state::Store,
+    tasks,
+};
+
+/// How long a client has to send its request.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The most header lines a request may have.
+const MAX_HEADERS: usize = 64;
+
+/// The biggest request body accepted, homeservers batch events into
+/// transactions.
+const MAX_BODY: usize = 4 * 1024 * 1024;
+
+/// Serve the operator endpoints on `address`: `/metrics` for Prometheus,
+/// `/health` and `/ready` for liveness and readiness probes, see
+/// [`health::check`], `POST /render` for other services, see
+/// [`api::render`], and the transactions of [`appservice`] mode.
+pub async fn serve(address: SocketAddr, store: Arc<Store>) {
+    let listener = match TcpListener::bind(address).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            eprintln!("Can't listen on {address} for metrics and health checks ({err})");
+            return;
+        }
+    };
+    println!("Serving metrics and health checks on http://{address}");
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let store = store.clone();
+                // Renders take a while, probes mustn't wait for them.
+                tasks::spawn("http request", async move {
+                    if let Err(err) = respond(stream, &store).await {
+                        eprintln!("Couldn't answer an HTTP request ({err})");
+                    }
+                });
+            }
+            Err(err) => eprintln!("Couldn't accept an HTTP connection ({err})"),
+        }
+    }
+}
+
+/// A request's method, path, `Authorization` header and body.
+struct Request {
+    method: String,
+    path: String,
+    authorization: Option<String>,
+    body: Vec<u8>,
+}
+
+async fn read(stream: &mut TcpStream) -> anyhow::Result<Request> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).await?;
+
+    // E.g. `GET /metrics HTTP/1.1`.
+    let mut parts = line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_owned();
+    let path = parts.next().unwrap_or_default();
+    let path = path.split('?').next().unwrap_or_default().to_owned();
+
+    let mut authorization = None;
+    let mut length = 0;
+    for _ in 0..MAX_HEADERS {
+        line.clear();
+        reader.read_line(&mut line).await?;
+        let Some((name, value)) = line.trim_end().split_once(':') else {
+            break;
+        };
+
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("authorization") {
+            authorization = Some(value.to_owned());
+        } else if name.eq_ignore_ascii_case("content-length") {
+            length = value.parse()?;
+        }
+    }
+
+    if length > MAX_BODY {
+        anyhow::bail!("The body is {length} bytes");
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).await?;
+
+    Ok(Request {
+        method,
+        path,
+        authorization,
+        body,
+    })
+}
+
+async fn respond(mut stream: TcpStream, store: &Arc<Store>) -> anyhow::Result<()> {
+    let request = tokio::time::timeout(READ_TIMEOUT, read(&mut stream)).await??;
+
+    let text =
+        |status, body: &str| -> Response { (status, "text/plain", body.as_bytes().to_vec()) };
+    let transaction = request
+        .path
+        .strip_prefix("/_matrix/app/v1/transactions/")
+        .filter(|_| appservice::enabled());
+    let (status, content_type, body) = match (request.method.as_str(), request.path.as_str()) {
+        ("PUT", _) if let Some(id) = transaction => {
+            appservice::transaction(id, request.authorization.as_deref(), &request.body, store)
+                .await
+        }
+        ("GET", "/metrics") => (
+            "200 OK",
+            "text/plain; version=0.0.4; charset=utf-8",
+            metrics::render().into_bytes(),
+        ),
+        ("GET", "/health" | "/ready") => {
+            let (ok, report) = health::check(request.path == "/ready");
+            let status = if ok {
+                "200 OK"
+            } else {
+                "503 Service Unavailable"
+            };
+            (status, "application/json", report.into_bytes())
+        }
+        ("POST", "/render") if api::authorized(request.authorization.as_deref()) => {
+            match String::from_utf8(request.body) {
+                Ok(body) => api::render(&body, store).await,
+                Err(_) => text("400 Bad Request", "The body isn't UTF-8\n"),
+            }
+        }
+        ("POST", "/render") => text("401 Unauthorized", "Missing or wrong bearer token\n"),
+        (_, "/metrics" | "/health" | "/ready" | "/render") => {
+            text("405 Method Not Allowed", "Method not allowed\n")
+        }
+        _ => text("404 Not Found", "Not found\n"),
+    };
+
+    let head = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n",
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(&body).await?;
+
+    Ok(())
+}
@@ -0,0 +1,113 @@
+use std::{sync::Arc, time::Duration};
+
+use matrix_sdk::{
+    Client, Room, RoomMemberships, RoomState,
+    event_handler::Ctx,
+    ruma::{
+        UserId,
+        events::room::member::{MembershipState, OriginalSyncRoomMemberEvent},
+    },
+};
+
+use crate::{assets, config, state::Store};
+
+/// How often every room is checked, in case a membership change was missed.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Leave the rooms the bot was left alone in, and forget the ones it left or
+/// was kicked or banned from, on membership changes.
+pub async fn on_member(
+    event: OriginalSyncRoomMemberEvent,
+    room: Room,
+    client: Client,
+    Ctx(store): Ctx<Arc<Store>>,
+) {
+    if !matches!(
+        event.content.membership,
+        MembershipState::Leave | MembershipState::Ban
+    ) {
+        return;
+    }
+
+    if client.user_id() == Some(&*event.state_key) {
+        if event.sender != event.state_key {
+            println!(
+                "Removed from {} by {} ({})",
+                room.room_id(),
+                event.sender,
+                event.content.membership
+            );
+        }
+        forget(&room, &store).await;
+    } else if abandoned(&room) {
+        leave(&room).await;
+    }
+}
+
+/// Every `SWEEP_INTERVAL`, leave the rooms of `client` nobody else is in
+/// anymore and forget the ones it isn't in.
+pub async fn sweep(client: Client, store: Arc<Store>) {
+    loop {
+        tokio::time::sleep(SWEEP_INTERVAL).await;
+
+        for room in client.joined_rooms() {
+            if abandoned(&room) {
+                leave(&room).await;
+            }
+        }
+
+        for room in client.rooms() {
+            if matches!(room.state(), RoomState::Left | RoomState::Banned) {
+                forget(&room, &store).await;
+            }
+        }
+    }
+}
+
+/// Whether the bot is the only one left in `room`, not even invited people.
+///
+/// The admin room is kept, the alerts still go there.
+fn abandoned(room: &Room) -> bool {
+    room.state() == RoomState::Joined
+        && room.joined_members_count() + room.invited_members_count() <= 1
+        && config::get().admin.room.as_deref() != Some(room.room_id())
+}
+
+/// Leave `room`, which gets it forgotten once the leave comes down the sync.
+async fn leave(room: &Room) {
+    println!("Leaving {}, nobody else is in it", room.room_id());
+    if let Err(err) = room.leave().await {
+        eprintln!("Couldn't leave {} ({err})", room.room_id());
+    }
+}
+
+/// Have the homeserver forget `room` and drop its settings, unless another of
+/// the bot's accounts is still in it.
+async fn forget(room: &Room, store: &Store) {
+    // Forgetting drops the members from the local store too.
+    let members = room
+        .members_no_sync(RoomMemberships::JOIN)
+        .await
+        .unwrap_or_default();
+    let shared = members.iter().any(|member| is_account(member.user_id()));
+
+    if let Err(err) = room.forget().await {
+        eprintln!("Couldn't forget {} ({err})", room.room_id());
+    }
+
+    if shared {
+        return;
+    }
+    if let Err(err) = store.forget_room(room.room_id()).await {
+        eprintln!("Couldn't drop the settings of {} ({err})", room.room_id());
+    }
+    assets::forget(room.room_id()).await;
+}
+
+/// Whether `user` is one of the accounts the bot runs as.
+fn is_account(user: &UserId) -> bool {
+    config::get().accounts().iter().any(|account| {
+        let username = &account.matrix.username;
+        username == user.as_str() || username == user.localpart()
+    })
+}
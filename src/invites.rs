@@ -0,0 +1,145 @@
+use matrix_sdk::ruma::{RoomId, UserId};
+
+use crate::{
+    config::{self, Invites},
+    space,
+};
+
+/// Why an invite from `inviter` to `room` is refused under `invites`, if it
+/// is.
+pub fn refusal(room: &RoomId, inviter: &UserId) -> Option<String> {
+    refusal_under(&config::get().invites, space::contains(room), room, inviter)
+}
+
+/// [`refusal`] under `invites`, with whether `room` is in the configured
+/// Space.
+fn refusal_under(
+    invites: &Invites,
+    in_space: bool,
+    room: &RoomId,
+    inviter: &UserId,
+) -> Option<String> {
+    if invites.deny_rooms.iter().any(|denied| denied == room) {
+        return Some(format!("{room} is denied"));
+    }
+    if !in_space {
+        return Some(format!("{room} isn't in the configured Space"));
+    }
+    let servers = [Some(inviter.server_name()), room.server_name()];
+    if let Some(server) = servers
+        .into_iter()
+        .flatten()
+        .find(|server| invites.deny_servers.iter().any(|denied| denied == server))
+    {
+        return Some(format!("{server} is denied"));
+    }
+
+    if invites.allow_rooms.is_empty() && invites.allow_servers.is_empty() {
+        return None;
+    }
+    let allowed = invites.allow_rooms.iter().any(|allowed| allowed == room)
+        || invites
+            .allow_servers
+            .iter()
+            .any(|allowed| allowed == inviter.server_name());
+
+    (!allowed).then(|| format!("neither {room} nor {} is allowed", inviter.server_name()))
+}
+
+#[cfg(test)]
+mod tests {
+    use matrix_sdk::ruma::{owned_room_id, owned_server_name, room_id, user_id};
+
+    use super::*;
+
+    #[test]
+    fn allows_anyone_by_default() {
+        let invites = Invites::default();
+
+        assert_eq!(
+            refusal_under(
+                &invites,
+                true,
+                room_id!("!a:example.org"),
+                user_id!("@b:other.org")
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn refuses_rooms_outside_the_space() {
+        let invites = Invites::default();
+
+        assert!(
+            refusal_under(
+                &invites,
+                false,
+                room_id!("!a:example.org"),
+                user_id!("@b:other.org")
+            )
+            .is_some()
+        );
+    }
+
+    #[test]
+    fn refuses_denied_rooms_and_servers() {
+        let invites = Invites {
+            deny_rooms: vec![owned_room_id!("!denied:example.org")],
+            deny_servers: vec![owned_server_name!("spam.org")],
+            ..Default::default()
+        };
+        let inviter = user_id!("@b:other.org");
+
+        assert!(refusal_under(&invites, true, room_id!("!denied:example.org"), inviter).is_some());
+        // Either the inviter's server or the room's.
+        assert!(refusal_under(&invites, true, room_id!("!a:spam.org"), inviter).is_some());
+        assert!(
+            refusal_under(
+                &invites,
+                true,
+                room_id!("!a:example.org"),
+                user_id!("@b:spam.org")
+            )
+            .is_some()
+        );
+        assert!(refusal_under(&invites, true, room_id!("!a:example.org"), inviter).is_none());
+    }
+
+    #[test]
+    fn allows_only_what_is_allowed() {
+        let invites = Invites {
+            allow_rooms: vec![owned_room_id!("!allowed:example.org")],
+            allow_servers: vec![owned_server_name!("friends.org")],
+            ..Default::default()
+        };
+
+        assert!(
+            refusal_under(
+                &invites,
+                true,
+                room_id!("!allowed:example.org"),
+                user_id!("@b:other.org")
+            )
+            .is_none()
+        );
+        assert!(
+            refusal_under(
+                &invites,
+                true,
+                room_id!("!a:example.org"),
+                user_id!("@b:friends.org")
+            )
+            .is_none()
+        );
+        assert!(
+            refusal_under(
+                &invites,
+                true,
+                room_id!("!a:friends.org"),
+                user_id!("@b:other.org")
+            )
+            .is_some()
+        );
+    }
+}
@@ -0,0 +1,46 @@
+use tokio::process::Command;
+
+use crate::config;
+
+/// Make `command` run under the resource limits, in its own process group so
+/// [`kill`] also gets whatever it spawns, and die with its handle.
+///
+/// The limits are `compiler.memory_limit`, in MiB of address space, and
+/// `compiler.cpu_limit`, in seconds of CPU time. Both are off by default, and
+/// the CPU time adds up over all of a process's threads.
+pub fn apply(command: &mut Command) {
+    let config = config::get();
+    let memory = config.compiler.memory_limit.map(|mib| mib * 1024 * 1024);
+    let cpu = config.compiler.cpu_limit;
+
+    command.kill_on_drop(true).process_group(0);
+
+    // SAFETY: `setrlimit` is async-signal-safe, and nothing is allocated.
+    unsafe {
+        command.pre_exec(move || {
+            for (resource, limit) in [(libc::RLIMIT_AS, memory), (libc::RLIMIT_CPU, cpu)] {
+                let Some(limit) = limit else {
+                    continue;
+                };
+                let limit = libc::rlimit {
+                    rlim_cur: limit,
+                    rlim_max: limit,
+                };
+
+                if libc::setrlimit(resource, &limit) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+
+            Ok(())
+        });
+    }
+}
+
+/// Kill the process group of the process `pid`, started with [`apply`].
+pub fn kill(pid: u32) {
+    // SAFETY: `kill` has no memory safety requirements.
+    unsafe {
+        libc::kill(-(pid as libc::pid_t), libc::SIGKILL);
+    }
+}
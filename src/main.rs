@@ -1,70 +1,232 @@
+mod abuse;
+mod admin;
+mod announce;
+mod api;
+mod appservice;
+mod assets;
+mod automath;
+mod budget;
+mod cache;
+mod card;
+mod changelog;
+mod codeblock;
+mod config;
+mod dedup;
+mod diagnostics;
+mod doc;
+mod encryption;
+mod features;
+mod first_sync;
+mod fonts;
+mod health;
+mod help;
+mod history;
+mod http;
+mod hygiene;
+mod invites;
+mod limits;
+mod matrix;
+mod mention;
 mod message;
-
-use std::{env, path::Path, time::Duration};
+mod metrics;
+mod options;
+mod outbox;
+mod packages;
+mod policy;
+mod preview;
+mod progress;
+mod provenance;
+mod queue;
+mod ratelimit;
+mod recovery;
+mod render;
+mod retry;
+mod sandbox;
+mod secrets;
+mod session;
+mod size;
+mod sliding;
+mod snippets;
+mod space;
+mod sso;
+mod state;
+mod stats;
+mod storage;
+mod style;
+mod tasks;
+mod templates;
+#[cfg(test)]
+mod tests;
+mod theme;
+mod trace;
+mod upload;
+mod usage;
+mod version;
+mod workers;
+
+use std::{
+    env,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use anyhow::bail;
+use config::{Account, Login, SyncMode};
+use first_sync::FirstSync;
 use matrix_sdk::{
-    Client, Error, LoopCtrl, Room,
-    authentication::matrix::MatrixSession,
+    Client, LoopCtrl, Room, SessionChange, SessionMeta,
+    authentication::{SessionTokens, matrix::MatrixSession},
     config::SyncSettings,
-    ruma::{api::client::filter::FilterDefinition, events::room::member::StrippedRoomMemberEvent},
+    ruma::{
+        api::client::filter::FilterDefinition, events::room::member::StrippedRoomMemberEvent,
+        presence::PresenceState,
+    },
 };
+use policy::Traffic;
 use serde::{Deserialize, Serialize};
-use tokio::fs::{self};
+use state::Store;
+use tokio::{
+    signal::unix::{SignalKind, signal},
+    sync::{Mutex, broadcast::error::RecvError},
+    task::JoinSet,
+};
+use tokio_util::sync::CancellationToken;
+
+/// How often the sync token is persisted at most.
+const SYNC_TOKEN_INTERVAL: Duration = Duration::from_secs(10);
 
 /// The full session to persist.
 #[derive(Debug, Serialize, Deserialize)]
 struct FullSession {
     user_session: MatrixSession,
-    /// The latest sync token.
+    /// The sync token from before it moved to the state store.
     #[serde(skip_serializing_if = "Option::is_none")]
     sync_token: Option<String>,
+    /// The session uses the access token from the config, which isn't written
+    /// to the session file.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    provisioned: bool,
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     // Ignore result since they might not provide env vars via .env
     let _ = dotenvy::dotenv();
-    for v in [
-        "SESSION_FILE",
-        "HOMESERVER",
-        "DB_DIR",
-        "USERNAME",
-        "PASSWORD",
-    ] {
-        if env::var(v).is_err() {
-            bail!("Environment variable `{v}` must be defined");
-        }
+    config::load()?;
+
+    if env::args().nth(1).as_deref() == Some("registration") {
+        print!("{}", appservice::registration()?);
+        return Ok(());
+    }
+
+    let first_sync = FirstSync::from_args()?;
+    templates::load()?;
+    println!("Running the compilers with sandbox {:?}", sandbox::get());
+    version::check().await?;
+    packages::prewarm().await?;
+    workers::start().await;
+
+    let store = Arc::new(Store::load(storage::from_config()?)?);
+
+    if appservice::enabled() {
+        return run_appservice(store).await;
     }
 
-    let session_file = env::var("SESSION_FILE").unwrap();
+    // Every account syncs on its own, sharing the state and the render queue.
+    let shutdown = CancellationToken::new();
+    let mut syncs = JoinSet::new();
+    for (i, account) in config::get().accounts().into_iter().enumerate() {
+        let restored = account.session_file.exists();
+        let (client, sync_token) = if restored {
+            restore_session(&account).await?
+        } else {
+            (login(&account).await?, None)
+        };
+
+        let sync_token = match client.device_id() {
+            Some(device) => store.sync_token(device).await?.or(sync_token),
+            None => sync_token,
+        };
+
+        client.add_event_handler_context(store.clone());
+
+        // The main account posts to the admin room and looks up the Space.
+        if i == 0 {
+            admin::init(&client);
+            spawn_background(&client, &store);
+        }
 
-    let (client, sync_token) = if Path::exists(session_file.as_ref()) {
-        restore_session(session_file.as_ref()).await?
-    } else {
-        (login(session_file.as_ref()).await?, None)
+        syncs.spawn(sync(
+            client,
+            sync_token,
+            restored,
+            first_sync,
+            account,
+            store.clone(),
+            shutdown.clone(),
+        ));
+    }
+
+    // An account that stops syncing is reported to the admin room, the others
+    // keep going. The process only stops once they all did.
+    let shutdown_requested = shutdown_requested();
+    tokio::pin!(shutdown_requested);
+    let mut result = Ok(());
+    let result = loop {
+        tokio::select! {
+            joined = syncs.join_next() => match joined {
+                Some(joined) => {
+                    result = joined.map_err(Into::into).and_then(|result| result);
+                    if let Err(err) = &result {
+                        eprintln!("An account stopped syncing: {err:#}");
+                    }
+                }
+                None => break result,
+            },
+            requested = &mut shutdown_requested => break requested,
+        }
     };
 
-    sync(client, sync_token, session_file.as_ref()).await
+    // The sync loops persist their tokens before returning.
+    shutdown.cancel();
+    while syncs.join_next().await.is_some() {}
+
+    queue::close();
+    tasks::shutdown(Duration::from_secs(config::get().timeouts.shutdown)).await;
+
+    result
 }
 
-async fn restore_session(session_file: &Path) -> anyhow::Result<(Client, Option<String>)> {
+async fn restore_session(account: &Account) -> anyhow::Result<(Client, Option<String>)> {
+    let session_file = &account.session_file;
     println!(
         "Previous session found in '{}'",
         session_file.to_string_lossy()
     );
 
     // The session was serialized as JSON in a file.
-    let serialized_session = fs::read_to_string(&env::var("SESSION_FILE")?).await?;
     let FullSession {
-        user_session,
+        mut user_session,
         sync_token,
-    } = serde_json::from_str(&serialized_session)?;
+        provisioned,
+    } = session::read(session_file).await?;
+
+    if provisioned {
+        let Some(access_token) = account.matrix.access_token.clone() else {
+            bail!(
+                "The session was provisioned with an access token, which isn't configured anymore"
+            );
+        };
+        user_session.tokens.access_token = access_token;
+    }
 
     // Build the client with the previous settings from the session.
     let client = Client::builder()
-        .homeserver_url(&env::var("HOMESERVER")?)
-        .sqlite_store(&env::var("DB_DIR")?, None)
+        .homeserver_url(&account.matrix.homeserver)
+        .sqlite_store(&account.db_dir, session::store_passphrase().as_deref())
+        .with_room_key_recipient_strategy(encryption::sharing().strategy())
+        .handle_refresh_tokens()
         .build()
         .await?;
 
@@ -76,45 +238,77 @@ async fn restore_session(session_file: &Path) -> anyhow::Result<(Client, Option<
     Ok((client, sync_token))
 }
 
-async fn login(session_file: &Path) -> anyhow::Result<Client> {
-    println!("No previous session found, logging in…");
-
-    let client = build_client().await?;
-    let matrix_auth = client.matrix_auth();
-    let username = env::var("USERNAME")?;
-
-    matrix_auth
-        .login_username(&username, &env::var("PASSWORD")?)
-        .initial_device_display_name(&username)
-        .await?;
-
-    let user_session = matrix_auth
-        .session()
-        .expect("A logged-in client should have a session");
+async fn login(account: &Account) -> anyhow::Result<Client> {
+    let client = build_client(account).await?;
+    let matrix = &account.matrix;
+    let session_file = &account.session_file;
+
+    let full_session = match (&matrix.access_token, &matrix.device_id) {
+        (Some(access_token), Some(device_id)) => {
+            println!("No previous session found, using the configured access token…");
+
+            let mut user_session = MatrixSession {
+                meta: SessionMeta {
+                    user_id: matrix.username.as_str().try_into()?,
+                    device_id: device_id.as_str().into(),
+                },
+                tokens: SessionTokens {
+                    access_token: access_token.clone(),
+                    refresh_token: None,
+                },
+            };
+            client.restore_session(user_session.clone()).await?;
+
+            // The token stays in the config only.
+            user_session.tokens.access_token = String::new();
+            FullSession {
+                user_session,
+                sync_token: None,
+                provisioned: true,
+            }
+        }
+        _ => {
+            println!("No previous session found, logging in…");
+
+            let matrix_auth = client.matrix_auth();
+            match matrix.login {
+                Login::Password => {
+                    matrix_auth
+                        .login_username(&matrix.username, &matrix.password)
+                        .initial_device_display_name(&matrix.username)
+                        .request_refresh_token()
+                        .await?;
+                }
+                Login::Sso => sso::login(&client, matrix.sso_port).await?,
+            }
 
-    let serialized_session = serde_json::to_string(&FullSession {
-        user_session,
-        sync_token: None,
-    })?;
+            FullSession {
+                user_session: matrix_auth
+                    .session()
+                    .expect("A logged-in client should have a session"),
+                sync_token: None,
+                provisioned: false,
+            }
+        }
+    };
 
-    fs::write(session_file, serialized_session).await?;
+    session::write(session_file, &full_session).await?;
 
     println!("Session persisted in {}", session_file.to_string_lossy());
 
-    // After logging in, you might want to verify this session with another one (see
-    // the `emoji_verification` example), or bootstrap cross-signing if this is your
-    // first session with encryption, or if you need to reset cross-signing because
-    // you don't have access to your old sessions (see the
-    // `cross_signing_bootstrap` example).
+    // Cross-signing and secret storage are set up once syncing, see
+    // `secrets::setup`.
 
     Ok(client)
 }
 
-/// Build a new client.
-async fn build_client() -> anyhow::Result<Client> {
+/// Build a new client for `account`.
+async fn build_client(account: &Account) -> anyhow::Result<Client> {
     match Client::builder()
-        .homeserver_url(env::var("HOMESERVER")?)
-        .sqlite_store(env::var("DB_DIR")?, None)
+        .homeserver_url(&account.matrix.homeserver)
+        .sqlite_store(&account.db_dir, session::store_passphrase().as_deref())
+        .with_room_key_recipient_strategy(encryption::sharing().strategy())
+        .handle_refresh_tokens()
         .build()
         .await
     {
@@ -136,76 +330,372 @@ async fn build_client() -> anyhow::Result<Client> {
     }
 }
 
-/// Setup the client to listen to new messages.
+/// Setup the client to listen to new messages, until `shutdown` is
+/// cancelled.
+///
+/// `restored` is whether the session was, which sliding sync needs to know
+/// whether there are missed commands to recover.
 async fn sync(
     client: Client,
     initial_sync_token: Option<String>,
-    session_file: &Path,
+    restored: bool,
+    first_sync: FirstSync,
+    account: Account,
+    store: Arc<Store>,
+    shutdown: CancellationToken,
 ) -> anyhow::Result<()> {
-    println!("Launching a first sync to ignore past messages…");
+    let user = client
+        .user_id()
+        .expect("A logged-in client should have a user ID")
+        .to_owned();
+
+    let (secrets_client, matrix) = (client.clone(), account.matrix.clone());
+    let key_file = secrets::key_file(&account.session_file);
+    tasks::spawn("secret storage", async move {
+        if let Err(err) = secrets::setup(&secrets_client, &matrix, &key_file).await {
+            eprintln!("Couldn't set up secret storage: {err}");
+        }
+    });
+
+    if account.matrix.sync == SyncMode::Sliding {
+        println!("Launching a sliding sync for {user}…");
+        if first_sync == FirstSync::Full {
+            println!("The `full` first sync only applies to the classic sync, ignoring it");
+        }
+
+        // Its sync position is in the matrix-sdk store rather than ours.
+        let recover = first_sync.recovers(restored);
+        let ready = ready(&client, account.session_file, &store, recover, None);
+        if let Err(err) = sliding::run(&client, ready, &shutdown).await {
+            admin::notify(&client, &format!("The sync loop of {user} stopped: {err}")).await;
+            return Err(err);
+        }
+
+        return Ok(());
+    }
+
+    println!("Launching a first sync for {user} ({first_sync:?})…");
 
     let filter = FilterDefinition::with_lazy_loading();
 
     let mut sync_settings = SyncSettings::default().filter(filter.into());
 
-    if let Some(sync_token) = initial_sync_token {
-        sync_settings = sync_settings.token(sync_token);
+    if !policy::allows(&store, None, Traffic::Presence).await {
+        sync_settings = sync_settings.set_presence(PresenceState::Offline);
+    }
+
+    // A fresh login has no commands it could have missed, unless asked to
+    // backfill.
+    let recover = first_sync.recovers(initial_sync_token.is_some());
+    if let Some(sync_token) = &initial_sync_token {
+        sync_settings = sync_settings.token(sync_token.clone());
     }
 
     loop {
-        match client.sync_once(sync_settings.clone()).await {
+        match client
+            .sync_once(first_sync.settings(sync_settings.clone()))
+            .await
+        {
             Ok(response) => {
                 // This is the last time we need to provide this token, the sync method after
                 // will handle it on its own.
                 sync_settings = sync_settings.token(response.next_batch.clone());
-                persist_sync_token(session_file, response.next_batch).await?;
+                health::synced();
+                persist_sync_token(&client, &store, &response.next_batch).await;
                 break;
             }
             Err(error) => {
-                println!("An error occurred during initial sync: {error}");
+                metrics::SYNC_ERRORS.inc();
+                println!("An error occurred during the initial sync for {user}: {error}");
                 println!("Trying again…");
             }
         }
     }
 
-    println!("The client is ready! Listening to new messages…");
+    ready(
+        &client,
+        account.session_file,
+        &store,
+        recover,
+        initial_sync_token,
+    )
+    .await;
+
+    // The latest sync token, if it wasn't persisted yet, and when one last was.
+    let unpersisted = std::sync::Mutex::new((None, Instant::now()));
+    let (client_ref, store_ref, unpersisted_ref) = (&client, &store, &unpersisted);
+
+    let sync = client.sync_with_result_callback(sync_settings, |sync_result| async move {
+        let response = sync_result.inspect_err(|_| metrics::SYNC_ERRORS.inc())?;
+        health::synced();
+
+        // Persisting every token would write on every sync, a restart only
+        // redoes a few seconds of it instead.
+        let due = {
+            let mut unpersisted = unpersisted_ref.lock().unwrap();
+            if unpersisted.1.elapsed() >= SYNC_TOKEN_INTERVAL {
+                *unpersisted = (None, Instant::now());
+                true
+            } else {
+                unpersisted.0 = Some(response.next_batch.clone());
+                false
+            }
+        };
+        if due {
+            persist_sync_token(client_ref, store_ref, &response.next_batch).await;
+        }
+
+        Ok(LoopCtrl::Continue)
+    });
+
+    tokio::select! {
+        result = sync => if let Err(err) = result {
+            admin::notify(&client, &format!("The sync loop of {user} stopped: {err}")).await;
+            return Err(err.into());
+        },
+        () = shutdown.cancelled() => {}
+    }
+
+    let sync_token = unpersisted.lock().unwrap().0.take();
+    if let Some(sync_token) = sync_token {
+        persist_sync_token(&client, &store, &sync_token).await;
+    }
+
+    Ok(())
+}
+
+/// Get going once the first sync of `client` is in: announce, resume and
+/// recover the commands from before, since the sync token `since` if there's
+/// one, and start listening to new ones.
+async fn ready(
+    client: &Client,
+    session_file: PathBuf,
+    store: &Arc<Store>,
+    recover: bool,
+    since: Option<String>,
+) {
+    if let Some(user) = client.user_id() {
+        println!("{user} is ready! Listening to new messages…");
+    }
+
+    encryption::check(client).await;
+    tasks::spawn(
+        "session tokens",
+        persist_tokens(client.clone(), session_file),
+    );
+
+    if let Err(err) = announce::startup(client, store).await {
+        eprintln!("Couldn't post the startup announcement: {err}");
+    }
+
+    if let Err(err) = announce::upgrade(client, store).await {
+        eprintln!("Couldn't announce the upgrade: {err}");
+    }
+
+    if let Some(alert) = version::alert() {
+        admin::notify(client, &alert).await;
+    }
+
+    {
+        let client = client.clone();
+        let store = store.clone();
+
+        // Published again on reloads, the policies show parts of the config.
+        let mut reloaded = config::subscribe();
+        tasks::spawn("policy", async move {
+            policy::publish_all(&client, &store).await;
+            while reloaded.changed().await.is_ok() {
+                policy::publish_all(&client, &store).await;
+            }
+        });
+    }
+
+    // Gathered before listening to new commands so they don't race, the
+    // queued first so those aren't answered twice.
+    let mut backlog = recovery::queued(client, store).await.unwrap_or_else(|err| {
+        eprintln!("Couldn't resume queued renders: {err}");
+        vec![]
+    });
+    if recover {
+        match recovery::missed(client, since.as_deref()).await {
+            Ok(missed) => backlog.extend(missed),
+            Err(err) => eprintln!("Couldn't recover missed commands: {err}"),
+        }
+    }
+
+    {
+        let client = client.clone();
+        let store = store.clone();
+
+        tasks::spawn("recovery", async move {
+            if let Err(err) = outbox::flush(&client, &store).await {
+                eprintln!("Couldn't send the replies left in the outbox: {err}");
+            }
+            recovery::answer(&client, &store, backlog).await;
+        });
+    }
 
     client.add_event_handler(message::on_room_message);
+    client.add_event_handler(policy::on_member);
+    client.add_event_handler(policy::on_power_levels);
+    client.add_event_handler(hygiene::on_member);
+    client.add_event_handler(message::on_reaction);
+    client.add_event_handler(progress::on_redaction);
     client.add_event_handler(on_stripped_member);
 
-    client
-        .sync_with_result_callback(sync_settings, |sync_result| async move {
-            let response = sync_result?;
-
-            // We persist the token each time to be able to restore our session
-            persist_sync_token(session_file, response.next_batch)
-                .await
-                .map_err(|err| Error::UnknownError(err.into()))?;
+    tasks::spawn("hygiene", hygiene::sweep(client.clone(), store.clone()));
+}
 
-            Ok(LoopCtrl::Continue)
+/// Run as an application service: the homeserver pushes events to the HTTP
+/// listener instead of the bot syncing.
+async fn run_appservice(store: Arc<Store>) -> anyhow::Result<()> {
+    let config = config::get();
+    let client = build_client(&config.accounts()[0]).await?;
+    client
+        .restore_session(MatrixSession {
+            meta: SessionMeta {
+                user_id: appservice::user_id()?,
+                device_id: config
+                    .matrix
+                    .device_id
+                    .as_deref()
+                    .unwrap_or("TYPIT_APPSERVICE")
+                    .into(),
+            },
+            tokens: SessionTokens {
+                access_token: config.appservice.as_token.clone().unwrap_or_default(),
+                refresh_token: None,
+            },
         })
         .await?;
+    println!(
+        "Running as the application service {} for {}",
+        config.appservice.id,
+        appservice::user_id()?
+    );
+
+    admin::init(&client);
+    appservice::init(&client);
+    if let Some(alert) = version::alert() {
+        admin::notify(&client, &alert).await;
+    }
+    spawn_background(&client, &store);
+
+    shutdown_requested().await?;
+    queue::close();
+    tasks::shutdown(Duration::from_secs(config::get().timeouts.shutdown)).await;
 
     Ok(())
 }
 
-async fn persist_sync_token(session_file: &Path, sync_token: String) -> anyhow::Result<()> {
-    let serialized_session = fs::read_to_string(session_file).await?;
-    let mut full_session: FullSession = serde_json::from_str(&serialized_session)?;
+/// Start the tasks both the sync loops and application service mode run, with
+/// the main account's `client`.
+fn spawn_background(client: &Client, store: &Arc<Store>) {
+    tasks::spawn("document expiry", doc::expire(store.clone()));
+    tasks::spawn("state expiry", state::expire(store.clone()));
+    tasks::spawn("config reload", config::watch());
+    tasks::spawn("workers", workers::keep_fresh());
+    tasks::spawn("audit", admin::post_audit(client.clone()));
+    tasks::spawn("space", space::watch(client.clone()));
+    if let Some(address) = config::get().http.address {
+        tasks::spawn("http", http::serve(address, store.clone()));
+    }
+}
+
+/// Wait for SIGINT, SIGTERM or an admin's `,typadmin shutdown`.
+async fn shutdown_requested() -> anyhow::Result<()> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => println!("Got SIGINT, shutting down…"),
+        _ = terminate.recv() => println!("Got SIGTERM, shutting down…"),
+        _ = admin::shutdown_requested() => println!("Shutting down as asked by an admin…"),
+    }
+
+    Ok(())
+}
+
+/// Serializes the changes to the session file, which rewrite all of it.
+static SESSION_LOCK: Mutex<()> = Mutex::const_new(());
 
-    full_session.sync_token = Some(sync_token);
-    let serialized_session = serde_json::to_string(&full_session)?;
-    fs::write(session_file, serialized_session).await?;
+/// Change the session persisted in `session_file` with `f`.
+async fn update_session(
+    session_file: &Path,
+    f: impl FnOnce(&mut FullSession),
+) -> anyhow::Result<()> {
+    let _guard = SESSION_LOCK.lock().await;
+
+    let mut full_session: FullSession = session::read(session_file).await?;
+    f(&mut full_session);
+    session::write(session_file, &full_session).await?;
 
     Ok(())
 }
 
+/// Remember where the sync of `client` is at, so a restart picks up there.
+async fn persist_sync_token(client: &Client, store: &Store, sync_token: &str) {
+    let Some(device) = client.device_id() else {
+        return;
+    };
+
+    if let Err(err) = store.set_sync_token(device, sync_token).await {
+        eprintln!("Couldn't persist the sync token: {err}");
+    }
+}
+
+/// Persist the tokens whenever the homeserver rotates them, refresh tokens
+/// being single use.
+async fn persist_tokens(client: Client, session_file: PathBuf) {
+    let mut changes = client.subscribe_to_session_changes();
+
+    loop {
+        match changes.recv().await {
+            Ok(SessionChange::TokensRefreshed) => {
+                let Some(tokens) = client.session_tokens() else {
+                    continue;
+                };
+
+                let updated = update_session(&session_file, |full_session| {
+                    // Provisioned tokens live in the config, not the file.
+                    if !full_session.provisioned {
+                        full_session.user_session.tokens = tokens;
+                    }
+                })
+                .await;
+                match updated {
+                    Ok(()) => println!("Persisted the refreshed access token"),
+                    Err(err) => eprintln!("Couldn't persist the refreshed access token: {err}"),
+                }
+            }
+            Ok(SessionChange::UnknownToken { soft_logout }) => eprintln!(
+                "The homeserver rejected the access token (soft logout: {soft_logout}), remove \
+                 the session file to log in again"
+            ),
+            Err(RecvError::Lagged(_)) => {}
+            Err(RecvError::Closed) => return,
+        }
+    }
+}
+
 async fn on_stripped_member(room_member: StrippedRoomMemberEvent, client: Client, room: Room) {
     if room_member.state_key != client.user_id().unwrap() {
         return;
     }
 
-    tokio::spawn(async move {
+    if let Some(reason) = invites::refusal(room.room_id(), &room_member.sender) {
+        println!(
+            "Refused the invite to {} from {}: {reason}",
+            room.room_id(),
+            room_member.sender
+        );
+        if config::get().invites.reject
+            && let Err(err) = room.leave().await
+        {
+            eprintln!("Couldn't reject the invite to {} ({err})", room.room_id());
+        }
+        return;
+    }
+
+    tasks::spawn("join", async move {
         let mut delay = 2;
 
         while let Err(err) = room.join().await {
@@ -214,6 +704,7 @@ async fn on_stripped_member(room_member: StrippedRoomMemberEvent, client: Client
 
             if delay >= 3600 {
                 eprintln!("Can't join room {} ({err:?})", room.room_id());
+                admin::notify(&client, &format!("Can't join {} ({err})", room.room_id())).await;
                 break;
             }
         }
@@ -0,0 +1,100 @@
+use std::time::Duration;
+
+use matrix_sdk::{
+    Client, Room,
+    ruma::{
+        MxcUri, OwnedMxcUri, RoomId, TransactionId,
+        api::client::{media::create_content_async, message::send_message_event},
+    },
+};
+use mime::Mime;
+use serde_json::Value;
+
+use crate::config;
+
+/// Sending messages to a room, which the [`crate::outbox`] goes through.
+pub trait Messages {
+    fn id(&self) -> &RoomId;
+
+    /// Send `content` as an `m.room.message` with `txn_id`.
+    fn send_message(
+        &self,
+        content: Value,
+        txn_id: &TransactionId,
+    ) -> impl Future<Output = matrix_sdk::Result<send_message_event::v3::Response>> + Send;
+}
+
+impl Messages for Room {
+    fn id(&self) -> &RoomId {
+        self.room_id()
+    }
+
+    async fn send_message(
+        &self,
+        content: Value,
+        txn_id: &TransactionId,
+    ) -> matrix_sdk::Result<send_message_event::v3::Response> {
+        self.send_raw("m.room.message", content)
+            .with_transaction_id(txn_id)
+            .await
+    }
+}
+
+/// Uploading to the homeserver's media repository.
+pub trait Media {
+    fn upload_media(
+        &self,
+        content_type: &Mime,
+        data: Vec<u8>,
+    ) -> impl Future<Output = matrix_sdk::Result<OwnedMxcUri>> + Send;
+
+    /// Reserve an MXC URI to upload to later, with [`Media::upload_reserved`].
+    fn reserve_uri(&self) -> impl Future<Output = matrix_sdk::Result<OwnedMxcUri>> + Send;
+
+    /// Upload to `uri`, reserved with [`Media::reserve_uri`].
+    fn upload_reserved(
+        &self,
+        uri: &MxcUri,
+        content_type: &Mime,
+        data: Vec<u8>,
+    ) -> impl Future<Output = matrix_sdk::Result<()>> + Send;
+}
+
+impl Media for Client {
+    async fn upload_media(
+        &self,
+        content_type: &Mime,
+        data: Vec<u8>,
+    ) -> matrix_sdk::Result<OwnedMxcUri> {
+        self.media()
+            .upload(content_type, data, None)
+            .await
+            .map(|response| response.content_uri)
+    }
+
+    async fn reserve_uri(&self) -> matrix_sdk::Result<OwnedMxcUri> {
+        self.media()
+            .create_content_uri()
+            .await
+            .map(|reserved| reserved.uri)
+    }
+
+    async fn upload_reserved(
+        &self,
+        uri: &MxcUri,
+        content_type: &Mime,
+        data: Vec<u8>,
+    ) -> matrix_sdk::Result<()> {
+        // Not `upload_preallocated`, which takes the reservation by value
+        // where a retry needs it again.
+        let mut request = create_content_async::v3::Request::from_url(uri, data)?;
+        request.content_type = Some(content_type.to_string());
+        let timeout = Duration::from_secs(config::get().timeouts.upload);
+
+        self.send(request)
+            .with_request_config(self.request_config().timeout(timeout))
+            .await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,79 @@
+use matrix_sdk::{
+    Client, Room,
+    ruma::{
+        UserId,
+        events::room::message::{
+            MessageType, OriginalSyncRoomMessageEvent, TextMessageEventContent,
+        },
+    },
+};
+
+use crate::{config, state::Store};
+
+/// The command `event` asks for by mentioning the bot, like
+/// `@typit: $integral x dif x$`, in rooms that turned it on with
+/// `,typ mentions on`.
+///
+/// It's `event` with the mention swapped for `,typ`, so it's handled, queued
+/// and resumed after a restart like any other command. A command after the
+/// mention, like `@typit: ,tex x^2`, is kept as is.
+pub async fn command(
+    event: &OriginalSyncRoomMessageEvent,
+    room: &Room,
+    client: &Client,
+    store: &Store,
+) -> Option<OriginalSyncRoomMessageEvent> {
+    let own_user = client.user_id()?;
+    let MessageType::Text(text) = &event.content.msgtype else {
+        return None;
+    };
+    if !mentions(event, text, own_user) || !store.room(room.room_id()).await.mentions {
+        return None;
+    }
+
+    // Clients put the display name in the body for a mention pill, people
+    // typing it out may use any of these.
+    let mut names = vec![own_user.to_string(), own_user.localpart().to_owned()];
+    if let Ok(Some(member)) = room.get_member_no_sync(own_user).await {
+        names.push(member.name().to_owned());
+    }
+    let body = text.body.trim_start();
+    let body = body.strip_prefix('@').unwrap_or(body);
+    let rest = names.iter().find_map(|name| {
+        let name = name.strip_prefix('@').unwrap_or(name);
+        body.strip_prefix(name)
+            .filter(|rest| rest.starts_with([':', ',']) || rest.starts_with(char::is_whitespace))
+    })?;
+    let rest = rest.strip_prefix([':', ',']).unwrap_or(rest).trim_start();
+    if rest.is_empty() {
+        return None;
+    }
+
+    let prefix = &config::get().commands.prefix;
+    let command = if rest.starts_with(prefix.as_str()) {
+        rest.to_owned()
+    } else {
+        format!("{prefix}typ {rest}")
+    };
+
+    let mut event = event.clone();
+    event.content.msgtype = MessageType::text_plain(command);
+    event.content.mentions = None;
+    Some(event)
+}
+
+/// Whether `event` mentions `user`.
+fn mentions(
+    event: &OriginalSyncRoomMessageEvent,
+    text: &TextMessageEventContent,
+    user: &UserId,
+) -> bool {
+    if let Some(mentions) = &event.content.mentions {
+        return mentions.user_ids.contains(user);
+    }
+
+    // Clients from before intentional mentions only link to the user.
+    text.formatted
+        .as_ref()
+        .is_some_and(|formatted| formatted.body.contains(&format!("matrix.to/#/{user}")))
+}
@@ -1,129 +1,2500 @@
 use std::{
-    process::Stdio,
-    time::{Duration, SystemTime},
+    borrow::Cow,
+    fmt,
+    sync::{Arc, LazyLock},
+    time::{Duration, Instant, SystemTime},
 };
 
+use anyhow::Context;
+use image::ImageFormat;
 use matrix_sdk::{
     Client, Room, RoomState,
-    ruma::events::room::{
-        ImageInfo,
-        message::{
-            AddMentions, ForwardThread, ImageMessageEventContent, MessageType,
-            OriginalSyncRoomMessageEvent, RoomMessageEventContent,
+    event_handler::Ctx,
+    ruma::{
+        EventId, Int, MilliSecondsSinceUnixEpoch, OwnedEventId, OwnedTransactionId, RoomId,
+        TransactionId, UInt, UserId,
+        api::client::{error::ErrorKind, message::send_message_event},
+        events::{
+            AnySyncMessageLikeEvent, AnySyncTimelineEvent, SyncMessageLikeEvent,
+            reaction::OriginalSyncReactionEvent,
+            room::{
+                ImageInfo, MediaSource, ThumbnailInfo,
+                message::{
+                    AddMentions, FileInfo, FileMessageEventContent, FormattedBody, ForwardThread,
+                    ImageMessageEventContent, MessageType, OriginalSyncRoomMessageEvent, Relation,
+                    ReplacementMetadata, RoomMessageEventContent,
+                    sanitize::remove_plain_reply_fallback,
+                },
+            },
         },
     },
 };
-use mime::IMAGE_PNG;
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    time::timeout,
+use mime::{APPLICATION_PDF, IMAGE_PNG, Mime, TEXT_PLAIN_UTF_8};
+use tokio::task::{JoinError, JoinSet};
+
+use crate::{
+    abuse, admin, assets, automath,
+    budget::{Deadline, Stage, Stopped},
+    card, changelog, codeblock, config, dedup, diagnostics, doc, encryption, features, fonts, help,
+    history, mention, metrics,
+    options::{self, Engine, Format, PageMode, RenderOptions},
+    outbox, policy,
+    preview::{self, Preview},
+    progress::{self, Tracker},
+    provenance::{self, Provenance},
+    queue::{self, Permit, Rejected},
+    ratelimit::{self, Limited},
+    render::{self, Evaluation, Output},
+    size, snippets, space,
+    state::{QueuedJob, RenderRecord, Store},
+    stats, tasks, templates, theme,
+    trace::RequestId,
+    upload, usage, version,
 };
 
-const PREAMBLE: &str = r#"
-#import "@preview/catppuccin:1.0.0": catppuccin, flavors;
-#show: catppuccin.with(flavors.mocha);
-#set page(height: auto, width: auto, margin: 28pt);
-#set text(size: 44pt);
-"#;
+/// The render commands, with their engine and whether they skip the preamble.
+///
+/// `,typraw` is a shorthand for `,typ --raw`, and has to be tried first. `,m`
+/// is turned into `,typ --math` before these are looked up.
+/// `,doc` renders documents built across several messages, `,template` the
+/// room's saved snippets.
+pub const COMMANDS: &[(&str, Engine, bool)] = &[
+    (",typraw", Engine::Typst, true),
+    (",typ", Engine::Typst, false),
+    (",doc", Engine::Typst, false),
+    (",template", Engine::Typst, false),
+    (",tex", Engine::Latex, false),
+    (",dot", Engine::Graphviz, false),
+    (",mermaid", Engine::Mermaid, false),
+];
+
+/// Plain text for a reply, a notice with `commands.notices` on so other bots
+/// don't answer it.
+pub fn plain(body: impl Into<String>) -> MessageType {
+    if config::get().commands.notices {
+        MessageType::notice_plain(body)
+    } else {
+        MessageType::text_plain(body)
+    }
+}
+
+/// Formatted text for a reply, like [`plain`].
+pub fn html(body: impl Into<String>, html_body: impl Into<String>) -> MessageType {
+    if config::get().commands.notices {
+        MessageType::notice_html(body, html_body)
+    } else {
+        MessageType::text_html(body, html_body)
+    }
+}
+
+/// How many characters of the source go into an image's caption.
+const CAPTION_LENGTH: usize = 500;
+
+/// mime has no constant for it.
+static IMAGE_WEBP: LazyLock<Mime> = LazyLock::new(|| "image/webp".parse().unwrap());
 
 /// Handle room messages.
-pub async fn on_room_message(event: OriginalSyncRoomMessageEvent, room: Room, client: Client) {
+pub async fn on_room_message(
+    event: OriginalSyncRoomMessageEvent,
+    room: Room,
+    client: Client,
+    Ctx(store): Ctx<Arc<Store>>,
+) {
     // We only want to log text messages in joined rooms.
     if room.state() != RoomState::Joined {
         return;
     }
 
-    if SystemTime::now()
-        .duration_since(event.origin_server_ts.to_system_time().unwrap())
-        .unwrap()
-        >= Duration::from_secs(5)
+    if stale(event.origin_server_ts, event.unsigned.age) {
+        return;
+    }
+
+    // The sync loop waits for handlers, so renders run on their own.
+    tasks::spawn("command", async move {
+        // In a set of its own, so a panic is caught here and the sender hears
+        // about it. Dropping the set on shutdown cancels the handler with it.
+        let mut handler = JoinSet::new();
+        handler.spawn({
+            let (event, room, store) = (event.clone(), room.clone(), store.clone());
+            async move { handle(&event, &room, &client, &store).await }
+        });
+
+        if let Some(Err(err)) = handler.join_next().await
+            && err.is_panic()
+        {
+            let language = store.room(room.room_id()).await.language;
+            let text = templates::fill(&templates::of(language.as_deref()).internal_error);
+            post(
+                &room,
+                &store,
+                RoomMessageEventContent::new(plain(text)).make_reply_to(
+                    &event,
+                    ForwardThread::Yes,
+                    AddMentions::Yes,
+                ),
+            )
+            .await;
+            // Logged and reported by the supervisor.
+            std::panic::resume_unwind(err.into_panic());
+        }
+    });
+}
+
+/// Whether an event sent at `sent`, and `age` milliseconds old according to
+/// the homeserver, is older than `commands.max_age`.
+///
+/// The homeserver's age is used when there is one, the clocks of the
+/// homeservers and the bot may not agree. Events from the future are fresh.
+fn stale(sent: MilliSecondsSinceUnixEpoch, age: Option<Int>) -> bool {
+    let age = match age {
+        Some(age) => Duration::from_millis(u64::try_from(i64::from(age)).unwrap_or_default()),
+        None => sent
+            .to_system_time()
+            .and_then(|sent| SystemTime::now().duration_since(sent).ok())
+            .unwrap_or_default(),
+    };
+
+    age >= Duration::from_secs(config::get().commands.max_age)
+}
+
+/// Handle reactions: 📄 on a render converts it to a PDF, 🖼️ to an image.
+pub async fn on_reaction(
+    event: OriginalSyncReactionEvent,
+    room: Room,
+    client: Client,
+    Ctx(store): Ctx<Arc<Store>>,
+) {
+    if room.state() != RoomState::Joined || client.user_id() == Some(&*event.sender) {
+        return;
+    }
+
+    if stale(event.origin_server_ts, event.unsigned.age) {
+        return;
+    }
+
+    let format = match event.content.relates_to.key.trim_end_matches('\u{fe0f}') {
+        "📄" => Format::Pdf,
+        "🖼" => Format::Png,
+        _ => return,
+    };
+
+    if store.is_blocked(&event.sender).await
+        || !space::contains(room.room_id())
+        || store.room(room.room_id()).await.disabled
+        || !policy::has_power(&room, &event.sender, &store).await
     {
         return;
     }
 
+    let record = match store
+        .render(room.room_id(), &event.content.relates_to.event_id)
+        .await
+    {
+        Ok(Some(record)) => record,
+        Ok(None) => return,
+        Err(err) => {
+            eprintln!("Couldn't look up a render source: {err}");
+            return;
+        }
+    };
+
+    match store.claim(event.event_id.as_str()).await {
+        Ok(true) => {}
+        Ok(false) => return,
+        Err(err) => {
+            eprintln!("Couldn't claim {}: {err}", event.event_id);
+            return;
+        }
+    }
+
+    tasks::spawn("conversion", async move {
+        // The conversion is posted as a reply to the render.
+        let target = match room.event(&event.content.relates_to.event_id, None).await {
+            Ok(target) => target,
+            Err(err) => {
+                eprintln!("Couldn't fetch the render reacted to: {err}");
+                return;
+            }
+        };
+        let Ok(AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::RoomMessage(
+            SyncMessageLikeEvent::Original(target),
+        ))) = target.raw().deserialize()
+        else {
+            return;
+        };
+
+        convert(
+            &target,
+            &event.sender,
+            record,
+            format,
+            &room,
+            &client,
+            &store,
+        )
+        .await;
+    });
+}
+
+/// Render `record` again as `format` for `user`, replying to the render
+/// `target`.
+async fn convert(
+    target: &OriginalSyncRoomMessageEvent,
+    user: &UserId,
+    record: RenderRecord,
+    format: Format,
+    room: &Room,
+    client: &Client,
+    store: &Store,
+) {
+    let id = RequestId::new();
+    println!(
+        "[{id}] {user} requested a {format:?} conversion in {}",
+        room.room_id()
+    );
+
+    if abuse::cooldown(store, user).await.is_some() || !admit(target, user, room, store, id).await {
+        return;
+    }
+
+    let settings = store.room(room.room_id()).await;
+    if format == Format::Png && settings.images_blocked {
+        return;
+    }
+
+    // The limit may have been lowered since the first render.
+    if let Some(text) = too_long(&record.source, id) {
+        let reply = RoomMessageEventContent::new(plain(format!("{text}\n\nref: {id}")))
+            .make_reply_to(target, ForwardThread::Yes, AddMentions::Yes);
+        post(room, store, reply).await;
+        return;
+    }
+
+    let mut options = RenderOptions {
+        format,
+        ..record.options
+    };
+    if options.flavor.is_none() {
+        options.flavor = policy::flavor(&settings);
+    }
+    policy::restrict(room, store, &mut options).await;
+    assets::attach(room, store, &mut options).await;
+
+    let Some((_permit, deadline)) = enqueue(target, user, room, store, id).await else {
+        return;
+    };
+
+    let msgs =
+        match render_messages(client, store, &record.source, &options, "", None, &deadline).await {
+            Ok(msgs) | Err(Failure::Rejected(msgs, _)) => msgs,
+            Err(Failure::Limit(err)) => vec![plain(format!("{err}\n\nref: {id}"))],
+            Err(Failure::Internal(err)) => {
+                vec![plain(format!("{}\n\nref: {id}", internal_error(&err, id)))]
+            }
+            Err(Failure::Cancelled) => return,
+        };
+
+    match send_replies(client, store, room, target, msgs, false, &deadline).await {
+        Ok(sent) => remember(store, room.room_id(), &sent, &record.source, &options, id).await,
+        Err(err) => eprintln!("[{id}] Couldn't reply: {err}"),
+    }
+}
+
+/// Answer `event` if it's a command that wasn't answered yet, whatever its age.
+pub async fn handle(
+    event: &OriginalSyncRoomMessageEvent,
+    room: &Room,
+    client: &Client,
+    store: &Store,
+) {
+    // Notices are from bots, answering them could start a loop.
     let MessageType::Text(text_content) = &event.content.msgtype else {
+        attachment(event, room, client, store).await;
+        return;
+    };
+    if client.user_id() == Some(&*event.sender) {
+        return;
+    }
+    if let Some(event) = without_reply_fallback(event) {
+        Box::pin(handle(&event, room, client, store)).await;
         return;
+    }
+    let body = config::command(&text_content.body);
+    // `,m` is a shorthand for `,typ --math` with the source as an equation.
+    let body = match subcommand(&body, ",m") {
+        Some(expr) => Cow::Owned(format!(",typ --math $ {expr} $")),
+        None => body,
     };
 
-    let Some(content) = text_content.body.strip_prefix(",typ") else {
+    if !is_command(&body) {
+        // A mention is handled as the command it stands for.
+        if let Some(event) = mention::command(event, room, client, store).await {
+            Box::pin(handle(&event, room, client, store)).await;
+            return;
+        }
+        // Math in plain messages, in rooms that asked for it.
+        if let Some(source) = automath::source(&text_content.body)
+            && store.room(room.room_id()).await.auto_math
+        {
+            auto_math(event, &source, room, client, store).await;
+        }
+        return;
+    }
+    if let Some(event) = codeblock::command(event, &body) {
+        Box::pin(handle(&event, room, client, store)).await;
+        return;
+    }
+
+    if !claim(event, room, store).await {
+        return;
+    }
+
+    let toggle = body
+        .strip_prefix(",typ")
+        .and_then(|args| match args.trim() {
+            "enable" => Some(true),
+            "disable" => Some(false),
+            _ => None,
+        });
+    if let Some(enabled) = toggle {
+        let text = match policy::enable_command(enabled, room, &event.sender, store).await {
+            Ok(text) | Err(text) => text,
+        };
+        post(
+            room,
+            store,
+            RoomMessageEventContent::new(plain(text)).make_reply_to(
+                event,
+                ForwardThread::Yes,
+                AddMentions::Yes,
+            ),
+        )
+        .await;
+        policy::publish(room, store).await;
+        return;
+    }
+    let settings = store.room(room.room_id()).await;
+    if settings.disabled {
+        return;
+    }
+
+    policy::send_receipt(store, room, &event.event_id).await;
+    let started = Instant::now();
+    let mut outcome = "answered".into();
+    templates::scope(
+        settings.language,
+        respond(event, &body, room, client, store, &mut outcome),
+    )
+    .await;
+
+    let command = body
+        .split_whitespace()
+        .take(2)
+        .collect::<Vec<_>>()
+        .join(" ");
+    admin::audit(format!(
+        "{} in {}: {command}, {outcome} after {:.1?}",
+        event.sender,
+        room.room_id(),
+        started.elapsed()
+    ));
+
+    if let Err(err) = store.mark_answered(room.room_id(), &event.event_id).await {
+        eprintln!("Couldn't record {} as answered: {err}", event.event_id);
+    }
+}
+
+/// Handle `,asset upload <name>` sent as the caption of the image or file
+/// `event` uploads.
+async fn attachment(
+    event: &OriginalSyncRoomMessageEvent,
+    room: &Room,
+    client: &Client,
+    store: &Store,
+) {
+    let caption = match &event.content.msgtype {
+        MessageType::Image(image) => image.caption(),
+        MessageType::File(file) => file.caption(),
+        _ => None,
+    };
+    let Some(caption) = caption else {
+        return;
+    };
+    let body = config::command(caption);
+    let (Some(args), Some(source)) = (
+        body.strip_prefix(",asset"),
+        assets::media(&event.content.msgtype),
+    ) else {
         return;
     };
+    if !claim(event, room, store).await
+        || store.room(room.room_id()).await.disabled
+        || !policy::has_power(room, &event.sender, store).await
+    {
+        return;
+    }
 
-    let reply = if content.trim().is_empty() {
-        RoomMessageEventContent::text_plain("<text> is needed to typeset").make_reply_to(
-            &event,
+    let attachment = Some(assets::Attachment::Media(source));
+    let text = match assets::command(args, attachment, room, client, &event.sender, store).await {
+        Ok(text) | Err(text) => text,
+    };
+    post(
+        room,
+        store,
+        RoomMessageEventContent::new(plain(text)).make_reply_to(
+            event,
             ForwardThread::Yes,
             AddMentions::Yes,
-        )
-    } else {
-        let mut child = tokio::process::Command::new("typst")
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .args(["compile", "-", "-", "--format", "png"])
-            .spawn()
-            .unwrap();
-
-        let mut stdin = child.stdin.take().unwrap();
-        stdin
-            .write_all(format!("{PREAMBLE}\n{content}").as_bytes())
-            .await
-            .unwrap();
-        drop(stdin);
+        ),
+    )
+    .await;
+    if let Err(err) = store.mark_answered(room.room_id(), &event.event_id).await {
+        eprintln!("Couldn't record {} as answered: {err}", event.event_id);
+    }
+}
 
-        let mut buf = vec![];
+/// `event` without the quote of the message it replies to that older clients
+/// put at the start of its bodies, if it has one.
+fn without_reply_fallback(
+    event: &OriginalSyncRoomMessageEvent,
+) -> Option<OriginalSyncRoomMessageEvent> {
+    let MessageType::Text(text) = &event.content.msgtype else {
+        return None;
+    };
+    let body = remove_plain_reply_fallback(&text.body);
+    let html = text
+        .formatted
+        .as_ref()
+        .and_then(|formatted| formatted.body.split_once("</mx-reply>"))
+        .map(|(_, html)| html);
+    if body.len() == text.body.len() && html.is_none() {
+        return None;
+    }
 
-        let mut stdout = child.stdout.take().unwrap();
-        if timeout(Duration::from_secs(25), stdout.read_to_end(&mut buf))
-            .await
-            .is_err()
-        {
-            room.send(
-                RoomMessageEventContent::text_plain("Your code took too long (>25s) to render")
-                    .make_reply_to(&event, ForwardThread::Yes, AddMentions::Yes),
-            )
-            .await
-            .unwrap();
+    let mut text = text.clone();
+    text.body = body.to_owned();
+    if let (Some(formatted), Some(html)) = (&mut text.formatted, html) {
+        formatted.body = html.to_owned();
+    }
+
+    let mut event = event.clone();
+    event.content.msgtype = MessageType::Text(text);
+    Some(event)
+}
+
+/// Whether `event` is for this instance to handle, and from a user and in a
+/// room the bot answers.
+async fn claim(event: &OriginalSyncRoomMessageEvent, room: &Room, store: &Store) -> bool {
+    // Another instance sharing our storage may already be on it.
+    match store.claim(event.event_id.as_str()).await {
+        Ok(true) => {}
+        Ok(false) => return false,
+        Err(err) => {
+            eprintln!("Couldn't claim {}: {err}", event.event_id);
+            return false;
+        }
+    }
+
+    if store.answered(room.room_id(), &event.event_id).await {
+        return false;
+    }
+
+    if store.is_blocked(&event.sender).await {
+        println!("Ignored {}, {} is blocked", event.event_id, event.sender);
+        return false;
+    }
+
+    space::contains(room.room_id())
+}
+
+/// Render the math `source` of `event`, a plain message in a room with
+/// `,typ automath on`, as a compact image.
+///
+/// The message may not have been meant as math, so nothing is posted when the
+/// render is rate limited, has to wait for a slot or doesn't compile.
+async fn auto_math(
+    event: &OriginalSyncRoomMessageEvent,
+    source: &str,
+    room: &Room,
+    client: &Client,
+    store: &Store,
+) {
+    if !claim(event, room, store).await
+        || store.room(room.room_id()).await.disabled
+        || !policy::has_power(room, &event.sender, store).await
+    {
+        return;
+    }
+
+    let id = RequestId::new();
+    if too_long(source, id).is_some() {
+        return;
+    }
+    if !matches!(
+        ratelimit::take(store, &event.sender, room.room_id()).await,
+        Ok(None)
+    ) {
+        println!(
+            "[{id}] Skipped the math of {}, rate limited",
+            event.event_id
+        );
+        return;
+    }
+    // Answering long after the conversation moved on isn't worth it.
+    let Ok(mut ticket) = queue::join(&event.sender, room.is_favourite()).await else {
+        return;
+    };
+    if ticket.ahead().await.is_some() {
+        println!("[{id}] Skipped the math of {}, busy", event.event_id);
+        return;
+    }
+    let Some(_permit) = ticket.wait().await else {
+        return;
+    };
+    metrics::RENDERS.inc();
+    println!(
+        "[{id}] Rendering the math of {} in {}",
+        event.event_id,
+        room.room_id()
+    );
+
+    let mut options = RenderOptions {
+        math: true,
+        ..Default::default()
+    };
+    options.style.margin = Some(options::MATH_MARGIN);
+    options.style.shadow = Some(false);
+    options.flavor = policy::flavor(&store.room(room.room_id()).await);
+    policy::restrict(room, store, &mut options).await;
 
+    let deadline = policy::deadline(room, id);
+    let provenance = Provenance::of(event, id);
+    let started = Instant::now();
+    let msgs = match render_messages(
+        client,
+        store,
+        source,
+        &options,
+        "",
+        provenance.as_ref(),
+        &deadline,
+    )
+    .await
+    {
+        Ok(msgs) => msgs,
+        Err(_) => {
+            println!("[{id}] The math didn't render, not replying");
             return;
-        };
+        }
+    };
+    usage::record(
+        store,
+        usage::Render {
+            room: room.room_id(),
+            user: &event.sender,
+            latency: started.elapsed(),
+            compile: deadline.compile_time(),
+            error: None,
+        },
+    )
+    .await;
 
-        let mut stderr = child.stderr.take().unwrap();
-        stderr.read_to_end(&mut buf).await.unwrap();
+    match send_replies(client, store, room, event, msgs, false, &deadline).await {
+        Ok(sent) => remember(store, room.room_id(), &sent, source, &options, id).await,
+        Err(err) => eprintln!("[{id}] Couldn't reply: {err}"),
+    }
+    if let Err(err) = store.mark_answered(room.room_id(), &event.event_id).await {
+        eprintln!("Couldn't record {} as answered: {err}", event.event_id);
+    }
+}
 
-        let stat = child.wait().await.unwrap();
+/// Whether `event` is a command, rather than chat, a mention of the bot or
+/// math answered in rooms that ask for it.
+pub fn is_command_event(event: &OriginalSyncRoomMessageEvent) -> bool {
+    let MessageType::Text(text) = &event.content.msgtype else {
+        return false;
+    };
+    let body = config::command(remove_plain_reply_fallback(&text.body));
 
-        let msg = if !stat.success() {
-            let err = String::from_utf8_lossy(&buf).into_owned();
-            let html_text = format!(
-                "<pre><code class=\"language-typst\">{}</code></pre>",
-                html_escape::encode_safe(&err)
-            );
+    is_command(&body) || subcommand(&body, ",m").is_some()
+}
 
-            MessageType::text_html(err, html_text)
-        } else {
-            let img = image::load_from_memory(&buf).unwrap();
-            let (width, height) = (img.width(), img.height());
+/// Whether `body`, with the prefix swapped for `,`, is a command.
+fn is_command(body: &str) -> bool {
+    body.starts_with(",calc")
+        || body.starts_with(",src")
+        || body.starts_with(",fonts")
+        || body.starts_with(",version")
+        || body.starts_with(",stats")
+        || body.starts_with(",asset")
+        || COMMANDS.iter().any(|(prefix, ..)| body.starts_with(prefix))
+}
 
-            let response = client.media().upload(&IMAGE_PNG, buf, None).await.unwrap();
+/// Answer the command in `body`, setting `outcome` for the audit trail when
+/// it's anything but answered.
+async fn respond(
+    event: &OriginalSyncRoomMessageEvent,
+    body: &str,
+    room: &Room,
+    client: &Client,
+    store: &Store,
+    outcome: &mut String,
+) {
+    if let Some(expr) = body.strip_prefix(",calc") {
+        calc(expr.trim(), room, event, store).await;
+        return;
+    }
 
-            let mut info = ImageInfo::new();
+    if body.starts_with(",src") {
+        src(room, event, store).await;
+        return;
+    }
 
-            info.height = Some(height.into());
-            info.width = Some(width.into());
+    if body.starts_with(",fonts") {
+        let text = fonts::command(room, event).await;
+        post(
+            room,
+            store,
+            RoomMessageEventContent::new(plain(text)).make_reply_to(
+                event,
+                ForwardThread::Yes,
+                AddMentions::Yes,
+            ),
+        )
+        .await;
+        return;
+    }
 
-            MessageType::Image(
-                ImageMessageEventContent::plain(String::new(), response.content_uri)
-                    .info(Some(Box::new(info))),
-            )
+    if let Some(args) = body.strip_prefix(",asset") {
+        let attachment = replied_to(event).map(assets::Attachment::Event);
+        let text = match assets::command(args, attachment, room, client, &event.sender, store).await
+        {
+            Ok(text) | Err(text) => text,
+        };
+        post(
+            room,
+            store,
+            RoomMessageEventContent::new(plain(text)).make_reply_to(
+                event,
+                ForwardThread::Yes,
+                AddMentions::Yes,
+            ),
+        )
+        .await;
+        return;
+    }
+
+    if body.starts_with(",stats") {
+        let text = match stats::command(room, store).await {
+            Ok(text) | Err(text) => text,
+        };
+        post(
+            room,
+            store,
+            RoomMessageEventContent::new(plain(text)).make_reply_to(
+                event,
+                ForwardThread::Yes,
+                AddMentions::Yes,
+            ),
+        )
+        .await;
+        return;
+    }
+
+    if body.starts_with(",version") {
+        let text = version::command().await;
+        post(
+            room,
+            store,
+            RoomMessageEventContent::new(plain(text)).make_reply_to(
+                event,
+                ForwardThread::Yes,
+                AddMentions::Yes,
+            ),
+        )
+        .await;
+        return;
+    }
+
+    if let Some(args) = body.strip_prefix(",typadmin") {
+        let text = match admin::command(args.trim(), client, &event.sender, store).await {
+            Ok(text) => text,
+            Err(err) => {
+                *outcome = "refused".into();
+                err
+            }
         };
+        post(
+            room,
+            store,
+            RoomMessageEventContent::new(plain(text)).make_reply_to(
+                event,
+                ForwardThread::Yes,
+                AddMentions::Yes,
+            ),
+        )
+        .await;
+        return;
+    }
+
+    let Some((engine, raw, content)) = COMMANDS
+        .iter()
+        .find_map(|(prefix, engine, raw)| Some((*engine, *raw, body.strip_prefix(prefix)?)))
+    else {
+        return;
+    };
+
+    let id = RequestId::new();
+    println!(
+        "[{id}] {} requested a render in {}",
+        event.sender,
+        room.room_id()
+    );
+    let provenance = Provenance::of(event, id);
 
-        RoomMessageEventContent::new(msg).make_reply_to(
-            &event,
+    // Every error reply carries the request ID so it can be found in the logs.
+    let reply_text = |text: &str| {
+        RoomMessageEventContent::new(plain(format!("{text}\n\nref: {id}"))).make_reply_to(
+            event,
             ForwardThread::Yes,
             AddMentions::Yes,
         )
     };
 
-    room.send(reply).await.unwrap();
+    if let Some(left) = abuse::cooldown(store, &event.sender).await {
+        println!("[{id}] Ignored, {} is on cooldown", event.sender);
+        *outcome = "ignored (cooldown)".into();
+        post(
+            room,
+            store,
+            reply_text(&templates::fill_limit(
+                &templates::get().cooldown,
+                left.as_secs().div_ceil(60),
+            )),
+        )
+        .await;
+        return;
+    }
+
+    if !encryption::can_read(client, room, &event.sender).await {
+        println!(
+            "[{id}] Ignored, {} has no device the room keys are shared with",
+            event.sender
+        );
+        *outcome = "ignored (no keys shared)".into();
+        return;
+    }
+
+    if let Some(args) = subcommand(content, "power") {
+        let reply = match policy::power_command(args, room, &event.sender, store).await {
+            Ok(text) => RoomMessageEventContent::new(plain(text)).make_reply_to(
+                event,
+                ForwardThread::Yes,
+                AddMentions::Yes,
+            ),
+            Err(err) => reply_text(&err),
+        };
+
+        post(room, store, reply).await;
+        policy::publish(room, store).await;
+        return;
+    }
+
+    if !policy::has_power(room, &event.sender, store).await {
+        println!("[{id}] Ignored, {} lacks the power level", event.sender);
+        *outcome = "ignored (power level)".into();
+        post(room, store, reply_text(&templates::get().power_required)).await;
+        return;
+    }
+
+    let action = if let Some(args) = body.strip_prefix(",doc") {
+        Some(doc::command(args, room, &event.sender, store).await)
+    } else if let Some(args) = body.strip_prefix(",template") {
+        Some(snippets::command(args, room, &event.sender, store).await)
+    } else {
+        None
+    };
+    let document = match action {
+        Some(action) => match action {
+            Ok(doc::Action::Render(source, flags)) => Some((
+                RenderRecord {
+                    source,
+                    options: RenderOptions {
+                        engine,
+                        raw,
+                        ..Default::default()
+                    },
+                },
+                flags,
+            )),
+            Ok(doc::Action::Reply(text)) => {
+                post(
+                    room,
+                    store,
+                    RoomMessageEventContent::new(plain(text)).make_reply_to(
+                        event,
+                        ForwardThread::Yes,
+                        AddMentions::Yes,
+                    ),
+                )
+                .await;
+                return;
+            }
+            Err(err) => {
+                post(room, store, reply_text(&err)).await;
+                return;
+            }
+        },
+        None => None,
+    };
+
+    if let Some(args) = subcommand(content, "theme") {
+        let reply =
+            match theme::command(args, room, &event.sender, &event.event_id, store, id).await {
+                Ok(Some(text)) => Some(RoomMessageEventContent::new(plain(text)).make_reply_to(
+                    event,
+                    ForwardThread::Yes,
+                    AddMentions::Yes,
+                )),
+                Ok(None) => None,
+                Err(err) => Some(reply_text(&err)),
+            };
+
+        if let Some(reply) = reply {
+            post(room, store, reply).await;
+        }
+        policy::publish(room, store).await;
+        return;
+    }
+
+    if let Some(args) = subcommand(content, "whatsnew") {
+        let reply = match changelog::command(args, room, &event.sender, store).await {
+            Ok(text) => RoomMessageEventContent::new(plain(text)).make_reply_to(
+                event,
+                ForwardThread::Yes,
+                AddMentions::Yes,
+            ),
+            Err(err) => reply_text(&err),
+        };
+
+        post(room, store, reply).await;
+        return;
+    }
+
+    if let Some("") = subcommand(content, "dashboard") {
+        let source = match usage::command(room, &event.sender, store).await {
+            Ok(source) => source,
+            Err(err) => {
+                post(room, store, reply_text(&err)).await;
+                return;
+            }
+        };
+
+        let Some((_permit, deadline)) = enqueue(event, &event.sender, room, store, id).await else {
+            return;
+        };
+
+        let mut options = RenderOptions {
+            raw: true,
+            ..Default::default()
+        };
+        if store.room(room.room_id()).await.images_blocked {
+            options.format = Format::Pdf;
+        }
+
+        let msgs = match render_messages(
+            client,
+            store,
+            &source,
+            &options,
+            "Usage dashboard",
+            None,
+            &deadline,
+        )
+        .await
+        {
+            Ok(msgs) | Err(Failure::Rejected(msgs, _)) => msgs,
+            Err(Failure::Limit(err)) => {
+                vec![plain(format!("{err}\n\nref: {id}"))]
+            }
+            Err(Failure::Internal(err)) => {
+                vec![plain(format!("{}\n\nref: {id}", internal_error(&err, id)))]
+            }
+            Err(Failure::Cancelled) => return,
+        };
+
+        if let Err(err) = send_replies(client, store, room, event, msgs, false, &deadline).await {
+            eprintln!("[{id}] Couldn't reply: {err}");
+        }
+        return;
+    }
+
+    if let Some(args) = subcommand(content, "quiet") {
+        let reply = match policy::command(args, room, &event.sender, store).await {
+            Ok(text) => RoomMessageEventContent::new(plain(text)).make_reply_to(
+                event,
+                ForwardThread::Yes,
+                AddMentions::Yes,
+            ),
+            Err(err) => reply_text(&err),
+        };
+
+        post(room, store, reply).await;
+        policy::publish(room, store).await;
+        return;
+    }
+
+    if let Some("") = subcommand(content, "help") {
+        let text = help::command(room, store).await;
+        post(
+            room,
+            store,
+            RoomMessageEventContent::new(plain(text)).make_reply_to(
+                event,
+                ForwardThread::Yes,
+                AddMentions::Yes,
+            ),
+        )
+        .await;
+        return;
+    }
+
+    if let Some(args) = subcommand(content, "language") {
+        let reply = match policy::language_command(args, room, &event.sender, store).await {
+            Ok(text) => RoomMessageEventContent::new(plain(text)).make_reply_to(
+                event,
+                ForwardThread::Yes,
+                AddMentions::Yes,
+            ),
+            Err(err) => reply_text(&err),
+        };
+
+        post(room, store, reply).await;
+        policy::publish(room, store).await;
+        return;
+    }
+
+    if let Some("") = subcommand(content, "features") {
+        let text = features::command(room, store).await;
+        post(
+            room,
+            store,
+            RoomMessageEventContent::new(plain(text)).make_reply_to(
+                event,
+                ForwardThread::Yes,
+                AddMentions::Yes,
+            ),
+        )
+        .await;
+        return;
+    }
+
+    if let Some(args) = subcommand(content, "automath") {
+        let reply = match policy::auto_math_command(args, room, &event.sender, store).await {
+            Ok(text) => RoomMessageEventContent::new(plain(text)).make_reply_to(
+                event,
+                ForwardThread::Yes,
+                AddMentions::Yes,
+            ),
+            Err(err) => reply_text(&err),
+        };
+
+        post(room, store, reply).await;
+        policy::publish(room, store).await;
+        return;
+    }
+
+    if let Some(args) = subcommand(content, "mentions") {
+        let reply = match policy::mentions_command(args, room, &event.sender, store).await {
+            Ok(text) => RoomMessageEventContent::new(plain(text)).make_reply_to(
+                event,
+                ForwardThread::Yes,
+                AddMentions::Yes,
+            ),
+            Err(err) => reply_text(&err),
+        };
+
+        post(room, store, reply).await;
+        policy::publish(room, store).await;
+        return;
+    }
+
+    if let Some(args) = subcommand(content, "safe") {
+        let reply = match policy::safe_command(args, room, &event.sender, store).await {
+            Ok(text) => RoomMessageEventContent::new(plain(text)).make_reply_to(
+                event,
+                ForwardThread::Yes,
+                AddMentions::Yes,
+            ),
+            Err(err) => reply_text(&err),
+        };
+
+        post(room, store, reply).await;
+        policy::publish(room, store).await;
+        return;
+    }
+
+    if let Some(args) = subcommand(content, "check") {
+        let (mut options, source) = match options::parse(args) {
+            Ok(parsed) => parsed,
+            Err(err) => {
+                post(room, store, reply_text(&err)).await;
+                return;
+            }
+        };
+        options.raw |= raw;
+        options.engine = engine;
+        if options.flavor.is_none() {
+            options.flavor = policy::flavor(&store.room(room.room_id()).await);
+        }
+        policy::restrict(room, store, &mut options).await;
+        assets::attach(room, store, &mut options).await;
+        // The output is thrown away, PDF is the cheapest to produce.
+        options.format = Format::Pdf;
+
+        if let Some(text) = too_long(source, id) {
+            post(room, store, reply_text(&text)).await;
+            return;
+        }
+        if !admit(event, &event.sender, room, store, id).await {
+            return;
+        }
+        let Some((_permit, deadline)) = enqueue(event, &event.sender, room, store, id).await else {
+            return;
+        };
+
+        let output = match render::render(source, &options, &deadline, id).await {
+            Ok(output) => output,
+            Err(err) => {
+                post(room, store, reply_text(&internal_error(&err, id))).await;
+                return;
+            }
+        };
+        if let Some(alert) = render::preamble_alert() {
+            admin::notify(client, &alert).await;
+        }
+
+        let msgs = match output {
+            Output::Timeout(exceeded) => {
+                let text = templates::fill_limit(
+                    &templates::get().check_timeout,
+                    exceeded.limit.as_secs(),
+                );
+                post(room, store, reply_text(&text)).await;
+                return;
+            }
+            Output::Cancelled => return,
+            Output::Error(diagnostics)
+            | Output::Pages {
+                warnings: diagnostics,
+                ..
+            } if !diagnostics.is_empty() => {
+                match error_messages(client, store, &diagnostics, source, &options, &deadline, id)
+                    .await
+                {
+                    Ok(msgs) | Err(Failure::Rejected(msgs, _)) => msgs,
+                    Err(Failure::Limit(err)) => vec![plain(format!("{err}\n\nref: {id}"))],
+                    Err(Failure::Internal(err)) => {
+                        vec![plain(format!("{}\n\nref: {id}", internal_error(&err, id)))]
+                    }
+                    Err(Failure::Cancelled) => return,
+                }
+            }
+            _ => vec![plain(templates::get().no_problems.clone())],
+        };
+
+        for msg in msgs {
+            post(
+                room,
+                store,
+                RoomMessageEventContent::new(msg).make_reply_to(
+                    event,
+                    ForwardThread::Yes,
+                    AddMentions::Yes,
+                ),
+            )
+            .await;
+        }
+        return;
+    }
+
+    if let Some(args) = subcommand(content, "matrix") {
+        let (mut variants, source) = match options::parse_matrix(args) {
+            Ok(parsed) => parsed,
+            Err(err) => {
+                post(room, store, reply_text(&err)).await;
+                return;
+            }
+        };
+
+        // Checked once for every variant, each compiles the whole source.
+        if let Some(text) = too_long(source, id) {
+            post(room, store, reply_text(&text)).await;
+            return;
+        }
+        if !admit(event, &event.sender, room, store, id).await {
+            return;
+        }
+        let Some((_permit, deadline)) = enqueue(event, &event.sender, room, store, id).await else {
+            return;
+        };
+
+        let flavor = policy::flavor(&store.room(room.room_id()).await);
+        let mut msgs = vec![];
+        for variant in &mut variants {
+            variant.options.raw |= raw;
+            variant.options.engine = engine;
+            variant.options.flavor = variant.options.flavor.or(flavor);
+            policy::restrict(room, store, &mut variant.options).await;
+            assets::attach(room, store, &mut variant.options).await;
+            // Variants are compared side by side, so each one is a single image.
+            variant.options.pages = PageMode::Stitch;
+
+            match render_messages(
+                client,
+                store,
+                source,
+                &variant.options,
+                &variant.label,
+                provenance.as_ref(),
+                &deadline,
+            )
+            .await
+            {
+                Ok(rendered) | Err(Failure::Rejected(rendered, _)) => msgs.extend(rendered),
+                Err(Failure::Limit(err)) => msgs.push(plain(format!("{}: {err}", variant.label))),
+                Err(Failure::Internal(err)) => msgs.push(plain(format!(
+                    "{}: {}",
+                    variant.label,
+                    internal_error(&err, id)
+                ))),
+                Err(Failure::Cancelled) => return,
+            }
+        }
+
+        let dm = variants.iter().any(|variant| variant.options.dm);
+        match send_replies(client, store, room, event, msgs, dm, &deadline).await {
+            // Conversions of any of the variants render like the first.
+            Ok(sent) => {
+                remember(
+                    store,
+                    room.room_id(),
+                    &sent,
+                    source,
+                    &variants[0].options,
+                    id,
+                )
+                .await
+            }
+            Err(err) => eprintln!("[{id}] Couldn't reply: {err}"),
+        }
+        return;
+    }
+
+    if let Some("") = subcommand(content, "history") {
+        let reply = match history::list(store, room.room_id(), &event.sender).await {
+            Ok(text) => RoomMessageEventContent::new(plain(text)).make_reply_to(
+                event,
+                ForwardThread::Yes,
+                AddMentions::Yes,
+            ),
+            Err(err) => reply_text(&err),
+        };
+
+        post(room, store, reply).await;
+        return;
+    }
+
+    // `,typ again [flags]` and `,typ history <n> [flags]` render a source from
+    // the history again, with new flags. `,doc render [flags]` and `,template
+    // render <name> [flags]` work the same.
+    let recalled = match recall_request(content) {
+        _ if document.is_some() => document,
+        Some(Ok((index, flags))) => {
+            match history::recall(store, room.room_id(), &event.sender, index).await {
+                Ok(record) => Some((record, flags)),
+                Err(err) => {
+                    post(room, store, reply_text(&err)).await;
+                    return;
+                }
+            }
+        }
+        Some(Err(err)) => {
+            post(room, store, reply_text(&err)).await;
+            return;
+        }
+        None => None,
+    };
+    let (engine, raw, content) = match &recalled {
+        Some((record, flags)) => (record.options.engine, record.options.raw, *flags),
+        None => (engine, raw, content),
+    };
+
+    let (mut options, content) = match options::parse(content) {
+        Ok(parsed) => parsed,
+        Err(err) => {
+            println!("[{id}] Rejected: {err}");
+            post(room, store, reply_text(&err)).await;
+            return;
+        }
+    };
+    let content = match &recalled {
+        Some((record, _)) => record.source.as_str(),
+        None => content,
+    };
+
+    // A bare `,typ` replied to a message renders that message.
+    let quoted;
+    let content = match replied_to(event) {
+        Some(replied_to) if content.trim().is_empty() && recalled.is_none() => {
+            match quoted_source(room, replied_to, store).await {
+                Ok(source) => {
+                    quoted = source;
+                    quoted.as_str()
+                }
+                Err(err) => {
+                    post(room, store, reply_text(err)).await;
+                    return;
+                }
+            }
+        }
+        _ => content,
+    };
+
+    if content.trim().is_empty() {
+        post(
+            room,
+            store,
+            reply_text(&templates::fill(&templates::get().empty_source)),
+        )
+        .await;
+        return;
+    }
+
+    if let Some(text) = too_long(content, id) {
+        post(room, store, reply_text(&text)).await;
+        return;
+    }
+
+    // Double sends and bridge echoes are answered once.
+    let guard = match dedup::check(room.room_id(), &event.sender, body, content) {
+        dedup::Check::Render(guard) => guard,
+        dedup::Check::Rendered(reply) => {
+            println!("[{id}] Duplicate of {reply}");
+            *outcome = "ignored (duplicate)".into();
+            let link = room.room_id().matrix_to_event_uri(reply).to_string();
+            post(
+                room,
+                store,
+                RoomMessageEventContent::new(plain(
+                    templates::get().duplicate.replace("{link}", &link),
+                ))
+                .make_reply_to(event, ForwardThread::Yes, AddMentions::Yes),
+            )
+            .await;
+            return;
+        }
+        dedup::Check::Running => {
+            println!("[{id}] Ignored, the same render is running");
+            *outcome = "ignored (duplicate)".into();
+            return;
+        }
+        dedup::Check::TooSoon => {
+            println!("[{id}] Ignored, {} rendered a moment ago", event.sender);
+            *outcome = "ignored (too soon)".into();
+            return;
+        }
+    };
+    if !admit(event, &event.sender, room, store, id).await {
+        *outcome = format!("rate limited, ref {id}");
+        return;
+    }
+    let Some((_permit, deadline)) = enqueue(event, &event.sender, room, store, id).await else {
+        *outcome = format!("not rendered, ref {id}");
+        return;
+    };
+
+    options.raw |= raw;
+    options.engine = engine;
+    policy::restrict(room, store, &mut options).await;
+    assets::attach(room, store, &mut options).await;
+    history::record(
+        store,
+        room.room_id(),
+        &event.sender,
+        RenderRecord {
+            source: content.to_owned(),
+            options: options.clone(),
+        },
+    )
+    .await;
+    if options.flavor.is_none() {
+        options.flavor = policy::flavor(&store.room(room.room_id()).await);
+    }
+
+    if options.format == Format::Png && store.room(room.room_id()).await.images_blocked {
+        options.format = Format::Pdf;
+    }
+
+    let started = Instant::now();
+    let rendered = render_messages(
+        client,
+        store,
+        content,
+        &options,
+        "",
+        provenance.as_ref(),
+        &deadline,
+    )
+    .await;
+
+    let error = match &rendered {
+        Ok(_) | Err(Failure::Cancelled) => None,
+        Err(Failure::Rejected(_, kind)) => Some(kind.as_str()),
+        Err(Failure::Limit(_)) => Some("limit exceeded"),
+        Err(Failure::Internal(_)) => Some("internal error"),
+    };
+    if !matches!(rendered, Err(Failure::Cancelled)) {
+        usage::record(
+            store,
+            usage::Render {
+                room: room.room_id(),
+                user: &event.sender,
+                latency: started.elapsed(),
+                compile: deadline.compile_time(),
+                error,
+            },
+        )
+        .await;
+    }
+    *outcome = match (&rendered, error) {
+        (Err(Failure::Cancelled), _) => format!("cancelled, ref {id}"),
+        (_, Some(error)) => format!("failed ({error}), ref {id}"),
+        (_, None) => format!("rendered, ref {id}"),
+    };
+
+    let msgs = match rendered {
+        Ok(msgs) => msgs,
+        Err(Failure::Cancelled) => return,
+        // Not the sender's fault, so it doesn't count towards a cooldown.
+        Err(Failure::Internal(err)) => {
+            post(room, store, reply_text(&internal_error(&err, id))).await;
+            return;
+        }
+        Err(failure) => {
+            abuse::record_failure(client, store, room, &event.sender, content).await;
+
+            match failure {
+                Failure::Rejected(msgs, _) => msgs,
+                Failure::Limit(err) => {
+                    post(room, store, reply_text(&err)).await;
+                    return;
+                }
+                Failure::Internal(_) | Failure::Cancelled => return,
+            }
+        }
+    };
+
+    match send_replies(client, store, room, event, msgs, options.dm, &deadline).await {
+        Ok(sent) => {
+            remember(store, room.room_id(), &sent, content, &options, id).await;
+            if let Some(reply) = sent.first() {
+                guard.answered(reply);
+            }
+        }
+        // Some rooms restrict `m.image`, remember that and send a PDF instead.
+        Err(SendError::ImagesForbidden(_)) if options.format == Format::Png => {
+            println!(
+                "[{id}] Posting images in {} is forbidden, falling back to PDF",
+                room.room_id()
+            );
+
+            store
+                .update(|state| {
+                    state
+                        .rooms
+                        .entry(room.room_id().to_owned())
+                        .or_default()
+                        .images_blocked = true
+                })
+                .await
+                .unwrap_or_else(|err| eprintln!("[{id}] Couldn't remember it: {err}"));
+            policy::publish(room, store).await;
+
+            options.format = Format::Pdf;
+            let result = match render_messages(
+                client,
+                store,
+                content,
+                &options,
+                "",
+                provenance.as_ref(),
+                &deadline,
+            )
+            .await
+            {
+                Ok(msgs) | Err(Failure::Rejected(msgs, _)) => {
+                    send_replies(client, store, room, event, msgs, options.dm, &deadline).await
+                }
+                Err(Failure::Limit(err)) => send(room, store, reply_text(&err), None)
+                    .await
+                    .map(|_| vec![])
+                    .map_err(Into::into),
+                Err(Failure::Internal(err)) => {
+                    send(room, store, reply_text(&internal_error(&err, id)), None)
+                        .await
+                        .map(|_| vec![])
+                        .map_err(Into::into)
+                }
+                Err(Failure::Cancelled) => return,
+            };
+
+            match result {
+                Ok(sent) => remember(store, room.room_id(), &sent, content, &options, id).await,
+                Err(err) => {
+                    eprintln!("[{id}] Couldn't reply with a PDF either: {err}");
+
+                    post(
+                        room,
+                        store,
+                        reply_text(&templates::fill(&templates::get().images_forbidden)),
+                    )
+                    .await;
+                }
+            }
+        }
+        Err(SendError::Stopped(Stopped::Exceeded(exceeded))) => {
+            post(room, store, reply_text(&exceeded.to_string())).await;
+        }
+        Err(SendError::Stopped(Stopped::Cancelled)) => {}
+        Err(err) => eprintln!("[{id}] Couldn't reply: {err}"),
+    }
+}
+
+/// Take a rate limit token for a render by `user`, telling them when to try
+/// again in a reply to `event` if there's none left. Returns whether to go on.
+async fn admit(
+    event: &OriginalSyncRoomMessageEvent,
+    user: &UserId,
+    room: &Room,
+    store: &Store,
+    id: RequestId,
+) -> bool {
+    let limited = match ratelimit::take(store, user, room.room_id()).await {
+        Ok(limited) => limited,
+        Err(err) => {
+            eprintln!("[{id}] Couldn't check the rate limits: {err}");
+            None
+        }
+    };
+
+    let (template, wait) = match limited {
+        None => return true,
+        Some(Limited::User(wait)) => (&templates::get().user_rate_limited, wait),
+        Some(Limited::Room(wait)) => (&templates::get().room_rate_limited, wait),
+    };
+    let text = templates::fill_limit(template, wait.as_secs() + 1);
+
+    println!("[{id}] Rate limited");
+    post(
+        room,
+        store,
+        RoomMessageEventContent::new(plain(text)).make_reply_to(
+            event,
+            ForwardThread::Yes,
+            AddMentions::Yes,
+        ),
+    )
+    .await;
+
+    false
+}
+
+/// Wait for a render slot for `user`, telling them in a reply to `event` when
+/// they can't be queued at all.
+///
+/// While it waits and runs, the render has a placeholder reply saying where
+/// it's at, which [`send_replies`] edits into the result. The time budget only
+/// starts once the render does.
+async fn enqueue(
+    event: &OriginalSyncRoomMessageEvent,
+    user: &UserId,
+    room: &Room,
+    store: &Store,
+    id: RequestId,
+) -> Option<((Permit, Tracker), Deadline)> {
+    let reply = |text: String| {
+        RoomMessageEventContent::notice_plain(text).make_reply_to(
+            event,
+            ForwardThread::Yes,
+            AddMentions::Yes,
+        )
+    };
+
+    metrics::RENDERS.inc();
+    let text = match queue::join(user, room.is_favourite()).await {
+        Ok(mut ticket) => {
+            // Renders that start right away aren't worth persisting, nor are
+            // conversions, which are for someone else than the sender.
+            if ticket.ahead().await.is_some() && event.sender == user {
+                persist(event, room, store).await;
+            }
+            let tracker = Tracker::new(room, event, id);
+            let mut shown = None;
+
+            loop {
+                if let Some(ahead) = ticket.ahead().await
+                    && shown != Some(ahead)
+                {
+                    println!("[{id}] Queued behind {ahead} render(s)");
+                    let text = match ahead {
+                        0 => templates::get().queued.clone(),
+                        ahead => templates::get()
+                            .queued_behind
+                            .replace("{ahead}", &ahead.to_string()),
+                    };
+                    tracker.show(&text).await;
+                    shown = Some(ahead);
+                }
+
+                tokio::select! {
+                    _ = tracker.cancel().cancelled_owned() => {
+                        println!("[{id}] Cancelled while queued");
+                        forget(event, store).await;
+                        return None;
+                    }
+                    permit = ticket.wait() => {
+                        // It's left persisted, to be resumed after the restart.
+                        let Some(permit) = permit else {
+                            println!("[{id}] Dropped from the queue, shutting down");
+                            return None;
+                        };
+                        forget(event, store).await;
+                        tracker.started(
+                            policy::allows(store, Some(room), policy::Traffic::Typing).await,
+                        );
+                        let deadline = policy::deadline(room, id).cancelled_by(tracker.cancel());
+                        return Some(((permit, tracker), deadline));
+                    }
+                    // Check the position again once in a while.
+                    _ = tokio::time::sleep(Duration::from_secs(5)) => {}
+                }
+            }
+        }
+        Err(Rejected::Busy) => &templates::get().queue_busy,
+        Err(Rejected::Full) => &templates::get().queue_full,
+        Err(Rejected::Closed) => &templates::get().restarting,
+    };
+
+    println!("[{id}] Not queued: {text}");
+    post(room, store, reply(format!("{text}\n\nref: {id}"))).await;
+
+    None
+}
+
+/// Keep the queued command `event` in the store until it starts, so
+/// [`recovery::resume`](crate::recovery::resume) can queue it again after a
+/// restart.
+async fn persist(event: &OriginalSyncRoomMessageEvent, room: &Room, store: &Store) {
+    let MessageType::Text(text) = &event.content.msgtype else {
+        return;
+    };
+    let job = QueuedJob {
+        room: room.room_id().to_owned(),
+        sender: event.sender.clone(),
+        body: text.body.clone(),
+        sent: event.origin_server_ts.get().into(),
+        thread: match &event.content.relates_to {
+            Some(Relation::Thread(thread)) => Some(thread.event_id.clone()),
+            _ => None,
+        },
+        in_reply_to: replied_to(event).map(ToOwned::to_owned),
+    };
+
+    if let Err(err) = store.queue(&event.event_id, &job).await {
+        eprintln!("Couldn't persist the queued {}: {err}", event.event_id);
+    }
+}
+
+/// Drop the command `event` from the persisted queue.
+async fn forget(event: &OriginalSyncRoomMessageEvent, store: &Store) {
+    if let Err(err) = store.unqueue(&event.event_id).await {
+        eprintln!("Couldn't drop the queued {}: {err}", event.event_id);
+    }
+}
+
+/// Record `sent` as renders of `source`, so `,src` can find it again.
+async fn remember(
+    store: &Store,
+    room: &RoomId,
+    sent: &[OwnedEventId],
+    source: &str,
+    options: &RenderOptions,
+    id: RequestId,
+) {
+    let record = RenderRecord {
+        source: source.to_owned(),
+        options: options.clone(),
+    };
+
+    for event in sent {
+        if let Err(err) = store.record_render(room, event, &record).await {
+            eprintln!("[{id}] Couldn't record the source of {event}: {err}");
+        }
+    }
+}
+
+/// Why a render has nothing to show.
+enum Failure {
+    /// The compiler rejected the source, with the replies explaining why and
+    /// the kind of error, see [`usage::kind`].
+    Rejected(Vec<MessageType>, String),
+    /// A limit was hit, with the text of the error reply.
+    Limit(String),
+    /// Something broke on our side, like an upload or decoding the render.
+    Internal(anyhow::Error),
+    /// The request was cancelled, nobody needs a reply.
+    Cancelled,
+}
+
+impl From<Stopped> for Failure {
+    fn from(stopped: Stopped) -> Self {
+        match stopped {
+            Stopped::Exceeded(exceeded) => Failure::Limit(exceeded.to_string()),
+            Stopped::Cancelled => Failure::Cancelled,
+        }
+    }
+}
+
+impl From<anyhow::Error> for Failure {
+    fn from(err: anyhow::Error) -> Self {
+        Failure::Internal(err)
+    }
+}
+
+impl From<JoinError> for Failure {
+    fn from(err: JoinError) -> Self {
+        Failure::Internal(err.into())
+    }
+}
+
+/// Log `err`, which broke request `id`, and the text to reply with instead.
+///
+/// The details are for the logs and the admin room, users get the
+/// `internal_error` template.
+fn internal_error(err: &anyhow::Error, id: RequestId) -> String {
+    eprintln!("[{id}] Internal error: {err:#}");
+    admin::report(format!("Request {id} failed: {err:#}"));
+
+    templates::fill(&templates::get().internal_error)
+}
+
+/// Post `content` to `room`, logging it if that fails.
+async fn post(room: &Room, store: &Store, content: RoomMessageEventContent) {
+    if let Err(err) = send(room, store, content, None).await {
+        eprintln!("Couldn't post to {}: {err}", room.room_id());
+    }
+}
+
+/// Why replies couldn't be posted.
+enum SendError {
+    Matrix(matrix_sdk::Error),
+    /// The room doesn't let the bot post images.
+    ImagesForbidden(matrix_sdk::Error),
+    Stopped(Stopped),
+}
+
+impl SendError {
+    /// The error of sending `msg`, telling apart images the room forbids.
+    fn of(err: matrix_sdk::Error, msg: &MessageType) -> Self {
+        if matches!(msg, MessageType::Image(_)) && is_forbidden(&err) {
+            SendError::ImagesForbidden(err)
+        } else {
+            SendError::Matrix(err)
+        }
+    }
+}
+
+impl From<matrix_sdk::Error> for SendError {
+    fn from(err: matrix_sdk::Error) -> Self {
+        SendError::Matrix(err)
+    }
+}
+
+impl From<Stopped> for SendError {
+    fn from(stopped: Stopped) -> Self {
+        SendError::Stopped(stopped)
+    }
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::Matrix(err) | SendError::ImagesForbidden(err) => err.fmt(f),
+            SendError::Stopped(stopped) => stopped.fmt(f),
+        }
+    }
+}
+
+/// Whether the homeserver refused an event because we lack permissions.
+fn is_forbidden(err: &matrix_sdk::Error) -> bool {
+    matches!(
+        err.client_api_error_kind(),
+        Some(ErrorKind::Forbidden { .. })
+    )
+}
+
+/// Reply to `,src` with the source of the render it's a reply to.
+async fn src(room: &Room, event: &OriginalSyncRoomMessageEvent, store: &Store) {
+    let record = match replied_to(event) {
+        Some(replied_to) => Some(store.render(room.room_id(), replied_to).await),
+        None => None,
+    };
+    let content = match record {
+        None => RoomMessageEventContent::new(plain(templates::get().src_usage.clone())),
+        Some(Ok(None)) => RoomMessageEventContent::new(plain(templates::get().src_unknown.clone())),
+        Some(Ok(Some(record))) => RoomMessageEventContent::new(html(
+            record.source.clone(),
+            format!(
+                "<pre><code class=\"language-{}\">{}</code></pre>",
+                record.options.engine.language(),
+                html_escape::encode_safe(&record.source)
+            ),
+        )),
+        Some(Err(err)) => {
+            eprintln!("Couldn't look up a render source: {err}");
+            RoomMessageEventContent::new(plain(templates::get().src_failed.clone()))
+        }
+    };
+
+    post(
+        room,
+        store,
+        content.make_reply_to(event, ForwardThread::Yes, AddMentions::Yes),
+    )
+    .await;
+}
+
+/// The message `event` replies to, if it does.
+fn replied_to(event: &OriginalSyncRoomMessageEvent) -> Option<&EventId> {
+    match &event.content.relates_to {
+        Some(Relation::Reply { in_reply_to }) => Some(&in_reply_to.event_id),
+        Some(Relation::Thread(thread)) => thread
+            .in_reply_to
+            .as_ref()
+            .map(|in_reply_to| &*in_reply_to.event_id),
+        _ => None,
+    }
+}
+
+/// The source of `event_id` in `room` for a bare `,typ` replied to it: the
+/// source of one of the bot's renders, the Typst code blocks of a formatted
+/// message, or else its text. Errors are the text of the error reply.
+async fn quoted_source(
+    room: &Room,
+    event_id: &EventId,
+    store: &Store,
+) -> Result<String, &'static str> {
+    if let Ok(Some(record)) = store.render(room.room_id(), event_id).await {
+        return Ok(record.source);
+    }
+
+    let event = room.event(event_id, None).await.map_err(|err| {
+        eprintln!("Couldn't fetch the replied to {event_id}: {err}");
+        templates::get().reply_unavailable.as_str()
+    })?;
+    let Ok(AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::RoomMessage(
+        SyncMessageLikeEvent::Original(event),
+    ))) = event.raw().deserialize()
+    else {
+        return Err(&templates::get().not_text);
+    };
+
+    let (body, formatted) = match &event.content.msgtype {
+        MessageType::Text(text) => (&text.body, &text.formatted),
+        MessageType::Notice(notice) => (&notice.body, &notice.formatted),
+        _ => return Err(&templates::get().not_text),
+    };
+    if let Some(formatted) = formatted {
+        let blocks = codeblock::blocks(&formatted.body);
+        if !blocks.is_empty() {
+            return Ok(blocks.join("\n\n"));
+        }
+    }
+
+    Ok(remove_plain_reply_fallback(body).to_owned())
+}
+
+/// Reply to `,calc` with the value of `expr`, as text.
+async fn calc(expr: &str, room: &Room, event: &OriginalSyncRoomMessageEvent, store: &Store) {
+    let id = RequestId::new();
+    println!(
+        "[{id}] {} requested a calculation in {}",
+        event.sender,
+        room.room_id()
+    );
+
+    let text = if expr.is_empty() {
+        format!(
+            "{}\n\nref: {id}",
+            templates::fill(&templates::get().empty_expression)
+        )
+    } else if let Some(text) = too_long(expr, id) {
+        format!("{text}\n\nref: {id}")
+    } else {
+        if !admit(event, &event.sender, room, store, id).await {
+            return;
+        }
+        // Evaluating runs the compiler too, so it waits for a slot like the
+        // renders. It has its own timeout rather than the render deadline.
+        let Some((_permit, _)) = enqueue(event, &event.sender, room, store, id).await else {
+            return;
+        };
+
+        match render::evaluate(expr, id).await {
+            Ok(Evaluation::Value(value)) => value,
+            Ok(Evaluation::Error(err)) => format!("{err}\n\nref: {id}"),
+            Ok(Evaluation::Timeout(exceeded)) => format!(
+                "{}\n\nref: {id}",
+                templates::fill_limit(&templates::get().calc_timeout, exceeded.limit.as_secs())
+            ),
+            Err(err) => format!("{}\n\nref: {id}", internal_error(&err, id)),
+        }
+    };
+
+    post(
+        room,
+        store,
+        RoomMessageEventContent::new(plain(text)).make_reply_to(
+            event,
+            ForwardThread::Yes,
+            AddMentions::Yes,
+        ),
+    )
+    .await;
+}
+
+/// The error reply to `source` if it's longer than `limits.max_source`, checked
+/// before anything compiles it.
+fn too_long(source: &str, id: RequestId) -> Option<String> {
+    if source.len() <= size::max_source() {
+        return None;
+    }
+
+    println!("[{id}] Rejected: {} bytes of source", source.len());
+    Some(templates::fill_limit(
+        &templates::get().source_too_long,
+        size::max_source() as u64,
+    ))
+}
+
+/// Render `source` into the messages to post, or why it couldn't be.
+///
+/// Images are captioned with `label`.
+async fn render_messages(
+    client: &Client,
+    store: &Store,
+    source: &str,
+    options: &RenderOptions,
+    label: &str,
+    provenance: Option<&Provenance>,
+    deadline: &Deadline,
+) -> Result<Vec<MessageType>, Failure> {
+    let id = deadline.id();
+    let output = render::render(source, options, deadline, id).await?;
+    if let Some(alert) = render::preamble_alert() {
+        admin::notify(client, &alert).await;
+    }
+
+    match output {
+        Output::Timeout(exceeded) => Err(Stopped::from(exceeded).into()),
+        Output::Cancelled => Err(Failure::Cancelled),
+        Output::Error(err) => {
+            let kind = usage::kind(&err, options);
+
+            Err(
+                error_messages(client, store, &err, source, options, deadline, id)
+                    .await
+                    .map_or_else(|failure| failure, |msgs| Failure::Rejected(msgs, kind)),
+            )
+        }
+        Output::Pages { pages, warnings } => {
+            let pages = match options.pages {
+                PageMode::Stitch if pages.len() > 1 => {
+                    let stitched = tokio::task::spawn_blocking(move || render::stitch(&pages));
+                    let stitched = deadline.run(Stage::Encode, stitched).await?;
+
+                    vec![stitched??]
+                }
+                _ => pages,
+            };
+
+            let max = size::max_upload(client).await;
+            let too_large = |max: usize| {
+                Failure::Limit(templates::fill_limit(
+                    &templates::get().output_too_large,
+                    max as u64,
+                ))
+            };
+
+            let mut msgs = vec![];
+            for mut page in pages {
+                if let Some(max) = max
+                    && page.len() > max
+                {
+                    if options.format == Format::Pdf {
+                        return Err(too_large(max));
+                    }
+
+                    println!("[{id}] Shrinking a {} byte image to fit", page.len());
+                    let fitted = tokio::task::spawn_blocking(move || size::fit(page, max));
+                    page = deadline
+                        .run(Stage::Encode, fitted)
+                        .await??
+                        .ok_or_else(|| too_large(max))?;
+                }
+
+                let msg = match options.format {
+                    Format::Png => {
+                        if options.webp || config::get().render.webp {
+                            let webp = tokio::task::spawn_blocking(move || size::webp(page));
+                            page = deadline.run(Stage::Encode, webp).await??;
+                        }
+                        // Into the PNG or the WebP, whichever is sent.
+                        if let Some(provenance) = provenance {
+                            provenance.embed(&mut page);
+                        }
+
+                        image_message(client, store, page, source, options, label, deadline).await
+                    }
+                    Format::Pdf => pdf_message(client, store, page, deadline, id).await,
+                };
+
+                msgs.push(msg?);
+            }
+
+            // Deprecated syntax and missing fonts don't fail the render, but
+            // users still want to hear about them.
+            if !warnings.is_empty() {
+                let html_text = format!(
+                    "<pre><code class=\"language-{}\">{}</code></pre>",
+                    options.engine.language(),
+                    html_escape::encode_safe(&warnings)
+                );
+
+                msgs.push(MessageType::notice_html(warnings, html_text));
+            }
+
+            Ok(msgs)
+        }
+    }
+}
+
+/// Compiler output as replies, attached as a text file when it's longer than
+/// `limits.max_error` bytes (4000 by default) since some servers reject huge
+/// messages.
+async fn error_messages(
+    client: &Client,
+    store: &Store,
+    output: &str,
+    source: &str,
+    options: &RenderOptions,
+    deadline: &Deadline,
+    id: RequestId,
+) -> Result<Vec<MessageType>, Failure> {
+    if card::enabled()
+        && options.format == Format::Png
+        && let Some(card) = error_card(client, store, output, source, options, deadline).await?
+    {
+        return Ok(vec![card]);
+    }
+
+    let limit = config::get().limits.max_error;
+
+    if output.len() <= limit {
+        return Ok(vec![diagnostics_message(output, source, options, id)]);
+    }
+
+    let first_line = output.lines().next().unwrap_or_default();
+    let summary = format!(
+        "{}\n\n{first_line}\n\nref: {id}",
+        templates::get().output_attached
+    );
+
+    let mut info = FileInfo::new();
+
+    info.mimetype = Some(TEXT_PLAIN_UTF_8.to_string());
+    info.size = UInt::new(output.len() as u64);
+
+    let uri = deadline
+        .run(
+            Stage::Upload,
+            upload::upload_cached(client, store, &TEXT_PLAIN_UTF_8, output.into(), id),
+        )
+        .await??;
+
+    Ok(vec![
+        plain(summary),
+        MessageType::File(
+            FileMessageEventContent::plain("output.txt".to_owned(), uri).info(Some(Box::new(info))),
+        ),
+    ])
+}
+
+/// The first diagnostic of `output` rendered on an error card, captioned with
+/// all of them as text, or `None` if it can't be.
+async fn error_card(
+    client: &Client,
+    store: &Store,
+    output: &str,
+    source: &str,
+    options: &RenderOptions,
+    deadline: &Deadline,
+) -> Result<Option<MessageType>, Failure> {
+    let id = deadline.id();
+    let parsed = match options.engine {
+        Engine::Typst => diagnostics::parse(output, render::line_offset(options)),
+        _ => return Ok(None),
+    };
+    let Some(first) = parsed.first() else {
+        return Ok(None);
+    };
+
+    let flavor = options.flavor.unwrap_or_default();
+    let card_options = RenderOptions {
+        flavor: Some(flavor),
+        raw: true,
+        ..Default::default()
+    };
+
+    let rendered = render::render(
+        &card::source(first, source, flavor),
+        &card_options,
+        deadline,
+        id,
+    )
+    .await;
+    let page = match rendered {
+        Ok(Output::Pages { mut pages, .. }) if !pages.is_empty() => pages.remove(0),
+        Ok(Output::Cancelled) => return Err(Failure::Cancelled),
+        Ok(_) | Err(_) => {
+            eprintln!("[{id}] Couldn't render the error card, sending text");
+            return Ok(None);
+        }
+    };
+
+    let text = format!("{}\n\nref: {id}", diagnostics::to_plain(&parsed, source));
+    image_message(client, store, page, &text, &card_options, "", deadline)
+        .await
+        .map(Some)
+}
+
+/// Format compiler output, pointing into `source` when we understand it and
+/// as a plain code block otherwise.
+fn diagnostics_message(
+    output: &str,
+    source: &str,
+    options: &RenderOptions,
+    id: RequestId,
+) -> MessageType {
+    let parsed = match options.engine {
+        Engine::Typst => diagnostics::parse(output, render::line_offset(options)),
+        _ => vec![],
+    };
+
+    if parsed.is_empty() {
+        let html_text = format!(
+            "<pre><code class=\"language-{}\">{}</code></pre><p>ref: {id}</p>",
+            options.engine.language(),
+            html_escape::encode_safe(output)
+        );
+
+        return html(format!("{output}\n\nref: {id}"), html_text);
+    }
+
+    html(
+        format!("{}\n\nref: {id}", diagnostics::to_plain(&parsed, source)),
+        format!("{}<p>ref: {id}</p>", diagnostics::to_html(&parsed, source)),
+    )
+}
+
+/// Post `msgs` as replies to `event`, or privately to its sender with `dm`,
+/// returning the events they were posted as.
+///
+/// The request's placeholder reply, if it has one, is edited into the first
+/// message.
+async fn send_replies(
+    client: &Client,
+    store: &Store,
+    room: &Room,
+    event: &OriginalSyncRoomMessageEvent,
+    msgs: Vec<MessageType>,
+    dm: bool,
+    deadline: &Deadline,
+) -> Result<Vec<OwnedEventId>, SendError> {
+    let id = deadline.id();
+    let mut sent = vec![];
+    let provenance = Provenance::of(event, id);
+    let mut placeholder = progress::finish(id).await;
+
+    if dm {
+        let dm = match client.get_dm_room(&event.sender) {
+            Some(dm) => dm,
+            None => client.create_dm(&event.sender).await?,
+        };
+
+        for msg in msgs {
+            let content = RoomMessageEventContent::new(msg);
+            let response =
+                send_within(&dm, store, content, provenance.as_ref(), deadline).await??;
+            sent.push(response.event_id);
+        }
+
+        let notice =
+            RoomMessageEventContent::notice_plain(templates::fill(&templates::get().dm_notice));
+        let notice = match placeholder {
+            Some(placeholder) => {
+                notice.make_replacement(ReplacementMetadata::new(placeholder, None))
+            }
+            None => notice.make_reply_to(event, ForwardThread::Yes, AddMentions::Yes),
+        };
+        send_within(room, store, notice, None, deadline).await??;
+
+        println!("[{id}] Replied in {}", dm.room_id());
+        return Ok(sent);
+    }
+
+    let mut sent_image = false;
+    for msg in msgs {
+        let is_image = matches!(msg, MessageType::Image(_));
+        let error = |err| SendError::of(err, &msg);
+        let content = RoomMessageEventContent::new(msg.clone());
+
+        if let Some(placeholder) = placeholder.take() {
+            let edit =
+                content.make_replacement(ReplacementMetadata::new(placeholder.clone(), None));
+            send_within(room, store, edit, provenance.as_ref(), deadline)
+                .await?
+                .map_err(error)?;
+            // Replies to the render point at the original event, not the edit.
+            sent.push(placeholder);
+            sent_image |= is_image;
+            continue;
+        }
+
+        let reply = content.make_reply_to(event, ForwardThread::Yes, AddMentions::Yes);
+        let response = send_within(room, store, reply, provenance.as_ref(), deadline)
+            .await?
+            .map_err(error)?;
+        sent.push(response.event_id);
+        sent_image |= is_image;
+    }
+
+    println!("[{id}] Replied");
+    if sent_image {
+        policy::images_allowed(room, store).await;
+    }
+    Ok(sent)
+}
+
+/// Send `content` to `room` through the outbox, with the provenance of the
+/// render if it has one.
+async fn send(
+    room: &Room,
+    store: &Store,
+    content: RoomMessageEventContent,
+    provenance: Option<&Provenance>,
+) -> matrix_sdk::Result<send_message_event::v3::Response> {
+    send_as(room, store, TransactionId::new(), content, provenance).await
+}
+
+/// [`send`] as `txn_id`.
+async fn send_as(
+    room: &Room,
+    store: &Store,
+    txn_id: OwnedTransactionId,
+    content: RoomMessageEventContent,
+    provenance: Option<&Provenance>,
+) -> matrix_sdk::Result<send_message_event::v3::Response> {
+    let mut content = serde_json::to_value(&content)?;
+    if let Some(provenance) = provenance {
+        content[provenance::EVENT_KEY] = provenance.json();
+    }
+
+    outbox::send(room, store, txn_id, content).await
+}
+
+/// [`send`] within the send budget of `deadline`.
+async fn send_within(
+    room: &Room,
+    store: &Store,
+    content: RoomMessageEventContent,
+    provenance: Option<&Provenance>,
+    deadline: &Deadline,
+) -> Result<matrix_sdk::Result<send_message_event::v3::Response>, Stopped> {
+    let txn_id = TransactionId::new();
+    let sent = deadline
+        .run(
+            Stage::Send,
+            send_as(room, store, txn_id.clone(), content, provenance),
+        )
+        .await;
+    // Given up on, it mustn't go out after a restart either.
+    if sent.is_err() {
+        outbox::forget(store, &txn_id).await;
+    }
+
+    sent
+}
+
+/// Upload a rendered PDF and build the file message pointing to it.
+async fn pdf_message(
+    client: &Client,
+    store: &Store,
+    pdf: Vec<u8>,
+    deadline: &Deadline,
+    id: RequestId,
+) -> Result<MessageType, Failure> {
+    let mut info = FileInfo::new();
+
+    info.mimetype = Some(APPLICATION_PDF.to_string());
+    info.size = UInt::new(pdf.len() as u64);
+
+    let uri = deadline
+        .run(
+            Stage::Upload,
+            upload::upload_cached(client, store, &APPLICATION_PDF, pdf, id),
+        )
+        .await??;
+
+    Ok(MessageType::File(
+        FileMessageEventContent::plain("render.pdf".to_owned(), uri).info(Some(Box::new(info))),
+    ))
+}
+
+/// Which history entry `,typ again` or `,typ history <n>` asks for, and the
+/// flags to render it with.
+fn recall_request(content: &str) -> Option<Result<(usize, &str), String>> {
+    if let Some(flags) = subcommand(content, "again") {
+        return Some(Ok((1, flags)));
+    }
+
+    let args = subcommand(content, "history")?;
+    let (index, flags) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+
+    Some(
+        index
+            .parse()
+            .map(|index| (index, flags.trim()))
+            .map_err(|_| templates::fill(&templates::get().history_usage)),
+    )
+}
+
+/// The arguments of `,typ <name> …`, if `content` is that subcommand.
+fn subcommand<'a>(content: &'a str, name: &str) -> Option<&'a str> {
+    let rest = content.trim_start().strip_prefix(name)?;
+
+    (rest.is_empty() || rest.starts_with(char::is_whitespace)).then(|| rest.trim())
+}
+
+/// Upload a rendered PNG or WebP and build the image message pointing to it.
+///
+/// The image is captioned with its (truncated) source, so it can be followed
+/// with a screen reader or a client that doesn't preview images.
+async fn image_message(
+    client: &Client,
+    store: &Store,
+    png: Vec<u8>,
+    source: &str,
+    options: &RenderOptions,
+    label: &str,
+    deadline: &Deadline,
+) -> Result<MessageType, Failure> {
+    let id = deadline.id();
+    let (mimetype, filename) = match image::guess_format(&png) {
+        Ok(ImageFormat::WebP) => (&*IMAGE_WEBP, "render.webp"),
+        _ => (&IMAGE_PNG, "render.png"),
+    };
+    let img = image::load_from_memory(&png)
+        .context("Couldn't decode the render")?
+        .to_rgba8();
+    let (width, height) = img.dimensions();
+    let size = png.len();
+
+    let uri = deadline
+        .run(
+            Stage::Upload,
+            upload::upload_cached(client, store, mimetype, png, id),
+        )
+        .await??;
+
+    let mut info = ImageInfo::new();
+
+    info.height = Some(height.into());
+    info.width = Some(width.into());
+    info.mimetype = Some(mimetype.to_string());
+    info.size = UInt::new(size as u64);
+
+    // Placeholders are nice to have, the render goes out without them.
+    let preview = tokio::task::spawn_blocking(move || preview::of(&img));
+    match deadline.run(Stage::Encode, preview).await? {
+        Ok(Preview {
+            blurhash,
+            thumbnail,
+        }) => {
+            info.blurhash = Some(blurhash);
+
+            if let Some(thumbnail) = thumbnail {
+                let size = thumbnail.png.len();
+                let uploaded = deadline
+                    .run(
+                        Stage::Upload,
+                        upload::upload_cached(client, store, &IMAGE_PNG, thumbnail.png, id),
+                    )
+                    .await?;
+
+                match uploaded {
+                    Ok(thumbnail_uri) => {
+                        let mut thumbnail_info = ThumbnailInfo::new();
+                        thumbnail_info.height = Some(thumbnail.height.into());
+                        thumbnail_info.width = Some(thumbnail.width.into());
+                        thumbnail_info.mimetype = Some(IMAGE_PNG.to_string());
+                        thumbnail_info.size = UInt::new(size as u64);
+
+                        info.thumbnail_info = Some(Box::new(thumbnail_info));
+                        info.thumbnail_source = Some(MediaSource::Plain(thumbnail_uri));
+                    }
+                    Err(err) => eprintln!("[{id}] Couldn't upload the thumbnail: {err:#}"),
+                }
+            }
+        }
+        Err(err) => eprintln!("[{id}] Couldn't make a preview of the render: {err}"),
+    }
+
+    let (caption, html_caption) = caption(source, options, label);
+    let mut content = ImageMessageEventContent::plain(caption, uri).info(Some(Box::new(info)));
+    content.formatted = Some(FormattedBody::html(html_caption));
+    // With a filename, clients show the body as a caption (MSC2530).
+    content.filename = Some(filename.to_owned());
+
+    Ok(MessageType::Image(content))
+}
+
+/// The plain and HTML caption of a render of `source`, prefixed with `label`.
+fn caption(source: &str, options: &RenderOptions, label: &str) -> (String, String) {
+    let source = source.trim();
+    let mut truncated: String = source.chars().take(CAPTION_LENGTH).collect();
+    if truncated.len() < source.len() {
+        truncated.push('…');
+    }
+
+    let html_code = format!(
+        "<pre><code class=\"language-{}\">{}</code></pre>",
+        options.engine.language(),
+        html_escape::encode_safe(&truncated)
+    );
+
+    if label.is_empty() {
+        (truncated, html_code)
+    } else {
+        (
+            format!("{label}\n{truncated}"),
+            format!("<p>{}</p>{html_code}", html_escape::encode_safe(label)),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{Value, json};
+
+    use super::*;
+
+    fn event(content: Value) -> OriginalSyncRoomMessageEvent {
+        serde_json::from_value(json!({
+            "type": "m.room.message",
+            "event_id": "$command",
+            "sender": "@alice:localhost",
+            "origin_server_ts": 0,
+            "content": content,
+        }))
+        .unwrap()
+    }
+
+    fn formatted(event: &OriginalSyncRoomMessageEvent) -> Option<&str> {
+        match &event.content.msgtype {
+            MessageType::Text(text) => text.formatted.as_ref().map(|html| html.body.as_str()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn strips_reply_fallbacks() {
+        let reply = event(json!({
+            "msgtype": "m.text",
+            "body": "> <@bob:localhost> $x$\n\n,typ $y$",
+            "format": "org.matrix.custom.html",
+            "formatted_body": "<mx-reply><blockquote>$x$</blockquote></mx-reply>,typ $y$",
+        }));
+
+        let stripped = without_reply_fallback(&reply).unwrap();
+        assert_eq!(stripped.content.body(), ",typ $y$");
+        assert_eq!(formatted(&stripped), Some(",typ $y$"));
+        assert!(without_reply_fallback(&stripped).is_none());
+    }
+
+    #[test]
+    fn strips_plain_reply_fallbacks() {
+        let reply = event(json!({
+            "msgtype": "m.text",
+            "body": "> <@bob:localhost> hi\n> there\n\n,version",
+        }));
+
+        let stripped = without_reply_fallback(&reply).unwrap();
+        assert_eq!(stripped.content.body(), ",version");
+        assert_eq!(formatted(&stripped), None);
+    }
+
+    #[test]
+    fn keeps_messages_without_fallbacks() {
+        let plain = event(json!({ "msgtype": "m.text", "body": "> quoting\nsomeone" }));
+        let notice = event(json!({ "msgtype": "m.notice", "body": "> <@bob:localhost> hi\n\nyo" }));
+
+        assert!(without_reply_fallback(&plain).is_none());
+        assert!(without_reply_fallback(&notice).is_none());
+    }
+
+    /// A timestamp `ago` before now, or after with a negative one.
+    fn sent(ago: i64) -> MilliSecondsSinceUnixEpoch {
+        let now = SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as i64;
+
+        MilliSecondsSinceUnixEpoch(UInt::new((now - ago) as u64).unwrap())
+    }
+
+    #[test]
+    fn stale_events() {
+        let max_age = config::get().commands.max_age as i64 * 1000;
+
+        assert!(!stale(sent(0), None));
+        assert!(!stale(sent(max_age - 5000), None));
+        assert!(stale(sent(max_age), None));
+    }
+
+    #[test]
+    fn stale_events_by_their_age() {
+        let max_age = config::get().commands.max_age as i64 * 1000;
+
+        // The homeserver's age wins over a clock that's off.
+        assert!(!stale(sent(10 * max_age), Some(Int::new(1000).unwrap())));
+        assert!(stale(sent(0), Some(Int::new(max_age).unwrap())));
+        assert!(!stale(sent(0), Some(Int::new(-1000).unwrap())));
+    }
+
+    #[test]
+    fn future_events_are_fresh() {
+        assert!(!stale(sent(-60 * 60 * 1000), None));
+    }
 }
@@ -0,0 +1,121 @@
+use std::{
+    fmt::Write,
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use crate::queue;
+
+/// A count that only goes up.
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    const fn new() -> Self {
+        Self(AtomicU64::new(0))
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+}
+
+/// How long something took, counted into buckets by their upper bound in
+/// seconds.
+pub struct Histogram {
+    bounds: &'static [f64],
+    /// The count of each bucket, then the sum and the total count.
+    inner: Mutex<(Vec<u64>, f64, u64)>,
+}
+
+impl Histogram {
+    const fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            inner: Mutex::new((Vec::new(), 0.0, 0)),
+        }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        let mut inner = self.inner.lock().unwrap();
+        let (buckets, sum, count) = &mut *inner;
+
+        buckets.resize(self.bounds.len(), 0);
+        for (bucket, bound) in buckets.iter_mut().zip(self.bounds) {
+            if secs <= *bound {
+                *bucket += 1;
+            }
+        }
+        *sum += secs;
+        *count += 1;
+    }
+}
+
+/// Renders asked for, including the ones that weren't queued.
+pub static RENDERS: Counter = Counter::new();
+/// Renders that didn't compile, timed out or couldn't run the compiler.
+pub static FAILURES: Counter = Counter::new();
+/// How long the compiler ran, for renders that weren't cached.
+pub static COMPILE_SECONDS: Histogram =
+    Histogram::new(&[0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 25.0, 60.0]);
+/// Bytes uploaded to the homeserver.
+pub static UPLOAD_BYTES: Counter = Counter::new();
+/// Syncs with the homeserver that failed.
+pub static SYNC_ERRORS: Counter = Counter::new();
+
+/// Every metric in the Prometheus text format.
+pub fn render() -> String {
+    let mut out = String::new();
+
+    let counters = [
+        ("typit_renders_total", "Renders requested", &RENDERS),
+        (
+            "typit_render_failures_total",
+            "Renders that failed",
+            &FAILURES,
+        ),
+        ("typit_upload_bytes_total", "Bytes uploaded", &UPLOAD_BYTES),
+        ("typit_sync_errors_total", "Failed syncs", &SYNC_ERRORS),
+    ];
+    for (name, help, counter) in counters {
+        let value = counter.0.load(Ordering::Relaxed);
+        let _ = writeln!(
+            out,
+            "# HELP {name} {help}\n# TYPE {name} counter\n{name} {value}"
+        );
+    }
+
+    let (running, waiting) = queue::depth();
+    let gauges = [
+        ("typit_queue_running", "Renders running", running),
+        ("typit_queue_waiting", "Renders waiting for a slot", waiting),
+    ];
+    for (name, help, value) in gauges {
+        let _ = writeln!(
+            out,
+            "# HELP {name} {help}\n# TYPE {name} gauge\n{name} {value}"
+        );
+    }
+
+    let name = "typit_compile_seconds";
+    let _ = writeln!(
+        out,
+        "# HELP {name} Time spent compiling\n# TYPE {name} histogram"
+    );
+    let (buckets, sum, count) = &*COMPILE_SECONDS.inner.lock().unwrap();
+    for (i, bound) in COMPILE_SECONDS.bounds.iter().enumerate() {
+        let value = buckets.get(i).copied().unwrap_or(0);
+        let _ = writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {value}");
+    }
+    let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {count}");
+    let _ = writeln!(out, "{name}_sum {sum}\n{name}_count {count}");
+
+    out
+}
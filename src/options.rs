@@ -0,0 +1,391 @@
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    assets::Assets,
+    style::{MAX_POINTS, MAX_TEXT_SIZE, Style},
+};
+
+/// How documents with more than one page are posted.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum PageMode {
+    /// Stack every page vertically into a single image.
+    #[default]
+    Stitch,
+    /// Send each page as its own image.
+    Separate,
+}
+
+/// The compiler a command renders with.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Engine {
+    #[default]
+    Typst,
+    Latex,
+    Graphviz,
+    Mermaid,
+}
+
+impl Engine {
+    /// The language name used to highlight the engine's source.
+    pub fn language(self) -> &'static str {
+        match self {
+            Engine::Typst => "typst",
+            Engine::Latex => "latex",
+            Engine::Graphviz => "dot",
+            Engine::Mermaid => "mermaid",
+        }
+    }
+
+    /// The program the engine compiles with.
+    pub fn program(self) -> &'static str {
+        match self {
+            Engine::Typst => "typst",
+            Engine::Latex => "tectonic",
+            Engine::Graphviz => "dot",
+            Engine::Mermaid => "mmdc",
+        }
+    }
+}
+
+/// The file type renders are produced as.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    #[default]
+    Png,
+    Pdf,
+}
+
+/// The catppuccin flavor the preamble themes documents with.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Flavor {
+    Latte,
+    Frappe,
+    Macchiato,
+    #[default]
+    Mocha,
+}
+
+impl Flavor {
+    pub const ALL: [Flavor; 4] = [
+        Flavor::Latte,
+        Flavor::Frappe,
+        Flavor::Macchiato,
+        Flavor::Mocha,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Flavor::Latte => "latte",
+            Flavor::Frappe => "frappe",
+            Flavor::Macchiato => "macchiato",
+            Flavor::Mocha => "mocha",
+        }
+    }
+
+    /// The flavor's base and text colors, as hex without the `#`.
+    pub fn colors(self) -> (&'static str, &'static str) {
+        match self {
+            Flavor::Latte => ("EFF1F5", "4C4F69"),
+            Flavor::Frappe => ("303446", "C6D0F5"),
+            Flavor::Macchiato => ("24273A", "CAD3F5"),
+            Flavor::Mocha => ("1E1E2E", "CDD6F4"),
+        }
+    }
+}
+
+impl fmt::Display for Flavor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Flavor {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Flavor::ALL
+            .into_iter()
+            .find(|flavor| flavor.as_str().eq_ignore_ascii_case(s))
+            .ok_or_else(|| {
+                format!("Unknown flavor `{s}`, expected one of latte, frappe, macchiato or mocha")
+            })
+    }
+}
+
+/// Options given to `,typ` as leading `--flag`s.
+///
+/// Kept with the renders so conversions look the same, all but the ones a
+/// conversion picks again.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RenderOptions {
+    #[serde(skip)]
+    pub pages: PageMode,
+    /// Falls back to the room's default when not given.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub flavor: Option<Flavor>,
+    /// Send the result to the requester privately instead of the room.
+    #[serde(skip)]
+    pub dm: bool,
+    /// Compile the source verbatim, without the preamble.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub raw: bool,
+    /// Pixels per inch, [`DEFAULT_PPI`] when not given.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ppi: Option<u32>,
+    #[serde(skip)]
+    pub format: Format,
+    /// Picked by the command rather than a flag.
+    pub engine: Engine,
+    pub style: Style,
+    /// Compile without packages and with the minimal preamble.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub safe: bool,
+    /// The base text size in points, picked from the source when not given.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u32>,
+    /// Lay the source out as an equation: centered, with [`MATH_MARGIN`]
+    /// unless `--margin` is given.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub math: bool,
+    /// Post images as lossless WebP when that's smaller.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub webp: bool,
+    /// The room's assets, set by the command rather than a flag.
+    #[serde(skip)]
+    pub assets: Option<Assets>,
+}
+
+/// The resolution renders use unless asked otherwise.
+pub const DEFAULT_PPI: u32 = 216;
+
+/// Higher resolutions make for enormous uploads.
+pub const MAX_PPI: u32 = 432;
+
+/// The margin of equations, in points, tighter than a card's.
+pub const MATH_MARGIN: u32 = 6;
+
+/// The most renders a single `,typ matrix` may ask for.
+pub const MAX_VARIANTS: usize = 8;
+
+/// One option combination rendered by `,typ matrix`.
+#[derive(Debug, Clone)]
+pub struct Variant {
+    /// Describes the combination, used as the image caption.
+    pub label: String,
+    pub options: RenderOptions,
+}
+
+/// Parse the arguments of `,typ matrix`: leading axis flags with comma
+/// separated values (`--themes latte,mocha`), followed by the usual flags and
+/// the source.
+pub fn parse_matrix(input: &str) -> Result<(Vec<Variant>, &str), String> {
+    let mut flavors = vec![];
+    let mut ppis = vec![];
+    let mut rest = input.trim_start();
+
+    while let Some(flag) = next_flag(rest) {
+        let (name, inline_value) = split_flag(flag);
+
+        match name {
+            "themes" | "flavors" => {
+                rest = rest[flag.len()..].trim_start();
+                flavors = take_value(&mut rest, name, inline_value)?
+                    .split(',')
+                    .map(str::parse)
+                    .collect::<Result<Vec<Flavor>, _>>()?;
+            }
+            "ppi" => {
+                rest = rest[flag.len()..].trim_start();
+                ppis = take_value(&mut rest, name, inline_value)?
+                    .split(',')
+                    .map(parse_ppi)
+                    .collect::<Result<_, _>>()?;
+            }
+            _ => break,
+        }
+    }
+
+    if flavors.is_empty() && ppis.is_empty() {
+        return Err(
+            "`,typ matrix` needs at least one axis, like `--themes latte,mocha` or `--ppi 144,300`"
+                .into(),
+        );
+    }
+
+    let (base, rest) = parse(rest)?;
+
+    // An axis that wasn't given only has the base value.
+    let flavors = if flavors.is_empty() {
+        vec![None]
+    } else {
+        flavors.into_iter().map(Some).collect()
+    };
+    let ppis = if ppis.is_empty() {
+        vec![None]
+    } else {
+        ppis.into_iter().map(Some).collect()
+    };
+
+    let mut variants = vec![];
+    for flavor in &flavors {
+        for ppi in &ppis {
+            let mut label = vec![];
+            if let Some(flavor) = flavor {
+                label.push(format!("flavor: {flavor}"));
+            }
+            if let Some(ppi) = ppi {
+                label.push(format!("ppi: {ppi}"));
+            }
+
+            variants.push(Variant {
+                label: label.join(", "),
+                options: RenderOptions {
+                    flavor: flavor.or(base.flavor),
+                    ppi: ppi.or(base.ppi),
+                    ..base.clone()
+                },
+            });
+        }
+    }
+
+    if variants.len() > MAX_VARIANTS {
+        return Err(format!(
+            "That's {} variants, `,typ matrix` renders at most {MAX_VARIANTS}",
+            variants.len()
+        ));
+    }
+
+    Ok((variants, rest))
+}
+
+/// The flags [`parse`] takes, with their value and what they do, for
+/// `,typ help`.
+pub const FLAGS: &[(&str, &str)] = &[
+    ("--flavor <flavor>", "themes the render with another flavor"),
+    ("--pdf", "sends a PDF instead of an image"),
+    ("--webp", "sends a WebP image when it's smaller"),
+    ("--pages", "sends each page as its own image"),
+    ("--stitch", "stacks the pages into one image, the default"),
+    ("--ppi <n>", "sets the resolution"),
+    ("--scale <n>", "scales the default resolution"),
+    ("--size <pt>", "sets the text size"),
+    ("--math", "lays the source out as an equation"),
+    ("--raw", "compiles the source without the preamble"),
+    ("--safe", "compiles without packages"),
+    ("--margin <pt>", "sets the margin around the card"),
+    ("--radius <pt>", "rounds the corners of the card"),
+    (
+        "--shadow, --no-shadow",
+        "draws a shadow under the card or not",
+    ),
+    ("--dm", "sends the render to you privately"),
+];
+
+/// Split the leading `--flag`s off a command, returning the parsed options and
+/// the remaining source.
+pub fn parse(input: &str) -> Result<(RenderOptions, &str), String> {
+    let mut options = RenderOptions::default();
+    let mut rest = input.trim_start();
+
+    while let Some(flag) = next_flag(rest) {
+        rest = rest[flag.len()..].trim_start();
+
+        let (name, inline_value) = split_flag(flag);
+
+        let mut value = || take_value(&mut rest, name, inline_value);
+
+        match name {
+            "stitch" => options.pages = PageMode::Stitch,
+            "pages" => options.pages = PageMode::Separate,
+            "flavor" => options.flavor = Some(value()?.parse()?),
+            "dm" => options.dm = true,
+            "raw" => options.raw = true,
+            "safe" => options.safe = true,
+            "math" => options.math = true,
+            "size" => {
+                options.size = Some(parse_points(name, value()?)?.clamp(1, MAX_TEXT_SIZE * 2))
+            }
+            "pdf" => options.format = Format::Pdf,
+            "webp" => options.webp = true,
+            "ppi" => options.ppi = Some(parse_ppi(value()?)?),
+            "margin" => options.style.margin = Some(parse_points(name, value()?)?),
+            "radius" => options.style.radius = Some(parse_points(name, value()?)?),
+            "shadow" => options.style.shadow = Some(true),
+            "no-shadow" => options.style.shadow = Some(false),
+            "scale" => {
+                let scale = value()?
+                    .parse::<f32>()
+                    .ok()
+                    .filter(|scale| *scale > 0.0)
+                    .ok_or("`--scale` needs a positive number")?;
+
+                options.ppi = Some(((DEFAULT_PPI as f32 * scale) as u32).clamp(1, MAX_PPI));
+            }
+            _ => return Err(format!("Unknown flag `--{name}`, see `,typ help`")),
+        }
+    }
+
+    if options.math {
+        options.style.margin.get_or_insert(MATH_MARGIN);
+    }
+
+    Ok((options, rest))
+}
+
+/// Parse a resolution, capped to [`MAX_PPI`].
+fn parse_ppi(value: &str) -> Result<u32, String> {
+    match value.parse::<u32>() {
+        Ok(ppi) if ppi > 0 => Ok(ppi.min(MAX_PPI)),
+        _ => Err(format!("Invalid resolution `{value}`")),
+    }
+}
+
+/// Parse a length in points for `--name`, capped to [`MAX_POINTS`].
+fn parse_points(name: &str, value: &str) -> Result<u32, String> {
+    value
+        .trim_end_matches("pt")
+        .parse::<u32>()
+        .map(|points| points.min(MAX_POINTS))
+        .map_err(|_| format!("`--{name}` needs a length in points"))
+}
+
+/// Split `--name=value` into its name and inline value.
+fn split_flag(flag: &str) -> (&str, Option<&str>) {
+    match flag[2..].split_once('=') {
+        Some((name, value)) => (name, Some(value)),
+        None => (&flag[2..], None),
+    }
+}
+
+/// Flags that take a value accept both `--flag value` and `--flag=value`.
+fn take_value<'a>(
+    rest: &mut &'a str,
+    name: &str,
+    inline_value: Option<&'a str>,
+) -> Result<&'a str, String> {
+    if let Some(value) = inline_value {
+        return Ok(value);
+    }
+
+    let value = rest
+        .split_whitespace()
+        .next()
+        .ok_or_else(|| format!("`--{name}` needs a value"))?;
+    *rest = rest[value.len()..].trim_start();
+
+    Ok(value)
+}
+
+/// Take the next `--flag` token, anything else (like typst's `---`) is source.
+fn next_flag(input: &str) -> Option<&str> {
+    let token = input.split_whitespace().next()?;
+    let name = token.strip_prefix("--")?;
+
+    name.starts_with(|c: char| c.is_ascii_alphabetic())
+        .then_some(token)
+}
@@ -0,0 +1,147 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use matrix_sdk::{
+    Client,
+    ruma::{OwnedTransactionId, TransactionId, api::client::message::send_message_event},
+};
+use serde_json::Value;
+
+use crate::{
+    matrix::Messages,
+    retry,
+    state::{PendingReply, Store},
+};
+
+/// Replies still unsent after this long are dropped, the conversation has
+/// moved on.
+const MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Send `content` as an `m.room.message` to `room` as `txn_id`, waiting out
+/// rate limits and retrying network and server errors.
+///
+/// Once an attempt failed, the reply is kept in the outbox until it's sent,
+/// so a restart in the meantime doesn't lose it, see [`flush`], unless the
+/// caller gives up on it and [`forget`]s it. Every attempt uses the same
+/// transaction ID, the homeserver drops duplicates.
+pub async fn send(
+    room: &impl Messages,
+    store: &Store,
+    txn_id: OwnedTransactionId,
+    content: Value,
+) -> matrix_sdk::Result<send_message_event::v3::Response> {
+    deliver(room, store, txn_id, content, false).await
+}
+
+/// Send the replies left in the outbox for the rooms of `client` when the bot
+/// stopped, unless they're too old by now.
+pub async fn flush(client: &Client, store: &Store) -> anyhow::Result<()> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    // The other accounts deliver the replies of their rooms.
+    let (stale, pending): (Vec<_>, Vec<_>) = store
+        .outbox()
+        .await?
+        .into_iter()
+        .partition(|(_, reply)| now.saturating_sub(reply.queued) > MAX_AGE.as_secs());
+    for (txn_id, _) in &stale {
+        store.remove_reply(txn_id).await?;
+    }
+    let stale = stale.len();
+
+    if stale > 0 {
+        println!("Dropped {stale} unsent replies, too old to send now");
+    }
+
+    for (txn_id, reply) in pending {
+        let Some(room) = client.get_room(&reply.room) else {
+            continue;
+        };
+
+        println!("Sending the reply {txn_id} left in the outbox");
+        if let Err(err) = deliver(&room, store, txn_id.clone(), reply.content, true).await {
+            eprintln!("Couldn't send the reply {txn_id} from the outbox: {err}");
+        }
+    }
+
+    Ok(())
+}
+
+/// Send `content` to `room` as `txn_id`, persisting it in the outbox when an
+/// attempt fails unless it already is.
+async fn deliver(
+    room: &impl Messages,
+    store: &Store,
+    txn_id: OwnedTransactionId,
+    content: Value,
+    mut persisted: bool,
+) -> matrix_sdk::Result<send_message_event::v3::Response> {
+    let mut attempt = 1;
+
+    loop {
+        let err = match room.send_message(content.clone(), &txn_id).await {
+            Ok(response) => {
+                if persisted {
+                    forget(store, &txn_id).await;
+                }
+                return Ok(response);
+            }
+            Err(err) => err,
+        };
+
+        // Retrying after a restart won't help either.
+        if !retry::is_retryable(&err) {
+            if persisted {
+                forget(store, &txn_id).await;
+            }
+            return Err(err);
+        }
+
+        if !persisted {
+            persisted = keep(room, store, &txn_id, &content).await;
+        }
+
+        let Some(delay) = retry::delay(&err, attempt) else {
+            return Err(err);
+        };
+        eprintln!(
+            "Sending to {} failed (attempt {attempt}/{}), retrying in {delay:?}: {err}",
+            room.id(),
+            retry::MAX_ATTEMPTS
+        );
+
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+/// Persist the reply `txn_id` to `room` in the outbox, returning whether it
+/// was.
+async fn keep(
+    room: &impl Messages,
+    store: &Store,
+    txn_id: &TransactionId,
+    content: &Value,
+) -> bool {
+    let reply = PendingReply {
+        room: room.id().to_owned(),
+        content: content.clone(),
+        queued: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+    };
+
+    match store.keep_reply(txn_id, &reply).await {
+        Ok(_) => true,
+        Err(err) => {
+            eprintln!("Couldn't persist the unsent reply {txn_id}: {err}");
+            false
+        }
+    }
+}
+
+/// Drop the reply `txn_id` from the outbox.
+pub async fn forget(store: &Store, txn_id: &TransactionId) {
+    if let Err(err) = store.remove_reply(txn_id).await {
+        eprintln!("Couldn't drop the sent reply {txn_id}: {err}");
+    }
+}
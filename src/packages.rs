@@ -0,0 +1,162 @@
+use std::{
+    env,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::Context;
+use tokio::{fs, process::Command};
+
+use crate::{config, render};
+
+/// The package the preamble themes renders with.
+pub const CATPPUCCIN: &str = "@preview/catppuccin:1.0.0";
+
+/// The packages every render needs.
+const REQUIRED: &[&str] = &[CATPPUCCIN];
+
+/// How long downloading the packages at startup may take.
+const PREWARM_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Where typst caches downloaded packages.
+///
+/// `packages.cache_dir` if set, otherwise typst's own default.
+pub fn cache() -> Option<PathBuf> {
+    if let Some(path) = config::get().packages.cache_dir.clone() {
+        return Some(path);
+    }
+    if let Some(path) = env::var_os("TYPST_PACKAGE_CACHE_PATH") {
+        return Some(path.into());
+    }
+
+    let cache = env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))?;
+
+    Some(cache.join("typst").join("packages"))
+}
+
+/// The data directory packages are vendored into, `packages.dir`.
+///
+/// Typst looks here before the cache, so vendored packages never hit the
+/// network.
+pub fn vendored() -> Option<PathBuf> {
+    config::get().packages.dir.clone()
+}
+
+/// Whether `packages.offline` forbids downloading packages at startup.
+pub fn offline() -> bool {
+    config::get().packages.offline
+}
+
+/// Point `command` at the package directories.
+pub fn configure(command: &mut Command) {
+    if let Some(cache) = cache() {
+        command.env("TYPST_PACKAGE_CACHE_PATH", cache);
+    }
+    if let Some(dir) = vendored() {
+        command.env("TYPST_PACKAGE_PATH", dir);
+    }
+}
+
+/// The directory of `spec` (`@namespace/name:version`) relative to a package
+/// directory.
+fn path(spec: &str) -> Option<PathBuf> {
+    let (namespace, package) = spec.strip_prefix('@')?.split_once('/')?;
+    let (name, version) = package.split_once(':')?;
+
+    Some([namespace, name, version].iter().collect())
+}
+
+/// Where `spec` is already available, if anywhere.
+fn find(spec: &str) -> Option<PathBuf> {
+    let path = path(spec)?;
+
+    [vendored(), cache()]
+        .into_iter()
+        .flatten()
+        .map(|dir| dir.join(&path))
+        .find(|dir| dir.join("typst.toml").is_file())
+}
+
+/// Make sure the packages renders need are available before the first one.
+///
+/// Missing packages are downloaded into the cache, unless `PACKAGES_OFFLINE`
+/// is set, in which case they have to be vendored in `PACKAGE_DIR` already.
+/// If `PACKAGE_DIR` is set, downloaded packages are copied there so the next
+/// start can be offline.
+pub async fn prewarm() -> anyhow::Result<()> {
+    for spec in REQUIRED {
+        if find(spec).is_none() {
+            if offline() {
+                anyhow::bail!(
+                    "The package `{spec}` isn't vendored in `PACKAGE_DIR` and `PACKAGES_OFFLINE` \
+                     forbids downloading it"
+                );
+            }
+
+            println!("Downloading the package `{spec}`");
+            download(spec)
+                .await
+                .with_context(|| format!("Can't download the package `{spec}`"))?;
+        }
+
+        if let (Some(from), Some(dir), Some(path)) = (find(spec), vendored(), path(spec)) {
+            let to = dir.join(path);
+            if from != to {
+                copy(&from, &to)
+                    .await
+                    .with_context(|| format!("Can't vendor the package `{spec}`"))?;
+                println!("Vendored the package `{spec}` into {}", to.display());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Have typst download `spec` into the cache by compiling a document
+/// importing it, outside of the sandbox since that has no network.
+async fn download(spec: &str) -> anyhow::Result<()> {
+    let dir = render::scratch_dir().await?;
+    fs::write(dir.join("main.typ"), format!("#import \"{spec}\"\n")).await?;
+
+    let mut command = Command::new("typst");
+    configure(&mut command);
+    command
+        .args(["compile", "main.typ", "main.pdf"])
+        .current_dir(&dir)
+        .kill_on_drop(true);
+
+    let output = tokio::time::timeout(PREWARM_TIMEOUT, command.output()).await;
+    let _ = fs::remove_dir_all(&dir).await;
+
+    let output = output.context("Timed out")??;
+    if !output.status.success() {
+        anyhow::bail!("{}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+
+    Ok(())
+}
+
+/// Copy the directory `from` to `to`, replacing anything there.
+async fn copy(from: &Path, to: &Path) -> anyhow::Result<()> {
+    let _ = fs::remove_dir_all(to).await;
+
+    let mut dirs = vec![(from.to_owned(), to.to_owned())];
+    while let Some((from, to)) = dirs.pop() {
+        fs::create_dir_all(&to).await?;
+
+        let mut entries = fs::read_dir(&from).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let target = to.join(entry.file_name());
+            if entry.file_type().await?.is_dir() {
+                dirs.push((entry.path(), target));
+            } else {
+                fs::copy(entry.path(), target).await?;
+            }
+        }
+    }
+
+    Ok(())
+}
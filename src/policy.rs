@@ -0,0 +1,574 @@
+use std::sync::Arc;
+
+use matrix_sdk::{
+    Client, Room,
+    deserialized_responses::RawAnySyncOrStrippedState,
+    event_handler::Ctx,
+    ruma::{
+        EventId, Int, UserId,
+        api::client::receipt::create_receipt::v3::ReceiptType,
+        events::{
+            reaction::ReactionEventContent,
+            receipt::ReceiptThread,
+            relation::Annotation,
+            room::{
+                member::{MembershipState, OriginalSyncRoomMemberEvent},
+                power_levels::{OriginalSyncRoomPowerLevelsEvent, UserPowerLevel},
+            },
+        },
+    },
+};
+use serde_json::json;
+
+use crate::{
+    admin,
+    budget::{self, Deadline},
+    config, message,
+    options::{DEFAULT_PPI, Flavor, MAX_PPI, RenderOptions},
+    ratelimit,
+    state::{RoomSettings, Store},
+    templates,
+    trace::RequestId,
+};
+
+/// The state event the effective policy of a room is published as.
+pub const POLICY_EVENT: &str = "io.github.togarashipepper.typit.policy";
+
+/// Traffic the bot sends besides its replies.
+///
+/// Quiet mode turns all of it off, deployment-wide with `QUIET_MODE=true` or
+/// per room with `,typ quiet on`, and so does tagging the room low priority
+/// from the bot's account. Everything goes through [`allows`] so the policy is
+/// enforced in one place.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Traffic {
+    /// Read receipts on handled commands.
+    Receipts,
+    /// The bot's online presence.
+    Presence,
+    /// Typing notices while a render runs.
+    Typing,
+    /// Reactions acknowledging commands.
+    Reactions,
+}
+
+/// Whether the whole deployment runs in quiet mode, `commands.quiet`.
+pub fn quiet_deployment() -> bool {
+    config::get().commands.quiet
+}
+
+/// Whether the bot may send `traffic`, in `room` when it's scoped to one.
+pub async fn allows(store: &Store, room: Option<&Room>, traffic: Traffic) -> bool {
+    if quiet_deployment() {
+        return false;
+    }
+
+    match (traffic, room) {
+        (Traffic::Presence, _) | (_, None) => true,
+        (_, Some(room)) => !room.is_low_priority() && !store.room(room.room_id()).await.quiet,
+    }
+}
+
+/// The time budget of a request in `room`, halved if the bot's account tagged
+/// it low priority.
+pub fn deadline(room: &Room, id: RequestId) -> Deadline {
+    let deadline = Deadline::start(id);
+
+    if room.is_low_priority() {
+        deadline.strict()
+    } else {
+        deadline
+    }
+}
+
+/// Apply the stricter limits of low priority rooms and safe mode rooms to
+/// `options`.
+pub async fn restrict(room: &Room, store: &Store, options: &mut RenderOptions) {
+    if room.is_low_priority() {
+        options.ppi = options.ppi.map(|ppi| ppi.min(DEFAULT_PPI));
+    }
+
+    options.safe |= store.room(room.room_id()).await.safe;
+}
+
+/// The flavor of renders in a room with `settings` that don't pick one: the
+/// room's, or else `render.flavor`.
+pub fn flavor(settings: &RoomSettings) -> Option<Flavor> {
+    settings.flavor.or(config::get().render.flavor)
+}
+
+/// Mark `event` as read, unless quiet mode forbids it.
+pub async fn send_receipt(store: &Store, room: &Room, event: &EventId) {
+    if !allows(store, Some(room), Traffic::Receipts).await {
+        return;
+    }
+
+    if let Err(err) = room
+        .send_single_receipt(
+            ReceiptType::Read,
+            ReceiptThread::Unthreaded,
+            event.to_owned(),
+        )
+        .await
+    {
+        eprintln!("Couldn't send a read receipt in {} ({err})", room.room_id());
+    }
+}
+
+/// React with `key` to `event`, unless quiet mode forbids it. Returns whether
+/// the reaction went out.
+pub async fn react(store: &Store, room: &Room, event: &EventId, key: &str) -> bool {
+    if !allows(store, Some(room), Traffic::Reactions).await {
+        return false;
+    }
+
+    let content = ReactionEventContent::new(Annotation::new(event.to_owned(), key.to_owned()));
+    match room.send(content).await {
+        Ok(_) => true,
+        Err(err) => {
+            eprintln!("Couldn't react in {} ({err})", room.room_id());
+            false
+        }
+    }
+}
+
+/// Whether `user` moderates `room`, i.e. can kick people from it, or is one of
+/// the configured admin users.
+pub async fn is_moderator(room: &Room, user: &UserId) -> bool {
+    if admin::is_admin(user) {
+        return true;
+    }
+
+    match room.get_member(user).await {
+        Ok(member) => member.is_some_and(|member| member.can_kick()),
+        Err(err) => {
+            eprintln!("Couldn't look up {user} in {} ({err})", room.room_id());
+            false
+        }
+    }
+}
+
+/// `template` filled in with the setting called `name` being `on` or off.
+fn setting(template: &str, name: &str, on: bool) -> String {
+    let templates = templates::get();
+    let state = if on { &templates.on } else { &templates.off };
+
+    template
+        .replace("{setting}", name)
+        .replace("{state}", state)
+}
+
+/// The usage of the on/off subcommand `command`.
+fn usage(command: &str) -> String {
+    templates::fill(&templates::get().setting_usage.replace("{command}", command))
+}
+
+/// Handle `,typ <command> on|off`, which shows or changes the room setting
+/// `field`, called `name` in the replies, returning the reply or the text of
+/// an error reply.
+async fn toggle(
+    command: &str,
+    name: &str,
+    field: fn(&mut RoomSettings) -> &mut bool,
+    args: &str,
+    room: &Room,
+    sender: &UserId,
+    store: &Store,
+) -> Result<String, String> {
+    let on = match args {
+        "on" => true,
+        "off" => false,
+        "" => {
+            let mut settings = store.room(room.room_id()).await;
+            return Ok(setting(
+                &templates::get().setting,
+                name,
+                *field(&mut settings),
+            ));
+        }
+        _ => return Err(usage(command)),
+    };
+
+    if !is_moderator(room, sender).await {
+        return Err(templates::get().moderators_only.clone());
+    }
+
+    store
+        .update(|state| *field(state.rooms.entry(room.room_id().to_owned()).or_default()) = on)
+        .await
+        .map_err(|err| err.to_string())?;
+
+    Ok(setting(&templates::get().setting_changed, name, on))
+}
+
+/// Handle `,typ quiet on|off`, returning the reply or the text of an error
+/// reply.
+pub async fn command(
+    args: &str,
+    room: &Room,
+    sender: &UserId,
+    store: &Store,
+) -> Result<String, String> {
+    let name = &templates::get().quiet_mode;
+    // Rooms can't turn it off for the whole deployment.
+    if args.is_empty() && quiet_deployment() {
+        return Ok(setting(&templates::get().setting, name, true));
+    }
+
+    toggle(
+        "quiet",
+        name,
+        |settings| &mut settings.quiet,
+        args,
+        room,
+        sender,
+        store,
+    )
+    .await
+}
+
+/// Handle `,typ safe on|off`, which makes every render in the room compile
+/// like `--safe`, returning the reply or the text of an error reply.
+pub async fn safe_command(
+    args: &str,
+    room: &Room,
+    sender: &UserId,
+    store: &Store,
+) -> Result<String, String> {
+    toggle(
+        "safe",
+        &templates::get().safe_mode,
+        |settings| &mut settings.safe,
+        args,
+        room,
+        sender,
+        store,
+    )
+    .await
+}
+
+/// Handle `,typ automath on|off`, which renders the `$...$` math of every
+/// message in the room, returning the reply or the text of an error reply.
+pub async fn auto_math_command(
+    args: &str,
+    room: &Room,
+    sender: &UserId,
+    store: &Store,
+) -> Result<String, String> {
+    let reply = toggle(
+        "automath",
+        &templates::get().auto_math,
+        |settings| &mut settings.auto_math,
+        args,
+        room,
+        sender,
+        store,
+    )
+    .await?;
+
+    // Turning it on explains what it does.
+    Ok(match args {
+        "on" => templates::get().auto_math_on.clone(),
+        _ => reply,
+    })
+}
+
+/// Handle `,typ mentions on|off`, which lets members use the bot by
+/// mentioning it, returning the reply or the text of an error reply.
+pub async fn mentions_command(
+    args: &str,
+    room: &Room,
+    sender: &UserId,
+    store: &Store,
+) -> Result<String, String> {
+    let reply = toggle(
+        "mentions",
+        &templates::get().mentions,
+        |settings| &mut settings.mentions,
+        args,
+        room,
+        sender,
+        store,
+    )
+    .await?;
+
+    Ok(match args {
+        "on" => templates::fill(&templates::get().mentions_on),
+        _ => reply,
+    })
+}
+
+/// Whether `user` has the power level `room` requires for commands, which
+/// the configured admin users always do.
+pub async fn has_power(room: &Room, user: &UserId, store: &Store) -> bool {
+    let Some(min_power) = store.room(room.room_id()).await.min_power else {
+        return true;
+    };
+    if admin::is_admin(user) {
+        return true;
+    }
+
+    match room.get_member(user).await {
+        Ok(member) => member.is_some_and(|member| {
+            member.power_level() >= UserPowerLevel::Int(Int::new_saturating(min_power))
+        }),
+        Err(err) => {
+            eprintln!("Couldn't look up {user} in {} ({err})", room.room_id());
+            false
+        }
+    }
+}
+
+/// Handle `,typ power <level>|off`, which restricts commands in the room to
+/// members with at least that power level, returning the reply or the text
+/// of an error reply.
+pub async fn power_command(
+    args: &str,
+    room: &Room,
+    sender: &UserId,
+    store: &Store,
+) -> Result<String, String> {
+    let usage = || templates::fill(&templates::get().power_usage);
+    let min_power = match args {
+        "" => {
+            return Ok(match store.room(room.room_id()).await.min_power {
+                Some(level) => templates::get()
+                    .power
+                    .replace("{limit}", &level.to_string()),
+                None => templates::get().power_off.clone(),
+            });
+        }
+        "off" => None,
+        level => Some(
+            level
+                .parse::<i64>()
+                .ok()
+                .filter(|level| Int::new(*level).is_some())
+                .ok_or_else(usage)?,
+        ),
+    };
+
+    if !is_moderator(room, sender).await {
+        return Err(templates::get().moderators_only.clone());
+    }
+
+    store
+        .update(|state| {
+            state
+                .rooms
+                .entry(room.room_id().to_owned())
+                .or_default()
+                .min_power = min_power
+        })
+        .await
+        .map_err(|err| err.to_string())?;
+
+    Ok(match min_power {
+        Some(level) => templates::get()
+            .power_set
+            .replace("{limit}", &level.to_string()),
+        None => templates::get().power_off_set.clone(),
+    })
+}
+
+/// Handle `,typ enable` and `,typ disable`, which switch the bot on and off
+/// in the room without it leaving, returning the reply or the text of an
+/// error reply.
+pub async fn enable_command(
+    enabled: bool,
+    room: &Room,
+    sender: &UserId,
+    store: &Store,
+) -> Result<String, String> {
+    if !is_moderator(room, sender).await {
+        return Err(templates::get().moderators_only.clone());
+    }
+
+    store
+        .update(|state| {
+            state
+                .rooms
+                .entry(room.room_id().to_owned())
+                .or_default()
+                .disabled = !enabled
+        })
+        .await
+        .map_err(|err| err.to_string())?;
+
+    Ok(if enabled {
+        templates::get().enabled.clone()
+    } else {
+        templates::fill(&templates::get().disabled)
+    })
+}
+
+/// Handle `,typ language [<language>|default]`, which picks the language of
+/// the replies in the room, returning the reply or the text of an error
+/// reply.
+pub async fn language_command(
+    args: &str,
+    room: &Room,
+    sender: &UserId,
+    store: &Store,
+) -> Result<String, String> {
+    let languages = templates::languages().join(", ");
+    let language = match args {
+        "" => {
+            let language = store
+                .room(room.room_id())
+                .await
+                .language
+                .unwrap_or_else(|| config::get().commands.language.clone());
+            return Ok(format!(
+                "Replies here are in `{language}`, pick one of {languages}"
+            ));
+        }
+        "default" => None,
+        language if templates::languages().contains(&language) => Some(language.to_owned()),
+        language => return Err(format!("There's no `{language}`, only {languages}")),
+    };
+
+    if !is_moderator(room, sender).await {
+        return Err(templates::get().moderators_only.clone());
+    }
+
+    let reply = templates::of(language.as_deref()).language_set.clone();
+    store
+        .update(|state| {
+            state
+                .rooms
+                .entry(room.room_id().to_owned())
+                .or_default()
+                .language = language
+        })
+        .await
+        .map_err(|err| err.to_string())?;
+
+    Ok(reply)
+}
+
+/// The limits and settings renders in `room` are subject to.
+async fn effective(room: &Room, store: &Store) -> serde_json::Value {
+    let settings = store.room(room.room_id()).await;
+    let low_priority = room.is_low_priority();
+    let divisor = if low_priority { 2 } else { 1 };
+    let language = settings
+        .language
+        .clone()
+        .unwrap_or_else(|| config::get().commands.language.clone());
+
+    let mut commands: Vec<_> = message::COMMANDS
+        .iter()
+        .map(|(prefix, ..)| *prefix)
+        .collect();
+    commands.extend([
+        ",m", ",calc", ",src", ",fonts", ",stats", ",asset", ",version",
+    ]);
+
+    let mut formats = vec!["pdf"];
+    if !settings.images_blocked {
+        formats.splice(0..0, ["png", "webp"]);
+    }
+
+    // Safe mode compiles without packages.
+    let packages: &[&str] = if settings.safe { &[] } else { &["@preview/*"] };
+
+    json!({
+        "prefix": format!("{}typ", config::get().commands.prefix),
+        "commands": commands,
+        "formats": formats,
+        "packages": packages,
+        "flavor": flavor(&settings).unwrap_or_default(),
+        "quiet": quiet_deployment() || settings.quiet,
+        "safe": settings.safe,
+        "mentions": settings.mentions,
+        "language": language,
+        "automath": settings.auto_math,
+        "disabled": settings.disabled,
+        "min_power": settings.min_power,
+        "low_priority": low_priority,
+        "limits": {
+            "compile_seconds": budget::get().compile.as_secs() / divisor,
+            "total_seconds": budget::get().total.as_secs() / divisor,
+            "max_ppi": if low_priority { DEFAULT_PPI } else { MAX_PPI },
+            "renders_per_minute": ratelimit::user_rate(),
+            "room_renders_per_minute": ratelimit::room_rate(),
+        },
+    })
+}
+
+/// Publish the effective policy of `room` as a [`POLICY_EVENT`] state event,
+/// unless it's already up to date.
+///
+/// Sending it needs the power to send state events, rooms that don't give the
+/// bot that just go without.
+pub async fn publish(room: &Room, store: &Store) {
+    let content = effective(room, store).await;
+
+    if let Ok(Some(RawAnySyncOrStrippedState::Sync(current))) =
+        room.get_state_event(POLICY_EVENT.into(), "").await
+        && current
+            .get_field::<serde_json::Value>("content")
+            .is_ok_and(|current| current.as_ref() == Some(&content))
+    {
+        return;
+    }
+
+    if let Err(err) = room.send_state_event_raw(POLICY_EVENT, "", content).await {
+        eprintln!("Couldn't publish the policy of {} ({err})", room.room_id());
+    }
+}
+
+/// Publish the policy of every room the bot is in.
+pub async fn publish_all(client: &Client, store: &Store) {
+    for room in client.joined_rooms() {
+        publish(&room, store).await;
+    }
+}
+
+/// Forget that `room` forbids the bot to post images, once it posted one or
+/// the room's power levels changed.
+pub async fn images_allowed(room: &Room, store: &Store) {
+    if !store.room(room.room_id()).await.images_blocked {
+        return;
+    }
+
+    let cleared = store
+        .update(|state| {
+            if let Some(settings) = state.rooms.get_mut(room.room_id()) {
+                settings.images_blocked = false;
+            }
+        })
+        .await;
+    match cleared {
+        Ok(()) => publish(room, store).await,
+        Err(err) => eprintln!(
+            "Couldn't forget that {} forbade images: {err}",
+            room.room_id()
+        ),
+    }
+}
+
+/// Try posting images again in rooms whose power levels changed, they may
+/// allow it now.
+pub async fn on_power_levels(
+    _event: OriginalSyncRoomPowerLevelsEvent,
+    room: Room,
+    Ctx(store): Ctx<Arc<Store>>,
+) {
+    images_allowed(&room, &store).await;
+}
+
+/// Publish the policy of the rooms the bot joins.
+pub async fn on_member(
+    event: OriginalSyncRoomMemberEvent,
+    room: Room,
+    client: Client,
+    Ctx(store): Ctx<Arc<Store>>,
+) {
+    if client.user_id() == Some(&*event.state_key)
+        && event.content.membership == MembershipState::Join
+    {
+        publish(&room, &store).await;
+    }
+}
@@ -0,0 +1,150 @@
+use std::{f64::consts::PI, io::Cursor};
+
+use image::{ImageFormat, RgbaImage, imageops};
+
+/// The box thumbnails are scaled down to fit, renders that already fit in it
+/// don't get one.
+const THUMBNAIL_SIZE: (u32, u32) = (800, 600);
+
+/// The blurhash is computed from a copy scaled down to fit this square, the
+/// result barely changes and it's much faster.
+const BLURHASH_SAMPLE: u32 = 64;
+
+/// How many horizontal and vertical components the blurhash has.
+const COMPONENTS: (u32, u32) = (4, 3);
+
+/// The digits of the base 83 blurhashes are written in.
+const BASE83: &[u8; 83] =
+    b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz#$%*+,-.:;=?@[]^_{|}~";
+
+/// What clients show while a render loads.
+pub struct Preview {
+    pub blurhash: String,
+    pub thumbnail: Option<Thumbnail>,
+}
+
+/// A scaled down PNG of a render.
+pub struct Thumbnail {
+    pub png: Vec<u8>,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// The blurhash of `img`, and a thumbnail if it's bigger than
+/// `THUMBNAIL_SIZE`.
+pub fn of(img: &RgbaImage) -> Preview {
+    let sample = imageops::thumbnail(
+        img,
+        img.width().min(BLURHASH_SAMPLE),
+        img.height().min(BLURHASH_SAMPLE),
+    );
+
+    Preview {
+        blurhash: blurhash(&sample),
+        thumbnail: thumbnail(img),
+    }
+}
+
+fn thumbnail(img: &RgbaImage) -> Option<Thumbnail> {
+    let (max_width, max_height) = THUMBNAIL_SIZE;
+    if img.width() <= max_width && img.height() <= max_height {
+        return None;
+    }
+
+    let scale = f64::min(
+        max_width as f64 / img.width() as f64,
+        max_height as f64 / img.height() as f64,
+    );
+    let (width, height) = (
+        ((img.width() as f64 * scale) as u32).max(1),
+        ((img.height() as f64 * scale) as u32).max(1),
+    );
+    let resized = imageops::resize(img, width, height, imageops::FilterType::Triangle);
+
+    let mut png = vec![];
+    resized
+        .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+        .ok()?;
+
+    Some(Thumbnail { png, width, height })
+}
+
+/// The [blurhash](https://blurha.sh) of `img`, ignoring transparency.
+fn blurhash(img: &RgbaImage) -> String {
+    let (components_x, components_y) = COMPONENTS;
+    let (width, height) = (img.width() as f64, img.height() as f64);
+
+    let mut factors = vec![];
+    for j in 0..components_y {
+        for i in 0..components_x {
+            let normalisation = if i == 0 && j == 0 { 1.0 } else { 2.0 };
+            let mut factor = [0.0; 3];
+
+            for (x, y, pixel) in img.enumerate_pixels() {
+                let basis = normalisation
+                    * (PI * i as f64 * x as f64 / width).cos()
+                    * (PI * j as f64 * y as f64 / height).cos();
+                for (factor, channel) in factor.iter_mut().zip(pixel.0) {
+                    *factor += basis * srgb_to_linear(channel);
+                }
+            }
+
+            factors.push(factor.map(|factor| factor / (width * height)));
+        }
+    }
+
+    let (dc, ac) = (factors[0], &factors[1..]);
+    let mut hash = String::new();
+    base83(&mut hash, (components_x - 1) + (components_y - 1) * 9, 1);
+
+    let max = ac.iter().flatten().fold(0.0_f64, |max, v| max.max(v.abs()));
+    let max = if ac.is_empty() || max == 0.0 {
+        base83(&mut hash, 0, 1);
+        1.0
+    } else {
+        let quantised = ((max * 166.0 - 0.5).floor() as i64).clamp(0, 82);
+        base83(&mut hash, quantised as u32, 1);
+        (quantised + 1) as f64 / 166.0
+    };
+
+    let [r, g, b] = dc.map(linear_to_srgb);
+    base83(&mut hash, (r << 16) + (g << 8) + b, 4);
+
+    for factor in ac {
+        let [r, g, b] = factor.map(|v| {
+            let v = (v / max).signum() * (v / max).abs().sqrt();
+            ((v * 9.0 + 9.5).floor() as i64).clamp(0, 18) as u32
+        });
+        base83(&mut hash, r * 19 * 19 + g * 19 + b, 2);
+    }
+
+    hash
+}
+
+/// Append `value` to `hash` as `length` base 83 digits.
+fn base83(hash: &mut String, value: u32, length: u32) {
+    for i in (0..length).rev() {
+        let digit = value / 83_u32.pow(i) % 83;
+        hash.push(BASE83[digit as usize] as char);
+    }
+}
+
+fn srgb_to_linear(channel: u8) -> f64 {
+    let v = channel as f64 / 255.0;
+    if v <= 0.04045 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(v: f64) -> u32 {
+    let v = v.clamp(0.0, 1.0);
+    let srgb = if v <= 0.003_130_8 {
+        v * 12.92
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    };
+
+    (srgb * 255.0 + 0.5) as u32
+}
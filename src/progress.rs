@@ -0,0 +1,225 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, LazyLock, Mutex},
+    time::Duration,
+};
+
+use matrix_sdk::{
+    Room,
+    ruma::{
+        OwnedEventId,
+        events::room::{
+            message::{
+                AddMentions, ForwardThread, OriginalSyncRoomMessageEvent, ReplacementMetadata,
+                RoomMessageEventContent,
+            },
+            redaction::OriginalSyncRoomRedactionEvent,
+        },
+    },
+};
+use tokio::task::AbortHandle;
+use tokio_util::sync::CancellationToken;
+
+use crate::{templates, trace::RequestId};
+
+/// How long a render may run before it gets a "Compiling…" reply.
+const SLOW: Duration = Duration::from_secs(3);
+
+/// How often the typing notice is renewed, the homeserver drops it after 4s.
+const TYPING: Duration = Duration::from_secs(3);
+
+/// The placeholder reply of a request, once it's posted.
+type Slot = Arc<tokio::sync::Mutex<Option<OwnedEventId>>>;
+
+/// A running request.
+struct Entry {
+    room: Room,
+    slot: Slot,
+    /// The timer that posts "Compiling…" and the typing notice loop.
+    tasks: Vec<AbortHandle>,
+    typing: bool,
+}
+
+impl Entry {
+    /// Stop the background tasks, and the typing notice.
+    fn stop(&self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+
+        if self.typing {
+            let room = self.room.clone();
+            tokio::spawn(async move {
+                if let Err(err) = room.typing_notice(false).await {
+                    eprintln!("Couldn't stop typing in {} ({err})", room.room_id());
+                }
+            });
+        }
+    }
+}
+
+/// The placeholders of running requests.
+static PLACEHOLDERS: LazyLock<Mutex<HashMap<RequestId, Entry>>> = LazyLock::new(Default::default);
+
+/// What cancels the running requests, by the event they answer.
+static CANCELS: LazyLock<Mutex<HashMap<OwnedEventId, CancellationToken>>> =
+    LazyLock::new(Default::default);
+
+/// Keeps the placeholder reply of a request up to date while it waits and
+/// runs, so it can be edited into the result.
+///
+/// A placeholder that never got its result is redacted on drop.
+///
+/// Redacting the event a request answers cancels it.
+pub struct Tracker {
+    room: Room,
+    event: OriginalSyncRoomMessageEvent,
+    id: RequestId,
+    slot: Slot,
+    cancel: CancellationToken,
+}
+
+impl Tracker {
+    pub fn new(room: &Room, event: &OriginalSyncRoomMessageEvent, id: RequestId) -> Self {
+        let slot = Slot::default();
+        PLACEHOLDERS.lock().unwrap().insert(
+            id,
+            Entry {
+                room: room.clone(),
+                slot: slot.clone(),
+                tasks: vec![],
+                typing: false,
+            },
+        );
+
+        let cancel = CancellationToken::new();
+        CANCELS
+            .lock()
+            .unwrap()
+            .insert(event.event_id.clone(), cancel.clone());
+
+        Self {
+            room: room.clone(),
+            event: event.clone(),
+            id,
+            slot,
+            cancel,
+        }
+    }
+
+    /// What cancels the request.
+    pub fn cancel(&self) -> CancellationToken {
+        self.cancel.clone()
+    }
+
+    /// Show `text` in the placeholder, posting it if needed.
+    pub async fn show(&self, text: &str) {
+        show(&self.room, &self.event, &self.slot, text).await;
+    }
+
+    /// The render started, show "Compiling…" if it takes a while, and with
+    /// `typing`, a typing notice until the result is sent.
+    pub fn started(&self, typing: bool) {
+        let (room, event, slot) = (self.room.clone(), self.event.clone(), self.slot.clone());
+        // The task doesn't inherit the language of the request.
+        let compiling = &templates::get().compiling;
+        let mut tasks = vec![
+            tokio::spawn(async move {
+                tokio::time::sleep(SLOW).await;
+                show(&room, &event, &slot, compiling).await;
+            })
+            .abort_handle(),
+        ];
+
+        if typing {
+            let room = self.room.clone();
+            tasks.push(
+                tokio::spawn(async move {
+                    loop {
+                        if let Err(err) = room.typing_notice(true).await {
+                            eprintln!("Couldn't start typing in {} ({err})", room.room_id());
+                            break;
+                        }
+                        tokio::time::sleep(TYPING).await;
+                    }
+                })
+                .abort_handle(),
+            );
+        }
+
+        if let Some(entry) = PLACEHOLDERS.lock().unwrap().get_mut(&self.id) {
+            entry.tasks = tasks;
+            entry.typing = typing;
+        }
+    }
+}
+
+async fn show(room: &Room, event: &OriginalSyncRoomMessageEvent, slot: &Slot, text: &str) {
+    let mut placeholder = slot.lock().await;
+    let content = RoomMessageEventContent::notice_plain(text);
+
+    let result = match &*placeholder {
+        Some(placeholder) => room
+            .send(content.make_replacement(ReplacementMetadata::new(placeholder.clone(), None)))
+            .await
+            .map(|_| ()),
+        None => room
+            .send(content.make_reply_to(event, ForwardThread::Yes, AddMentions::Yes))
+            .await
+            .map(|response| *placeholder = Some(response.event_id)),
+    };
+
+    if let Err(err) = result {
+        eprintln!(
+            "Couldn't update a progress reply in {} ({err})",
+            room.room_id()
+        );
+    }
+}
+
+/// Stop updating the placeholder of request `id`, returning it so it can be
+/// edited into the result.
+pub async fn finish(id: RequestId) -> Option<OwnedEventId> {
+    let entry = PLACEHOLDERS.lock().unwrap().remove(&id)?;
+
+    // Waiting for the lock lets a placeholder being posted finish first.
+    let mut placeholder = entry.slot.lock().await;
+    entry.stop();
+
+    placeholder.take()
+}
+
+/// Cancel the request answering the event redacted by `event`.
+pub async fn on_redaction(event: OriginalSyncRoomRedactionEvent) {
+    let Some(redacted) = event.redacts.as_ref().or(event.content.redacts.as_ref()) else {
+        return;
+    };
+
+    if let Some(cancel) = CANCELS.lock().unwrap().get(redacted) {
+        println!("{redacted} was redacted, cancelling its request");
+        cancel.cancel();
+    }
+}
+
+impl Drop for Tracker {
+    fn drop(&mut self) {
+        CANCELS.lock().unwrap().remove(&self.event.event_id);
+
+        let Some(entry) = PLACEHOLDERS.lock().unwrap().remove(&self.id) else {
+            return;
+        };
+        entry.stop();
+
+        let (room, slot) = (self.room.clone(), self.slot.clone());
+        tokio::spawn(async move {
+            if let Some(placeholder) = slot.lock().await.take()
+                && let Err(err) = room.redact(&placeholder, None, None).await
+            {
+                eprintln!(
+                    "Couldn't remove a progress reply in {} ({err})",
+                    room.room_id()
+                );
+            }
+        });
+    }
+}
@@ -0,0 +1,201 @@
+use matrix_sdk::ruma::events::room::message::OriginalSyncRoomMessageEvent;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+use crate::{config, trace::RequestId};
+
+/// The custom event content key provenance goes under.
+pub const EVENT_KEY: &str = "io.github.togarashipepper.typit.provenance";
+
+/// Where a render came from, embedded into it with `render.provenance` so
+/// shared or forwarded renders can be traced back when investigating misuse.
+pub struct Provenance {
+    request: RequestId,
+    /// A hash of the sender, to match against a suspect without publishing
+    /// their MXID in the image.
+    sender: String,
+    /// When the command was sent, in milliseconds since the epoch.
+    timestamp: u64,
+}
+
+impl Provenance {
+    /// The provenance of the render requested by `event`, if it's enabled.
+    pub fn of(event: &OriginalSyncRoomMessageEvent, request: RequestId) -> Option<Self> {
+        if !config::get().render.provenance {
+            return None;
+        }
+
+        let hash = Sha256::digest(event.sender.as_bytes());
+        let sender = hash[..8].iter().map(|byte| format!("{byte:02x}")).collect();
+
+        Some(Self {
+            request,
+            sender,
+            timestamp: event.origin_server_ts.get().into(),
+        })
+    }
+
+    /// The provenance as the value of [`EVENT_KEY`].
+    pub fn json(&self) -> serde_json::Value {
+        json!({
+            "request": self.request.to_string(),
+            "sender": self.sender,
+            "timestamp": self.timestamp,
+        })
+    }
+
+    /// The provenance as metadata keywords and their values.
+    fn fields(&self) -> [(&'static str, String); 3] {
+        [
+            ("typit:request", self.request.to_string()),
+            ("typit:sender", self.sender.clone()),
+            ("typit:timestamp", self.timestamp.to_string()),
+        ]
+    }
+
+    /// Add the provenance to `image`, a PNG or a lossless WebP.
+    pub fn embed(&self, image: &mut Vec<u8>) {
+        if image.starts_with(b"RIFF") {
+            self.embed_webp(image);
+        } else {
+            self.embed_png(image);
+        }
+    }
+
+    /// Add the provenance to `png` as `tEXt` chunks.
+    fn embed_png(&self, png: &mut Vec<u8>) {
+        // The signature and the IHDR chunk, which has to come first.
+        const HEADER: usize = 8 + 4 + 4 + 13 + 4;
+        if png.len() < HEADER {
+            return;
+        }
+
+        let mut chunks = vec![];
+        for (keyword, text) in self.fields() {
+            let data = [keyword.as_bytes(), &[0], text.as_bytes()].concat();
+            let body = [b"tEXt".as_slice(), &data].concat();
+
+            chunks.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            chunks.extend_from_slice(&body);
+            chunks.extend_from_slice(&crc32fast::hash(&body).to_be_bytes());
+        }
+
+        png.splice(HEADER..HEADER, chunks);
+    }
+
+    /// Add the provenance to the lossless `webp` as XMP metadata, turning it
+    /// into the extended format that can hold it.
+    fn embed_webp(&self, webp: &mut Vec<u8>) {
+        // The RIFF header, then the `VP8L` chunk header and its signature
+        // byte, followed by the size of the image.
+        const HEADER: usize = 12 + 8 + 1;
+        if webp.len() < HEADER + 4 || &webp[8..12] != b"WEBP" || &webp[12..16] != b"VP8L" {
+            return;
+        }
+
+        // 14 bits each for the width and height minus one, then whether
+        // there's alpha.
+        let bits = u32::from_le_bytes(webp[HEADER..HEADER + 4].try_into().unwrap());
+        let (width, height) = (bits & 0x3fff, (bits >> 14) & 0x3fff);
+        let alpha = (bits >> 28) & 1 == 1;
+
+        let mut vp8x = vec![if alpha { 0x10 | 0x04 } else { 0x04 }, 0, 0, 0];
+        vp8x.extend_from_slice(&width.to_le_bytes()[..3]);
+        vp8x.extend_from_slice(&height.to_le_bytes()[..3]);
+
+        let fields: String = self
+            .fields()
+            .iter()
+            .map(|(keyword, value)| format!(" {keyword}=\"{value}\""))
+            .collect();
+        let xmp = format!(
+            "<x:xmpmeta xmlns:x=\"adobe:ns:meta/\"><rdf:RDF \
+             xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\"><rdf:Description \
+             rdf:about=\"\" xmlns:typit=\"urn:typit:provenance:1\"{fields}/></rdf:RDF></x:xmpmeta>"
+        );
+
+        let mut extended = b"RIFF\0\0\0\0WEBP".to_vec();
+        push_chunk(&mut extended, b"VP8X", &vp8x);
+        extended.extend_from_slice(&webp[12..]);
+        push_chunk(&mut extended, b"XMP ", xmp.as_bytes());
+        let size = (extended.len() - 8) as u32;
+        extended[4..8].copy_from_slice(&size.to_le_bytes());
+
+        *webp = extended;
+    }
+}
+
+/// Append a RIFF chunk with `data` to `riff`, padded to an even size.
+fn push_chunk(riff: &mut Vec<u8>, fourcc: &[u8; 4], data: &[u8]) {
+    riff.extend_from_slice(fourcc);
+    riff.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    riff.extend_from_slice(data);
+    if data.len() % 2 == 1 {
+        riff.push(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use image::{
+        ExtendedColorType, ImageEncoder, ImageFormat, RgbaImage,
+        codecs::{png::PngEncoder, webp::WebPEncoder},
+    };
+
+    use super::*;
+
+    fn provenance() -> Provenance {
+        Provenance {
+            request: RequestId::new(),
+            sender: "0123456789abcdef".into(),
+            timestamp: 1_700_000_000_000,
+        }
+    }
+
+    fn image() -> RgbaImage {
+        RgbaImage::from_fn(300, 20, |x, y| image::Rgba([x as u8, y as u8, 0, 128]))
+    }
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack
+            .windows(needle.len())
+            .any(|window| window == needle)
+    }
+
+    #[test]
+    fn embeds_into_png() {
+        let img = image();
+        let mut png = vec![];
+        PngEncoder::new(Cursor::new(&mut png))
+            .write_image(&img, img.width(), img.height(), ExtendedColorType::Rgba8)
+            .unwrap();
+
+        provenance().embed(&mut png);
+
+        assert!(contains(&png, b"typit:sender\x000123456789abcdef"));
+        let decoded = image::load_from_memory_with_format(&png, ImageFormat::Png).unwrap();
+        assert_eq!(decoded.to_rgba8(), img);
+    }
+
+    #[test]
+    fn embeds_into_webp() {
+        let img = image();
+        let mut webp = vec![];
+        WebPEncoder::new_lossless(Cursor::new(&mut webp))
+            .write_image(&img, img.width(), img.height(), ExtendedColorType::Rgba8)
+            .unwrap();
+
+        provenance().embed(&mut webp);
+
+        assert_eq!(&webp[12..16], b"VP8X");
+        assert!(contains(&webp, b"typit:sender=\"0123456789abcdef\""));
+        assert_eq!(
+            u32::from_le_bytes(webp[4..8].try_into().unwrap()) as usize,
+            webp.len() - 8
+        );
+        let decoded = image::load_from_memory_with_format(&webp, ImageFormat::WebP).unwrap();
+        assert_eq!(decoded.to_rgba8(), img);
+    }
+}
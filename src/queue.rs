@@ -0,0 +1,301 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    sync::{LazyLock, Mutex},
+    thread,
+    time::Duration,
+};
+
+use matrix_sdk::ruma::{OwnedUserId, UserId};
+use tokio::sync::Notify;
+
+use crate::{config, storage};
+
+/// Renders waiting for a slot, and who's rendering.
+struct Inner {
+    running: usize,
+    /// Users with a render waiting or running.
+    users: HashSet<OwnedUserId>,
+    /// Waiting tickets in order, with whether they're from a favourite room.
+    waiting: VecDeque<(u64, bool)>,
+    next_ticket: u64,
+    /// The bot is shutting down, nothing new starts.
+    closed: bool,
+}
+
+struct Queue {
+    inner: Mutex<Inner>,
+    changed: Notify,
+}
+
+/// How many renders may run at once, `render.concurrency` (the number of CPUs
+/// by default).
+fn concurrency() -> usize {
+    config::get()
+        .render
+        .concurrency
+        .or_else(|| thread::available_parallelism().ok().map(Into::into))
+        .unwrap_or(2)
+}
+
+/// How many renders may wait, `render.queue` (16 by default).
+fn capacity() -> usize {
+    config::get().render.queue
+}
+
+/// How often renders waiting in the queue [shared](storage::shared) with
+/// other instances check whether it's their turn.
+const SHARED_RETRY: Duration = Duration::from_millis(200);
+
+/// How long a render may hold a slot of the shared queue, in seconds, longer
+/// than any render's deadline. Older ones are from crashed instances.
+fn shared_ttl() -> u64 {
+    config::get().timeouts.total + storage::LOCK_TTL
+}
+
+static QUEUE: LazyLock<Queue> = LazyLock::new(|| Queue {
+    inner: Mutex::new(Inner {
+        running: 0,
+        users: HashSet::new(),
+        waiting: VecDeque::new(),
+        next_ticket: 0,
+        closed: false,
+    }),
+    changed: Notify::new(),
+});
+
+/// Why a render wasn't queued.
+pub enum Rejected {
+    /// The user already has a render waiting or running.
+    Busy,
+    /// Too many renders are waiting.
+    Full,
+    /// The bot is shutting down.
+    Closed,
+}
+
+/// A place in the queue.
+pub struct Ticket {
+    id: u64,
+    user: OwnedUserId,
+    favourite: bool,
+    started: bool,
+    /// Its place in the queue shared with other instances, if there are any.
+    shared: Option<String>,
+}
+
+/// Allows a render to run until dropped.
+pub struct Permit {
+    user: OwnedUserId,
+    shared: Option<String>,
+}
+
+/// Queue a render by `user`.
+///
+/// Every user gets one render at a time so a single one can't hog the
+/// workers, and renders from rooms the bot's account marked as favourite skip
+/// ahead of the others.
+///
+/// Instances sharing their storage share the queue too: the user and the
+/// `render.concurrency` and `render.queue` limits count across all of them.
+pub async fn join(user: &UserId, favourite: bool) -> Result<Ticket, Rejected> {
+    let mut ticket = {
+        let mut inner = QUEUE.inner.lock().unwrap();
+
+        if inner.closed {
+            return Err(Rejected::Closed);
+        }
+        if inner.users.contains(user) {
+            return Err(Rejected::Busy);
+        }
+        if inner.waiting.len() >= capacity() {
+            return Err(Rejected::Full);
+        }
+
+        let id = inner.next_ticket;
+        inner.next_ticket += 1;
+        inner.users.insert(user.to_owned());
+
+        let position = if favourite {
+            inner
+                .waiting
+                .iter()
+                .position(|(_, favourite)| !favourite)
+                .unwrap_or(inner.waiting.len())
+        } else {
+            inner.waiting.len()
+        };
+        inner.waiting.insert(position, (id, favourite));
+
+        Ticket {
+            id,
+            user: user.to_owned(),
+            favourite,
+            started: false,
+            shared: None,
+        }
+    };
+
+    let Some(instance) = &config::get().storage.instance_id else {
+        return Ok(ticket);
+    };
+    let owner = format!("{instance}:{}", ticket.id);
+    let (shared_owner, user) = (owner.clone(), user.to_string());
+    let joined = storage::shared(move |shared| {
+        shared.join(&shared_owner, &user, favourite, capacity(), shared_ttl())
+    })
+    .await;
+    match joined {
+        Some(Ok(Ok(()))) => ticket.shared = Some(owner),
+        // Dropping the ticket gives up its place here.
+        Some(Ok(Err(rejected))) => return Err(rejected),
+        Some(Err(err)) => eprintln!("Couldn't join the shared queue, rendering alone: {err}"),
+        None => {}
+    }
+
+    Ok(ticket)
+}
+
+impl Ticket {
+    /// How many renders have to start before this one, if it can't start
+    /// right away.
+    pub async fn ahead(&self) -> Option<usize> {
+        if let Some(owner) = self.shared.clone() {
+            let ahead = storage::shared(move |shared| shared.ahead(&owner, concurrency())).await;
+            match ahead {
+                Some(Ok(ahead)) => return ahead,
+                Some(Err(err)) => eprintln!("Couldn't read the shared queue: {err}"),
+                None => {}
+            }
+        }
+
+        let inner = QUEUE.inner.lock().unwrap();
+        let position = inner.waiting.iter().position(|(id, _)| *id == self.id)?;
+
+        (position > 0 || inner.running >= concurrency()).then_some(position)
+    }
+
+    /// Wait for this render's turn, which never comes once the queue is
+    /// [closed](close).
+    ///
+    /// In a queue shared with other instances, the turns are taken in the
+    /// order of the shared queue instead.
+    pub async fn wait(&mut self) -> Option<Permit> {
+        loop {
+            let changed = QUEUE.changed.notified();
+            tokio::pin!(changed);
+            changed.as_mut().enable();
+
+            {
+                let mut inner = QUEUE.inner.lock().unwrap();
+                if inner.closed {
+                    return None;
+                }
+                if self.shared.is_none()
+                    && inner.running < concurrency()
+                    && inner.waiting.front().is_some_and(|(id, _)| *id == self.id)
+                {
+                    inner.waiting.pop_front();
+                    return Some(self.start(&mut inner));
+                }
+            }
+
+            let Some(owner) = self.shared.clone() else {
+                changed.await;
+                continue;
+            };
+            let (user, favourite) = (self.user.to_string(), self.favourite);
+            let started = storage::shared(move |shared| {
+                shared.start(&owner, &user, favourite, concurrency(), shared_ttl())
+            })
+            .await;
+            match started {
+                Some(Ok(true)) => {
+                    let mut inner = QUEUE.inner.lock().unwrap();
+                    // Gives up the shared slot on drop, unstarted.
+                    if inner.closed {
+                        return None;
+                    }
+                    inner.waiting.retain(|(id, _)| *id != self.id);
+                    return Some(self.start(&mut inner));
+                }
+                Some(Ok(false)) => {}
+                Some(Err(err)) => eprintln!("Couldn't take a turn in the shared queue: {err}"),
+                None => {}
+            }
+
+            tokio::select! {
+                _ = changed => {}
+                _ = tokio::time::sleep(SHARED_RETRY) => {}
+            }
+        }
+    }
+
+    /// Count this render as running, no longer waiting.
+    fn start(&mut self, inner: &mut Inner) -> Permit {
+        inner.running += 1;
+        self.started = true;
+
+        Permit {
+            user: self.user.clone(),
+            shared: self.shared.take(),
+        }
+    }
+}
+
+/// Give up `owner`'s place in the shared queue, from a drop. Without a
+/// runtime left to do it, the place expires.
+fn leave(owner: String) {
+    let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+        return;
+    };
+
+    runtime.spawn(async move {
+        if let Some(Err(err)) = storage::shared(move |shared| shared.leave(&owner)).await {
+            eprintln!("Couldn't leave the shared queue: {err}");
+        }
+    });
+}
+
+/// How many renders are running and how many are waiting.
+pub fn depth() -> (usize, usize) {
+    let inner = QUEUE.inner.lock().unwrap();
+
+    (inner.running, inner.waiting.len())
+}
+
+/// Stop starting renders, for shutting down. Waiting ones give up their
+/// place and the running ones finish.
+pub fn close() {
+    QUEUE.inner.lock().unwrap().closed = true;
+    QUEUE.changed.notify_waiters();
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        if self.started {
+            return;
+        }
+
+        if let Some(owner) = self.shared.take() {
+            leave(owner);
+        }
+
+        let mut inner = QUEUE.inner.lock().unwrap();
+        inner.waiting.retain(|(id, _)| *id != self.id);
+        inner.users.remove(&self.user);
+        QUEUE.changed.notify_waiters();
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if let Some(owner) = self.shared.take() {
+            leave(owner);
+        }
+
+        let mut inner = QUEUE.inner.lock().unwrap();
+        inner.running -= 1;
+        inner.users.remove(&self.user);
+        QUEUE.changed.notify_waiters();
+    }
+}
@@ -0,0 +1,96 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use matrix_sdk::ruma::{RoomId, UserId};
+use serde::{Deserialize, Serialize};
+
+use crate::{config, state::Store};
+
+/// A token bucket, refilled continuously up to the per-minute rate.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Bucket {
+    tokens: f64,
+    /// When `tokens` was last updated, in milliseconds since the epoch.
+    updated: u64,
+}
+
+impl Bucket {
+    /// Whether the bucket went untouched for an hour as of `now`, in
+    /// milliseconds since the epoch, and is full again.
+    pub fn is_idle(&self, now: u64) -> bool {
+        now.saturating_sub(self.updated) >= 3_600_000
+    }
+}
+
+/// Which limit a render ran into.
+pub enum Limited {
+    User(Duration),
+    Room(Duration),
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Renders per minute allowed for each user, `RATE_LIMIT_USER` (6 by default).
+pub fn user_rate() -> f64 {
+    config::get().limits.user_rate
+}
+
+/// Renders per minute allowed in each room, `RATE_LIMIT_ROOM` (30 by default).
+pub fn room_rate() -> f64 {
+    config::get().limits.room_rate
+}
+
+/// Refill `bucket` up to `now`, returning how long until a token is available
+/// if there isn't one.
+fn refill(bucket: &mut Bucket, rate: f64, now: u64) -> Option<Duration> {
+    let minutes = now.saturating_sub(bucket.updated) as f64 / 60_000.0;
+    bucket.tokens = (bucket.tokens + minutes * rate).min(rate);
+    bucket.updated = now;
+
+    (bucket.tokens < 1.0).then(|| Duration::from_secs_f64((1.0 - bucket.tokens) / rate * 60.0))
+}
+
+/// Take a token for a render by `user` in `room`.
+///
+/// The buckets are persisted, so restarting doesn't reset them.
+pub async fn take(store: &Store, user: &UserId, room: &RoomId) -> anyhow::Result<Option<Limited>> {
+    let user_rate = user_rate();
+    let room_rate = room_rate();
+    let now = now();
+
+    store
+        .update_buckets(user, room, |user_bucket, room_bucket| {
+            // Any bucket untouched for an hour is full again.
+            let full = |bucket: &Option<Bucket>, rate| {
+                bucket
+                    .clone()
+                    .filter(|bucket| !bucket.is_idle(now))
+                    .unwrap_or(Bucket {
+                        tokens: rate,
+                        updated: now,
+                    })
+            };
+
+            let mut user_next = full(user_bucket, user_rate);
+            if let Some(wait) = refill(&mut user_next, user_rate, now) {
+                return Some(Limited::User(wait));
+            }
+
+            let mut room_next = full(room_bucket, room_rate);
+            if let Some(wait) = refill(&mut room_next, room_rate, now) {
+                return Some(Limited::Room(wait));
+            }
+
+            user_next.tokens -= 1.0;
+            room_next.tokens -= 1.0;
+            *user_bucket = Some(user_next);
+            *room_bucket = Some(room_next);
+
+            None
+        })
+        .await
+}
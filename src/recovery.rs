@@ -0,0 +1,164 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use matrix_sdk::{
+    Client, Room,
+    room::MessagesOptions,
+    ruma::{
+        UInt,
+        events::{
+            AnySyncMessageLikeEvent, AnySyncTimelineEvent, SyncMessageLikeEvent,
+            room::message::OriginalSyncRoomMessageEvent,
+        },
+    },
+};
+use serde_json::json;
+
+use crate::{config, message, state::Store};
+
+/// How many events are fetched at a time when looking for missed commands.
+const PAGE_SIZE: u32 = 100;
+
+/// A command from before the bot started, with its room.
+pub type Backlog = Vec<(Room, OriginalSyncRoomMessageEvent)>;
+
+/// Commands older than `recovery.window` seconds (10 minutes by default) are
+/// likely stale by now and aren't answered. `0` turns recovery off.
+fn window() -> Duration {
+    Duration::from_secs(config::get().recovery.window)
+}
+
+/// At most `recovery.max` missed commands (20 by default) are answered, the
+/// latest ones, so a long outage doesn't flood the queue on startup.
+fn max_recovered() -> usize {
+    config::get().recovery.max
+}
+
+/// The renders in the rooms of `client` that were still waiting for a slot
+/// when the bot stopped, unless they're older than the recovery window.
+pub async fn queued(client: &Client, store: &Store) -> anyhow::Result<Backlog> {
+    let since = SystemTime::now() - window();
+    // The other accounts resume the renders of their rooms.
+    let mut jobs: Vec<_> = store
+        .queued()
+        .await?
+        .into_iter()
+        .filter(|(_, job)| client.get_room(&job.room).is_some())
+        .collect();
+    for (event_id, _) in &jobs {
+        store.unqueue(event_id).await?;
+    }
+    jobs.sort_by_key(|(_, job)| job.sent);
+
+    let mut queued = vec![];
+    for (event_id, job) in jobs {
+        if UNIX_EPOCH + Duration::from_millis(job.sent) < since {
+            println!("Dropping the stale queued render {event_id}");
+            continue;
+        }
+        let Some(room) = client.get_room(&job.room) else {
+            continue;
+        };
+
+        // Rebuild the command's event from what was kept of it, its thread
+        // and reply included so the render is posted there.
+        let mut event = json!({
+            "type": "m.room.message",
+            "event_id": event_id,
+            "sender": job.sender,
+            "origin_server_ts": job.sent,
+            "content": { "msgtype": "m.text", "body": job.body },
+        });
+        let mut relates_to = serde_json::Map::new();
+        if let Some(thread) = job.thread {
+            relates_to.insert("rel_type".into(), json!("m.thread"));
+            relates_to.insert("event_id".into(), json!(thread));
+        }
+        if let Some(in_reply_to) = job.in_reply_to {
+            relates_to.insert("m.in_reply_to".into(), json!({ "event_id": in_reply_to }));
+        }
+        if !relates_to.is_empty() {
+            event["content"]["m.relates_to"] = relates_to.into();
+        }
+        let event: OriginalSyncRoomMessageEvent = serde_json::from_value(event)?;
+
+        println!("Resuming the queued render {event_id}");
+        queued.push((room, event));
+    }
+
+    Ok(queued)
+}
+
+/// The commands sent while the bot was down, since the sync token `since`
+/// when there is one, within the recovery window and up to the recovery
+/// maximum.
+pub async fn missed(client: &Client, since: Option<&str>) -> anyhow::Result<Backlog> {
+    let window = window();
+    if window.is_zero() {
+        return Ok(vec![]);
+    }
+    let oldest = SystemTime::now() - window;
+    let own_user = client.user_id().map(ToOwned::to_owned);
+    let mut missed = vec![];
+
+    for room in client.joined_rooms() {
+        let mut from = None;
+
+        // Page back until the token, or the start of the window.
+        'pages: loop {
+            let mut options = MessagesOptions::backward();
+            options.limit = UInt::from(PAGE_SIZE);
+            options.from = from.take();
+            options.to = since.map(ToOwned::to_owned);
+
+            let messages = match room.messages(options).await {
+                Ok(messages) => messages,
+                Err(err) => {
+                    eprintln!("Can't scan {} for missed commands ({err})", room.room_id());
+                    break;
+                }
+            };
+
+            // The events come newest first.
+            for event in &messages.chunk {
+                let Ok(AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::RoomMessage(
+                    SyncMessageLikeEvent::Original(event),
+                ))) = event.raw().deserialize()
+                else {
+                    continue;
+                };
+
+                if event
+                    .origin_server_ts
+                    .to_system_time()
+                    .is_none_or(|sent| sent < oldest)
+                {
+                    break 'pages;
+                }
+
+                if Some(&event.sender) != own_user.as_ref() && message::is_command_event(&event) {
+                    missed.push((room.clone(), event));
+                }
+            }
+
+            match messages.end {
+                Some(end) if !messages.chunk.is_empty() => from = Some(end),
+                _ => break,
+            }
+        }
+    }
+
+    missed.sort_by_key(|(_, event)| event.origin_server_ts);
+    let skipped = missed.len().saturating_sub(max_recovered());
+    if skipped > 0 {
+        println!("Skipping the {skipped} oldest missed command(s)");
+    }
+
+    Ok(missed.into_iter().skip(skipped).collect())
+}
+
+/// Answer the commands of `backlog`, one at a time.
+pub async fn answer(client: &Client, store: &Store, backlog: Backlog) {
+    for (room, event) in backlog {
+        message::handle(&event, &room, client, store).await;
+    }
+}
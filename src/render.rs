@@ -0,0 +1,668 @@
+use std::{
+    io::Cursor,
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use image::{ImageFormat, RgbaImage, imageops};
+use tokio::{fs, io::AsyncWriteExt, process::Child, time::timeout_at};
+
+use crate::{
+    assets,
+    budget::{self, Deadline, Exceeded},
+    cache, diagnostics, limits, metrics,
+    options::{DEFAULT_PPI, Engine, Flavor, Format, RenderOptions},
+    packages::CATPPUCCIN,
+    sandbox,
+    style::{self, MAX_TEXT_SIZE, Style},
+    trace::RequestId,
+    workers,
+};
+
+/// The preamble prepended to every document, themed with `flavor`, with text
+/// `size` points large and drawn on a card styled with `style`.
+fn preamble(flavor: Flavor, style: &Style, size: u32) -> String {
+    let margin = style.margin();
+    let card = style.card(flavor.colors().0);
+
+    format!(
+        r#"
+#import "{CATPPUCCIN}": catppuccin, flavors;
+#show: catppuccin.with(flavors.{flavor});
+#set page(height: auto, width: auto, margin: {margin}pt);
+#set text(size: {size}pt);
+{card}
+"#
+    )
+}
+
+/// A stand-in for [`preamble`] without packages, used while it doesn't
+/// compile. It's as many lines long so diagnostics still line up.
+fn fallback_preamble(flavor: Flavor, style: &Style, size: u32) -> String {
+    let (base, text) = flavor.colors();
+    let margin = style.margin();
+    let card = style.card(base);
+
+    format!(
+        r##"
+// The themed preamble is broken, this is the built-in fallback.
+#set page(height: auto, width: auto, margin: {margin}pt, fill: rgb("#{base}"));
+#set text(size: {size}pt, fill: rgb("#{text}"));
+
+{card}
+"##
+    )
+}
+
+/// How long the fallback preamble is used before trying the real one again.
+const PREAMBLE_RETRY: Duration = Duration::from_secs(10 * 60);
+
+/// When the preamble was found broken, if it is.
+static PREAMBLE_BROKEN: Mutex<Option<Instant>> = Mutex::new(None);
+
+/// What to tell the admin room about the preamble, taken with
+/// [`preamble_alert`].
+static PREAMBLE_ALERT: Mutex<Option<String>> = Mutex::new(None);
+
+/// Whether renders use the fallback preamble right now.
+fn preamble_broken() -> bool {
+    PREAMBLE_BROKEN
+        .lock()
+        .unwrap()
+        .is_some_and(|since| since.elapsed() < PREAMBLE_RETRY)
+}
+
+/// Take the pending alert about the preamble breaking or being fixed.
+pub fn preamble_alert() -> Option<String> {
+    PREAMBLE_ALERT.lock().unwrap().take()
+}
+
+/// Whether `err` is the preamble failing rather than the user's source, e.g.
+/// because a package release broke.
+fn preamble_failed(err: &str, options: &RenderOptions) -> bool {
+    diagnostics::parse(err, 0).iter().any(|diagnostic| {
+        diagnostic.severity == "error"
+            && diagnostic
+                .position
+                .is_some_and(|(line, _)| line <= line_offset(options))
+    })
+}
+
+/// How many lines the preamble adds before the user's source.
+pub fn line_offset(options: &RenderOptions) -> usize {
+    match (options.engine, options.raw) {
+        (Engine::Typst, false) => {
+            preamble(
+                options.flavor.unwrap_or_default(),
+                &options.style,
+                MAX_TEXT_SIZE,
+            )
+            .matches('\n')
+            .count()
+                + 1
+        }
+        _ => 0,
+    }
+}
+
+/// Used to give every render its own scratch directory.
+static RENDER_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// The path of a new scratch directory, which isn't created yet.
+pub fn scratch_path() -> PathBuf {
+    std::env::temp_dir().join(format!(
+        "typit-{}-{}",
+        std::process::id(),
+        RENDER_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ))
+}
+
+/// Create a new scratch directory.
+pub async fn scratch_dir() -> anyhow::Result<PathBuf> {
+    let dir = scratch_path();
+    fs::create_dir_all(&dir).await?;
+
+    Ok(dir)
+}
+
+/// The result of running the compiler.
+#[derive(Clone)]
+pub enum Output {
+    /// One PNG per page in order, or a single PDF.
+    Pages {
+        pages: Vec<Vec<u8>>,
+        /// Whatever the compiler printed despite succeeding.
+        warnings: String,
+    },
+    /// The compiler failed, with its diagnostics.
+    Error(String),
+    /// The compiler didn't finish in time.
+    Timeout(Exceeded),
+    /// The request was cancelled while compiling.
+    Cancelled,
+}
+
+/// Compile `source` with `options.engine`, producing a PNG for every page or
+/// one PDF.
+///
+/// Unless `options.raw` is set, the source is wrapped in the themed preamble.
+/// Recent results are reused, see [`cache`], and typst renders go to a warm
+/// worker when there's one, see [`workers`].
+pub async fn render(
+    source: &str,
+    options: &RenderOptions,
+    deadline: &Deadline,
+    id: RequestId,
+) -> anyhow::Result<Output> {
+    let key = cache::key(source, options, preamble_broken());
+    if let Some(output) = cache::get(&key).await {
+        println!("[{id}] Reusing a cached result");
+        return Ok(output);
+    }
+
+    // A worker already has its scratch directory.
+    let worker = workers::take(options);
+    let dir = match &worker {
+        Some(worker) => worker.dir.clone(),
+        None => scratch_dir().await?,
+    };
+
+    if let Some(assets) = &options.assets
+        && let Err(err) = assets::copy(assets, &dir).await
+    {
+        eprintln!("[{id}] Couldn't copy the assets of the room: {err}");
+    }
+
+    let started = Instant::now();
+    let mut output = compile(
+        source,
+        options,
+        deadline,
+        &dir,
+        worker.map(|worker| worker.child),
+    )
+    .await;
+
+    if options.engine == Engine::Typst && !options.raw && !preamble_broken() {
+        let mut broken = PREAMBLE_BROKEN.lock().unwrap().take();
+        let mut retry = false;
+
+        match &output {
+            Ok(Output::Error(err)) if preamble_failed(err, options) => {
+                eprintln!("[{id}] The preamble doesn't compile, using the fallback");
+                if broken.is_none() {
+                    *PREAMBLE_ALERT.lock().unwrap() = Some(format!(
+                        "The preamble stopped compiling, renders use the built-in fallback \
+                         until it works again:\n{err}"
+                    ));
+                }
+                broken = Some(Instant::now());
+                retry = true;
+            }
+            Ok(Output::Pages { .. }) if broken.is_some() => {
+                println!("[{id}] The preamble compiles again");
+                *PREAMBLE_ALERT.lock().unwrap() = Some("The preamble compiles again".into());
+                broken = None;
+            }
+            _ => {}
+        }
+
+        *PREAMBLE_BROKEN.lock().unwrap() = broken;
+        if retry {
+            output = compile(source, options, deadline, &dir, None).await;
+        }
+    }
+
+    let _ = fs::remove_dir_all(&dir).await;
+
+    let elapsed = started.elapsed();
+    if let Ok(Output::Pages { .. } | Output::Error(_) | Output::Timeout(_)) = &output {
+        metrics::COMPILE_SECONDS.observe(elapsed);
+        deadline.compiled(elapsed);
+    }
+    if let Ok(Output::Error(_) | Output::Timeout(_)) | Err(_) = &output {
+        metrics::FAILURES.inc();
+    }
+    match &output {
+        Ok(Output::Pages { pages, .. }) => {
+            println!("[{id}] Compiled {} page(s) in {elapsed:?}", pages.len())
+        }
+        Ok(Output::Error(_)) => println!("[{id}] Compilation failed after {elapsed:?}"),
+        Ok(Output::Timeout(_)) => println!("[{id}] Compilation timed out"),
+        Ok(Output::Cancelled) => println!("[{id}] Compilation cancelled"),
+        Err(err) => eprintln!("[{id}] Couldn't run the compiler: {err}"),
+    }
+
+    if let Ok(output) = &output {
+        // The preamble may have been found broken or fixed meanwhile.
+        cache::insert(cache::key(source, options, preamble_broken()), output).await;
+    }
+
+    output
+}
+
+/// Compile `source` in `dir`, handing the first command's input to `worker`
+/// instead of spawning it if there is one.
+async fn compile(
+    source: &str,
+    options: &RenderOptions,
+    deadline: &Deadline,
+    dir: &Path,
+    mut worker: Option<Child>,
+) -> anyhow::Result<Output> {
+    // Every command of a render shares the compile budget.
+    let (end, exceeded) = deadline.window(budget::Stage::Compile);
+
+    let stages = match options.engine {
+        Engine::Typst => typst(source, options, dir),
+        Engine::Latex => latex(source, options, dir).await?,
+        Engine::Graphviz => graphviz(source, options, dir),
+        Engine::Mermaid => mermaid(source, options, dir).await?,
+    };
+
+    let mut warnings = String::new();
+    for (mut command, stdin) in stages {
+        let child = match worker.take() {
+            Some(child) => child,
+            None => spawn(&mut command, stdin.is_some())?,
+        };
+        match finish(child, stdin, deadline, end).await? {
+            Stage::Done { stderr, .. } => warnings.push_str(&stderr),
+            Stage::Failed(err) => return Ok(Output::Error(err)),
+            Stage::Timeout => return Ok(Output::Timeout(deadline.record(exceeded))),
+            Stage::Cancelled => return Ok(Output::Cancelled),
+        }
+    }
+
+    let extension = match options.format {
+        Format::Png => "png",
+        Format::Pdf => "pdf",
+    };
+
+    let mut files = vec![];
+    let mut entries = fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.extension().is_some_and(|ext| ext == extension) {
+            files.push(path);
+        }
+    }
+    // The page numbers are zero padded, so this is page order.
+    files.sort();
+
+    let mut pages = vec![];
+    for file in files {
+        pages.push(fs::read(file).await?);
+    }
+
+    Ok(Output::Pages {
+        pages,
+        warnings: warnings.trim().to_owned(),
+    })
+}
+
+/// A command to run, with what to write to its stdin.
+type Step = (tokio::process::Command, Option<String>);
+
+/// How running a single command went.
+enum Stage {
+    /// The command succeeded, with its output.
+    Done {
+        stdout: Vec<u8>,
+        stderr: String,
+    },
+    /// The command failed, with its stderr.
+    Failed(String),
+    Timeout,
+    Cancelled,
+}
+
+/// Start `command` with the resource limits, its output captured and its
+/// stdin open if it's to be written to.
+pub fn spawn(command: &mut tokio::process::Command, stdin: bool) -> std::io::Result<Child> {
+    limits::apply(command);
+    command
+        .stdin(if stdin { Stdio::piped() } else { Stdio::null() })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+}
+
+/// Run one stage of a render, giving up at `end` or when the request is
+/// cancelled.
+///
+/// A stage that runs out of time is killed, with everything it started.
+async fn run(
+    command: &mut tokio::process::Command,
+    stdin: Option<String>,
+    deadline: &Deadline,
+    end: Instant,
+) -> anyhow::Result<Stage> {
+    let child = spawn(command, stdin.is_some())?;
+    finish(child, stdin, deadline, end).await
+}
+
+/// Write `stdin` to `child` and wait for it like [`run`].
+async fn finish(
+    mut child: Child,
+    stdin: Option<String>,
+    deadline: &Deadline,
+    end: Instant,
+) -> anyhow::Result<Stage> {
+    let pid = child.id();
+
+    // Writing the input counts too, a stuck process may never read it.
+    let finished = async {
+        if let Some(input) = stdin {
+            let mut stdin = child.stdin.take().unwrap();
+            stdin.write_all(input.as_bytes()).await?;
+            drop(stdin);
+        }
+
+        child.wait_with_output().await
+    };
+
+    let stage = tokio::select! {
+        biased;
+        _ = deadline.cancelled() => Stage::Cancelled,
+        output = timeout_at(end.into(), finished) => match output {
+            Ok(output) => exited(output?),
+            Err(_) => Stage::Timeout,
+        },
+    };
+
+    if matches!(stage, Stage::Timeout | Stage::Cancelled)
+        && let Some(pid) = pid
+    {
+        limits::kill(pid);
+    }
+
+    Ok(stage)
+}
+
+/// How a command that exited with `output` went.
+fn exited(output: std::process::Output) -> Stage {
+    if !output.status.success() {
+        return Stage::Failed(String::from_utf8_lossy(&output.stderr).into_owned());
+    }
+
+    Stage::Done {
+        stdout: output.stdout,
+        stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+    }
+}
+
+/// The result of evaluating an expression with [`evaluate`].
+pub enum Evaluation {
+    Value(String),
+    Error(String),
+    Timeout(Exceeded),
+}
+
+/// Evaluate a typst code expression, with the `calc` module in scope, to its
+/// JSON representation.
+pub async fn evaluate(expr: &str, id: RequestId) -> anyhow::Result<Evaluation> {
+    let document = format!(
+        "#metadata(eval(\"{}\", mode: \"code\", scope: dictionary(calc))) <calc>",
+        escape_string(expr)
+    );
+
+    let dir = scratch_dir().await?;
+    let mut command = sandbox::typst("query", &dir);
+    command.args(["-", "<calc>", "--field", "value", "--one"]);
+
+    let deadline = Deadline::start(id);
+    let (end, exceeded) = deadline.window(budget::Stage::Compile);
+    let result = run(&mut command, Some(document), &deadline, end).await;
+    let _ = fs::remove_dir_all(&dir).await;
+
+    let evaluation = match result? {
+        Stage::Done { stdout, .. } => {
+            Evaluation::Value(String::from_utf8_lossy(&stdout).trim().to_owned())
+        }
+        Stage::Failed(err) => Evaluation::Error(err),
+        // Nothing cancels evaluations.
+        Stage::Timeout | Stage::Cancelled => Evaluation::Timeout(deadline.record(exceeded)),
+    };
+
+    if let Evaluation::Error(_) = evaluation {
+        println!("[{id}] Evaluation failed");
+    }
+
+    Ok(evaluation)
+}
+
+/// Escape `s` to go between the quotes of a typst string literal.
+pub fn escape_string(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+fn typst(source: &str, options: &RenderOptions, dir: &Path) -> Vec<Step> {
+    vec![(
+        typst_command(options, dir),
+        Some(typst_document(source, options)),
+    )]
+}
+
+/// The `typst compile` command for renders with `options`, reading the
+/// document from stdin and writing the pages to `dir`.
+pub fn typst_command(options: &RenderOptions, dir: &Path) -> tokio::process::Command {
+    let mut command = sandbox::typst("compile", dir);
+    command.arg("-").args(["--diagnostic-format", "short"]);
+
+    if options.safe {
+        // Empty package directories and a proxy nobody listens on, so any
+        // import fails instead of downloading.
+        let none = dir.join("no-packages");
+        command
+            .env("TYPST_PACKAGE_PATH", &none)
+            .env("TYPST_PACKAGE_CACHE_PATH", &none)
+            .env("HTTPS_PROXY", "http://127.0.0.1:9")
+            .env("https_proxy", "http://127.0.0.1:9");
+    }
+
+    match options.format {
+        Format::Png => {
+            // typst refuses to write several pages to stdout, so every page gets a file.
+            command
+                .arg(dir.join("page-{0p}.png"))
+                .args(["--format", "png"])
+                .arg("--ppi")
+                .arg(options.ppi.unwrap_or(DEFAULT_PPI).to_string());
+        }
+        Format::Pdf => {
+            command
+                .arg(dir.join("document.pdf"))
+                .args(["--format", "pdf"]);
+        }
+    }
+
+    command
+}
+
+/// `source` as the document to compile with `options`, in the preamble
+/// unless it's raw.
+fn typst_document(source: &str, options: &RenderOptions) -> String {
+    if options.raw {
+        source.to_owned()
+    } else {
+        let flavor = options.flavor.unwrap_or_default();
+        let size = options.size.unwrap_or_else(|| style::text_size(source));
+        let preamble = if options.safe || preamble_broken() {
+            fallback_preamble(flavor, &options.style, size)
+        } else {
+            preamble(flavor, &options.style, size)
+        };
+
+        // On the source's first line, so diagnostics still line up.
+        let align = if options.math {
+            "#set align(center);"
+        } else {
+            ""
+        };
+        format!("{preamble}\n{align}{source}")
+    }
+}
+
+/// LaTeX goes through tectonic to a PDF, then pdftoppm for images.
+async fn latex(source: &str, options: &RenderOptions, dir: &Path) -> anyhow::Result<Vec<Step>> {
+    let document = if options.raw {
+        source.to_owned()
+    } else {
+        latex_document(source, options.flavor.unwrap_or_default())
+    };
+    let input = dir.join("document.tex");
+    fs::write(&input, document).await?;
+
+    // Without shell escapes and the like, whatever the document asks for.
+    let mut tectonic = sandbox::command("tectonic", dir);
+    tectonic
+        .args(["--untrusted", "--chatter", "minimal", "--outdir"])
+        .arg(dir)
+        .arg(&input);
+
+    let mut stages = vec![(tectonic, None)];
+
+    if options.format == Format::Png {
+        let mut pdftoppm = sandbox::command("pdftoppm", dir);
+        pdftoppm
+            .arg("-png")
+            .arg("-r")
+            .arg(options.ppi.unwrap_or(DEFAULT_PPI).to_string())
+            .arg(dir.join("document.pdf"))
+            .arg(dir.join("page"));
+
+        stages.push((pdftoppm, None));
+    }
+
+    Ok(stages)
+}
+
+/// Wrap a LaTeX snippet in a standalone document colored like the typst
+/// preamble's catppuccin `flavor`.
+fn latex_document(source: &str, flavor: Flavor) -> String {
+    let (base, text) = flavor.colors();
+
+    format!(
+        r"\documentclass[preview,border=28pt,varwidth]{{standalone}}
+\usepackage{{amsmath,amssymb}}
+\usepackage{{xcolor}}
+\pagecolor[HTML]{{{base}}}
+\color[HTML]{{{text}}}
+\begin{{document}}
+\Huge
+{source}
+\end{{document}}
+"
+    )
+}
+
+fn graphviz(source: &str, options: &RenderOptions, dir: &Path) -> Vec<Step> {
+    let mut command = sandbox::command("dot", dir);
+
+    match options.format {
+        Format::Png => {
+            command
+                .arg("-Tpng")
+                .arg(format!("-Gdpi={}", options.ppi.unwrap_or(DEFAULT_PPI)))
+                .arg("-o")
+                .arg(dir.join("page.png"));
+        }
+        Format::Pdf => {
+            command.arg("-Tpdf").arg("-o").arg(dir.join("document.pdf"));
+        }
+    }
+
+    if !options.raw {
+        // Defaults only, attributes set in the graph itself still win.
+        let (base, text) = options.flavor.unwrap_or_default().colors();
+
+        command
+            .arg(format!("-Gbgcolor=#{base}"))
+            .arg(format!("-Gfontcolor=#{text}"))
+            .arg(format!("-Ncolor=#{text}"))
+            .arg(format!("-Nfontcolor=#{text}"))
+            .arg(format!("-Ecolor=#{text}"))
+            .arg(format!("-Efontcolor=#{text}"));
+    }
+
+    vec![(command, Some(source.to_owned()))]
+}
+
+async fn mermaid(source: &str, options: &RenderOptions, dir: &Path) -> anyhow::Result<Vec<Step>> {
+    let input = dir.join("diagram.mmd");
+    fs::write(&input, source).await?;
+
+    let output = match options.format {
+        Format::Png => dir.join("page.png"),
+        Format::Pdf => dir.join("document.pdf"),
+    };
+
+    let mut command = sandbox::command("mmdc", dir);
+    command
+        .arg("--quiet")
+        .arg("-i")
+        .arg(&input)
+        .arg("-o")
+        .arg(output)
+        // mermaid renders at 96 dpi.
+        .arg("-s")
+        .arg((options.ppi.unwrap_or(DEFAULT_PPI) as f32 / 96.0).to_string());
+
+    if !options.raw {
+        let flavor = options.flavor.unwrap_or_default();
+        let (base, _) = flavor.colors();
+        let theme = match flavor {
+            Flavor::Latte => "default",
+            _ => "dark",
+        };
+
+        command
+            .args(["-t", theme])
+            .arg("-b")
+            .arg(format!("#{base}"));
+    }
+
+    Ok(vec![(command, None)])
+}
+
+/// Stack pages vertically into one PNG, centering narrower pages.
+pub fn stitch(pages: &[Vec<u8>]) -> anyhow::Result<Vec<u8>> {
+    let images = pages
+        .iter()
+        .map(|page| image::load_from_memory(page).map(|img| img.to_rgba8()))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let width = images.iter().map(|img| img.width()).max().unwrap_or(0);
+    let height = images.iter().map(|img| img.height()).sum();
+
+    let mut canvas = RgbaImage::new(width, height);
+    let mut y = 0;
+    for img in &images {
+        let x = (width - img.width()) / 2;
+        imageops::overlay(&mut canvas, img, x.into(), y.into());
+        y += img.height();
+    }
+
+    let mut buf = vec![];
+    canvas.write_to(&mut Cursor::new(&mut buf), ImageFormat::Png)?;
+
+    Ok(buf)
+}
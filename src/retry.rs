@@ -0,0 +1,65 @@
+use std::{
+    hash::{BuildHasher, RandomState},
+    time::{Duration, SystemTime},
+};
+
+use matrix_sdk::{
+    HttpError,
+    ruma::api::client::error::{ErrorKind, RetryAfter},
+};
+
+/// How many times a send or an upload is attempted before giving up.
+pub const MAX_ATTEMPTS: u32 = 5;
+
+/// The longest a rate limit is waited out, past that the request fails.
+const MAX_WAIT: Duration = Duration::from_secs(60);
+
+/// How long to wait after `err` failed attempt `attempt` (counting from 1)
+/// before trying again, or `None` if trying again won't help.
+///
+/// Rate limits are waited out as long as the homeserver asks, network and
+/// server errors back off exponentially. Anything else, like a permission
+/// error, fails right away.
+pub fn delay(err: &matrix_sdk::Error, attempt: u32) -> Option<Duration> {
+    if attempt >= MAX_ATTEMPTS || !is_retryable(err) {
+        return None;
+    }
+
+    let wait = match err.client_api_error_kind() {
+        Some(ErrorKind::LimitExceeded {
+            retry_after: Some(RetryAfter::Delay(delay)),
+        }) => *delay,
+        Some(ErrorKind::LimitExceeded {
+            retry_after: Some(RetryAfter::DateTime(at)),
+        }) => at.duration_since(SystemTime::now()).unwrap_or_default(),
+        _ => backoff(attempt),
+    };
+
+    (wait <= MAX_WAIT).then_some(wait)
+}
+
+/// Whether `err` is a rate limit, a network or a server error, which may go
+/// away by itself.
+pub fn is_retryable(err: &matrix_sdk::Error) -> bool {
+    let network =
+        matches!(err, matrix_sdk::Error::Http(http) if matches!(**http, HttpError::Reqwest(_)));
+
+    network
+        || matches!(
+            err.client_api_error_kind(),
+            Some(ErrorKind::LimitExceeded { .. })
+        )
+        || err
+            .as_client_api_error()
+            .is_some_and(|err| err.status_code.is_server_error())
+}
+
+/// 1s, 2s, 4s… for attempt 1, 2, 3…, with up to half of it taken off at
+/// random so the bot's requests don't all come back at once.
+fn backoff(attempt: u32) -> Duration {
+    let base = Duration::from_secs(1 << (attempt - 1).min(6));
+    // `RandomState` is randomly seeded, which is plenty for jitter.
+    let jitter = RandomState::new().hash_one(SystemTime::now()) % 1000;
+
+    base.mul_f64(1.0 - jitter as f64 / 2000.0)
+}
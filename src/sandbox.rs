@@ -0,0 +1,165 @@
+use std::{
+    env,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use serde::Deserialize;
+use tokio::process::Command;
+
+use crate::{config, fonts, packages};
+
+/// How the compilers are confined, typst as well as tectonic, pdftoppm, dot
+/// and mmdc.
+///
+/// Configured with `compiler.sandbox` or `SANDBOX`: `none` (the default) runs
+/// them directly, `bwrap` in a bubblewrap sandbox with no network and a
+/// read-only view of the system, and anything else is a wrapper command the
+/// compiler's command line is appended to, e.g. a landlock or seccomp
+/// launcher.
+///
+/// Either way typst only sees the render's scratch directory as its project
+/// root, and in the `bwrap` sandbox the compilers can only write there and to
+/// their caches. With no network, packages that aren't cached yet can't be
+/// downloaded, which is why the ones renders need are prewarmed at startup.
+/// The same goes for the TeX bundle of tectonic.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Sandbox {
+    #[default]
+    None,
+    Bubblewrap,
+    Wrapper(Vec<String>),
+}
+
+impl Sandbox {
+    /// The program the compilers are run through, if any.
+    pub fn program(&self) -> Option<&str> {
+        match self {
+            Sandbox::None => None,
+            Sandbox::Bubblewrap => Some("bwrap"),
+            Sandbox::Wrapper(wrapper) => wrapper.first().map(String::as_str),
+        }
+    }
+}
+
+impl FromStr for Sandbox {
+    type Err = anyhow::Error;
+
+    fn from_str(sandbox: &str) -> Result<Self, Self::Err> {
+        Ok(match sandbox.trim() {
+            "" | "none" => Sandbox::None,
+            "bwrap" => Sandbox::Bubblewrap,
+            wrapper => Sandbox::Wrapper(wrapper.split_whitespace().map(Into::into).collect()),
+        })
+    }
+}
+
+impl TryFrom<String> for Sandbox {
+    type Error = anyhow::Error;
+
+    fn try_from(sandbox: String) -> Result<Self, Self::Error> {
+        sandbox.parse()
+    }
+}
+
+/// The configured sandbox.
+pub fn get() -> Sandbox {
+    config::get().compiler.sandbox.clone()
+}
+
+/// A command running `program` in the scratch directory `dir`, confined by
+/// the sandbox.
+pub fn command(program: &str, dir: &Path) -> Command {
+    let mut command = match config::get().compiler.sandbox {
+        Sandbox::None => Command::new(program),
+        Sandbox::Bubblewrap => bubblewrap(program, dir),
+        Sandbox::Wrapper(ref wrapper) => {
+            let mut command = Command::new(&wrapper[0]);
+            command.args(&wrapper[1..]).arg(program);
+            command
+        }
+    };
+
+    command.current_dir(dir);
+    command
+}
+
+/// A `typst` command running `subcommand`, confined to the scratch directory
+/// `dir`.
+pub fn typst(subcommand: &str, dir: &Path) -> Command {
+    let mut command = command("typst", dir);
+
+    packages::configure(&mut command);
+    fonts::configure(&mut command);
+    command.arg(subcommand).arg("--root").arg(dir);
+
+    command
+}
+
+/// Where tectonic caches the TeX bundle and the formats it builds.
+fn tectonic_cache() -> Option<PathBuf> {
+    let cache = env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| Some(PathBuf::from(env::var_os("HOME")?).join(".cache")))?;
+
+    Some(cache.join("Tectonic"))
+}
+
+/// Where mmdc's headless browser is installed.
+fn puppeteer_cache() -> Option<PathBuf> {
+    env::var_os("PUPPETEER_CACHE_DIR")
+        .map(PathBuf::from)
+        .or_else(|| Some(PathBuf::from(env::var_os("HOME")?).join(".cache/puppeteer")))
+}
+
+/// `bwrap`, set up to run `program` with no network and nothing writable but
+/// `dir` and the program's cache.
+fn bubblewrap(program: &str, dir: &Path) -> Command {
+    let mut command = Command::new("bwrap");
+    command
+        .args(["--unshare-all", "--die-with-parent", "--new-session"])
+        .args(["--ro-bind", "/usr", "/usr"])
+        .args(["--symlink", "usr/lib", "/lib"])
+        .args(["--symlink", "usr/lib64", "/lib64"])
+        .args(["--symlink", "usr/bin", "/bin"])
+        .args(["--ro-bind-try", "/etc/fonts", "/etc/fonts"])
+        .args(["--proc", "/proc", "--dev", "/dev", "--tmpfs", "/tmp"]);
+
+    // The compiler may live outside of /usr, e.g. in ~/.cargo/bin.
+    let path = which(program).unwrap_or_else(|| Path::new("/usr/bin").join(program));
+    command.arg("--ro-bind").arg(&path).arg(&path);
+
+    let (writable, readable) = match program {
+        "typst" => (packages::cache(), packages::vendored()),
+        "tectonic" => (tectonic_cache(), None),
+        "mmdc" => (None, puppeteer_cache()),
+        _ => (None, None),
+    };
+    if let Some(writable) = writable {
+        let _ = std::fs::create_dir_all(&writable);
+        command.arg("--bind").arg(&writable).arg(&writable);
+    }
+    if let Some(readable) = readable {
+        command.arg("--ro-bind-try").arg(&readable).arg(&readable);
+    }
+    for dir in fonts::dirs() {
+        command.arg("--ro-bind-try").arg(&dir).arg(&dir);
+    }
+
+    command.arg("--bind").arg(dir).arg(dir);
+    command.arg("--").arg(path);
+
+    command
+}
+
+/// The full path of `program`, looked up in `PATH`.
+pub fn which(program: &str) -> Option<PathBuf> {
+    if program.contains('/') {
+        return Some(program.into()).filter(|path: &PathBuf| path.exists());
+    }
+
+    env::split_paths(&env::var_os("PATH")?)
+        .map(|dir| dir.join(program))
+        .find(|path| path.is_file())
+}
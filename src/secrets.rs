@@ -0,0 +1,112 @@
+use std::path::{Path, PathBuf};
+
+use matrix_sdk::{
+    Client,
+    encryption::recovery::RecoveryState,
+    ruma::api::client::uiaa::{AuthData, Password, UserIdentifier},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{config::Matrix, session};
+
+/// The recovery key, as kept next to the session file.
+#[derive(Serialize, Deserialize)]
+struct Saved {
+    recovery_key: String,
+}
+
+/// Where the recovery key of the account with `session_file` is kept,
+/// encrypted like the session file.
+pub fn key_file(session_file: &Path) -> PathBuf {
+    session_file.with_extension("recovery-key.json")
+}
+
+/// Make sure the cross-signing identity and the room keys of `client` survive
+/// losing its matrix-sdk store.
+///
+/// Without secret storage on the account yet, cross-signing is bootstrapped
+/// and the keys are backed up with a new recovery key, which is saved to
+/// `key_file` and printed. When secret storage is there but this device can't
+/// read it, like after a fresh login once the store was wiped, the secrets are
+/// restored with `matrix.recovery_key` or the one in `key_file`.
+pub async fn setup(client: &Client, matrix: &Matrix, key_file: &Path) -> anyhow::Result<()> {
+    let encryption = client.encryption();
+    encryption.wait_for_e2ee_initialization_tasks().await;
+    let recovery = encryption.recovery();
+    let user = client
+        .user_id()
+        .map(ToString::to_string)
+        .unwrap_or_default();
+
+    match recovery.state() {
+        RecoveryState::Enabled => Ok(()),
+        RecoveryState::Incomplete => {
+            let recovery_key = match &matrix.recovery_key {
+                Some(recovery_key) => recovery_key.clone(),
+                None => match session::read::<Saved>(key_file).await {
+                    Ok(saved) => saved.recovery_key,
+                    Err(_) => {
+                        eprintln!(
+                            "{user} has secret storage this device can't read, set \
+                             `matrix.recovery_key` to restore its identity and keys"
+                        );
+                        return Ok(());
+                    }
+                },
+            };
+
+            recovery.recover(&recovery_key).await?;
+            println!("Restored the cross-signing identity and key backup of {user}");
+
+            Ok(())
+        }
+        RecoveryState::Disabled => {
+            bootstrap_cross_signing(client, matrix).await?;
+            let recovery_key = recovery.enable().wait_for_backups_to_upload().await?;
+
+            // Keep a copy outside of the data directory too, it's what
+            // restores the identity once that's lost.
+            println!(
+                "Set up secret storage for {user}, its recovery key is:\n\n{recovery_key}\n\n\
+                 Keep it safe, as `matrix.recovery_key` it restores the bot's identity and keys"
+            );
+            let saved = Saved { recovery_key };
+            session::write(key_file, &saved).await?;
+            println!("Recovery key saved in {}", key_file.display());
+
+            Ok(())
+        }
+        RecoveryState::Unknown => {
+            anyhow::bail!("Couldn't find out whether {user} has secret storage")
+        }
+    }
+}
+
+/// Create the cross-signing keys of `client` if it has none, authenticating
+/// with the password of `matrix` when the homeserver asks.
+async fn bootstrap_cross_signing(client: &Client, matrix: &Matrix) -> anyhow::Result<()> {
+    let encryption = client.encryption();
+    let Err(err) = encryption.bootstrap_cross_signing_if_needed(None).await else {
+        return Ok(());
+    };
+    let Some(response) = err.as_uiaa_response() else {
+        return Err(err.into());
+    };
+    if matrix.password.is_empty() {
+        anyhow::bail!(
+            "The homeserver wants a password to set up cross-signing, set it up from another \
+             client or log in with a password"
+        );
+    }
+
+    let mut password = Password::new(
+        UserIdentifier::UserIdOrLocalpart(matrix.username.clone()),
+        matrix.password.clone(),
+    );
+    password.session = response.session.clone();
+    encryption
+        .bootstrap_cross_signing(Some(AuthData::Password(password)))
+        .await?;
+
+    Ok(())
+}
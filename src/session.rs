@@ -0,0 +1,136 @@
+use std::{
+    path::Path,
+    process::Command,
+    sync::{Arc, Mutex, OnceLock},
+};
+
+use anyhow::{Context, bail};
+use matrix_sdk_store_encryption::StoreCipher;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use serde_json::Value;
+use tokio::fs;
+
+use crate::config;
+
+/// A session file encrypted with a passphrase.
+#[derive(Serialize, Deserialize)]
+struct Encrypted {
+    /// The key the session is encrypted with, itself encrypted with the
+    /// passphrase.
+    key: Value,
+    session: Value,
+}
+
+/// The unlocked key, and its encrypted form to write back with the session.
+type Unlocked = Arc<(StoreCipher, Value)>;
+
+/// Deriving the key from the passphrase is slow on purpose, so it's done once.
+static CIPHER: Mutex<Option<Unlocked>> = Mutex::new(None);
+
+/// Read the secret for `account` from the OS keyring, through libsecret's
+/// `secret-tool`.
+fn keyring(account: &str) -> Option<String> {
+    let output = match Command::new("secret-tool")
+        .args(["lookup", "service", "typit-matrix", "account", account])
+        .output()
+    {
+        Ok(output) => output,
+        Err(err) => {
+            eprintln!("Can't read the `{account}` passphrase from the keyring ({err})");
+            return None;
+        }
+    };
+
+    let secret = String::from_utf8_lossy(&output.stdout).trim().to_owned();
+    if !output.status.success() || secret.is_empty() {
+        eprintln!("The keyring has no `{account}` passphrase");
+        return None;
+    }
+
+    Some(secret)
+}
+
+/// The passphrase configured as `configured`, or in the keyring under
+/// `account` when `storage.keyring` is on.
+fn passphrase(configured: Option<String>, account: &str) -> Option<String> {
+    configured.or_else(|| config::get().storage.keyring.then(|| keyring(account))?)
+}
+
+/// The passphrase the session file is encrypted with, if it is.
+fn session_passphrase() -> Option<String> {
+    static PASSPHRASE: OnceLock<Option<String>> = OnceLock::new();
+
+    PASSPHRASE
+        .get_or_init(|| passphrase(config::get().storage.session_passphrase.clone(), "session"))
+        .clone()
+}
+
+/// The passphrase the matrix-sdk store is encrypted with, if it is.
+pub fn store_passphrase() -> Option<String> {
+    passphrase(config::get().storage.store_passphrase.clone(), "store")
+}
+
+/// The key to encrypt the session with, unlocking `key` or making a new one.
+fn cipher(passphrase: &str, key: Option<&Value>) -> anyhow::Result<Unlocked> {
+    let mut cipher = CIPHER.lock().unwrap();
+    if let Some(cipher) = &*cipher {
+        return Ok(cipher.clone());
+    }
+
+    let unlocked = match key {
+        Some(key) => {
+            let store_cipher = StoreCipher::import(passphrase, &serde_json::to_vec(key)?)
+                .context("Can't decrypt the session file, is the passphrase right?")?;
+            (store_cipher, key.clone())
+        }
+        None => {
+            let store_cipher = StoreCipher::new()?;
+            let key = serde_json::from_slice(&store_cipher.export(passphrase)?)?;
+            (store_cipher, key)
+        }
+    };
+
+    Ok(cipher.insert(Arc::new(unlocked)).clone())
+}
+
+/// Read the session persisted at `path`, decrypting it if it's encrypted.
+pub async fn read<T: DeserializeOwned>(path: &Path) -> anyhow::Result<T> {
+    let serialized = fs::read_to_string(path).await?;
+
+    match serde_json::from_str::<Encrypted>(&serialized) {
+        Ok(encrypted) => {
+            let Some(passphrase) = session_passphrase() else {
+                bail!("The session file is encrypted, but no passphrase is configured");
+            };
+            let cipher = cipher(&passphrase, Some(&encrypted.key))?;
+
+            Ok(cipher
+                .0
+                .decrypt_value(&serde_json::to_vec(&encrypted.session)?)?)
+        }
+        // Plaintext sessions get encrypted when next written.
+        Err(_) => Ok(serde_json::from_str(&serialized)?),
+    }
+}
+
+/// Persist `session` at `path`, encrypted if a passphrase is configured.
+pub async fn write<T: Serialize>(path: &Path, session: &T) -> anyhow::Result<()> {
+    let serialized = match session_passphrase() {
+        Some(passphrase) => {
+            let cipher = cipher(&passphrase, None)?;
+
+            serde_json::to_string(&Encrypted {
+                key: cipher.1.clone(),
+                session: serde_json::from_slice(&cipher.0.encrypt_value(session)?)?,
+            })?
+        }
+        None => serde_json::to_string(session)?,
+    };
+
+    // Write a copy and swap it in, so a crash can't leave half a file.
+    let temp = path.with_extension("tmp");
+    fs::write(&temp, serialized).await?;
+    fs::rename(&temp, path).await?;
+
+    Ok(())
+}
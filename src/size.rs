@@ -0,0 +1,108 @@
+use std::io::Cursor;
+
+use image::{
+    ImageEncoder,
+    codecs::{
+        png::{CompressionType, FilterType, PngEncoder},
+        webp::WebPEncoder,
+    },
+    imageops,
+};
+use matrix_sdk::Client;
+
+use crate::config;
+
+/// Room left for the metadata added to a PNG after it's been fitted.
+const HEADROOM: usize = 4096;
+
+/// How many times an image is shrunk before giving up.
+const MAX_STEPS: u32 = 6;
+
+/// The longest source accepted, in bytes, `limits.max_source` (20000 by
+/// default).
+pub fn max_source() -> usize {
+    config::get().limits.max_source
+}
+
+/// The biggest upload the homeserver accepts, from its `m.upload.size`.
+pub async fn max_upload(client: &Client) -> Option<usize> {
+    match client.load_or_fetch_max_upload_size().await {
+        Ok(size) => usize::try_from(u64::from(size)).ok(),
+        Err(err) => {
+            eprintln!("Couldn't get the maximum upload size ({err})");
+            None
+        }
+    }
+}
+
+/// Make `png` fit in `max` bytes, compressing it harder and then downscaling
+/// it, or `None` if it can't be done.
+pub fn fit(png: Vec<u8>, max: usize) -> Option<Vec<u8>> {
+    let max = max.saturating_sub(HEADROOM);
+    if png.len() <= max {
+        return Some(png);
+    }
+
+    let mut img = image::load_from_memory(&png).ok()?.to_rgba8();
+    let mut encoded = encode(&img)?;
+
+    for _ in 0..MAX_STEPS {
+        if encoded.len() <= max {
+            return Some(encoded);
+        }
+
+        // The size goes roughly with the area, aim a bit under.
+        let scale = (max as f64 / encoded.len() as f64).sqrt() * 0.9;
+        let (width, height) = (
+            (img.width() as f64 * scale) as u32,
+            (img.height() as f64 * scale) as u32,
+        );
+        if width == 0 || height == 0 {
+            return None;
+        }
+
+        img = imageops::resize(&img, width, height, imageops::FilterType::Lanczos3);
+        encoded = encode(&img)?;
+    }
+
+    (encoded.len() <= max).then_some(encoded)
+}
+
+/// `png` re-encoded as lossless WebP, or `png` itself if that isn't smaller.
+pub fn webp(png: Vec<u8>) -> Vec<u8> {
+    let Ok(img) = image::load_from_memory(&png) else {
+        return png;
+    };
+    let img = img.to_rgba8();
+
+    let mut buf = vec![];
+    let encoded = WebPEncoder::new_lossless(Cursor::new(&mut buf)).write_image(
+        &img,
+        img.width(),
+        img.height(),
+        image::ExtendedColorType::Rgba8,
+    );
+
+    match encoded {
+        Ok(()) if buf.len() < png.len() => buf,
+        _ => png,
+    }
+}
+
+fn encode(img: &image::RgbaImage) -> Option<Vec<u8>> {
+    let mut buf = vec![];
+    PngEncoder::new_with_quality(
+        Cursor::new(&mut buf),
+        CompressionType::Best,
+        FilterType::Adaptive,
+    )
+    .write_image(
+        img,
+        img.width(),
+        img.height(),
+        image::ExtendedColorType::Rgba8,
+    )
+    .ok()?;
+
+    Some(buf)
+}
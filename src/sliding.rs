@@ -0,0 +1,101 @@
+use std::{pin::pin, time::Duration};
+
+use futures_util::StreamExt;
+use matrix_sdk::{
+    Client, SlidingSync, SlidingSyncList, SlidingSyncMode,
+    ruma::{api::client::sync::sync_events::v5 as http, assign, events::StateEventType},
+    sliding_sync::Version,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{health, metrics};
+
+/// The ID of the bot's sliding sync connection, at most 16 characters.
+const CONNECTION_ID: &str = "typit";
+
+/// How many more rooms each request asks for until it has all of them.
+const BATCH_SIZE: u32 = 50;
+
+/// The most events of a room a response has, the ones past that are missed.
+const TIMELINE_LIMIT: u32 = 20;
+
+/// How long to wait before reconnecting after an error.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Sync `client` with sliding sync until `shutdown` is cancelled, running
+/// `ready` once the first response is in.
+///
+/// The sync position is kept in the matrix-sdk store, so a restart picks up
+/// where it left off. Errors, like the homeserver expiring the connection,
+/// restart it. The rooms that come in after the first response bring their
+/// latest events along, the message handlers drop them as stale.
+pub async fn run(
+    client: &Client,
+    ready: impl Future<Output = ()>,
+    shutdown: &CancellationToken,
+) -> anyhow::Result<()> {
+    let sliding_sync = build(client).await?;
+    let mut updates = pin!(sliding_sync.sync());
+    let mut ready = pin!(Some(ready));
+
+    loop {
+        let update = tokio::select! {
+            update = updates.next() => update,
+            () = shutdown.cancelled() => return Ok(()),
+        };
+
+        match update {
+            Some(Ok(_)) => {
+                health::synced();
+                if let Some(ready) = ready.as_mut().as_pin_mut() {
+                    ready.await;
+                }
+                ready.set(None);
+            }
+            Some(Err(err)) => {
+                metrics::SYNC_ERRORS.inc();
+                eprintln!("Sliding sync failed, reconnecting: {err}");
+                tokio::time::sleep(RECONNECT_DELAY).await;
+                updates.set(sliding_sync.sync());
+            }
+            // The stream only ends after an error it already returned.
+            None => updates.set(sliding_sync.sync()),
+        }
+    }
+}
+
+/// A sliding sync connection over every room of `client`, with what the bot
+/// needs of their state.
+async fn build(client: &Client) -> anyhow::Result<SlidingSync> {
+    let rooms = SlidingSyncList::builder("rooms")
+        .sync_mode(SlidingSyncMode::new_growing(BATCH_SIZE))
+        .timeline_limit(TIMELINE_LIMIT)
+        .required_state(vec![
+            (StateEventType::RoomCreate, String::new()),
+            (StateEventType::RoomEncryption, String::new()),
+            (StateEventType::RoomPowerLevels, String::new()),
+            (StateEventType::RoomName, String::new()),
+            (StateEventType::RoomCanonicalAlias, String::new()),
+            (StateEventType::RoomTombstone, String::new()),
+            (StateEventType::SpaceParent, "*".to_owned()),
+            (StateEventType::RoomMember, "$LAZY".to_owned()),
+            (StateEventType::RoomMember, "$ME".to_owned()),
+        ]);
+
+    let sliding_sync = client
+        .sliding_sync(CONNECTION_ID)?
+        .version(Version::Native)
+        .add_list(rooms)
+        .with_e2ee_extension(assign!(http::request::E2EE::default(), { enabled: Some(true) }))
+        .with_to_device_extension(
+            assign!(http::request::ToDevice::default(), { enabled: Some(true) }),
+        )
+        .with_account_data_extension(
+            assign!(http::request::AccountData::default(), { enabled: Some(true) }),
+        )
+        .share_pos()
+        .build()
+        .await?;
+
+    Ok(sliding_sync)
+}
@@ -0,0 +1,259 @@
+use matrix_sdk::{Room, ruma::UserId};
+
+use crate::{
+    doc::Action,
+    options, policy, size,
+    state::{Store, Template},
+};
+
+/// How many templates a room can have.
+const MAX_TEMPLATES: usize = 100;
+
+/// The longest template name.
+const MAX_NAME_LENGTH: usize = 32;
+
+const USAGE: &str = "Usage: `,template save <name> <code>`, `,template render <name> [flags] \
+                     [args]`, `,template list` or `,template delete <name>`";
+
+/// Handle `,template save|render|list|delete`, returning what to do or the
+/// text of an error reply.
+///
+/// Saved templates are shared by the whole room. `{{1}}`, `{{2}}`… in their
+/// source are replaced by the arguments they're rendered with, and `{{args}}`
+/// by all of them. Arguments are separated by spaces, quote them to put
+/// spaces in one.
+pub async fn command<'a>(
+    args: &'a str,
+    room: &Room,
+    sender: &UserId,
+    store: &Store,
+) -> Result<Action<'a>, String> {
+    let args = args.trim();
+    let (action, rest) = args
+        .split_once(char::is_whitespace)
+        .map_or((args, ""), |(action, rest)| (action, rest.trim()));
+    let (name, rest) = rest
+        .split_once(char::is_whitespace)
+        .map_or((rest, ""), |(name, rest)| (name, rest.trim()));
+
+    let templates = store.room(room.room_id()).await.templates;
+
+    match action {
+        "save" if !name.is_empty() => {
+            if name.len() > MAX_NAME_LENGTH
+                || !name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            {
+                return Err(format!(
+                    "Template names are up to {MAX_NAME_LENGTH} letters, digits, `-` and `_`"
+                ));
+            }
+            if rest.is_empty() {
+                return Err("Usage: `,template save <name> <code>`".into());
+            }
+            if rest.len() > size::max_source() {
+                return Err(format!(
+                    "Templates can be up to {} bytes long",
+                    size::max_source()
+                ));
+            }
+
+            let replaced = match templates.get(name) {
+                Some(existing) => {
+                    if !can_change(existing, room, sender).await {
+                        return Err(format!(
+                            "`{name}` was saved by {}, only they and moderators can replace it",
+                            existing.author
+                        ));
+                    }
+                    true
+                }
+                None if templates.len() >= MAX_TEMPLATES => {
+                    return Err(format!(
+                        "This room already has {MAX_TEMPLATES} templates, delete one first"
+                    ));
+                }
+                None => false,
+            };
+
+            let template = Template {
+                source: rest.to_owned(),
+                author: sender.to_owned(),
+            };
+            let arity = arity(&template.source);
+            save(room, store, name, Some(template)).await?;
+
+            Ok(Action::Reply(format!(
+                "{} `{name}`, which takes {arity} argument(s), render it with `,template render \
+                 {name}`",
+                if replaced { "Replaced" } else { "Saved" }
+            )))
+        }
+        "render" if !name.is_empty() => {
+            let template = templates.get(name).ok_or_else(|| unknown(name))?;
+
+            // The flags come first, the rest are the arguments.
+            let (_, rest_args) = options::parse(rest)?;
+            let flags = &rest[..rest.len() - rest_args.len()];
+            let values = split_args(rest_args);
+
+            let arity = arity(&template.source);
+            if values.len() < arity {
+                return Err(format!(
+                    "`{name}` takes {arity} argument(s), got {}",
+                    values.len()
+                ));
+            }
+
+            Ok(Action::Render(fill(&template.source, &values), flags))
+        }
+        "list" => {
+            if templates.is_empty() {
+                return Ok(Action::Reply(
+                    "No templates here yet, save one with `,template save <name> <code>`".into(),
+                ));
+            }
+
+            let mut names: Vec<_> = templates.iter().collect();
+            names.sort_by_key(|(name, _)| *name);
+
+            let mut lines = vec![format!("{} template(s) here:", names.len())];
+            lines.extend(names.into_iter().map(|(name, template)| {
+                format!(
+                    "• {name} ({} argument(s), by {})",
+                    arity(&template.source),
+                    template.author
+                )
+            }));
+
+            Ok(Action::Reply(lines.join("\n")))
+        }
+        "delete" if !name.is_empty() => {
+            let template = templates.get(name).ok_or_else(|| unknown(name))?;
+            if !can_change(template, room, sender).await {
+                return Err(format!(
+                    "`{name}` was saved by {}, only they and moderators can delete it",
+                    template.author
+                ));
+            }
+
+            save(room, store, name, None).await?;
+            Ok(Action::Reply(format!("Deleted `{name}`")))
+        }
+        _ => Err(USAGE.into()),
+    }
+}
+
+fn unknown(name: &str) -> String {
+    format!("There's no template called `{name}` here, see `,template list`")
+}
+
+/// Whether `user` may replace or delete `template`, being its author or a
+/// moderator.
+async fn can_change(template: &Template, room: &Room, user: &UserId) -> bool {
+    template.author == user || policy::is_moderator(room, user).await
+}
+
+/// Replace the template `name` of `room`, or delete it.
+async fn save(
+    room: &Room,
+    store: &Store,
+    name: &str,
+    template: Option<Template>,
+) -> Result<(), String> {
+    store
+        .update(|state| {
+            let templates = &mut state
+                .rooms
+                .entry(room.room_id().to_owned())
+                .or_default()
+                .templates;
+
+            match template {
+                Some(template) => {
+                    templates.insert(name.to_owned(), template);
+                }
+                None => {
+                    templates.remove(name);
+                }
+            }
+        })
+        .await
+        .map_err(|err| err.to_string())
+}
+
+/// How many arguments `source` takes, the highest `{{n}}` in it.
+fn arity(source: &str) -> usize {
+    source
+        .split("{{")
+        .skip(1)
+        .filter_map(|part| part.split_once("}}")?.0.parse().ok())
+        .max()
+        .unwrap_or(0)
+}
+
+/// `source` with its placeholders replaced by `args`, in one pass so
+/// arguments that look like placeholders are left alone.
+fn fill(source: &str, args: &[String]) -> String {
+    let mut filled = String::with_capacity(source.len());
+    let mut rest = source;
+
+    while let Some(start) = rest.find("{{") {
+        filled.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let value = rest[2..].split_once("}}").and_then(|(placeholder, _)| {
+            let value = match placeholder {
+                "args" => args.join(" "),
+                n => args.get(n.parse::<usize>().ok()?.checked_sub(1)?)?.clone(),
+            };
+            Some((value, placeholder.len() + 4))
+        });
+
+        match value {
+            Some((value, length)) => {
+                filled.push_str(&value);
+                rest = &rest[length..];
+            }
+            None => {
+                filled.push_str("{{");
+                rest = &rest[2..];
+            }
+        }
+    }
+    filled.push_str(rest);
+
+    filled
+}
+
+/// `args` split on whitespace, keeping what's in double quotes together.
+fn split_args(args: &str) -> Vec<String> {
+    let mut split = vec![];
+    let mut current = String::new();
+    let (mut quoted, mut started) = (false, false);
+
+    for c in args.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                started = true;
+            }
+            c if c.is_whitespace() && !quoted => {
+                if started {
+                    split.push(std::mem::take(&mut current));
+                    started = false;
+                }
+            }
+            c => {
+                current.push(c);
+                started = true;
+            }
+        }
+    }
+    if started {
+        split.push(current);
+    }
+
+    split
+}
@@ -0,0 +1,70 @@
+use std::{
+    collections::HashSet,
+    sync::{LazyLock, RwLock},
+    time::Duration,
+};
+
+use matrix_sdk::{
+    Client,
+    ruma::{OwnedRoomId, RoomId, api::client::space::get_hierarchy},
+};
+
+use crate::config;
+
+/// The rooms in the configured Space, once it was resolved.
+static ROOMS: LazyLock<RwLock<Option<HashSet<OwnedRoomId>>>> = LazyLock::new(Default::default);
+
+/// Whether the bot may operate in `room`: always without a Space configured,
+/// otherwise only in the Space's rooms, which are none until it's resolved.
+pub fn contains(room: &RoomId) -> bool {
+    if config::get().space.id.is_none() {
+        return true;
+    }
+
+    ROOMS
+        .read()
+        .unwrap()
+        .as_ref()
+        .is_some_and(|rooms| rooms.contains(room))
+}
+
+/// Every room in the hierarchy of the Space `space`, itself included.
+async fn resolve(client: &Client, space: &RoomId) -> anyhow::Result<HashSet<OwnedRoomId>> {
+    let mut rooms = HashSet::new();
+    let mut from = None;
+
+    loop {
+        let mut request = get_hierarchy::v1::Request::new(space.to_owned());
+        request.from = from;
+        let response = client.send(request).await?;
+
+        rooms.extend(response.rooms.into_iter().map(|room| room.summary.room_id));
+        from = response.next_batch;
+        if from.is_none() {
+            return Ok(rooms);
+        }
+    }
+}
+
+/// Resolve the configured Space every `space.refresh` minutes, keeping the
+/// rooms found last when it fails.
+pub async fn watch(client: Client) {
+    loop {
+        let config = config::get();
+        let Some(space) = &config.space.id else {
+            // It may be configured on a reload.
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            continue;
+        };
+
+        match resolve(&client, space).await {
+            Ok(rooms) => {
+                println!("The Space {space} has {} room(s)", rooms.len());
+                *ROOMS.write().unwrap() = Some(rooms);
+            }
+            Err(err) => eprintln!("Couldn't resolve the Space {space} ({err})"),
+        }
+
+        tokio::time::sleep(Duration::from_secs(config.space.refresh * 60)).await;
+    }
+}
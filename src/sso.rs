@@ -0,0 +1,94 @@
+use matrix_sdk::Client;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpListener,
+};
+
+/// Log `client` in through the homeserver's single sign-on.
+///
+/// The login URL is printed for the operator to open in a browser, which
+/// redirects back to a listener on `matrix.sso_port` with a login token. The
+/// URL the browser ends up on can also be pasted into the terminal, for when
+/// the browser runs on another machine.
+pub async fn login(client: &Client, port: u16) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port)).await?;
+    let redirect = format!("http://{}/", listener.local_addr()?);
+    let url = client
+        .matrix_auth()
+        .get_sso_login_url(&redirect, None)
+        .await?;
+
+    println!("Open this URL to log in:\n\n{url}\n");
+    println!("Or paste the URL it redirects to here:");
+
+    let token = tokio::select! {
+        token = callback(&listener) => token?,
+        token = pasted() => token?,
+    };
+
+    client
+        .matrix_auth()
+        .login_token(&token)
+        .initial_device_display_name("typit")
+        .request_refresh_token()
+        .await?;
+
+    Ok(())
+}
+
+/// The login token in `text`, a redirect URL or the bare token.
+fn token(text: &str) -> Option<String> {
+    let text = text.trim();
+    if let Some((_, rest)) = text.split_once("loginToken=") {
+        return rest
+            .split(['&', ' ', '#'])
+            .next()
+            .filter(|token| !token.is_empty())
+            .map(Into::into);
+    }
+
+    (!text.is_empty() && !text.contains(['/', ' ', '?'])).then(|| text.to_owned())
+}
+
+/// Wait for the browser to be redirected to `listener` with a login token.
+async fn callback(listener: &TcpListener) -> anyhow::Result<String> {
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+
+        let mut request = vec![0; 8192];
+        let read = stream.read(&mut request).await?;
+        let request = String::from_utf8_lossy(&request[..read]);
+        // Only the request line matters, e.g. `GET /?loginToken=… HTTP/1.1`.
+        let target = request.lines().next().unwrap_or_default();
+
+        let (status, body, token) = match token(target) {
+            Some(token) => ("200 OK", "Logged in, you can close this tab.", Some(token)),
+            None => ("400 Bad Request", "No login token in the request.", None),
+        };
+        let response = format!(
+            "HTTP/1.1 {status}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\
+             Connection: close\r\n\r\n{body}",
+            body.len()
+        );
+        let _ = stream.write_all(response.as_bytes()).await;
+
+        if let Some(token) = token {
+            return Ok(token);
+        }
+    }
+}
+
+/// Wait for a redirect URL or token to be pasted into the terminal.
+async fn pasted() -> anyhow::Result<String> {
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        match token(&line) {
+            Some(token) => return Ok(token),
+            None => println!("That has no login token in it, paste the whole URL"),
+        }
+    }
+
+    // Without a terminal, only the browser can finish the login.
+    std::future::pending().await
+}
@@ -0,0 +1,725 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use matrix_sdk::ruma::{
+    DeviceId, EventId, MxcUri, OwnedEventId, OwnedMxcUri, OwnedRoomId, OwnedTransactionId,
+    OwnedUserId, RoomId, TransactionId, UserId,
+};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use tokio::sync::Mutex;
+
+use crate::{
+    config,
+    options::{Flavor, RenderOptions},
+    ratelimit::Bucket,
+    storage::Backend,
+    usage::Usage,
+};
+
+/// Everything the bot remembers across restarts besides the session itself.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct State {
+    #[serde(default)]
+    pub rooms: HashMap<OwnedRoomId, RoomSettings>,
+    /// When the last startup announcement went out, in seconds since the epoch.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_announcement: Option<u64>,
+    /// The version that last announced its upgrade.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_version: Option<String>,
+    /// Users whose commands are ignored, besides the ones blocked in the
+    /// config.
+    #[serde(default, skip_serializing_if = "HashSet::is_empty")]
+    pub blocked: HashSet<OwnedUserId>,
+}
+
+/// Per-room preferences.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct RoomSettings {
+    /// The flavor used when a render doesn't pick one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub flavor: Option<Flavor>,
+    /// Posting images failed with a permission error, send PDFs instead.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub images_blocked: bool,
+    /// Don't send typing notifications, receipts or reactions here.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub quiet: bool,
+    /// Compile every render here like `--safe`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub safe: bool,
+    /// Ignore every command here but `,typ enable`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub disabled: bool,
+    /// Render the `$...$` math of every message here.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub auto_math: bool,
+    /// Mentioning the bot works like `,typ` here.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub mentions: bool,
+    /// The language replies here are in, the deployment's default if unset.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+    /// The power level members need to use commands here.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_power: Option<i64>,
+    /// Each member's vote for the room flavor.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub theme_votes: HashMap<OwnedUserId, Flavor>,
+    /// Announce upgrades here.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub whatsnew: bool,
+    /// The documents members are building with `,doc`.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub documents: HashMap<OwnedUserId, Document>,
+    /// The snippets saved with `,template save`, by name.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub templates: HashMap<String, Template>,
+    /// The files uploaded with `,asset upload`, by name.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub assets: HashMap<String, Asset>,
+}
+
+/// A render that was queued but hadn't started yet, kept so a restart doesn't
+/// drop it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueuedJob {
+    pub room: OwnedRoomId,
+    pub sender: OwnedUserId,
+    /// The command, with its flags and source.
+    pub body: String,
+    /// When the command was sent, in milliseconds since the epoch.
+    pub sent: u64,
+    /// The root of the thread the command was sent in, so the render is
+    /// posted there too.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thread: Option<OwnedEventId>,
+    /// The message the command replied to, which a bare `,typ` renders.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub in_reply_to: Option<OwnedEventId>,
+}
+
+/// A reply that couldn't be sent yet, kept so a restart delivers it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingReply {
+    pub room: OwnedRoomId,
+    /// The content of the `m.room.message`.
+    pub content: serde_json::Value,
+    /// When it was first attempted, in seconds since the epoch.
+    pub queued: u64,
+}
+
+/// A document built across several messages with `,doc`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Document {
+    pub source: String,
+    /// When it was last changed, in seconds since the epoch.
+    pub updated: u64,
+}
+
+/// A snippet saved for the whole room with `,template save`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Template {
+    /// The Typst source, with `{{1}}`, `{{2}}`… where the arguments go.
+    pub source: String,
+    pub author: OwnedUserId,
+}
+
+/// A file uploaded for the room's documents with `,asset upload`, kept in its
+/// asset directory.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Asset {
+    pub author: OwnedUserId,
+    /// In bytes.
+    pub size: u64,
+    /// The SHA-256 of the file, in hex.
+    pub hash: String,
+}
+
+/// What one of the bot's messages is a render of, for `,src` and the history.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RenderRecord {
+    pub source: String,
+    /// What it was rendered with, besides the format, `--dm` and the page
+    /// mode.
+    #[serde(default)]
+    pub options: RenderOptions,
+}
+
+/// The key the usage of `room` is stored under, or of every room.
+fn usage_key(room: Option<&RoomId>) -> String {
+    match room {
+        Some(room) => format!("usage:{room}"),
+        None => "usage:all".into(),
+    }
+}
+
+/// The keys that pile up with every command and render, pruned once older
+/// than `storage.retention_days`.
+const PRUNED: [&str; 3] = ["answered:", "render:", "media:"];
+
+/// When the entry stored as `value` under one of the [`PRUNED`] keys was
+/// written, in seconds since the epoch. Entries from before they had one are
+/// as old as it gets.
+fn written_at(value: &str) -> u64 {
+    #[derive(Deserialize)]
+    struct Stamped {
+        at: u64,
+    }
+
+    value
+        .parse()
+        .or_else(|_| serde_json::from_str::<Stamped>(value).map(|stamped| stamped.at))
+        .unwrap_or(0)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Prune the entries older than `storage.retention_days` every hour.
+pub async fn expire(store: Arc<Store>) {
+    loop {
+        tokio::time::sleep(Duration::from_secs(60 * 60)).await;
+
+        if let Err(err) = store.drop_expired().await {
+            eprintln!("Couldn't drop the expired rate limits and cooldowns: {err}");
+        }
+
+        let days = config::get().storage.retention_days;
+        if days == 0 {
+            continue;
+        }
+        match store.prune(days).await {
+            Ok(0) => {}
+            Ok(pruned) => println!("Pruned {pruned} expired entries from the state"),
+            Err(err) => eprintln!("Couldn't prune the state: {err}"),
+        }
+    }
+}
+
+/// The prefixes of the keys the rate limit buckets, cooldowns, queued renders
+/// and unsent replies are stored under.
+const BUCKET: &str = "bucket:";
+const COOLDOWN: &str = "cooldown:";
+const QUEUED: &str = "queued:";
+const OUTBOX: &str = "outbox:";
+
+/// The key the [`State`] is stored under.
+pub const STATE_KEY: &str = "state";
+
+/// The key of a token changed with every write of the [`State`], so instances
+/// sharing the backend only reload it after another one changed it.
+const VERSION_KEY: &str = "state_version";
+
+/// How many events [`Store::claim`] remembers when running alone, the same
+/// one reaches every account in the room within a few syncs.
+const MAX_CLAIMED: usize = 1024;
+
+/// How long to wait before trying again for a key another instance holds.
+const LOCK_RETRY: Duration = Duration::from_millis(50);
+
+/// The [`State`], persisted through a storage [`Backend`].
+///
+/// With `storage.instance_id` set, several bot processes can share the backend: the
+/// state is reloaded whenever another one changed it and events are claimed
+/// before handling.
+pub struct Store {
+    backend: Arc<dyn Backend>,
+    state: Mutex<State>,
+    /// The [`VERSION_KEY`] of `state`, only changed along with it.
+    version: std::sync::Mutex<Option<String>>,
+    instance: Option<String>,
+    /// The latest events claimed by the accounts of this process.
+    claimed: std::sync::Mutex<VecDeque<String>>,
+    /// Held while changing the histories and usage, which aren't in `state`.
+    writes: Mutex<()>,
+}
+
+impl Store {
+    /// Load the state from `backend`, starting fresh if it doesn't have one yet.
+    pub fn load(backend: Box<dyn Backend>) -> anyhow::Result<Self> {
+        let state = match backend.get(STATE_KEY)? {
+            Some(serialized) => serde_json::from_str(&serialized)?,
+            None => State::default(),
+        };
+
+        let version = backend.get(VERSION_KEY)?;
+
+        Ok(Self {
+            backend: backend.into(),
+            state: Mutex::new(state),
+            version: std::sync::Mutex::new(version),
+            instance: config::get().storage.instance_id.clone(),
+            claimed: std::sync::Mutex::new(VecDeque::new()),
+            writes: Mutex::new(()),
+        })
+    }
+
+    /// Run `f` with the backend on a blocking thread, sqlite doesn't await.
+    async fn blocking<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&dyn Backend) -> anyhow::Result<T> + Send + 'static,
+    ) -> anyhow::Result<T> {
+        let backend = self.backend.clone();
+
+        tokio::task::spawn_blocking(move || f(&*backend)).await?
+    }
+
+    /// Run `f` holding `key` in the backend, so the read-modify-writes of
+    /// instances sharing it don't interleave.
+    async fn exclusive<T>(
+        &self,
+        key: &str,
+        f: impl Future<Output = anyhow::Result<T>>,
+    ) -> anyhow::Result<T> {
+        let Some(instance) = &self.instance else {
+            return f.await;
+        };
+
+        loop {
+            let (key, owner) = (key.to_owned(), instance.clone());
+            if self
+                .blocking(move |backend| backend.lock(&key, &owner))
+                .await?
+            {
+                break;
+            }
+            tokio::time::sleep(LOCK_RETRY).await;
+        }
+
+        let result = f.await;
+
+        let (key, owner) = (key.to_owned(), instance.clone());
+        if let Err(err) = self
+            .blocking(move |backend| backend.unlock(&key, &owner))
+            .await
+        {
+            eprintln!("Couldn't release a lock on the shared state: {err}");
+        }
+
+        result
+    }
+
+    /// Pick up changes made by other instances.
+    async fn refresh(&self, state: &mut State) -> anyhow::Result<()> {
+        if self.instance.is_none() {
+            return Ok(());
+        }
+
+        let version = self.get(VERSION_KEY.into()).await?;
+        if *self.version.lock().unwrap() == version {
+            return Ok(());
+        }
+        // Read after the version, so it's at least as recent.
+        if let Some(serialized) = self.get(STATE_KEY.into()).await? {
+            *state = serde_json::from_str(&serialized)?;
+        }
+        *self.version.lock().unwrap() = version;
+
+        Ok(())
+    }
+
+    /// Read from the state.
+    pub async fn read<T>(&self, f: impl FnOnce(&State) -> T) -> T {
+        let mut state = self.state.lock().await;
+        if let Err(err) = self.refresh(&mut state).await {
+            eprintln!("Couldn't reload the shared state, using the local copy: {err}");
+        }
+
+        f(&state)
+    }
+
+    /// Change the state and write it back to the backend.
+    pub async fn update<T>(&self, f: impl FnOnce(&mut State) -> T) -> anyhow::Result<T> {
+        let mut state = self.state.lock().await;
+
+        self.exclusive(STATE_KEY, async {
+            self.refresh(&mut state).await?;
+            let ret = f(&mut state);
+
+            let serialized = serde_json::to_string(&*state)?;
+            self.set(STATE_KEY.into(), serialized).await?;
+            if let Some(instance) = &self.instance {
+                let nanos = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_nanos();
+                let version = format!("{instance}:{nanos}");
+                self.set(VERSION_KEY.into(), version.clone()).await?;
+                *self.version.lock().unwrap() = Some(version);
+            }
+
+            Ok(ret)
+        })
+        .await
+    }
+
+    /// Read the value stored under `key`.
+    async fn get(&self, key: String) -> anyhow::Result<Option<String>> {
+        self.blocking(move |backend| backend.get(&key)).await
+    }
+
+    /// Store `value` under `key`.
+    async fn set(&self, key: String, value: String) -> anyhow::Result<()> {
+        self.blocking(move |backend| backend.set(&key, &value))
+            .await
+    }
+
+    /// Whether this instance should handle the event `key`, only once even if
+    /// several of its accounts see it.
+    pub async fn claim(&self, key: &str) -> anyhow::Result<bool> {
+        if let Some(instance) = &self.instance {
+            let (key, instance) = (key.to_owned(), instance.clone());
+            return self
+                .blocking(move |backend| backend.claim(&key, &instance))
+                .await;
+        }
+
+        let mut claimed = self.claimed.lock().unwrap();
+        if claimed.iter().any(|claimed| claimed == key) {
+            return Ok(false);
+        }
+        if claimed.len() == MAX_CLAIMED {
+            claimed.pop_front();
+        }
+        claimed.push_back(key.to_owned());
+
+        Ok(true)
+    }
+
+    /// Whether the command `event` in `room` was already answered.
+    pub async fn answered(&self, room: &RoomId, event: &EventId) -> bool {
+        match self.get(format!("answered:{room}:{event}")).await {
+            Ok(answered) => answered.is_some(),
+            Err(err) => {
+                eprintln!("Couldn't check whether {event} was answered: {err}");
+                false
+            }
+        }
+    }
+
+    /// Record that the command `event` in `room` was answered, so it isn't
+    /// again after a restart.
+    pub async fn mark_answered(&self, room: &RoomId, event: &EventId) -> anyhow::Result<()> {
+        self.set(format!("answered:{room}:{event}"), now().to_string())
+            .await
+    }
+
+    /// Remember that the bot's message `event` in `room` is a render of
+    /// `record`.
+    pub async fn record_render(
+        &self,
+        room: &RoomId,
+        event: &EventId,
+        record: &RenderRecord,
+    ) -> anyhow::Result<()> {
+        let mut value = serde_json::to_value(record)?;
+        value["at"] = now().into();
+
+        self.set(format!("render:{room}:{event}"), value.to_string())
+            .await
+    }
+
+    /// What the bot's message `event` in `room` is a render of, if it's a
+    /// render.
+    pub async fn render(
+        &self,
+        room: &RoomId,
+        event: &EventId,
+    ) -> anyhow::Result<Option<RenderRecord>> {
+        match self.get(format!("render:{room}:{event}")).await? {
+            Some(serialized) => Ok(Some(serde_json::from_str(&serialized)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// The sources `user` rendered in `room`, latest first. Kept per room, so
+    /// a source from a private room doesn't show up in a public one.
+    pub async fn history(&self, room: &RoomId, user: &UserId) -> anyhow::Result<Vec<RenderRecord>> {
+        match self.get(format!("history:{room}:{user}")).await? {
+            Some(serialized) => Ok(serde_json::from_str(&serialized)?),
+            None => Ok(vec![]),
+        }
+    }
+
+    /// Change the sources `user` rendered in `room`.
+    pub async fn update_history(
+        &self,
+        room: &RoomId,
+        user: &UserId,
+        f: impl FnOnce(&mut Vec<RenderRecord>),
+    ) -> anyhow::Result<()> {
+        let key = format!("history:{room}:{user}");
+        let _guard = self.writes.lock().await;
+
+        self.exclusive(&key, async {
+            let mut history = self.history(room, user).await?;
+            f(&mut history);
+            self.set(key.clone(), serde_json::to_string(&history)?)
+                .await
+        })
+        .await
+    }
+
+    /// The recent renders in `room`, or in every room.
+    pub async fn usage(&self, room: Option<&RoomId>) -> anyhow::Result<Usage> {
+        match self.get(usage_key(room)).await? {
+            Some(serialized) => Ok(serde_json::from_str(&serialized)?),
+            None => Ok(Usage::default()),
+        }
+    }
+
+    /// Change the recent renders in `room`, or in every room.
+    pub async fn update_usage(
+        &self,
+        room: Option<&RoomId>,
+        f: impl FnOnce(&mut Usage),
+    ) -> anyhow::Result<()> {
+        let key = usage_key(room);
+        let _guard = self.writes.lock().await;
+
+        self.exclusive(&key, async {
+            let mut usage = self.usage(room).await?;
+            f(&mut usage);
+            self.set(key.clone(), serde_json::to_string(&usage)?).await
+        })
+        .await
+    }
+
+    /// Forget everything about `room`: its settings, its usage, the histories,
+    /// commands answered and renders posted there, and the renders and replies
+    /// still waiting to go there.
+    pub async fn forget_room(&self, room: &RoomId) -> anyhow::Result<()> {
+        self.update(|state| state.rooms.remove(room)).await?;
+
+        for (event, job) in self.queued().await? {
+            if job.room == room {
+                self.unqueue(&event).await?;
+            }
+        }
+        for (txn_id, reply) in self.outbox().await? {
+            if reply.room == room {
+                self.remove_reply(&txn_id).await?;
+            }
+        }
+
+        let room = room.to_owned();
+        self.blocking(move |backend| {
+            backend.remove(&usage_key(Some(&room)))?;
+            backend.remove_prefix(&format!("answered:{room}:"))?;
+            backend.remove_prefix(&format!("history:{room}:"))?;
+            backend.remove_prefix(&format!("render:{room}:"))
+        })
+        .await
+    }
+
+    /// Drop the answered commands, render sources and uploads older than
+    /// `days`, returning how many were.
+    pub async fn prune(&self, days: u64) -> anyhow::Result<usize> {
+        let cutoff = now().saturating_sub(days * 24 * 60 * 60);
+
+        self.blocking(move |backend| {
+            let mut pruned = 0;
+            for prefix in PRUNED {
+                for (key, value) in backend.entries(prefix)? {
+                    if written_at(&value) < cutoff {
+                        backend.remove(&key)?;
+                        pruned += 1;
+                    }
+                }
+            }
+
+            Ok(pruned)
+        })
+        .await
+    }
+
+    /// Drop the rate limit buckets untouched for an hour, full again by now,
+    /// and the cooldowns that ran out.
+    pub async fn drop_expired(&self) -> anyhow::Result<()> {
+        let now = now();
+        let now_millis = now * 1000;
+
+        self.blocking(move |backend| {
+            for (key, value) in backend.entries(BUCKET)? {
+                if serde_json::from_str::<Bucket>(&value)?.is_idle(now_millis) {
+                    backend.remove(&key)?;
+                }
+            }
+            for (key, value) in backend.entries(COOLDOWN)? {
+                if value.parse::<u64>()? <= now {
+                    backend.remove(&key)?;
+                }
+            }
+
+            Ok(())
+        })
+        .await
+    }
+
+    /// Change the rate limit buckets of `user` and `room` together, [`None`]
+    /// for the ones never used.
+    pub async fn update_buckets<T>(
+        &self,
+        user: &UserId,
+        room: &RoomId,
+        f: impl FnOnce(&mut Option<Bucket>, &mut Option<Bucket>) -> T,
+    ) -> anyhow::Result<T> {
+        let (user_key, room_key) = (format!("{BUCKET}{user}"), format!("{BUCKET}{room}"));
+        let _guard = self.writes.lock().await;
+
+        self.exclusive(&user_key, async {
+            self.exclusive(&room_key, async {
+                let mut user_bucket = self.entry(user_key.clone()).await?;
+                let mut room_bucket = self.entry(room_key.clone()).await?;
+                let ret = f(&mut user_bucket, &mut room_bucket);
+
+                self.put(user_key.clone(), user_bucket).await?;
+                self.put(room_key.clone(), room_bucket).await?;
+
+                Ok(ret)
+            })
+            .await
+        })
+        .await
+    }
+
+    /// Until when `user` is on cooldown, in seconds since the epoch.
+    pub async fn cooldown(&self, user: &UserId) -> anyhow::Result<Option<u64>> {
+        self.entry(format!("{COOLDOWN}{user}")).await
+    }
+
+    /// Put `user` on cooldown until `until`, in seconds since the epoch.
+    pub async fn set_cooldown(&self, user: &UserId, until: u64) -> anyhow::Result<()> {
+        self.put(format!("{COOLDOWN}{user}"), Some(until)).await
+    }
+
+    /// The renders waiting for a slot, by the event of their command.
+    pub async fn queued(&self) -> anyhow::Result<Vec<(OwnedEventId, QueuedJob)>> {
+        self.entries(QUEUED).await
+    }
+
+    /// Keep the render `job` queued by the command `event`.
+    pub async fn queue(&self, event: &EventId, job: &QueuedJob) -> anyhow::Result<()> {
+        self.put(format!("{QUEUED}{event}"), Some(job)).await
+    }
+
+    /// Drop the render queued by the command `event`.
+    pub async fn unqueue(&self, event: &EventId) -> anyhow::Result<()> {
+        self.put(format!("{QUEUED}{event}"), None::<QueuedJob>)
+            .await
+    }
+
+    /// The replies that couldn't be sent yet, by their transaction ID.
+    pub async fn outbox(&self) -> anyhow::Result<Vec<(OwnedTransactionId, PendingReply)>> {
+        self.entries(OUTBOX).await
+    }
+
+    /// Keep `reply` until it's sent as `txn_id`.
+    pub async fn keep_reply(
+        &self,
+        txn_id: &TransactionId,
+        reply: &PendingReply,
+    ) -> anyhow::Result<()> {
+        self.put(format!("{OUTBOX}{txn_id}"), Some(reply)).await
+    }
+
+    /// Drop the reply sent, or given up on, as `txn_id`.
+    pub async fn remove_reply(&self, txn_id: &TransactionId) -> anyhow::Result<()> {
+        self.put(format!("{OUTBOX}{txn_id}"), None::<PendingReply>)
+            .await
+    }
+
+    /// Read the value stored as JSON under `key`.
+    async fn entry<T: DeserializeOwned>(&self, key: String) -> anyhow::Result<Option<T>> {
+        match self.get(key).await? {
+            Some(serialized) => Ok(Some(serde_json::from_str(&serialized)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Read the values stored as JSON under the keys starting with `prefix`,
+    /// by the rest of their key.
+    async fn entries<K, T>(&self, prefix: &'static str) -> anyhow::Result<Vec<(K, T)>>
+    where
+        K: TryFrom<String, Error: std::error::Error + Send + Sync + 'static>,
+        T: DeserializeOwned,
+    {
+        self.blocking(move |backend| backend.entries(prefix))
+            .await?
+            .into_iter()
+            .map(|(key, value)| {
+                Ok((
+                    K::try_from(key[prefix.len()..].to_owned())?,
+                    serde_json::from_str(&value)?,
+                ))
+            })
+            .collect()
+    }
+
+    /// Store `value` as JSON under `key`, or remove it if there's none.
+    async fn put<T: Serialize>(&self, key: String, value: Option<T>) -> anyhow::Result<()> {
+        match value {
+            Some(value) => self.set(key, serde_json::to_string(&value)?).await,
+            None => self.blocking(move |backend| backend.remove(&key)).await,
+        }
+    }
+
+    /// Where bytes with the SHA-256 `hash` were uploaded to, if they were.
+    pub async fn media(&self, hash: &str) -> anyhow::Result<Option<OwnedMxcUri>> {
+        #[derive(Deserialize)]
+        struct Media {
+            uri: OwnedMxcUri,
+        }
+
+        // Entries from before they were stamped are just the URI, those are
+        // uploaded again.
+        Ok(self
+            .get(format!("media:{hash}"))
+            .await?
+            .and_then(|value| serde_json::from_str::<Media>(&value).ok())
+            .map(|media| media.uri))
+    }
+
+    /// Remember that bytes with the SHA-256 `hash` were uploaded to `uri`.
+    pub async fn set_media(&self, hash: &str, uri: &MxcUri) -> anyhow::Result<()> {
+        let value = serde_json::json!({ "uri": uri, "at": now() });
+
+        self.set(format!("media:{hash}"), value.to_string()).await
+    }
+
+    /// Where the sync of the session on `device` is at.
+    pub async fn sync_token(&self, device: &DeviceId) -> anyhow::Result<Option<String>> {
+        self.get(format!("sync_token:{device}")).await
+    }
+
+    /// Remember where the sync of the session on `device` is at.
+    pub async fn set_sync_token(&self, device: &DeviceId, token: &str) -> anyhow::Result<()> {
+        self.set(format!("sync_token:{device}"), token.to_owned())
+            .await
+    }
+
+    /// Whether the commands of `user` are ignored, having been blocked with
+    /// `,typadmin block` or in the config.
+    pub async fn is_blocked(&self, user: &UserId) -> bool {
+        config::get()
+            .admin
+            .blocked
+            .iter()
+            .any(|blocked| blocked == user)
+            || self.read(|state| state.blocked.contains(user)).await
+    }
+
+    /// The settings of `room`, or the defaults if it never changed any.
+    pub async fn room(&self, room: &RoomId) -> RoomSettings {
+        self.read(|state| state.rooms.get(room).cloned().unwrap_or_default())
+            .await
+    }
+}
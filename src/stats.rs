@@ -0,0 +1,136 @@
+use std::collections::HashMap;
+
+use matrix_sdk::Room;
+
+use crate::{
+    state::Store,
+    usage::{DAYS, Usage},
+};
+
+/// How many users `,stats` lists.
+const TOP_USERS: usize = 3;
+
+/// How many users, rooms and errors `,typadmin stats` lists.
+const TOP_ADMIN: usize = 10;
+
+/// The usage of every day added up.
+#[derive(Default)]
+struct Totals<'a> {
+    renders: u32,
+    failures: u32,
+    latency_ms: u64,
+    compile_ms: u64,
+    compiles: u32,
+    users: HashMap<&'a str, u32>,
+    rooms: HashMap<&'a str, u32>,
+    errors: HashMap<&'a str, u32>,
+}
+
+impl<'a> Totals<'a> {
+    fn of(usage: &'a Usage) -> Self {
+        let mut totals = Self::default();
+
+        for day in usage.days.values() {
+            totals.renders += day.renders;
+            totals.failures += day.failures;
+            totals.latency_ms += day.latency_ms;
+            totals.compile_ms += day.compile_ms;
+            totals.compiles += day.compiles;
+            for (user, count) in &day.users {
+                *totals.users.entry(user.as_str()).or_default() += count;
+            }
+            for (room, count) in &day.rooms {
+                *totals.rooms.entry(room.as_str()).or_default() += count;
+            }
+            for (kind, count) in &day.errors {
+                *totals.errors.entry(kind).or_default() += count;
+            }
+        }
+
+        totals
+    }
+
+    /// Renders, failures and timings, on one line.
+    fn summary(&self) -> String {
+        let failure_rate = match self.renders {
+            0 => 0.0,
+            renders => 100.0 * self.failures as f64 / renders as f64,
+        };
+
+        format!(
+            "{} renders, {} failed ({failure_rate:.0}%), {} ms on average, {} ms compiling",
+            self.renders,
+            self.failures,
+            self.latency_ms
+                .checked_div(self.renders.into())
+                .unwrap_or(0),
+            self.compile_ms
+                .checked_div(self.compiles.into())
+                .unwrap_or(0),
+        )
+    }
+}
+
+/// The `n` keys of `counts` with the highest counts, as a bulleted list.
+fn top(counts: &HashMap<&str, u32>, n: usize) -> Vec<String> {
+    let mut counts: Vec<_> = counts.iter().collect();
+    counts.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+
+    counts
+        .into_iter()
+        .take(n)
+        .map(|(key, count)| format!("• {key} ({count})"))
+        .collect()
+}
+
+/// Handle `,stats`, summing up the renders of the last [`DAYS`] days in
+/// `room` and in every room.
+pub async fn command(room: &Room, store: &Store) -> Result<String, String> {
+    let usage = store
+        .usage(Some(room.room_id()))
+        .await
+        .map_err(|err| err.to_string())?;
+    let all = store.usage(None).await.map_err(|err| err.to_string())?;
+    let (totals, all) = (Totals::of(&usage), Totals::of(&all));
+
+    let mut lines = vec![
+        format!("Here over the last {DAYS} days: {}", totals.summary()),
+        format!("Everywhere: {}", all.summary()),
+    ];
+    if !totals.users.is_empty() {
+        lines.push("Top users here:".into());
+        lines.extend(top(&totals.users, TOP_USERS));
+    }
+
+    Ok(lines.join("\n"))
+}
+
+/// Handle `,typadmin stats`, detailing the renders of the last [`DAYS`] days
+/// in every room.
+pub async fn admin(store: &Store) -> Result<String, String> {
+    let usage = store.usage(None).await.map_err(|err| err.to_string())?;
+    let totals = Totals::of(&usage);
+    let today = usage.days.last_key_value().map(|(_, day)| day.renders);
+
+    let mut lines = vec![
+        format!("Over the last {DAYS} days: {}", totals.summary()),
+        format!(
+            "{} users in {} rooms, {} renders on the latest day",
+            totals.users.len(),
+            totals.rooms.len(),
+            today.unwrap_or(0)
+        ),
+    ];
+    for (title, counts) in [
+        ("Top users:", &totals.users),
+        ("Top rooms:", &totals.rooms),
+        ("Top errors:", &totals.errors),
+    ] {
+        if !counts.is_empty() {
+            lines.push(title.into());
+            lines.extend(top(counts, TOP_ADMIN));
+        }
+    }
+
+    Ok(lines.join("\n"))
+}
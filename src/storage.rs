@@ -0,0 +1,675 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use rusqlite::{Connection, OptionalExtension, Transaction, TransactionBehavior};
+
+use crate::{config, queue::Rejected};
+
+/// Somewhere to persist the bot's data, as string values under string keys.
+pub trait Backend: Send + Sync {
+    /// Read the value stored under `key`.
+    fn get(&self, key: &str) -> anyhow::Result<Option<String>>;
+
+    /// Store `value` under `key`, replacing any previous value.
+    fn set(&self, key: &str, value: &str) -> anyhow::Result<()>;
+
+    /// Drop the value stored under `key`, if there is one.
+    fn remove(&self, key: &str) -> anyhow::Result<()>;
+
+    /// Every key starting with `prefix`, with its value.
+    fn entries(&self, prefix: &str) -> anyhow::Result<Vec<(String, String)>>;
+
+    /// Drop the values stored under every key starting with `prefix`.
+    fn remove_prefix(&self, prefix: &str) -> anyhow::Result<()>;
+
+    /// Claim `key` for `owner`, returning whether `owner` holds it.
+    ///
+    /// Instances sharing a backend claim events before handling them so only
+    /// one of them replies.
+    fn claim(&self, key: &str, owner: &str) -> anyhow::Result<bool>;
+
+    /// Try to take the lock on `key` for `owner`, returning whether `owner`
+    /// holds it. Locks left behind by crashed instances expire after
+    /// [`LOCK_TTL`].
+    ///
+    /// Only the sqlite backend is shared between instances, the others hold
+    /// every lock right away.
+    fn lock(&self, _key: &str, _owner: &str) -> anyhow::Result<bool> {
+        Ok(true)
+    }
+
+    /// Release the lock on `key`, if `owner` holds it.
+    fn unlock(&self, _key: &str, _owner: &str) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// How long a lock lasts when it isn't released, in seconds.
+pub const LOCK_TTL: u64 = 30;
+
+/// How long a claim is kept, in seconds. Events are only handled right after
+/// they arrive, old claims are useless.
+const CLAIM_TTL: u64 = 24 * 60 * 60;
+
+/// The claims of a backend that can't be shared, kept in memory since only
+/// this process ever claims anything.
+#[derive(Default)]
+struct Claims(Mutex<HashMap<String, (String, u64)>>);
+
+impl Claims {
+    /// Claim `key` for `owner` at `now`, in seconds since the epoch, see
+    /// [`Backend::claim`].
+    fn claim(&self, key: &str, owner: &str, now: u64) -> bool {
+        let mut claims = self.0.lock().unwrap();
+        claims.retain(|_, (_, claimed_at)| *claimed_at + CLAIM_TTL >= now);
+
+        claims
+            .entry(key.to_owned())
+            .or_insert_with(|| (owner.to_owned(), now))
+            .0
+            == owner
+    }
+}
+
+/// The current time, in seconds since the epoch.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// The database shared with the other instances, with `storage.instance_id`
+/// set. The render cache and queue go through it rather than the [`Backend`]
+/// of the state, on a connection of their own.
+static SHARED: OnceLock<Sqlite> = OnceLock::new();
+
+/// Whether other instances may share the renders, see [`shared`].
+pub fn is_shared() -> bool {
+    SHARED.get().is_some()
+}
+
+/// Run `f` on a blocking thread with the database shared with the other
+/// instances, or `None` when running alone.
+pub async fn shared<T: Send + 'static>(
+    f: impl FnOnce(&Sqlite) -> anyhow::Result<T> + Send + 'static,
+) -> Option<anyhow::Result<T>> {
+    let sqlite = SHARED.get()?;
+
+    Some(
+        tokio::task::spawn_blocking(move || f(sqlite))
+            .await
+            .map_err(Into::into)
+            .and_then(|result| result),
+    )
+}
+
+/// Open the configured backend (`sqlite`, `json` or `memory`), storing at the
+/// configured state file.
+///
+/// With `storage.instance_id` set, the sqlite database is [`shared`] with the
+/// other instances for their renders too.
+pub fn from_config() -> anyhow::Result<Box<dyn Backend>> {
+    let config = config::get();
+    let storage = &config.storage;
+    let state_file = |default: &str| {
+        storage
+            .state_file
+            .clone()
+            .unwrap_or_else(|| default.to_owned())
+    };
+
+    Ok(match storage.backend.as_str() {
+        "sqlite" => {
+            let path = PathBuf::from(state_file("state.sqlite3"));
+            if let Some(instance) = &storage.instance_id {
+                let shared = Sqlite::open(&path)?;
+                // What it queued before a crash won't run anymore.
+                shared.leave_all(instance)?;
+                let _ = SHARED.set(shared);
+            }
+
+            Box::new(Sqlite::open(&path)?)
+        }
+        "json" => Box::new(JsonFile::new(state_file("state.json").into())),
+        "memory" => Box::new(Memory::default()),
+        backend => anyhow::bail!("Unknown storage backend `{backend}`"),
+    })
+}
+
+/// A single key-value table in a sqlite database.
+pub struct Sqlite {
+    conn: Mutex<Connection>,
+}
+
+impl Sqlite {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let conn = Connection::open(path)?;
+        // Other instances may be writing to the same database.
+        conn.busy_timeout(Duration::from_secs(5))?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS kv (key TEXT PRIMARY KEY, value TEXT NOT NULL);
+             CREATE TABLE IF NOT EXISTS claims (
+                 key TEXT PRIMARY KEY,
+                 owner TEXT NOT NULL,
+                 claimed_at INTEGER NOT NULL
+             );
+             CREATE TABLE IF NOT EXISTS locks (
+                 key TEXT PRIMARY KEY,
+                 owner TEXT NOT NULL,
+                 locked_at INTEGER NOT NULL
+             );
+             CREATE TABLE IF NOT EXISTS renders (
+                 key TEXT PRIMARY KEY,
+                 output BLOB NOT NULL,
+                 inserted_at INTEGER NOT NULL,
+                 used_at INTEGER NOT NULL
+             );
+             CREATE TABLE IF NOT EXISTS queue (
+                 owner TEXT PRIMARY KEY,
+                 user TEXT NOT NULL,
+                 favourite INTEGER NOT NULL,
+                 seen_at INTEGER NOT NULL,
+                 started_at INTEGER
+             );",
+        )?;
+
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    /// The render output cached under `key` in the last `ttl` seconds, see
+    /// [`cache`](crate::cache).
+    pub fn cached(&self, key: &str, ttl: u64) -> anyhow::Result<Option<Vec<u8>>> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "DELETE FROM renders WHERE inserted_at < unixepoch() - ?1",
+            [ttl],
+        )?;
+        conn.execute(
+            "UPDATE renders SET used_at = unixepoch() WHERE key = ?1",
+            [key],
+        )?;
+
+        Ok(conn
+            .query_row("SELECT output FROM renders WHERE key = ?1", [key], |row| {
+                row.get(0)
+            })
+            .optional()?)
+    }
+
+    /// Cache the render `output` under `key`, keeping the `size` most
+    /// recently used ones.
+    pub fn cache(&self, key: &str, output: &[u8], size: usize) -> anyhow::Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO renders (key, output, inserted_at, used_at)
+             VALUES (?1, ?2, unixepoch(), unixepoch())
+             ON CONFLICT (key) DO UPDATE SET
+                 output = excluded.output,
+                 inserted_at = excluded.inserted_at,
+                 used_at = excluded.used_at",
+            (key, output),
+        )?;
+        conn.execute(
+            "DELETE FROM renders WHERE key NOT IN
+                 (SELECT key FROM renders ORDER BY used_at DESC, rowid DESC LIMIT ?1)",
+            [size as u64],
+        )?;
+
+        Ok(())
+    }
+
+    /// Queue a render by `user` as `owner`, unless the user has one waiting
+    /// or running on any instance or `capacity` renders are waiting, see
+    /// [`queue::join`](crate::queue::join).
+    pub fn join(
+        &self,
+        owner: &str,
+        user: &str,
+        favourite: bool,
+        capacity: usize,
+        ttl: u64,
+    ) -> anyhow::Result<Result<(), Rejected>> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        expire_queue(&tx, ttl)?;
+
+        let busy: bool = tx.query_row(
+            "SELECT EXISTS (SELECT 1 FROM queue WHERE user = ?1)",
+            [user],
+            |row| row.get(0),
+        )?;
+        if busy {
+            return Ok(Err(Rejected::Busy));
+        }
+        let waiting: u64 = tx.query_row(
+            "SELECT count(*) FROM queue WHERE started_at IS NULL",
+            [],
+            |row| row.get(0),
+        )?;
+        if waiting >= capacity as u64 {
+            return Ok(Err(Rejected::Full));
+        }
+
+        tx.execute(
+            "INSERT INTO queue (owner, user, favourite, seen_at) VALUES (?1, ?2, ?3, unixepoch())",
+            (owner, user, favourite),
+        )?;
+        tx.commit()?;
+
+        Ok(Ok(()))
+    }
+
+    /// How many renders of every instance have to start before `owner`'s,
+    /// if it can't start right away with `concurrency` running at once.
+    pub fn ahead(&self, owner: &str, concurrency: usize) -> anyhow::Result<Option<usize>> {
+        let conn = self.conn.lock().unwrap();
+        let (ahead, running) = place(&conn, owner)?;
+
+        Ok((ahead > 0 || running >= concurrency).then_some(ahead))
+    }
+
+    /// Start `owner`'s render if it's its turn, keeping its place otherwise.
+    ///
+    /// Waiting renders call this regularly, the ones that stop are from
+    /// crashed instances and lose their place after [`LOCK_TTL`]. Running
+    /// ones are dropped after `ttl` seconds.
+    pub fn start(
+        &self,
+        owner: &str,
+        user: &str,
+        favourite: bool,
+        concurrency: usize,
+        ttl: u64,
+    ) -> anyhow::Result<bool> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        expire_queue(&tx, ttl)?;
+
+        let started_at: Option<Option<u64>> = tx
+            .query_row(
+                "SELECT started_at FROM queue WHERE owner = ?1",
+                [owner],
+                |row| row.get(0),
+            )
+            .optional()?;
+        // Started by an earlier call whose caller stopped waiting for it.
+        if let Some(Some(_)) = started_at {
+            return Ok(true);
+        }
+
+        tx.execute(
+            "UPDATE queue SET seen_at = unixepoch() WHERE owner = ?1",
+            [owner],
+        )?;
+        // Lost while this instance stalled, back in line.
+        if started_at.is_none() {
+            tx.execute(
+                "INSERT INTO queue (owner, user, favourite, seen_at) VALUES (?1, ?2, ?3, unixepoch())",
+                (owner, user, favourite),
+            )?;
+        }
+
+        let (ahead, running) = place(&tx, owner)?;
+        let started = ahead == 0 && running < concurrency;
+        if started {
+            tx.execute(
+                "UPDATE queue SET started_at = unixepoch() WHERE owner = ?1",
+                [owner],
+            )?;
+        }
+        tx.commit()?;
+
+        Ok(started)
+    }
+
+    /// Drop `owner`'s render from the queue, waiting or running.
+    pub fn leave(&self, owner: &str) -> anyhow::Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute("DELETE FROM queue WHERE owner = ?1", [owner])?;
+
+        Ok(())
+    }
+
+    /// Drop every render `instance` queued.
+    fn leave_all(&self, instance: &str) -> anyhow::Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "DELETE FROM queue WHERE substr(owner, 1, length(?1)) = ?1",
+            [format!("{instance}:")],
+        )?;
+
+        Ok(())
+    }
+}
+
+/// Drop the waiting renders whose instance stopped asking for their turn and
+/// the ones running for longer than `ttl` seconds.
+fn expire_queue(tx: &Transaction, ttl: u64) -> anyhow::Result<()> {
+    tx.execute(
+        "DELETE FROM queue
+         WHERE (started_at IS NULL AND seen_at < unixepoch() - ?1)
+             OR started_at < unixepoch() - ?2",
+        [LOCK_TTL, ttl],
+    )?;
+
+    Ok(())
+}
+
+/// How many waiting renders are ahead of `owner`'s, favourites first, and
+/// how many are running.
+fn place(conn: &Connection, owner: &str) -> anyhow::Result<(usize, usize)> {
+    let ahead: u64 = conn.query_row(
+        "SELECT count(*) FROM queue AS other, queue AS own
+         WHERE own.owner = ?1
+             AND other.started_at IS NULL
+             AND other.owner != own.owner
+             AND (other.favourite > own.favourite
+                 OR (other.favourite = own.favourite AND other.rowid < own.rowid))",
+        [owner],
+        |row| row.get(0),
+    )?;
+    let running: u64 = conn.query_row(
+        "SELECT count(*) FROM queue WHERE started_at IS NOT NULL",
+        [],
+        |row| row.get(0),
+    )?;
+
+    Ok((ahead as usize, running as usize))
+}
+
+impl Backend for Sqlite {
+    fn get(&self, key: &str) -> anyhow::Result<Option<String>> {
+        let conn = self.conn.lock().unwrap();
+
+        Ok(conn
+            .query_row("SELECT value FROM kv WHERE key = ?1", [key], |row| {
+                row.get(0)
+            })
+            .optional()?)
+    }
+
+    fn set(&self, key: &str, value: &str) -> anyhow::Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO kv (key, value) VALUES (?1, ?2)
+             ON CONFLICT (key) DO UPDATE SET value = excluded.value",
+            [key, value],
+        )?;
+
+        Ok(())
+    }
+
+    fn remove(&self, key: &str) -> anyhow::Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute("DELETE FROM kv WHERE key = ?1", [key])?;
+
+        Ok(())
+    }
+
+    fn entries(&self, prefix: &str) -> anyhow::Result<Vec<(String, String)>> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement =
+            conn.prepare("SELECT key, value FROM kv WHERE substr(key, 1, length(?1)) = ?1")?;
+        let entries = statement
+            .query_map([prefix], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<_, _>>()?;
+
+        Ok(entries)
+    }
+
+    fn remove_prefix(&self, prefix: &str) -> anyhow::Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "DELETE FROM kv WHERE substr(key, 1, length(?1)) = ?1",
+            [prefix],
+        )?;
+
+        Ok(())
+    }
+
+    fn claim(&self, key: &str, owner: &str) -> anyhow::Result<bool> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.execute(
+            "DELETE FROM claims WHERE claimed_at < unixepoch() - ?1",
+            [CLAIM_TTL],
+        )?;
+        conn.execute(
+            "INSERT OR IGNORE INTO claims (key, owner, claimed_at) VALUES (?1, ?2, unixepoch())",
+            [key, owner],
+        )?;
+
+        let holder: String =
+            conn.query_row("SELECT owner FROM claims WHERE key = ?1", [key], |row| {
+                row.get(0)
+            })?;
+
+        Ok(holder == owner)
+    }
+
+    fn lock(&self, key: &str, owner: &str) -> anyhow::Result<bool> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.execute(
+            "DELETE FROM locks WHERE key = ?1 AND locked_at < unixepoch() - ?2",
+            (key, LOCK_TTL),
+        )?;
+        let inserted = conn.execute(
+            "INSERT OR IGNORE INTO locks (key, owner, locked_at) VALUES (?1, ?2, unixepoch())",
+            [key, owner],
+        )?;
+
+        Ok(inserted == 1)
+    }
+
+    fn unlock(&self, key: &str, owner: &str) -> anyhow::Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "DELETE FROM locks WHERE key = ?1 AND owner = ?2",
+            [key, owner],
+        )?;
+
+        Ok(())
+    }
+}
+
+/// Every key in one JSON object on disk.
+pub struct JsonFile {
+    path: PathBuf,
+    // Writes rewrite the whole file, so they can't interleave.
+    lock: Mutex<()>,
+    claims: Claims,
+}
+
+impl JsonFile {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            lock: Mutex::new(()),
+            claims: Claims::default(),
+        }
+    }
+
+    fn read(&self) -> anyhow::Result<HashMap<String, String>> {
+        match std::fs::read_to_string(&self.path) {
+            Ok(serialized) => Ok(serde_json::from_str(&serialized)?),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(HashMap::new()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn write(&self, entries: &HashMap<String, String>) -> anyhow::Result<()> {
+        // Write a copy and swap it in, so a crash can't leave half a file.
+        let temp = self.path.with_extension("tmp");
+        std::fs::write(&temp, serde_json::to_string(entries)?)?;
+        std::fs::rename(&temp, &self.path)?;
+
+        Ok(())
+    }
+}
+
+impl Backend for JsonFile {
+    fn get(&self, key: &str) -> anyhow::Result<Option<String>> {
+        let _guard = self.lock.lock().unwrap();
+
+        Ok(self.read()?.remove(key))
+    }
+
+    fn set(&self, key: &str, value: &str) -> anyhow::Result<()> {
+        let _guard = self.lock.lock().unwrap();
+
+        let mut entries = self.read()?;
+        entries.insert(key.to_owned(), value.to_owned());
+
+        self.write(&entries)
+    }
+
+    fn remove(&self, key: &str) -> anyhow::Result<()> {
+        let _guard = self.lock.lock().unwrap();
+
+        let mut entries = self.read()?;
+        if entries.remove(key).is_none() {
+            return Ok(());
+        }
+
+        self.write(&entries)
+    }
+
+    fn entries(&self, prefix: &str) -> anyhow::Result<Vec<(String, String)>> {
+        let _guard = self.lock.lock().unwrap();
+
+        Ok(self
+            .read()?
+            .into_iter()
+            .filter(|(key, _)| key.starts_with(prefix))
+            .collect())
+    }
+
+    fn remove_prefix(&self, prefix: &str) -> anyhow::Result<()> {
+        let _guard = self.lock.lock().unwrap();
+
+        let mut entries = self.read()?;
+        let len = entries.len();
+        entries.retain(|key, _| !key.starts_with(prefix));
+        if entries.len() == len {
+            return Ok(());
+        }
+
+        self.write(&entries)
+    }
+
+    fn claim(&self, key: &str, owner: &str) -> anyhow::Result<bool> {
+        Ok(self.claims.claim(key, owner, now()))
+    }
+}
+
+/// Nothing survives a restart, for tests and throwaway instances.
+#[derive(Default)]
+pub struct Memory {
+    entries: Mutex<HashMap<String, String>>,
+    claims: Claims,
+}
+
+impl Backend for Memory {
+    fn get(&self, key: &str) -> anyhow::Result<Option<String>> {
+        Ok(self.entries.lock().unwrap().get(key).cloned())
+    }
+
+    fn set(&self, key: &str, value: &str) -> anyhow::Result<()> {
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(key.to_owned(), value.to_owned());
+
+        Ok(())
+    }
+
+    fn remove(&self, key: &str) -> anyhow::Result<()> {
+        self.entries.lock().unwrap().remove(key);
+
+        Ok(())
+    }
+
+    fn entries(&self, prefix: &str) -> anyhow::Result<Vec<(String, String)>> {
+        Ok(self
+            .entries
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(key, _)| key.starts_with(prefix))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect())
+    }
+
+    fn remove_prefix(&self, prefix: &str) -> anyhow::Result<()> {
+        self.entries
+            .lock()
+            .unwrap()
+            .retain(|key, _| !key.starts_with(prefix));
+
+        Ok(())
+    }
+
+    fn claim(&self, key: &str, owner: &str) -> anyhow::Result<bool> {
+        Ok(self.claims.claim(key, owner, now()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn claims_expire() {
+        let claims = Claims::default();
+
+        assert!(claims.claim("$event", "a", 1000));
+        assert!(claims.claim("$old", "a", 1000));
+        assert!(!claims.claim("$event", "b", 1000 + CLAIM_TTL));
+
+        assert!(claims.claim("$event", "b", 1001 + CLAIM_TTL));
+        assert_eq!(claims.0.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn shares_the_queue() {
+        let sqlite = Sqlite::open(Path::new(":memory:")).unwrap();
+        let join = |owner, user, favourite| sqlite.join(owner, user, favourite, 2, 60).unwrap();
+
+        assert!(join("a:0", "@alice", false).is_ok());
+        assert!(matches!(join("b:0", "@alice", false), Err(Rejected::Busy)));
+        assert!(join("b:0", "@bob", false).is_ok());
+        assert!(matches!(join("b:1", "@carol", false), Err(Rejected::Full)));
+
+        // One at a time, in order.
+        assert!(!sqlite.start("b:0", "@bob", false, 1, 60).unwrap());
+        assert!(sqlite.start("a:0", "@alice", false, 1, 60).unwrap());
+        assert!(sqlite.start("a:0", "@alice", false, 1, 60).unwrap());
+        assert_eq!(sqlite.ahead("b:0", 1).unwrap(), Some(0));
+
+        // Favourites skip ahead of the others.
+        assert!(join("b:1", "@carol", true).is_ok());
+        assert_eq!(sqlite.ahead("b:0", 1).unwrap(), Some(1));
+        sqlite.leave("a:0").unwrap();
+        assert!(!sqlite.start("b:0", "@bob", false, 1, 60).unwrap());
+        assert!(sqlite.start("b:1", "@carol", true, 1, 60).unwrap());
+    }
+
+    #[test]
+    fn shares_the_render_cache() {
+        let sqlite = Sqlite::open(Path::new(":memory:")).unwrap();
+        for key in ["a", "b", "c"] {
+            sqlite.cache(key, key.as_bytes(), 2).unwrap();
+        }
+
+        assert_eq!(sqlite.cached("a", 60).unwrap(), None);
+        assert_eq!(sqlite.cached("c", 60).unwrap().as_deref(), Some(&b"c"[..]));
+    }
+}
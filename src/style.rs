@@ -0,0 +1,92 @@
+use serde::{Deserialize, Serialize};
+
+use crate::config;
+
+/// The largest margin and corner radius, in points.
+pub const MAX_POINTS: u32 = 200;
+
+/// How far the drop shadow is offset, in points.
+const SHADOW_OFFSET: u32 = 8;
+
+/// The text size of one-liners, in points.
+pub const MAX_TEXT_SIZE: u32 = 44;
+
+/// The text size long sources shrink down to, in points.
+const MIN_TEXT_SIZE: u32 = 12;
+
+/// About how many characters fit on a line before it wraps.
+const LINE_LENGTH: usize = 60;
+
+/// The base text size for `source` when `--size` doesn't give one.
+///
+/// One-liners get [`MAX_TEXT_SIZE`] and the size shrinks with the square root
+/// of the line count, wrapped lines included, so long snippets don't turn
+/// into huge images.
+pub fn text_size(source: &str) -> u32 {
+    let lines: usize = source
+        .trim()
+        .lines()
+        .map(|line| line.chars().count().div_ceil(LINE_LENGTH).max(1))
+        .sum();
+
+    ((MAX_TEXT_SIZE as f64 / (lines.max(1) as f64).sqrt()).round() as u32)
+        .clamp(MIN_TEXT_SIZE, MAX_TEXT_SIZE)
+}
+
+/// The card renders are drawn on.
+///
+/// Set per request with `--margin`, `--radius` and `--shadow`/`--no-shadow`,
+/// falling back to `style.margin` and `style.radius` (in points, 28 and 0 by
+/// default) and `style.shadow`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Style {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub margin: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub radius: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shadow: Option<bool>,
+}
+
+impl Style {
+    pub fn margin(&self) -> u32 {
+        self.margin.unwrap_or_else(|| config::get().style.margin)
+    }
+
+    pub fn radius(&self) -> u32 {
+        self.radius.unwrap_or_else(|| config::get().style.radius)
+    }
+
+    pub fn shadow(&self) -> bool {
+        self.shadow.unwrap_or_else(|| config::get().style.shadow)
+    }
+
+    /// A single line of typst drawing the card with the `base` color behind
+    /// the page, or nothing if it's a plain page.
+    pub fn card(&self, base: &str) -> String {
+        let (radius, shadow) = (self.radius(), self.shadow());
+        if radius == 0 && !shadow {
+            return String::new();
+        }
+
+        let offset = if shadow { SHADOW_OFFSET } else { 0 };
+        let rect = |fill: &str| {
+            format!("rect(width: 100%, height: 100%, radius: {radius}pt, fill: {fill})")
+        };
+        let shadow = if shadow {
+            format!(
+                "place(dx: {offset}pt, dy: {offset}pt, {}); ",
+                rect("luma(0).transparentize(60%)")
+            )
+        } else {
+            String::new()
+        };
+
+        format!(
+            "#set page(fill: none, background: block(width: 100%, height: 100%, \
+             inset: (right: {offset}pt, bottom: {offset}pt), {{ {shadow}{} }}));",
+            rect(&format!("rgb(\"#{base}\")"))
+        )
+    }
+}
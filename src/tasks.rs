@@ -0,0 +1,127 @@
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, Mutex},
+    time::Duration,
+};
+
+use tokio::task::{AbortHandle, Id, JoinError, JoinSet};
+
+use crate::{admin, config};
+
+/// The tasks spawned by event handlers and at startup, named for the logs.
+///
+/// At most `limits.max_tasks` (1024 by default) run at once, past that new ones are
+/// dropped. Panics are logged as the tasks are reaped, and [`shutdown`]
+/// lets the ones answering commands finish and cancels the rest.
+struct Supervisor {
+    set: JoinSet<()>,
+    names: HashMap<Id, (&'static str, AbortHandle)>,
+}
+
+/// The tasks answering commands, which are given time to finish on shutdown.
+const REQUESTS: &[&str] = &["command", "conversion"];
+
+static TASKS: LazyLock<Mutex<Supervisor>> = LazyLock::new(|| {
+    Mutex::new(Supervisor {
+        set: JoinSet::new(),
+        names: HashMap::new(),
+    })
+});
+
+impl Supervisor {
+    /// Forget the tasks that are done, logging the ones that panicked.
+    fn reap(&mut self) {
+        while let Some(result) = self.set.try_join_next_with_id() {
+            let id = match &result {
+                Ok((id, ())) => *id,
+                Err(err) => err.id(),
+            };
+            let name = self.names.remove(&id).map_or("task", |(name, _)| name);
+
+            if let Err(err) = result {
+                log(name, err);
+            }
+        }
+    }
+}
+
+fn log(name: &str, err: JoinError) {
+    if !err.is_panic() {
+        return;
+    }
+
+    let payload = err.into_panic();
+    let message = payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("no message");
+
+    eprintln!("The {name} task panicked: {message}");
+    admin::report(format!("The {name} task panicked: {message}"));
+}
+
+/// Run `future` in the background as `name`, returning whether there was
+/// room for it.
+pub fn spawn<F>(name: &'static str, future: F) -> bool
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let mut tasks = TASKS.lock().unwrap();
+    tasks.reap();
+
+    let limit = config::get().limits.max_tasks;
+    if tasks.set.len() >= limit {
+        eprintln!("Dropped a {name} task, {limit} are already running");
+        return false;
+    }
+
+    let handle = tasks.set.spawn(future);
+    tasks.names.insert(handle.id(), (name, handle));
+
+    true
+}
+
+/// Give the tasks answering commands up to `grace` to finish, cancel every
+/// other task and wait for them all to stop.
+pub async fn shutdown(grace: Duration) {
+    let (mut set, mut names) = {
+        let mut tasks = TASKS.lock().unwrap();
+        (
+            std::mem::take(&mut tasks.set),
+            std::mem::take(&mut tasks.names),
+        )
+    };
+
+    let mut requests = 0;
+    for (name, handle) in names.values() {
+        if REQUESTS.contains(name) {
+            requests += 1;
+        } else {
+            handle.abort();
+        }
+    }
+    println!(
+        "Waiting up to {}s for {requests} request(s) to finish…",
+        grace.as_secs()
+    );
+
+    if tokio::time::timeout(grace, reap(&mut set, &mut names))
+        .await
+        .is_err()
+    {
+        println!("Cancelling the requests that didn't finish in time");
+        set.abort_all();
+        reap(&mut set, &mut names).await;
+    }
+}
+
+/// Wait for every task in `set` to stop, logging the ones that panicked.
+async fn reap(set: &mut JoinSet<()>, names: &mut HashMap<Id, (&'static str, AbortHandle)>) {
+    while let Some(result) = set.join_next_with_id().await {
+        if let Err(err) = result {
+            let name = names.remove(&err.id()).map_or("task", |(name, _)| name);
+            log(name, err);
+        }
+    }
+}
@@ -0,0 +1,262 @@
+use std::{collections::HashMap, path::Path, sync::OnceLock};
+
+use serde::Deserialize;
+
+use crate::config;
+
+/// The text of the standard replies, in one language.
+///
+/// Operators can override any of the English ones with a JSON object in
+/// `templates.file`, and add or override languages with `<language>.json`
+/// files in `templates.locales_dir`. What a language leaves out is in English.
+/// Templates can use `{limit}` (the relevant limit), `{prefix}` (the main
+/// command) and `{contact}` (`templates.contact`). The timeout templates can
+/// also use `{stage}`, the stage that ran out of time, and the rate limit ones
+/// have the seconds to wait as `{limit}`.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct Templates {
+    pub timeout: String,
+    pub stage_timeout: String,
+    pub calc_timeout: String,
+    pub check_timeout: String,
+    pub empty_source: String,
+    pub empty_expression: String,
+    pub dm_notice: String,
+    pub images_forbidden: String,
+    pub source_too_long: String,
+    pub output_too_large: String,
+    pub internal_error: String,
+    pub power_required: String,
+    /// `{limit}` is in minutes.
+    pub cooldown: String,
+    pub user_rate_limited: String,
+    pub room_rate_limited: String,
+    pub no_problems: String,
+    /// `{link}` is the reply with the render.
+    pub duplicate: String,
+    /// Confirms `,typ language`, in the language picked.
+    pub language_set: String,
+    /// The first line of `,typ help`.
+    pub help_header: String,
+    pub help_commands: String,
+    pub help_flags: String,
+    /// `{default}` is the room's flavor.
+    pub help_flavors: String,
+    pub help_limits: String,
+    pub queued: String,
+    /// `{ahead}` is how many renders are ahead.
+    pub queued_behind: String,
+    /// Shown while a render takes a while.
+    pub compiling: String,
+    pub queue_busy: String,
+    pub queue_full: String,
+    pub restarting: String,
+    pub src_usage: String,
+    pub src_unknown: String,
+    pub src_failed: String,
+    pub not_text: String,
+    pub reply_unavailable: String,
+    pub output_attached: String,
+    pub history_usage: String,
+    /// How a switched on setting reads in `setting` and `setting_changed`.
+    pub on: String,
+    pub off: String,
+    /// The names of the settings, for `{setting}`.
+    pub quiet_mode: String,
+    pub safe_mode: String,
+    pub auto_math: String,
+    pub mentions: String,
+    /// `{setting}` is the setting, `{state}` `on` or `off`.
+    pub setting: String,
+    pub setting_changed: String,
+    /// `{command}` is the subcommand.
+    pub setting_usage: String,
+    pub moderators_only: String,
+    pub auto_math_on: String,
+    pub mentions_on: String,
+    pub enabled: String,
+    pub disabled: String,
+    /// `{limit}` is the power level.
+    pub power: String,
+    pub power_set: String,
+    pub power_off: String,
+    pub power_off_set: String,
+    pub power_usage: String,
+    /// What the commands and flags in `,typ help` do, by their usage like
+    /// `,calc <expression>` or `--pdf`. The ones missing are in English.
+    pub help: HashMap<String, String>,
+}
+
+impl Default for Templates {
+    fn default() -> Self {
+        Self {
+            timeout: "Your code took too long (>{limit}s) to render".into(),
+            stage_timeout: "Rendering took too long, the {stage} stage ran out of time (>{limit}s)"
+                .into(),
+            calc_timeout: "Your expression took too long (>{limit}s) to calculate".into(),
+            check_timeout: "Your code took too long (>{limit}s) to check".into(),
+            empty_source: "<text> is needed to typeset".into(),
+            empty_expression: "<expression> is needed to calculate".into(),
+            dm_notice: "Sent you the render in a direct message".into(),
+            images_forbidden: "This room doesn't allow me to post renders".into(),
+            source_too_long: "Your code is too long to render (>{limit} bytes)".into(),
+            output_too_large: "The render is too large for the homeserver (>{limit} bytes)".into(),
+            internal_error: "Something went wrong on my side, please try again later".into(),
+            power_required: "Commands here are limited to members with a higher power level".into(),
+            cooldown: "You keep sending the same failing source, try again in {limit} minute(s)"
+                .into(),
+            user_rate_limited: "You're rendering a lot, please try again in {limit} second(s)"
+                .into(),
+            room_rate_limited:
+                "This room is rendering a lot, please try again in {limit} second(s)".into(),
+            no_problems: "No errors or warnings".into(),
+            duplicate: "You sent this a moment ago, here's the render: {link}".into(),
+            language_set: "Replies here are in English now".into(),
+            help_header: "Send `{prefix} <code>` to render Typst, or one of these:".into(),
+            help_commands: "Commands:".into(),
+            help_flags: "Flags, before the source:".into(),
+            help_flavors: "Flavors ({default} here):".into(),
+            help_limits: "Limits:".into(),
+            queued: "Your render is queued, it starts as soon as another one finishes".into(),
+            queued_behind: "Your render is queued, {ahead} render(s) ahead of it".into(),
+            compiling: "Compiling…".into(),
+            queue_busy: "You already have a render in progress, please wait for it".into(),
+            queue_full: "Too many renders are queued, please try again in a bit".into(),
+            restarting: "I'm restarting, please send it again in a minute".into(),
+            src_usage: "Reply `,src` to one of my renders".into(),
+            src_unknown: "I don't know the source of that".into(),
+            src_failed: "Couldn't look up the source of that".into(),
+            not_text: "I can only render text messages".into(),
+            reply_unavailable: "Couldn't fetch the message you replied to".into(),
+            output_attached: "The compiler output is too long to post, it's attached.".into(),
+            history_usage: "Usage: `{prefix} history [<n> [flags]]`".into(),
+            on: "on".into(),
+            off: "off".into(),
+            quiet_mode: "Quiet mode".into(),
+            safe_mode: "Safe mode".into(),
+            auto_math: "Automatic math".into(),
+            mentions: "Mentioning me".into(),
+            setting: "{setting} is {state}".into(),
+            setting_changed: "{setting} is now {state}".into(),
+            setting_usage: "Usage: `{prefix} {command} on|off`".into(),
+            moderators_only: "Only moderators can change that".into(),
+            auto_math_on: "I now render the `$...$` math of every message here".into(),
+            mentions_on: "Mentioning me now works like `{prefix}`, e.g. `@typit: $x^2$`".into(),
+            enabled: "I'm back on in this room".into(),
+            disabled: "I'm off in this room, `{prefix} enable` switches me back on".into(),
+            power: "Commands need power level {limit} here".into(),
+            power_set: "Commands now need power level {limit} here".into(),
+            power_off: "Everyone can use commands here".into(),
+            power_off_set: "Everyone can use commands here now".into(),
+            power_usage: "Usage: `{prefix} power <level>|off`, e.g. `{prefix} power 50`".into(),
+            help: HashMap::new(),
+        }
+    }
+}
+
+/// The languages that come with the bot, besides English.
+const BUNDLED: &[(&str, &str)] = &[
+    ("de", include_str!("../locales/de.json")),
+    ("es", include_str!("../locales/es.json")),
+    ("fr", include_str!("../locales/fr.json")),
+];
+
+/// The templates of every language, by language code.
+static TEMPLATES: OnceLock<HashMap<String, Templates>> = OnceLock::new();
+
+tokio::task_local! {
+    /// The language of the room the current command came from.
+    static LANGUAGE: Option<String>;
+}
+
+/// Read the English templates from `templates.file` and the other languages
+/// from the bundled ones and `templates.locales_dir`.
+pub fn load() -> anyhow::Result<()> {
+    let config = config::get();
+    let english = match &config.templates.file {
+        Some(path) => read(path)?,
+        None => Templates::default(),
+    };
+
+    let mut templates = HashMap::from([("en".to_owned(), english)]);
+    for (language, json) in BUNDLED {
+        templates.insert((*language).to_owned(), serde_json::from_str(json)?);
+    }
+    if let Some(dir) = &config.templates.locales_dir {
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path
+                .extension()
+                .is_some_and(|extension| extension == "json")
+                && let Some(language) = path.file_stem().and_then(|stem| stem.to_str())
+            {
+                templates.insert(language.to_owned(), read(&path)?);
+            }
+        }
+    }
+
+    let default = &config.commands.language;
+    if !templates.contains_key(default) {
+        anyhow::bail!("`commands.language` is `{default}`, which has no templates");
+    }
+
+    let _ = TEMPLATES.set(templates);
+
+    Ok(())
+}
+
+fn read(path: &Path) -> anyhow::Result<Templates> {
+    let json = std::fs::read_to_string(path)?;
+    serde_json::from_str(&json)
+        .map_err(|err| anyhow::anyhow!("Invalid templates in {}: {err}", path.display()))
+}
+
+/// The templates of `language`, or of the deployment's default language if
+/// it's `None` or has none.
+pub fn of(language: Option<&str>) -> &'static Templates {
+    let templates = TEMPLATES.get_or_init(|| HashMap::from([("en".into(), Templates::default())]));
+
+    language
+        .and_then(|language| templates.get(language))
+        .or_else(|| templates.get(&config::get().commands.language))
+        .or_else(|| templates.get("en"))
+        .expect("the English templates are always loaded")
+}
+
+/// The templates of the language the current command is answered in, see
+/// [`scope`].
+pub fn get() -> &'static Templates {
+    let language = LANGUAGE.try_with(Clone::clone).ok().flatten();
+    of(language.as_deref())
+}
+
+/// Run `f` answering in `language`, so [`get`] picks its templates from
+/// anywhere down the call stack.
+pub async fn scope<F: Future>(language: Option<String>, f: F) -> F::Output {
+    LANGUAGE.scope(language, f).await
+}
+
+/// The languages there are templates for, sorted.
+pub fn languages() -> Vec<&'static str> {
+    let mut languages: Vec<_> = TEMPLATES
+        .get()
+        .into_iter()
+        .flat_map(|templates| templates.keys().map(String::as_str))
+        .collect();
+    languages.sort_unstable();
+
+    languages
+}
+
+/// Fill in the variables of `template`.
+pub fn fill(template: &str) -> String {
+    template
+        .replace("{prefix}", &format!("{}typ", config::get().commands.prefix))
+        .replace("{contact}", &config::get().templates.contact)
+}
+
+/// Fill in the variables of `template`, `{limit}` being `limit`.
+pub fn fill_limit(template: &str, limit: u64) -> String {
+    fill(&template.replace("{limit}", &limit.to_string()))
+}
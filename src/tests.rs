@@ -0,0 +1,998 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use matrix_sdk::{
+    Client, HttpError, Room, RumaApiError, SessionMeta,
+    authentication::{SessionTokens, matrix::MatrixSession},
+    config::SyncSettings,
+    event_handler::Ctx,
+    ruma::{
+        EventId, MxcUri, OwnedMxcUri, RoomId, TransactionId, UserId,
+        api::{
+            MatrixVersion,
+            client::{
+                self,
+                error::{ErrorBody, ErrorKind, RetryAfter, StandardErrorBody},
+                message::send_message_event,
+            },
+            error::FromHttpResponseError,
+        },
+        events::room::message::OriginalSyncRoomMessageEvent,
+        exports::http::StatusCode,
+        owned_event_id, owned_mxc_uri,
+    },
+};
+use mime::Mime;
+use serde_json::{Value, json};
+use wiremock::{
+    Mock, MockServer, Request, ResponseTemplate,
+    matchers::{method, path, path_regex},
+};
+
+use crate::{
+    cache,
+    config::{self, Config},
+    matrix::{Media, Messages},
+    message,
+    options::{Flavor, Format, RenderOptions},
+    outbox, progress,
+    queue::{self, Permit, Ticket},
+    ratelimit,
+    render::Output,
+    sandbox,
+    state::{RenderRecord, Store},
+    storage::{Backend, Memory, Sqlite},
+    templates,
+    trace::RequestId,
+    upload,
+};
+
+const BOT: &str = "@typit:localhost";
+const SENDER: &str = "@alice:localhost";
+
+/// How long to wait for replies before giving up.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A homeserver with the bot and [`SENDER`] joined to one room.
+struct Homeserver {
+    server: MockServer,
+    client: Client,
+    room: Room,
+    store: Arc<Store>,
+}
+
+impl Homeserver {
+    /// Start a fake homeserver for `room` and sync the bot with it.
+    async fn start(room: &str) -> Self {
+        let server = MockServer::start().await;
+
+        Mock::given(method("GET"))
+            .and(path("/_matrix/client/v3/sync"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(sync(room)))
+            .mount(&server)
+            .await;
+        Mock::given(method("PUT"))
+            .and(path_regex(
+                r"^/_matrix/client/v3/rooms/.+/send/m\.room\.message/.+$",
+            ))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "event_id": "$reply" })))
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path_regex(r"^/_matrix/media/v3/upload$"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(json!({ "content_uri": "mxc://localhost/render" })),
+            )
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path_regex(r"^/_matrix/client/v3/rooms/.+/state/.+$"))
+            .respond_with(
+                ResponseTemplate::new(404).set_body_json(
+                    json!({ "errcode": "M_NOT_FOUND", "error": "Event not found." }),
+                ),
+            )
+            .mount(&server)
+            .await;
+        // Receipts, typing notices, key uploads and the like.
+        Mock::given(wiremock::matchers::any())
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({})))
+            .with_priority(10)
+            .mount(&server)
+            .await;
+
+        let client = Client::builder()
+            .homeserver_url(server.uri())
+            .server_versions([MatrixVersion::V1_12])
+            .build()
+            .await
+            .unwrap();
+        client
+            .restore_session(MatrixSession {
+                meta: SessionMeta {
+                    user_id: BOT.try_into().unwrap(),
+                    device_id: "TESTS".into(),
+                },
+                tokens: SessionTokens {
+                    access_token: "token".into(),
+                    refresh_token: None,
+                },
+            })
+            .await
+            .unwrap();
+        client.sync_once(SyncSettings::default()).await.unwrap();
+
+        let room = client
+            .get_room(<&RoomId>::try_from(room).unwrap())
+            .expect("the sync joined the room");
+        let store = Arc::new(Store::load(Box::new(Memory::default())).unwrap());
+
+        Self {
+            server,
+            client,
+            room,
+            store,
+        }
+    }
+
+    /// Have [`SENDER`] send `body` to the room.
+    async fn send(&self, body: &str) {
+        self.send_event(text(body)).await;
+    }
+
+    /// Have `event` sent to the room.
+    async fn send_event(&self, event: OriginalSyncRoomMessageEvent) {
+        message::on_room_message(
+            event,
+            self.room.clone(),
+            self.client.clone(),
+            Ctx(self.store.clone()),
+        )
+        .await;
+    }
+
+    /// The contents of the messages the bot sent, once there are `count`.
+    async fn replies(&self, count: usize) -> Vec<Value> {
+        let started = Instant::now();
+
+        loop {
+            let replies = self.sent().await;
+            if replies.len() >= count {
+                return replies;
+            }
+            assert!(
+                started.elapsed() < TIMEOUT,
+                "expected {count} replies, got {replies:?}"
+            );
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    }
+
+    /// The contents of the messages the bot sent so far.
+    async fn sent(&self) -> Vec<Value> {
+        self.server
+            .received_requests()
+            .await
+            .unwrap_or_default()
+            .iter()
+            .filter(|request| is_message(request))
+            .map(|request| request.body_json().unwrap())
+            .collect()
+    }
+
+    /// Wait for the bot to redact an event.
+    async fn redacted(&self) {
+        let started = Instant::now();
+
+        while !self
+            .server
+            .received_requests()
+            .await
+            .unwrap_or_default()
+            .iter()
+            .any(|request| request.url.path().contains("/redact/"))
+        {
+            assert!(started.elapsed() < TIMEOUT, "nothing was redacted");
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    }
+}
+
+/// Held by the tests that render, which share the queue and may change the
+/// config. The config goes back to the defaults on drop.
+struct Exclusive {
+    _guard: tokio::sync::MutexGuard<'static, ()>,
+}
+
+impl Exclusive {
+    async fn with(config: Config) -> Self {
+        static RENDERS: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+        let _guard = RENDERS.lock().await;
+        config::set(config);
+        Self { _guard }
+    }
+}
+
+impl Drop for Exclusive {
+    fn drop(&mut self) {
+        config::set(Config::default());
+    }
+}
+
+/// A config rendering one at a time, with room for `queue` more to wait.
+fn one_at_a_time(queue: usize) -> Config {
+    Config {
+        render: config::Render {
+            concurrency: Some(1),
+            queue,
+            ..Default::default()
+        },
+        ..Default::default()
+    }
+}
+
+/// Take the only render slot, for `user`.
+async fn take_slot(user: &str) -> (Ticket, Permit) {
+    let Ok(mut ticket) = queue::join(<&UserId>::try_from(user).unwrap(), false).await else {
+        panic!("the queue is taken");
+    };
+    let permit = ticket.wait().await.unwrap();
+
+    (ticket, permit)
+}
+
+/// The part of `template` before its first placeholder.
+fn fixed(template: &str) -> &str {
+    template.split('{').next().unwrap()
+}
+
+fn is_message(request: &Request) -> bool {
+    request.method == wiremock::http::Method::PUT
+        && request.url.path().contains("/send/m.room.message/")
+}
+
+/// A sync response joining the bot to `room`, with [`SENDER`] in it too.
+fn sync(room: &str) -> Value {
+    let member = |user: &str| {
+        json!({
+            "type": "m.room.member",
+            "state_key": user,
+            "sender": user,
+            "event_id": format!("$join-{user}"),
+            "origin_server_ts": 0,
+            "content": { "membership": "join" },
+        })
+    };
+
+    json!({
+        "next_batch": "s1",
+        "rooms": {
+            "join": {
+                room: {
+                    "state": {
+                        "events": [
+                            {
+                                "type": "m.room.create",
+                                "state_key": "",
+                                "sender": SENDER,
+                                "event_id": "$create",
+                                "origin_server_ts": 0,
+                                "content": { "room_version": "11" },
+                            },
+                            member(BOT),
+                            member(SENDER),
+                        ],
+                    },
+                    "timeline": { "events": [] },
+                },
+            },
+        },
+    })
+}
+
+/// A text message from [`SENDER`], sent just now.
+fn text(body: &str) -> OriginalSyncRoomMessageEvent {
+    message(SENDER, json!({ "msgtype": "m.text", "body": body }))
+}
+
+/// A message from `sender` with `content`, sent just now.
+fn message(sender: &str, content: Value) -> OriginalSyncRoomMessageEvent {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    serde_json::from_value(json!({
+        "type": "m.room.message",
+        "event_id": format!("$message-{}", COUNTER.fetch_add(1, Ordering::Relaxed)),
+        "sender": sender,
+        "origin_server_ts": now.as_millis() as u64,
+        "content": content,
+    }))
+    .unwrap()
+}
+
+fn body(reply: &Value) -> &str {
+    reply["body"].as_str().unwrap_or_default()
+}
+
+#[tokio::test]
+async fn answers_version() {
+    let homeserver = Homeserver::start("!version:localhost").await;
+
+    homeserver.send(",version").await;
+
+    let replies = homeserver.replies(1).await;
+    assert!(body(&replies[0]).starts_with("typit "), "{replies:?}");
+    assert_eq!(
+        replies[0]["m.relates_to"]["m.in_reply_to"]["event_id"]
+            .as_str()
+            .map(|event| event.starts_with("$message-")),
+        Some(true)
+    );
+}
+
+#[tokio::test]
+async fn answers_help() {
+    let homeserver = Homeserver::start("!help:localhost").await;
+
+    homeserver.send(",typ help").await;
+
+    let replies = homeserver.replies(1).await;
+    assert!(body(&replies[0]).contains(",typ check"), "{replies:?}");
+}
+
+#[tokio::test]
+async fn ignores_chat() {
+    let homeserver = Homeserver::start("!chat:localhost").await;
+
+    homeserver.send("just chatting").await;
+
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert_eq!(homeserver.sent().await, Vec::<Value>::new());
+}
+
+#[tokio::test]
+async fn renders() {
+    // Renders need the compiler, which not every machine running the tests
+    // has.
+    if sandbox::which("typst").is_none() {
+        eprintln!("Skipped, typst isn't installed");
+        return;
+    }
+    let _exclusive = Exclusive::with(Config::default()).await;
+    let homeserver = Homeserver::start("!render:localhost").await;
+
+    homeserver
+        .send_event(message(
+            "@renders:localhost",
+            json!({ "msgtype": "m.text", "body": ",typ $x^2$" }),
+        ))
+        .await;
+
+    let replies = homeserver.replies(1).await;
+    let image = replies
+        .iter()
+        .find(|reply| reply["msgtype"] == "m.image")
+        .unwrap_or_else(|| panic!("no image in {replies:?}"));
+    assert_eq!(image["url"], "mxc://localhost/render");
+}
+
+#[tokio::test]
+async fn reports_compile_errors() {
+    if sandbox::which("typst").is_none() {
+        eprintln!("Skipped, typst isn't installed");
+        return;
+    }
+    let _exclusive = Exclusive::with(Config::default()).await;
+    let homeserver = Homeserver::start("!error:localhost").await;
+
+    homeserver
+        .send_event(message(
+            "@error:localhost",
+            json!({ "msgtype": "m.text", "body": ",typ #nope" }),
+        ))
+        .await;
+
+    let replies = homeserver.replies(1).await;
+    assert!(
+        replies
+            .iter()
+            .any(|reply| body(reply).contains("unknown variable: nope")),
+        "{replies:?}"
+    );
+}
+
+#[tokio::test]
+async fn reports_timeouts() {
+    if sandbox::which("typst").is_none() {
+        eprintln!("Skipped, typst isn't installed");
+        return;
+    }
+    let _exclusive = Exclusive::with(Config {
+        timeouts: config::Timeouts {
+            compile: 1,
+            ..Default::default()
+        },
+        ..Default::default()
+    })
+    .await;
+    let homeserver = Homeserver::start("!timeout:localhost").await;
+
+    homeserver
+        .send_event(message(
+            "@timeout:localhost",
+            json!({ "msgtype": "m.text", "body": ",typ #for i in range(1000000000) {}" }),
+        ))
+        .await;
+
+    let replies = homeserver.replies(1).await;
+    let timeout = fixed(&templates::get().timeout);
+    assert!(
+        replies.iter().any(|reply| body(reply).starts_with(timeout)),
+        "{replies:?}"
+    );
+}
+
+#[tokio::test]
+async fn reports_rate_limits() {
+    let homeserver = Homeserver::start("!limited:localhost").await;
+    let user = <&UserId>::try_from("@limited:localhost").unwrap();
+    while ratelimit::take(&homeserver.store, user, homeserver.room.room_id())
+        .await
+        .unwrap()
+        .is_none()
+    {}
+
+    homeserver
+        .send_event(message(
+            user.as_str(),
+            json!({ "msgtype": "m.text", "body": ",typ $x$" }),
+        ))
+        .await;
+
+    let replies = homeserver.replies(1).await;
+    let limited = fixed(&templates::get().user_rate_limited);
+    assert!(body(&replies[0]).starts_with(limited), "{replies:?}");
+}
+
+#[tokio::test]
+async fn reports_a_full_queue() {
+    let _exclusive = Exclusive::with(one_at_a_time(1)).await;
+    let _running = take_slot("@running:localhost").await;
+    let Ok(_waiting) = queue::join(<&UserId>::try_from("@waiting:localhost").unwrap(), false).await
+    else {
+        panic!("the queue is full already");
+    };
+    let homeserver = Homeserver::start("!full:localhost").await;
+
+    homeserver
+        .send_event(message(
+            "@full:localhost",
+            json!({ "msgtype": "m.text", "body": ",typ $x$" }),
+        ))
+        .await;
+
+    let replies = homeserver.replies(1).await;
+    assert!(
+        body(&replies[0]).starts_with(&templates::get().queue_full),
+        "{replies:?}"
+    );
+}
+
+#[tokio::test]
+async fn rejects_long_matrix_sources() {
+    let homeserver = Homeserver::start("!long:localhost").await;
+    let source = "x".repeat(Config::default().limits.max_source + 1);
+
+    homeserver
+        .send_event(message(
+            "@long:localhost",
+            json!({ "msgtype": "m.text", "body": format!(",typ matrix --themes latte,mocha {source}") }),
+        ))
+        .await;
+
+    let replies = homeserver.replies(1).await;
+    let too_long = fixed(&templates::get().source_too_long);
+    assert!(body(&replies[0]).starts_with(too_long), "{replies:?}");
+}
+
+#[tokio::test]
+async fn converts_with_the_options_of_the_render() {
+    let _exclusive = Exclusive::with(Config::default()).await;
+    let homeserver = Homeserver::start("!convert:localhost").await;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    let render = json!({
+        "type": "m.room.message",
+        "event_id": "$render",
+        "room_id": "!convert:localhost",
+        "sender": BOT,
+        "origin_server_ts": now.as_millis() as u64,
+        "content": { "msgtype": "m.image", "body": "x^2", "url": "mxc://localhost/render" },
+    });
+    Mock::given(method("GET"))
+        .and(path_regex(r"^/_matrix/client/v3/rooms/.+/event/.+$"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(render))
+        .mount(&homeserver.server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/_matrix/client/v1/media/config"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "m.upload.size": 1 << 20 })))
+        .mount(&homeserver.server)
+        .await;
+
+    let options = RenderOptions {
+        math: true,
+        flavor: Some(Flavor::Latte),
+        ..Default::default()
+    };
+    let record = RenderRecord {
+        source: "x^2".into(),
+        options: options.clone(),
+    };
+    homeserver
+        .store
+        .record_render(
+            homeserver.room.room_id(),
+            <&EventId>::try_from("$render").unwrap(),
+            &record,
+        )
+        .await
+        .unwrap();
+    // Only a conversion keeping `--math` and the flavor finds the PDF, the
+    // compiler isn't needed.
+    let converted = RenderOptions {
+        format: Format::Pdf,
+        ..options
+    };
+    cache::insert(
+        cache::key("x^2", &converted, false),
+        &Output::Pages {
+            pages: vec![b"%PDF-1.7".to_vec()],
+            warnings: String::new(),
+        },
+    )
+    .await;
+
+    let reaction = serde_json::from_value(json!({
+        "type": "m.reaction",
+        "event_id": "$reaction",
+        "sender": "@converts:localhost",
+        "origin_server_ts": now.as_millis() as u64,
+        "content": {
+            "m.relates_to": { "rel_type": "m.annotation", "event_id": "$render", "key": "📄" },
+        },
+    }))
+    .unwrap();
+    message::on_reaction(
+        reaction,
+        homeserver.room.clone(),
+        homeserver.client.clone(),
+        Ctx(homeserver.store.clone()),
+    )
+    .await;
+
+    let replies = homeserver.replies(1).await;
+    assert_eq!(replies[0]["msgtype"], "m.file", "{replies:?}");
+}
+
+#[tokio::test]
+async fn answers_in_threads() {
+    let homeserver = Homeserver::start("!thread:localhost").await;
+
+    homeserver
+        .send_event(message(
+            SENDER,
+            json!({
+                "msgtype": "m.text",
+                "body": ",version",
+                "m.relates_to": {
+                    "rel_type": "m.thread",
+                    "event_id": "$root",
+                    "is_falling_back": true,
+                    "m.in_reply_to": { "event_id": "$root" },
+                },
+            }),
+        ))
+        .await;
+
+    let replies = homeserver.replies(1).await;
+    let relates_to = &replies[0]["m.relates_to"];
+    assert_eq!(relates_to["rel_type"], "m.thread", "{replies:?}");
+    assert_eq!(relates_to["event_id"], "$root");
+    assert_ne!(relates_to["m.in_reply_to"]["event_id"], "$root");
+}
+
+#[tokio::test]
+async fn answers_commands_sent_as_replies() {
+    let homeserver = Homeserver::start("!reply:localhost").await;
+    let command = message(
+        SENDER,
+        json!({
+            "msgtype": "m.text",
+            "body": "> <@bob:localhost> hello\n\n,version",
+            "m.relates_to": { "m.in_reply_to": { "event_id": "$earlier" } },
+        }),
+    );
+    let event_id = command.event_id.clone();
+
+    homeserver.send_event(command).await;
+
+    let replies = homeserver.replies(1).await;
+    assert!(body(&replies[0]).starts_with("typit "), "{replies:?}");
+    assert_eq!(
+        replies[0]["m.relates_to"]["m.in_reply_to"]["event_id"],
+        event_id.as_str()
+    );
+}
+
+#[tokio::test]
+async fn redacting_a_queued_command_cancels_it() {
+    let _exclusive = Exclusive::with(one_at_a_time(4)).await;
+    let _running = take_slot("@busy:localhost").await;
+    let homeserver = Homeserver::start("!redacted:localhost").await;
+    let command = message(
+        "@redacts:localhost",
+        json!({ "msgtype": "m.text", "body": ",typ $x$" }),
+    );
+    let event_id = command.event_id.clone();
+
+    homeserver.send_event(command).await;
+    let replies = homeserver.replies(1).await;
+    assert_eq!(body(&replies[0]), templates::get().queued, "{replies:?}");
+    assert_eq!(homeserver.store.queued().await.unwrap().len(), 1);
+
+    progress::on_redaction(
+        serde_json::from_value(json!({
+            "type": "m.room.redaction",
+            "event_id": "$redaction",
+            "sender": "@redacts:localhost",
+            "origin_server_ts": 0,
+            "redacts": event_id,
+            "content": { "redacts": event_id },
+        }))
+        .unwrap(),
+    )
+    .await;
+
+    // The placeholder goes with the request.
+    homeserver.redacted().await;
+    assert!(homeserver.store.queued().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn edits_the_placeholder_into_the_render() {
+    if sandbox::which("typst").is_none() {
+        eprintln!("Skipped, typst isn't installed");
+        return;
+    }
+    let _exclusive = Exclusive::with(one_at_a_time(4)).await;
+    let running = take_slot("@before:localhost").await;
+    let homeserver = Homeserver::start("!edited:localhost").await;
+
+    homeserver
+        .send_event(message(
+            "@edits:localhost",
+            json!({ "msgtype": "m.text", "body": ",typ $x$" }),
+        ))
+        .await;
+    let replies = homeserver.replies(1).await;
+    assert_eq!(body(&replies[0]), templates::get().queued, "{replies:?}");
+
+    drop(running);
+
+    let replies = homeserver.replies(2).await;
+    let edit = &replies[1];
+    assert_eq!(edit["m.relates_to"]["rel_type"], "m.replace", "{replies:?}");
+    assert_eq!(edit["m.relates_to"]["event_id"], "$reply");
+    assert_eq!(edit["m.new_content"]["msgtype"], "m.image");
+}
+
+#[tokio::test]
+async fn ignores_edited_commands() {
+    let homeserver = Homeserver::start("!edit:localhost").await;
+
+    homeserver
+        .send_event(message(
+            SENDER,
+            json!({
+                "msgtype": "m.text",
+                "body": "* ,version",
+                "m.new_content": { "msgtype": "m.text", "body": ",version" },
+                "m.relates_to": { "rel_type": "m.replace", "event_id": "$command" },
+            }),
+        ))
+        .await;
+
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert_eq!(homeserver.sent().await, Vec::<Value>::new());
+}
+
+/// A room that records what's sent to it.
+#[derive(Default)]
+struct FakeRoom {
+    sent: Mutex<Vec<Value>>,
+}
+
+impl Messages for FakeRoom {
+    fn id(&self) -> &RoomId {
+        <&RoomId>::try_from("!fake:localhost").unwrap()
+    }
+
+    async fn send_message(
+        &self,
+        content: Value,
+        _txn_id: &TransactionId,
+    ) -> matrix_sdk::Result<send_message_event::v3::Response> {
+        self.sent.lock().unwrap().push(content);
+
+        Ok(send_message_event::v3::Response::new(owned_event_id!(
+            "$sent"
+        )))
+    }
+}
+
+#[tokio::test]
+async fn outbox_sends_once() {
+    let room = FakeRoom::default();
+    let store = Store::load(Box::new(Memory::default())).unwrap();
+
+    let response = outbox::send(&room, &store, TransactionId::new(), json!({ "body": "hi" }))
+        .await
+        .unwrap();
+
+    assert_eq!(response.event_id, "$sent");
+    assert_eq!(*room.sent.lock().unwrap(), [json!({ "body": "hi" })]);
+    assert!(store.outbox().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn memory_store_keeps_state() {
+    let store = Store::load(Box::new(Memory::default())).unwrap();
+    let user = <&UserId>::try_from(SENDER).unwrap();
+
+    store
+        .update(|state| state.blocked.insert(user.to_owned()))
+        .await
+        .unwrap();
+    assert!(store.is_blocked(user).await);
+
+    let room = <&RoomId>::try_from("!room:localhost").unwrap();
+    let event = owned_event_id!("$command");
+    assert!(!store.answered(room, &event).await);
+    store.mark_answered(room, &event).await.unwrap();
+    assert!(store.answered(room, &event).await);
+}
+
+#[tokio::test]
+async fn shared_stores_see_each_others_changes() {
+    let _exclusive = Exclusive::with(Config {
+        storage: config::Storage {
+            instance_id: Some("tests".into()),
+            ..Default::default()
+        },
+        ..Default::default()
+    })
+    .await;
+    let path = std::env::temp_dir().join(format!("typit-shared-{}.sqlite3", std::process::id()));
+    let one = Store::load(Box::new(Sqlite::open(&path).unwrap())).unwrap();
+    let other = Store::load(Box::new(Sqlite::open(&path).unwrap())).unwrap();
+    let user = <&UserId>::try_from(SENDER).unwrap();
+
+    assert!(!other.is_blocked(user).await);
+    one.update(|state| state.blocked.insert(user.to_owned()))
+        .await
+        .unwrap();
+    assert!(other.is_blocked(user).await);
+    other
+        .update(|state| state.blocked.remove(user))
+        .await
+        .unwrap();
+    assert!(!one.is_blocked(user).await);
+
+    let _ = std::fs::remove_file(path);
+}
+
+#[tokio::test]
+async fn forgets_room_entries() {
+    let store = Store::load(Box::new(Memory::default())).unwrap();
+    let room = <&RoomId>::try_from("!room:localhost").unwrap();
+    let other = <&RoomId>::try_from("!other:localhost").unwrap();
+    let event = owned_event_id!("$command");
+
+    store.mark_answered(room, &event).await.unwrap();
+    store.mark_answered(other, &event).await.unwrap();
+    store.forget_room(room).await.unwrap();
+
+    assert!(!store.answered(room, &event).await);
+    assert!(store.answered(other, &event).await);
+    // Fresh entries outlive the retention.
+    assert_eq!(store.prune(30).await.unwrap(), 0);
+    assert!(store.answered(other, &event).await);
+}
+
+#[tokio::test]
+async fn keeps_hot_entries_under_keys_of_their_own() {
+    let store = Store::load(Box::new(Memory::default())).unwrap();
+    let user = <&UserId>::try_from(SENDER).unwrap();
+    let job = serde_json::from_value(json!({
+        "room": "!room:localhost",
+        "sender": SENDER,
+        "body": ",typ $x$",
+        "sent": 0,
+    }))
+    .unwrap();
+
+    store.set_cooldown(user, 4_000_000_000).await.unwrap();
+    store
+        .queue(<&EventId>::try_from("$command").unwrap(), &job)
+        .await
+        .unwrap();
+
+    assert_eq!(store.cooldown(user).await.unwrap(), Some(4_000_000_000));
+    let queued = store.queued().await.unwrap();
+    assert_eq!(queued.len(), 1);
+    assert_eq!(queued[0].0, "$command");
+
+    store
+        .forget_room(<&RoomId>::try_from("!room:localhost").unwrap())
+        .await
+        .unwrap();
+    assert!(store.queued().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn memory_store_claims_once() {
+    let store = Store::load(Box::new(Memory::default())).unwrap();
+
+    assert!(store.claim("$event").await.unwrap());
+    assert!(!store.claim("$event").await.unwrap());
+    assert!(store.claim("$other").await.unwrap());
+}
+
+#[test]
+fn memory_backend_claims_once() {
+    let memory = Memory::default();
+
+    assert!(memory.claim("$event", "a").unwrap());
+    assert!(!memory.claim("$event", "b").unwrap());
+    // The holder keeps its claim.
+    assert!(memory.claim("$event", "a").unwrap());
+    assert!(memory.claim("$other", "b").unwrap());
+}
+
+/// A media repository answering requests with the errors it's given, in
+/// order. `None` and anything past the end go through.
+#[derive(Default)]
+struct FakeMedia {
+    errors: Mutex<VecDeque<Option<matrix_sdk::Error>>>,
+    requests: Mutex<Vec<String>>,
+}
+
+impl FakeMedia {
+    fn new(errors: impl IntoIterator<Item = Option<matrix_sdk::Error>>) -> Self {
+        Self {
+            errors: Mutex::new(errors.into_iter().collect()),
+            requests: Mutex::default(),
+        }
+    }
+
+    fn request(&self, request: String) -> matrix_sdk::Result<()> {
+        self.requests.lock().unwrap().push(request);
+
+        match self.errors.lock().unwrap().pop_front().flatten() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Media for FakeMedia {
+    async fn upload_media(
+        &self,
+        _content_type: &Mime,
+        _data: Vec<u8>,
+    ) -> matrix_sdk::Result<OwnedMxcUri> {
+        self.request("upload".into())?;
+        Ok(owned_mxc_uri!("mxc://localhost/direct"))
+    }
+
+    async fn reserve_uri(&self) -> matrix_sdk::Result<OwnedMxcUri> {
+        self.request("reserve".into())?;
+        Ok(owned_mxc_uri!("mxc://localhost/reserved"))
+    }
+
+    async fn upload_reserved(
+        &self,
+        uri: &MxcUri,
+        _content_type: &Mime,
+        _data: Vec<u8>,
+    ) -> matrix_sdk::Result<()> {
+        self.request(format!("upload to {uri}"))
+    }
+}
+
+/// The homeserver answering with `kind`.
+fn api_error(status: StatusCode, kind: ErrorKind) -> Option<matrix_sdk::Error> {
+    let err = client::Error::new(
+        status,
+        ErrorBody::Standard(StandardErrorBody::new(kind, String::new())),
+    );
+
+    Some(HttpError::from(FromHttpResponseError::Server(RumaApiError::ClientApi(err))).into())
+}
+
+/// A rate limit that's over right away.
+fn rate_limited() -> Option<matrix_sdk::Error> {
+    api_error(
+        StatusCode::TOO_MANY_REQUESTS,
+        ErrorKind::LimitExceeded {
+            retry_after: Some(RetryAfter::Delay(Duration::ZERO)),
+        },
+    )
+}
+
+/// Big enough to upload to a reserved URI.
+const LARGE: usize = 1024 * 1024;
+
+async fn upload(media: &FakeMedia, size: usize) -> anyhow::Result<OwnedMxcUri> {
+    upload::upload_with_retry(media, &mime::IMAGE_PNG, vec![0; size], RequestId::new()).await
+}
+
+#[tokio::test]
+async fn uploads_small_outputs_directly() {
+    let media = FakeMedia::new([rate_limited()]);
+
+    assert_eq!(upload(&media, 16).await.unwrap(), "mxc://localhost/direct");
+    assert_eq!(*media.requests.lock().unwrap(), ["upload", "upload"]);
+}
+
+#[tokio::test]
+async fn retries_large_uploads_to_the_same_uri() {
+    let media = FakeMedia::new([None, rate_limited()]);
+
+    assert_eq!(
+        upload(&media, LARGE).await.unwrap(),
+        "mxc://localhost/reserved"
+    );
+    assert_eq!(
+        *media.requests.lock().unwrap(),
+        [
+            "reserve",
+            "upload to mxc://localhost/reserved",
+            "upload to mxc://localhost/reserved"
+        ]
+    );
+}
+
+#[tokio::test]
+async fn takes_a_lost_upload_response_for_a_success() {
+    let media = FakeMedia::new([
+        None,
+        rate_limited(),
+        api_error(StatusCode::CONFLICT, ErrorKind::CannotOverwriteMedia),
+    ]);
+
+    assert_eq!(
+        upload(&media, LARGE).await.unwrap(),
+        "mxc://localhost/reserved"
+    );
+    assert_eq!(media.requests.lock().unwrap().len(), 3);
+}
+
+#[tokio::test]
+async fn uploads_directly_without_async_uploads() {
+    let media = FakeMedia::new([api_error(StatusCode::NOT_FOUND, ErrorKind::Unrecognized)]);
+
+    assert_eq!(
+        upload(&media, LARGE).await.unwrap(),
+        "mxc://localhost/direct"
+    );
+    assert_eq!(*media.requests.lock().unwrap(), ["reserve", "upload"]);
+}
@@ -0,0 +1,151 @@
+use std::collections::HashMap;
+
+use matrix_sdk::{
+    Room,
+    ruma::{EventId, UserId},
+};
+
+use crate::{options::Flavor, policy, state::Store, trace::RequestId};
+
+const USAGE: &str = "Usage: `,typ theme <flavor>`, `,typ theme vote <flavor>`, `,typ theme \
+                     votes` or `,typ theme apply`, with latte, frappe, macchiato or mocha";
+
+/// Handle `,typ theme …` sent as `event`, returning the reply, if there's one
+/// besides a reaction, or the text of an error reply.
+///
+/// - `,typ theme <flavor>` sets the room default, for moderators
+/// - `,typ theme vote <flavor>` records the sender's vote, acknowledged with a
+///   reaction unless quiet mode forbids them
+/// - `,typ theme votes` shows the tally
+/// - `,typ theme apply` makes the winner the room default, for moderators
+pub async fn command(
+    args: &str,
+    room: &Room,
+    sender: &UserId,
+    event: &EventId,
+    store: &Store,
+    id: RequestId,
+) -> Result<Option<String>, String> {
+    let (action, rest) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+
+    match action {
+        "" => Err(USAGE.into()),
+        "vote" if rest.trim().is_empty() => Err(USAGE.into()),
+        "vote" => {
+            let flavor = rest.trim().parse::<Flavor>()?;
+
+            store
+                .update(|state| {
+                    state
+                        .rooms
+                        .entry(room.room_id().to_owned())
+                        .or_default()
+                        .theme_votes
+                        .insert(sender.to_owned(), flavor)
+                })
+                .await
+                .map_err(|err| err.to_string())?;
+
+            if policy::react(store, room, event, "✅").await {
+                return Ok(None);
+            }
+
+            Ok(Some(format!(
+                "Voted for {flavor}\n\n{}",
+                tally(room, store).await
+            )))
+        }
+        "votes" => Ok(Some(tally(room, store).await)),
+        "apply" => {
+            if !policy::is_moderator(room, sender).await {
+                return Err("Only moderators can apply the vote".into());
+            }
+
+            let Some(winner) = winner(&store.room(room.room_id()).await.theme_votes) else {
+                return Err("Nobody voted yet".into());
+            };
+
+            store
+                .update(|state| {
+                    let settings = state.rooms.entry(room.room_id().to_owned()).or_default();
+
+                    settings.flavor = Some(winner);
+                    settings.theme_votes.clear();
+                })
+                .await
+                .map_err(|err| err.to_string())?;
+
+            println!("[{id}] Applied the vote, the room flavor is now {winner}");
+            Ok(Some(format!(
+                "{winner} won, renders in this room now default to it"
+            )))
+        }
+        flavor => {
+            if !rest.trim().is_empty() {
+                return Err(USAGE.into());
+            }
+            let flavor = flavor.parse::<Flavor>()?;
+            if !policy::is_moderator(room, sender).await {
+                return Err(
+                    "Only moderators can set the room flavor, vote for it with `,typ theme vote`"
+                        .into(),
+                );
+            }
+
+            store
+                .update(|state| {
+                    state
+                        .rooms
+                        .entry(room.room_id().to_owned())
+                        .or_default()
+                        .flavor = Some(flavor)
+                })
+                .await
+                .map_err(|err| err.to_string())?;
+
+            println!("[{id}] Set the room flavor to {flavor}");
+            Ok(Some(format!(
+                "Renders in this room now default to {flavor}"
+            )))
+        }
+    }
+}
+
+/// Describe the current votes of `room`.
+async fn tally(room: &Room, store: &Store) -> String {
+    let votes = store.room(room.room_id()).await.theme_votes;
+    if votes.is_empty() {
+        return "No votes yet".into();
+    }
+
+    let counts = count(&votes);
+    let mut lines = vec!["Votes:".to_owned()];
+    for flavor in Flavor::ALL {
+        if let Some(count) = counts.get(&flavor) {
+            lines.push(format!("- {flavor}: {count}"));
+        }
+    }
+
+    lines.join("\n")
+}
+
+fn count(votes: &HashMap<impl Sized, Flavor>) -> HashMap<Flavor, usize> {
+    let mut counts = HashMap::new();
+    for flavor in votes.values() {
+        *counts.entry(*flavor).or_insert(0) += 1;
+    }
+
+    counts
+}
+
+/// The flavor with the most votes, ties going to the first in [`Flavor::ALL`].
+fn winner(votes: &HashMap<impl Sized, Flavor>) -> Option<Flavor> {
+    let counts = count(votes);
+
+    Flavor::ALL
+        .into_iter()
+        .filter_map(|flavor| Some((flavor, *counts.get(&flavor)?)))
+        .rev()
+        .max_by_key(|(_, count)| *count)
+        .map(|(flavor, _)| flavor)
+}
@@ -0,0 +1,25 @@
+use std::{
+    fmt,
+    hash::{BuildHasher, RandomState},
+    time::SystemTime,
+};
+
+/// A short ID attached to a single render, shown to users as `ref: ab12cd` so
+/// reports can be matched with the logs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RequestId(u32);
+
+impl RequestId {
+    pub fn new() -> Self {
+        // `RandomState` is randomly seeded, which is plenty for a log reference.
+        let hash = RandomState::new().hash_one(SystemTime::now());
+
+        Self((hash & 0xff_ffff) as u32)
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:06x}", self.0)
+    }
+}
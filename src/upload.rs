@@ -0,0 +1,118 @@
+use matrix_sdk::{
+    Client,
+    ruma::{OwnedMxcUri, api::client::error::ErrorKind},
+};
+use mime::Mime;
+use sha2::{Digest, Sha256};
+
+use crate::{matrix::Media, metrics, retry, state::Store, trace::RequestId};
+
+/// Outputs at least this big go through the async (preallocated) upload path.
+const LARGE_UPLOAD: usize = 1024 * 1024;
+
+/// Upload rendered output like [`upload_with_retry`], reusing the MXC URI of
+/// an earlier upload of the same bytes.
+pub async fn upload_cached(
+    client: &Client,
+    store: &Store,
+    content_type: &Mime,
+    data: Vec<u8>,
+    id: RequestId,
+) -> anyhow::Result<OwnedMxcUri> {
+    let hash = Sha256::digest(&data)
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect::<String>();
+
+    match store.media(&hash).await {
+        Ok(Some(uri)) => {
+            println!("[{id}] Reusing the upload {uri}");
+            return Ok(uri);
+        }
+        Ok(None) => {}
+        Err(err) => eprintln!("[{id}] Couldn't look up the media cache: {err}"),
+    }
+
+    let uri = upload_with_retry(client, content_type, data, id).await?;
+    if let Err(err) = store.set_media(&hash, &uri).await {
+        eprintln!("[{id}] Couldn't cache the upload {uri}: {err}");
+    }
+
+    Ok(uri)
+}
+
+/// Upload rendered output, retrying from scratch when [`retry::delay`] says
+/// it's worth it.
+///
+/// Large outputs (e.g. multi-megabyte PDFs) reserve their MXC URI once, and
+/// every attempt uploads to that same URI, so a retry only has to resend the
+/// bytes we already hold, never re-render, and a failed attempt doesn't leave
+/// an unused reservation behind. Homeservers without async upload support
+/// fall back to a plain upload.
+pub async fn upload_with_retry(
+    media: &(impl Media + Sync),
+    content_type: &Mime,
+    data: Vec<u8>,
+    id: RequestId,
+) -> anyhow::Result<OwnedMxcUri> {
+    let reserved = if data.len() >= LARGE_UPLOAD {
+        match media.reserve_uri().await {
+            Ok(uri) => Some(uri),
+            Err(err) => {
+                // Also when the homeserver doesn't implement async uploads.
+                if !matches!(
+                    err.client_api_error_kind(),
+                    Some(ErrorKind::Unrecognized | ErrorKind::NotFound)
+                ) {
+                    eprintln!("[{id}] Couldn't reserve an MXC URI, uploading directly: {err}");
+                }
+                None
+            }
+        }
+    } else {
+        None
+    };
+    let mut attempt = 1;
+
+    loop {
+        let result = match &reserved {
+            Some(uri) => media
+                .upload_reserved(uri, content_type, data.clone())
+                .await
+                .map(|()| uri.clone()),
+            None => media.upload_media(content_type, data.clone()).await,
+        };
+
+        match (result, &reserved) {
+            (Ok(uri), _) => {
+                metrics::UPLOAD_BYTES.add(data.len() as u64);
+                return Ok(uri);
+            }
+            // An earlier attempt went through after all, only its response
+            // got lost.
+            (Err(err), Some(uri))
+                if attempt > 1
+                    && matches!(
+                        err.client_api_error_kind(),
+                        Some(ErrorKind::CannotOverwriteMedia)
+                    ) =>
+            {
+                metrics::UPLOAD_BYTES.add(data.len() as u64);
+                return Ok(uri.clone());
+            }
+            (Err(err), _) => {
+                let Some(delay) = retry::delay(&err, attempt) else {
+                    return Err(err.into());
+                };
+                eprintln!(
+                    "[{id}] Upload of {} bytes failed (attempt {attempt}/{}), retrying in {delay:?}: {err}",
+                    data.len(),
+                    retry::MAX_ATTEMPTS
+                );
+
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+        }
+    }
+}
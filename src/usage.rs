@@ -0,0 +1,212 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use matrix_sdk::{
+    Room,
+    ruma::{OwnedRoomId, OwnedUserId, RoomId, UserId},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    diagnostics,
+    options::{Engine, Flavor, RenderOptions},
+    policy, render,
+    state::Store,
+};
+
+/// How many days of usage are kept, and shown on the dashboard.
+pub const DAYS: u64 = 30;
+
+/// How many error kinds the dashboard lists.
+const TOP_ERRORS: usize = 5;
+
+/// How long an error kind can be.
+const KIND_LENGTH: usize = 60;
+
+/// The renders of a room, or of every room, by day since the epoch.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Usage {
+    pub days: BTreeMap<u64, Day>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Day {
+    pub renders: u32,
+    pub failures: u32,
+    /// Summed over the renders.
+    pub latency_ms: u64,
+    /// How often each kind of error happened.
+    pub errors: HashMap<String, u32>,
+    /// Summed over the renders that weren't cached.
+    #[serde(default)]
+    pub compile_ms: u64,
+    /// How many renders weren't cached.
+    #[serde(default)]
+    pub compiles: u32,
+    /// How many renders each user asked for.
+    #[serde(default)]
+    pub users: HashMap<OwnedUserId, u32>,
+    /// How many renders each room had, only counted for every room.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub rooms: HashMap<OwnedRoomId, u32>,
+}
+
+/// One render, as counted in the usage.
+pub struct Render<'a> {
+    pub room: &'a RoomId,
+    pub user: &'a UserId,
+    pub latency: Duration,
+    /// Zero if the result was cached.
+    pub compile: Duration,
+    /// The kind of error it failed with.
+    pub error: Option<&'a str>,
+}
+
+impl Day {
+    fn count(&mut self, render: &Render) {
+        self.renders += 1;
+        self.latency_ms += render.latency.as_millis() as u64;
+        if !render.compile.is_zero() {
+            self.compiles += 1;
+            self.compile_ms += render.compile.as_millis() as u64;
+        }
+        *self.users.entry(render.user.to_owned()).or_default() += 1;
+        if let Some(error) = render.error {
+            self.failures += 1;
+            *self.errors.entry(error.to_owned()).or_default() += 1;
+        }
+    }
+}
+
+fn today() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        / 86400
+}
+
+/// What kind of error the compiler `output` is: the message of its first
+/// diagnostic, or its first line.
+pub fn kind(output: &str, options: &RenderOptions) -> String {
+    let kind = match options.engine {
+        Engine::Typst => diagnostics::parse(output, render::line_offset(options))
+            .into_iter()
+            .next()
+            .map(|diagnostic| diagnostic.message),
+        _ => None,
+    }
+    .unwrap_or_else(|| output.lines().next().unwrap_or_default().to_owned());
+
+    kind.chars().take(KIND_LENGTH).collect()
+}
+
+/// Count `render` in the usage of its room and of every room.
+pub async fn record(store: &Store, render: Render<'_>) {
+    let today = today();
+    let room = render.room;
+
+    let result = store
+        .update_usage(Some(room), |usage| {
+            usage.days.retain(|day, _| day + DAYS > today);
+            usage.days.entry(today).or_default().count(&render);
+        })
+        .await;
+    if let Err(err) = result {
+        eprintln!("Couldn't record the usage of {room}: {err}");
+    }
+
+    let result = store
+        .update_usage(None, |usage| {
+            usage.days.retain(|day, _| day + DAYS > today);
+            let day = usage.days.entry(today).or_default();
+            day.count(&render);
+            *day.rooms.entry(room.to_owned()).or_default() += 1;
+        })
+        .await;
+    if let Err(err) = result {
+        eprintln!("Couldn't record the usage of every room: {err}");
+    }
+}
+
+/// Handle `,typ dashboard`, returning the typst source of the dashboard or
+/// the text of an error reply.
+pub async fn command(room: &Room, sender: &UserId, store: &Store) -> Result<String, String> {
+    if !policy::is_moderator(room, sender).await {
+        return Err("Only moderators can see the dashboard".into());
+    }
+
+    let usage = store
+        .usage(Some(room.room_id()))
+        .await
+        .map_err(|err| err.to_string())?;
+    let flavor = policy::flavor(&store.room(room.room_id()).await).unwrap_or_default();
+
+    Ok(dashboard(&usage, flavor))
+}
+
+/// A standalone typst document charting `usage`, themed with `flavor`.
+fn dashboard(usage: &Usage, flavor: Flavor) -> String {
+    let (base, text) = flavor.colors();
+    let today = today();
+
+    let counts: Vec<_> = (today + 1 - DAYS..=today)
+        .map(|day| {
+            usage
+                .days
+                .get(&day)
+                .map_or(0, |day| day.renders)
+                .to_string()
+        })
+        .collect();
+
+    let renders: u32 = usage.days.values().map(|day| day.renders).sum();
+    let failures: u32 = usage.days.values().map(|day| day.failures).sum();
+    let latency: u64 = usage.days.values().map(|day| day.latency_ms).sum();
+    let average = latency.checked_div(renders.into()).unwrap_or(0);
+
+    let mut errors: HashMap<&str, u32> = HashMap::new();
+    for day in usage.days.values() {
+        for (kind, count) in &day.errors {
+            *errors.entry(kind).or_default() += count;
+        }
+    }
+    let mut errors: Vec<_> = errors.into_iter().collect();
+    errors.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+
+    let mut document = format!(
+        r##"#set page(width: 18cm, height: auto, margin: 16pt, fill: rgb("#{base}"))
+#set text(size: 12pt, fill: rgb("#{text}"))
+
+= Renders over the last {DAYS} days
+
+#let counts = ({counts},)
+#let peak = calc.max(1, ..counts)
+#grid(
+  columns: (1fr,) * counts.len(),
+  column-gutter: 2pt,
+  align: bottom,
+  ..counts.map(n => rect(width: 100%, height: 4cm * n / peak, fill: rgb("#89b4fa"))),
+)
+
+{renders} renders, {failures} failed, {average} ms on average
+
+== Top errors
+"##,
+        counts = counts.join(", "),
+    );
+
+    if errors.is_empty() {
+        document.push_str("No errors\n");
+    }
+    for (kind, count) in errors.iter().take(TOP_ERRORS) {
+        document.push_str(&format!(
+            "+ #\"{}\" ({count})\n",
+            render::escape_string(kind)
+        ));
+    }
+
+    document
+}
@@ -0,0 +1,96 @@
+use std::sync::Mutex;
+
+use tokio::process::Command;
+
+use crate::{config, sandbox};
+
+/// The oldest typst the preamble and the flags of renders are known to work
+/// with.
+const MIN_TYPST: (u32, u32, u32) = (0, 13, 0);
+
+/// What to tell the admin room about typst, taken with [`alert`].
+static ALERT: Mutex<Option<String>> = Mutex::new(None);
+
+/// The matrix-sdk the bot was built with, from the lockfile.
+fn matrix_sdk() -> &'static str {
+    include_str!("../Cargo.lock")
+        .split("[[package]]")
+        .find_map(|package| {
+            package
+                .trim()
+                .strip_prefix("name = \"matrix-sdk\"\nversion = \"")?
+                .split_once('"')
+                .map(|(version, _)| version)
+        })
+        .unwrap_or("unknown")
+}
+
+/// The version of the installed typst, like `0.13.1`, if there is one.
+pub async fn typst() -> Option<String> {
+    let output = Command::new(sandbox::which("typst")?)
+        .arg("--version")
+        .output()
+        .await
+        .ok()?;
+
+    // `typst 0.13.1 (8ace67d9 @ 2025-03-13)`
+    String::from_utf8_lossy(&output.stdout)
+        .split_whitespace()
+        .nth(1)
+        .map(Into::into)
+}
+
+/// `version` as numbers, ignoring pre-release suffixes like `-rc1`.
+fn parse(version: &str) -> Option<(u32, u32, u32)> {
+    let mut parts = version
+        .split(['-', '+'])
+        .next()?
+        .split('.')
+        .map(|part| part.parse().ok());
+
+    Some((
+        parts.next()??,
+        parts.next()??,
+        parts.next().flatten().unwrap_or(0),
+    ))
+}
+
+/// Make sure typst is installed and recent enough, failing if
+/// `render.require_typst` is on and warning the admin room otherwise.
+pub async fn check() -> anyhow::Result<()> {
+    let (major, minor, patch) = MIN_TYPST;
+    let problem = match typst().await {
+        Some(version) if parse(&version).is_some_and(|version| version >= MIN_TYPST) => {
+            println!("Rendering with typst {version}");
+            return Ok(());
+        }
+        Some(version) => {
+            format!("typst {version} is installed, renders need {major}.{minor}.{patch} or newer")
+        }
+        None => "typst can't be found, renders need it installed".into(),
+    };
+
+    if config::get().render.require_typst {
+        anyhow::bail!(problem);
+    }
+
+    eprintln!("{problem}");
+    *ALERT.lock().unwrap() = Some(problem);
+    Ok(())
+}
+
+/// Take the pending alert about typst missing or being too old.
+pub fn alert() -> Option<String> {
+    ALERT.lock().unwrap().take()
+}
+
+/// Handle `,version`, reporting what the bot runs on.
+pub async fn command() -> String {
+    let typst = typst().await.unwrap_or_else(|| "not installed".into());
+
+    format!(
+        "typit {}, matrix-sdk {}, typst {typst}",
+        env!("CARGO_PKG_VERSION"),
+        matrix_sdk()
+    )
+}
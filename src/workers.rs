@@ -0,0 +1,150 @@
+use std::{path::PathBuf, sync::Mutex};
+
+use tokio::process::Child;
+
+use crate::{
+    config,
+    options::{DEFAULT_PPI, Engine, Format, RenderOptions},
+    render, tasks,
+};
+
+/// A `typst compile` started ahead of a render, waiting for its document on
+/// stdin.
+///
+/// typst looks up the fonts before reading the document, so by the time a
+/// render takes the worker, starting the process and the font search are
+/// done.
+pub struct Worker {
+    pub child: Child,
+    /// The scratch directory the pages are written to.
+    pub dir: PathBuf,
+}
+
+/// The workers waiting for a render.
+static POOL: Mutex<Vec<Worker>> = Mutex::new(Vec::new());
+
+/// Held while starting workers, so two refills don't both start the missing
+/// ones, and a drain doesn't race a refill into keeping a stale worker.
+static REFILL: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// Whether renders with `options` have the command line of the workers, which
+/// are started for the default options.
+fn pooled(options: &RenderOptions) -> bool {
+    options.engine == Engine::Typst
+        && options.format == Format::Png
+        && options.ppi.unwrap_or(DEFAULT_PPI) == DEFAULT_PPI
+        && !options.safe
+}
+
+/// Start a worker, on a blocking thread since creating its directory and
+/// the process block.
+async fn spawn() -> anyhow::Result<Worker> {
+    tokio::task::spawn_blocking(|| {
+        let dir = render::scratch_path();
+        std::fs::create_dir_all(&dir)?;
+
+        let mut command = render::typst_command(&RenderOptions::default(), &dir);
+        match render::spawn(&mut command, true) {
+            Ok(child) => Ok(Worker { child, dir }),
+            Err(err) => {
+                let _ = std::fs::remove_dir_all(&dir);
+                Err(err.into())
+            }
+        }
+    })
+    .await?
+}
+
+/// Start workers until there are `render.workers` of them.
+async fn refill() -> anyhow::Result<()> {
+    let _refill = REFILL.lock().await;
+    while POOL.lock().unwrap().len() < config::get().render.workers {
+        let worker = spawn().await?;
+        POOL.lock().unwrap().push(worker);
+    }
+
+    Ok(())
+}
+
+/// Stop the waiting workers, their processes are killed on drop, and start
+/// new ones with the current config.
+async fn drain() -> anyhow::Result<()> {
+    {
+        let _refill = REFILL.lock().await;
+        let stale = std::mem::take(&mut *POOL.lock().unwrap());
+        let dirs = stale
+            .into_iter()
+            .map(|worker| worker.dir)
+            .collect::<Vec<_>>();
+        tokio::task::spawn_blocking(move || {
+            for dir in dirs {
+                let _ = std::fs::remove_dir_all(dir);
+            }
+        })
+        .await?;
+    }
+
+    refill().await
+}
+
+/// Start the first workers, `render.workers` of them.
+pub async fn start() {
+    let size = config::get().render.workers;
+    if size == 0 {
+        return;
+    }
+
+    match refill().await {
+        Ok(()) => println!("Keeping {size} typst worker(s) warm"),
+        Err(err) => eprintln!("Couldn't start the typst workers: {err}"),
+    }
+}
+
+/// Replace the workers on every config reload, they were started with the
+/// old fonts, limits and command line.
+pub async fn keep_fresh() {
+    let mut reloaded = config::subscribe();
+    while reloaded.changed().await.is_ok() {
+        if let Err(err) = drain().await {
+            eprintln!("Couldn't restart the typst workers: {err}");
+        }
+    }
+}
+
+/// A warm worker for a render with `options`, if it can use one, starting
+/// another in its place in the background.
+pub fn take(options: &RenderOptions) -> Option<Worker> {
+    if !pooled(options) {
+        return None;
+    }
+
+    let mut dead = vec![];
+    let worker = {
+        let mut pool = POOL.lock().unwrap();
+        loop {
+            let Some(mut worker) = pool.pop() else {
+                break None;
+            };
+            // One that died while waiting, e.g. killed by the OOM killer, is
+            // of no use.
+            if let Ok(None) = worker.child.try_wait() {
+                break Some(worker);
+            }
+            dead.push(worker.dir);
+        }
+    };
+
+    tasks::spawn("workers", async move {
+        let _ = tokio::task::spawn_blocking(move || {
+            for dir in dead {
+                let _ = std::fs::remove_dir_all(dir);
+            }
+        })
+        .await;
+        if let Err(err) = refill().await {
+            eprintln!("Couldn't start a typst worker: {err}");
+        }
+    });
+
+    worker
+}